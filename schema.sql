@@ -33,4 +33,127 @@ CREATE TABLE IF NOT EXISTS config (
 CREATE TABLE IF NOT EXISTS device_codes (
 	code BLOB NOT NULL,
 	date_created INTEGER NOT NULL
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+	id INTEGER PRIMARY KEY,
+	url TEXT NOT NULL,
+	events TEXT NOT NULL,
+	folder TEXT,
+	secret TEXT NOT NULL,
+	date_created INTEGER NOT NULL
+);
+
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+	id INTEGER PRIMARY KEY,
+	webhook_id INTEGER NOT NULL,
+	event TEXT NOT NULL,
+	document_id TEXT NOT NULL,
+	payload TEXT NOT NULL,
+	status TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	next_attempt INTEGER NOT NULL,
+	last_status_code INTEGER,
+	last_error TEXT,
+	date_created INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_status ON webhook_deliveries (status, next_attempt);
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
+
+
+CREATE TABLE IF NOT EXISTS trash (
+	id TEXT PRIMARY KEY NOT NULL,
+	previous_parent TEXT NOT NULL,
+	date_trashed INTEGER NOT NULL
+);
+
+
+CREATE TABLE IF NOT EXISTS thumbnails (
+	id TEXT NOT NULL,
+	version INTEGER NOT NULL,
+	page INTEGER NOT NULL,
+	size TEXT NOT NULL,
+	format TEXT NOT NULL,
+	data BLOB NOT NULL,
+	date_created INTEGER NOT NULL,
+	PRIMARY KEY (id, version, page, size, format)
+);
+
+
+CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(
+	id UNINDEXED,
+	page UNINDEXED,
+	chapter UNINDEXED,
+	name,
+	path,
+	text,
+	tokenize = 'unicode61 remove_diacritics 2'
+);
+
+
+-- Which version of each document is in search_index, and a hash of its data so metadata-only changes don't re-extract its text
+CREATE TABLE IF NOT EXISTS search_documents (
+	id TEXT PRIMARY KEY NOT NULL,
+	version INTEGER NOT NULL,
+	data_hash TEXT NOT NULL
+);
+
+
+-- What documents' PDFs and EPUBs say about themselves, read when they're added
+CREATE TABLE IF NOT EXISTS document_info (
+	id TEXT PRIMARY KEY NOT NULL,
+	title TEXT,
+	authors TEXT NOT NULL,
+	date TEXT,
+	cover BLOB,
+	cover_type TEXT
+);
+
+
+-- Tags from documents' .content: the document's own if page_id is NULL, otherwise those of the page (counting from 0)
+CREATE TABLE IF NOT EXISTS tags (
+	id TEXT NOT NULL,
+	tag TEXT NOT NULL,
+	page_id TEXT,
+	page INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_tags_id ON tags (id);
+CREATE INDEX IF NOT EXISTS idx_tags_tag ON tags (tag);
+
+
+-- Which version of each document the tags table has the tags of
+CREATE TABLE IF NOT EXISTS tag_documents (
+	id TEXT PRIMARY KEY NOT NULL,
+	version INTEGER NOT NULL
+);
+
+
+-- Filing rules; see rules.rs
+CREATE TABLE IF NOT EXISTS rules (
+	id INTEGER PRIMARY KEY,
+	name TEXT NOT NULL,
+	enabled INTEGER NOT NULL,
+	events TEXT NOT NULL,
+	conditions TEXT NOT NULL,
+	actions TEXT NOT NULL,
+	date_created INTEGER NOT NULL
+);
+
+
+-- Documents delivered at a set time, or on a schedule; see deliveries.rs
+CREATE TABLE IF NOT EXISTS deliveries (
+	id INTEGER PRIMARY KEY,
+	name TEXT NOT NULL,
+	folder TEXT NOT NULL,
+	source TEXT NOT NULL,
+	data BLOB,
+	schedule TEXT,
+	next_run INTEGER,
+	document_id TEXT,
+	last_run INTEGER,
+	last_error TEXT,
+	data_hash TEXT,
+	date_created INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_deliveries_next_run ON deliveries (next_run);
@@ -1,3 +1,4 @@
+use super::{deliveries, documents, pages, rules, search, tags, trash, webhooks};
 use crate::{
 	auth::{UserTokenClaims, ValidatedAdminToken},
 	config::ServerConfig,
@@ -28,18 +29,47 @@ pub fn service() -> impl actix_web::dev::HttpServiceFactory {
 				.content_type("application/json")
 				.body(include_str!("../../admin-webapp/dist/main.bundle.js.map"))
 		}))
-		.service(web::resource("/pdf.worker.js").to(|| async {
-			HttpResponse::Ok()
-				.content_type("text/javascript")
-				.body(include_str!("../../admin-webapp/dist/pdf.worker.js"))
-		}))
-		.service(web::resource("/pdf.worker.js.map").to(|| async {
-			HttpResponse::Ok()
-				.content_type("application/json")
-				.body(include_str!("../../admin-webapp/dist/pdf.worker.js.map"))
-		}))
 		.service(new_device_code)
 		.service(new_user_token)
+		.service(webhooks::list)
+		.service(webhooks::create)
+		.service(webhooks::delete)
+		.service(webhooks::deliveries)
+		.service(webhooks::resend)
+		.service(trash::list)
+		.service(trash::stats)
+		.service(trash::restore)
+		.service(trash::empty)
+		.service(documents::add)
+		.service(documents::create_folder)
+		.service(documents::create_notebook)
+		.service(pages::merge)
+		.service(pages::split)
+		.service(pages::extract)
+		.service(pages::reorder)
+		.service(documents::info)
+		.service(documents::cover)
+		.service(documents::render_page)
+		.service(documents::export_pdf)
+		.service(documents::export_markdown)
+		.service(documents::export_folder_markdown)
+		.service(documents::diff_versions)
+		.service(documents::thumbnail)
+		.service(search::search)
+		.service(tags::list)
+		.service(tags::tagged)
+		.service(tags::change)
+		.service(rules::list)
+		.service(rules::create)
+		.service(rules::run)
+		.service(rules::get)
+		.service(rules::update)
+		.service(rules::delete)
+		.service(deliveries::list)
+		.service(deliveries::create)
+		.service(deliveries::get)
+		.service(deliveries::delete)
+		.service(deliveries::run)
 }
 
 
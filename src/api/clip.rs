@@ -0,0 +1,74 @@
+use crate::{
+	auth::{UserOrAdminClaims, ValidatedUserOrAdminToken},
+	clipper::{self, ClipError, Fetcher},
+	config::ServerConfig,
+	error::ServerError,
+	events::{EventBus, EventSource},
+	library, tree,
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+#[derive(Deserialize)]
+struct ClipRequest {
+	/// Where the page is from; it's fetched from here if `html` isn't given
+	url: Option<String>,
+	/// The page as the browser has it, e.g. so pages behind a login can be clipped
+	html: Option<String>,
+	/// Overrides the page's own title
+	title: Option<String>,
+}
+
+
+/// Saves the article in a web page to the clippings folder as an EPUB, for browser extensions and bookmarklets.
+/// The request body is a JSON `ClipRequest`, which can be large since it may hold the whole page.
+#[actix_web::post("/clip")]
+async fn clip(
+	token: ValidatedUserOrAdminToken,
+	body: web::Bytes,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+	server_config: web::Data<ServerConfig>,
+) -> Result<HttpResponse, ServerError> {
+	let request: ClipRequest = match serde_json::from_slice(&body) {
+		Ok(request) => request,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid request: {}", err))),
+	};
+	let source = match &token.0 {
+		UserOrAdminClaims::User(claims) => EventSource::new(&claims.device_desc, &claims.device_id),
+		UserOrAdminClaims::Admin => EventSource::admin(),
+	};
+
+	let fetcher = Fetcher::new(&server_config.options.clip_fetch_origins);
+	let clipping = match clipper::clip(request.url.as_deref(), request.html, request.title, &fetcher).await? {
+		Ok(clipping) => clipping,
+		Err(err @ ClipError::FetchFailed(_)) => return Ok(HttpResponse::BadGateway().body(err.to_string())),
+		Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+	};
+
+	let (folder, folder_event) = library::folder_by_name(&server_config.options.clip_folder, tree::ROOT, source.clone(), &db_pool)
+		.await?
+		.map_err(|err| anyhow!("Creating the clippings folder: {}", err))?;
+	let document = library::archive_epub(clipping.epub);
+	let event = library::add_document(&document, &clipping.title, &folder, source, &db_pool)
+		.await?
+		.map_err(|err| anyhow!("Adding the clipping: {}", err))?;
+
+	let response = json!({
+		"id": event.document.id,
+		"version": event.document.version,
+		"name": event.document.name,
+		"parent": event.document.parent,
+	});
+	if let Some(folder_event) = folder_event {
+		folder_event.publish(&event_bus);
+	}
+	event.publish(&event_bus);
+
+	Ok(HttpResponse::Ok().json(response))
+}
@@ -0,0 +1,215 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	config::ServerConfig,
+	cron::Schedule,
+	database,
+	deliveries::{self, Delivery, DeliveryScheduler, RunDue, Source},
+	error::ServerError,
+	library,
+	rmdoc::planner::PlannerLayout,
+	tree,
+	tree::FolderTree,
+	MAX_PLANNER_DAYS,
+};
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+
+
+#[actix_web::get("/deliveries")]
+async fn list(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(deliveries::list_deliveries(&db_pool).await?))
+}
+
+
+#[derive(Deserialize)]
+struct CreateDeliveryQuery {
+	name: String,
+	/// ID of the folder to deliver to; the root by default
+	folder: Option<String>,
+	/// When to deliver, e.g. "2026-10-19T06:00" (local time) or "2026-10-19T06:00:00Z"
+	at: Option<String>,
+	/// A cron expression, to deliver again on a schedule
+	schedule: Option<String>,
+	/// A file on the server to read on each run, instead of the request body.  For planners, an .ics file or a directory of them.
+	path: Option<PathBuf>,
+	/// Deliver a planner made from calendars (the body, or `path`), daily or weekly
+	planner: Option<PlannerLayout>,
+	/// The planner's first day; today by default, or for weekly planners, this week's Monday
+	start: Option<NaiveDate>,
+	/// How many days the planner covers; a week of daily pages, or four weekly pages, by default
+	days: Option<u32>,
+}
+
+
+/// Queues a file (the request body), or a file on the server, to be added to the library at a set time and/or on a schedule.
+/// Uploaded files are checked now, so that a file that can't be added is turned away rather than failing later.  With
+/// `planner`, delivers a planner made from the calendars instead.
+#[actix_web::post("/deliveries")]
+async fn create(
+	_admin_token: ValidatedAdminToken,
+	req: HttpRequest,
+	query: web::Query<CreateDeliveryQuery>,
+	body: web::Bytes,
+	db_pool: web::Data<SqlitePool>,
+	delivery_scheduler: web::Data<Addr<DeliveryScheduler>>,
+	server_config: web::Data<ServerConfig>,
+) -> Result<HttpResponse, ServerError> {
+	let name = query.name.trim().to_owned();
+	if name.is_empty() {
+		return Ok(HttpResponse::BadRequest().body("The name can't be empty"));
+	}
+
+	let schedule = match query.schedule.as_deref().map(str::parse::<Schedule>) {
+		Some(Ok(schedule)) => Some(schedule),
+		Some(Err(err)) => return Ok(HttpResponse::BadRequest().body(format!("Invalid schedule: {}", err))),
+		None => None,
+	};
+	let at = match query.at.as_deref().map(parse_time) {
+		Some(Some(at)) => Some(at.timestamp()),
+		Some(None) => return Ok(HttpResponse::BadRequest().body("Invalid time; use e.g. 2026-10-19T06:00 or 2026-10-19T06:00:00Z")),
+		None => None,
+	};
+	let next_run = match (at, &schedule) {
+		(Some(at), _) => at,
+		(None, Some(schedule)) => match deliveries::next_run(schedule) {
+			Some(next_run) => next_run,
+			None => return Ok(HttpResponse::BadRequest().body("The schedule never comes round")),
+		},
+		(None, None) => return Ok(HttpResponse::BadRequest().body("A delivery needs a time (at) and/or a schedule")),
+	};
+
+	let folder = query.folder.clone().unwrap_or_else(|| tree::ROOT.to_owned());
+	let tree = FolderTree::from_metadata(&database::list_metadata(&**db_pool).await?);
+	if let Err(err) = tree.validate_parent(&uuid::Uuid::new_v4().to_string(), &folder) {
+		return Ok(HttpResponse::BadRequest().body(format!("Invalid folder: {}", err)));
+	}
+
+	let (source, data) = match (query.planner, &query.path) {
+		(_, Some(path)) if !path.is_absolute() => return Ok(HttpResponse::BadRequest().body("The path has to be absolute")),
+		(Some(layout), path) => {
+			let today = Local::today().naive_local();
+			let start = query.start.unwrap_or(match layout {
+				PlannerLayout::Daily => today,
+				PlannerLayout::Weekly => today - Duration::days(today.weekday().num_days_from_monday() as i64),
+			});
+			let days = query.days.unwrap_or(match layout {
+				PlannerLayout::Daily => 7,
+				PlannerLayout::Weekly => 28,
+			});
+			if !(1..=MAX_PLANNER_DAYS).contains(&days) {
+				return Ok(HttpResponse::BadRequest().body(format!("A planner can cover 1 to {} days", MAX_PLANNER_DAYS)));
+			}
+
+			match path {
+				Some(path) if !path.exists() => {
+					return Ok(HttpResponse::BadRequest().body(format!("No such file or directory: {}", path.display())));
+				}
+				None if !body.windows(15).any(|window| window == b"BEGIN:VCALENDAR") => {
+					return Ok(HttpResponse::BadRequest().body("The body isn't an iCalendar file"));
+				}
+				_ => (),
+			}
+
+			let source = Source::Planner {
+				path: path.clone(),
+				layout,
+				start,
+				days,
+			};
+			(source, path.is_none().then_some(body))
+		}
+		(None, Some(path)) if !path.is_file() => return Ok(HttpResponse::BadRequest().body(format!("No such file: {}", path.display()))),
+		(None, Some(path)) => (Source::File { path: path.clone() }, None),
+		(None, None) => {
+			let content_type = req.content_type().to_owned();
+			let check = {
+				let (data, content_type, name) = (body.to_vec(), content_type.clone(), name.clone());
+				let layout = server_config.options.image_page_layout();
+				web::block(move || library::archive_file(data, &content_type, &name, &layout))
+					.await
+					.context("Converting document")??
+			};
+			if let Err(err) = check {
+				return Ok(HttpResponse::BadRequest().body(err.to_string()));
+			}
+
+			(Source::Upload { content_type }, Some(body))
+		}
+	};
+
+	let mut delivery = Delivery {
+		id: 0,
+		name,
+		folder,
+		source,
+		schedule: query.schedule.as_ref().map(|schedule| schedule.trim().to_owned()),
+		next_run: Some(next_run),
+		document_id: None,
+		last_run: None,
+		last_error: None,
+		data_hash: None,
+		date_created: Utc::now().timestamp(),
+	};
+	delivery.id = deliveries::add_delivery(&delivery, data.as_deref(), &db_pool).await?;
+
+	// In case it's due already
+	delivery_scheduler.do_send(RunDue);
+
+	Ok(HttpResponse::Ok().json(delivery))
+}
+
+
+/// Times with an offset, or in the server's time zone without one
+fn parse_time(time: &str) -> Option<DateTime<Local>> {
+	if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+		return Some(time.with_timezone(&Local));
+	}
+
+	["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S"]
+		.iter()
+		.find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+		.and_then(|time| Local.from_local_datetime(&time).earliest())
+}
+
+
+#[actix_web::get("/deliveries/{id}")]
+async fn get(_admin_token: ValidatedAdminToken, id: web::Path<i64>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	match deliveries::get_delivery(*id, &db_pool).await? {
+		Some(delivery) => Ok(HttpResponse::Ok().json(delivery)),
+		None => Ok(HttpResponse::NotFound().body("Not Found")),
+	}
+}
+
+
+/// Cancels a delivery, or stops one from recurring.  Documents it already delivered stay.
+#[actix_web::delete("/deliveries/{id}")]
+async fn delete(_admin_token: ValidatedAdminToken, id: web::Path<i64>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	if !deliveries::delete_delivery(*id, &db_pool).await? {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	Ok(HttpResponse::Ok().finish())
+}
+
+
+/// Runs a delivery now, rather than waiting until it's due.  A recurring delivery then carries on with its schedule.
+#[actix_web::post("/deliveries/{id}/run")]
+async fn run(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<i64>,
+	db_pool: web::Data<SqlitePool>,
+	delivery_scheduler: web::Data<Addr<DeliveryScheduler>>,
+) -> Result<HttpResponse, ServerError> {
+	if !deliveries::run_now(*id, &db_pool).await? {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	delivery_scheduler.do_send(RunDue);
+
+	Ok(HttpResponse::Ok().finish())
+}
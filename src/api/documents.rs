@@ -0,0 +1,467 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	config::ServerConfig,
+	database,
+	error::ServerError,
+	events::{EventBus, EventSource},
+	export, library,
+	rmdoc::{
+		self, diff,
+		document_info::DocumentInfo,
+		lines::Lines,
+		markdown, pdf,
+		raster::ImageFormat,
+		svg::{self, SvgOptions},
+		FileType,
+	},
+	thumbnails::{self, ThumbnailSize},
+	tree, MAX_NEW_NOTEBOOK_PAGES,
+};
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+/// What's inside a document's latest version: its file type, its tags, and its pages and which of them have been written on.
+#[actix_web::get("/documents/{id}")]
+async fn info(_admin_token: ValidatedAdminToken, id: web::Path<String>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match rmdoc::load_document(&id, None, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such document")),
+	};
+
+	let pages: Vec<_> = document
+		.pages
+		.iter()
+		.map(|page| {
+			json!({
+				"id": page.id,
+				"template": page.template,
+				"annotated": page.lines.is_some(),
+				"tags": document
+					.content
+					.page_tags
+					.iter()
+					.flatten()
+					.filter(|tag| tag.page_id == page.id)
+					.map(|tag| &tag.name)
+					.collect::<Vec<_>>(),
+			})
+		})
+		.collect();
+	let document_info = library::get_document_info(&metadata.id, &db_pool).await?.map(|document_info| {
+		json!({
+			"title": document_info.title,
+			"authors": document_info.authors,
+			"date": document_info.date,
+			"has_cover": document_info.cover.is_some(),
+		})
+	});
+
+	Ok(HttpResponse::Ok().json(json!({
+		"id": metadata.id,
+		"version": metadata.version,
+		"name": metadata.name,
+		"file_type": document.file_type().as_str(),
+		"page_count": pages.len(),
+		"pages": pages,
+		"tags": document.content.tags.iter().flatten().map(|tag| &tag.name).collect::<Vec<_>>(),
+		"info": document_info,
+	})))
+}
+
+
+/// The cover image found in a document's EPUB when it was added
+#[actix_web::get("/documents/{id}/cover")]
+async fn cover(_admin_token: ValidatedAdminToken, id: web::Path<String>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	match library::get_document_info(&id, &db_pool)
+		.await?
+		.and_then(|document_info| document_info.cover)
+	{
+		Some(cover) => Ok(HttpResponse::Ok().content_type(cover.content_type).body(cover.data)),
+		None => Ok(HttpResponse::NotFound().body("No cover for this document")),
+	}
+}
+
+
+/// Renders a page (counting from 0) of a document's latest version as SVG.
+#[actix_web::get("/documents/{id}/pages/{page}.svg")]
+async fn render_page(
+	_admin_token: ValidatedAdminToken,
+	path: web::Path<(String, usize)>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let (id, page) = path.into_inner();
+	let (_, document) = match rmdoc::load_document(&id, None, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such document")),
+	};
+
+	let data = match document.pages.into_iter().nth(page) {
+		Some(page) => page.lines,
+		None => return Ok(HttpResponse::NotFound().body("No such page")),
+	};
+
+	// Parsing and rendering a page with a lot of strokes can take a while
+	let svg = web::block(move || -> anyhow::Result<String> {
+		let lines = match data {
+			Some(data) => Lines::parse(&data).with_context(|| format!("Parsing page {} of {}", page, id))?,
+			// Nothing has been drawn on this page
+			None => Lines::default(),
+		};
+		Ok(svg::render_svg(&lines, &SvgOptions::default()))
+	})
+	.await
+	.context("Rendering page")??;
+
+	Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+}
+
+
+#[derive(Deserialize)]
+struct ExportQuery {
+	version: Option<i64>,
+}
+
+
+/// Exports a PDF or notebook (by default its latest version) as a PDF, with the handwriting drawn onto its pages.
+#[actix_web::get("/documents/{id}/export.pdf")]
+async fn export_pdf(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	query: web::Query<ExportQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match rmdoc::load_document(&id, query.version, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such document or version")),
+	};
+
+	if document.file_type() == FileType::Epub {
+		return Ok(HttpResponse::BadRequest().body("Only PDFs and notebooks can be exported as PDF"));
+	}
+
+	// Drawing every page's handwriting can take a while
+	let exported = web::block(move || pdf::export_pdf(&document)).await.context("Exporting PDF")??;
+
+	Ok(HttpResponse::Ok()
+		.content_type("application/pdf")
+		.insert_header((
+			"Content-Disposition",
+			format!("attachment; filename=\"{}.pdf\"", attachment_name(&metadata.name)),
+		))
+		.body(exported))
+}
+
+
+/// Exports what's been typed and highlighted in a document's latest version as Markdown.
+#[actix_web::get("/documents/{id}/export.md")]
+async fn export_markdown(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match rmdoc::load_document(&id, None, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such document")),
+	};
+
+	let name = metadata.name.clone();
+	let exported = web::block(move || markdown::export_markdown(&document, &name))
+		.await
+		.context("Exporting Markdown")?;
+
+	Ok(HttpResponse::Ok()
+		.content_type("text/markdown; charset=utf-8")
+		.insert_header((
+			"Content-Disposition",
+			format!("attachment; filename=\"{}.md\"", attachment_name(&metadata.name)),
+		))
+		.body(exported))
+}
+
+
+#[derive(Deserialize)]
+struct FolderExportQuery {
+	/// The top level by default
+	folder: Option<String>,
+}
+
+
+/// Exports every document in a folder, including its subfolders, as Markdown, in a zip.
+#[actix_web::get("/export/markdown.zip")]
+async fn export_folder_markdown(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<FolderExportQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let folder = query.folder.as_deref().unwrap_or(tree::ROOT);
+
+	match export::folder_markdown(folder, &db_pool).await? {
+		Some(zip) => Ok(HttpResponse::Ok()
+			.content_type("application/zip")
+			.insert_header(("Content-Disposition", "attachment; filename=\"markdown.zip\""))
+			.body(zip)),
+		None => Ok(HttpResponse::NotFound().body("No such folder")),
+	}
+}
+
+
+/// A document's name, made safe for a Content-Disposition filename
+fn attachment_name(name: &str) -> String {
+	name.replace(|c: char| c == '"' || c == '\\' || !c.is_ascii() || c.is_ascii_control(), "_")
+}
+
+
+#[derive(Deserialize)]
+struct DiffQuery {
+	/// The version before `to` by default
+	from: Option<i64>,
+	/// The latest version by default
+	to: Option<i64>,
+}
+
+
+/// What changed between two versions of a document: pages added, removed and moved, and for each changed page, how many strokes
+/// were added and erased, and an SVG showing them.
+#[actix_web::get("/documents/{id}/diff")]
+async fn diff_versions(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	query: web::Query<DiffQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let (new_metadata, new) = match rmdoc::load_document(&id, query.to, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such document or version")),
+	};
+	let from = query.from.unwrap_or(new_metadata.version - 1);
+	let (old_metadata, old) = match rmdoc::load_document(&id, Some(from), &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body("No such version to compare with")),
+	};
+
+	// Comparing can take a while for pages with a lot of strokes
+	let diff = web::block(move || diff::diff(&old, &new)).await.context("Comparing versions")??;
+
+	Ok(HttpResponse::Ok().json(json!({
+		"id": new_metadata.id,
+		"from": old_metadata.version,
+		"to": new_metadata.version,
+		"pages_added": diff.pages_added,
+		"pages_removed": diff.pages_removed,
+		"pages_moved": diff.pages_moved,
+		"pages_changed": diff.pages_changed,
+	})))
+}
+
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+	#[serde(default)]
+	page: usize,
+	size: Option<String>,
+	format: Option<String>,
+}
+
+
+/// A PNG or WebP thumbnail of a page (by default the first) of a PDF or notebook's latest version.
+/// Thumbnails are rendered the first time they're asked for and cached until the document changes.
+#[actix_web::get("/thumbnail/{id}")]
+async fn thumbnail(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	query: web::Query<ThumbnailQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let size = match ThumbnailSize::parse(query.size.as_deref().unwrap_or("medium")) {
+		Some(size) => size,
+		None => return Ok(HttpResponse::BadRequest().body("Size must be small, medium or large")),
+	};
+	let format = match ImageFormat::parse(query.format.as_deref().unwrap_or("png")) {
+		Some(format) => format,
+		None => return Ok(HttpResponse::BadRequest().body("Format must be png or webp")),
+	};
+	let metadata = match database::get_metadata_by_id(&id, &**db_pool).await? {
+		Some(metadata) if metadata.file_type != tree::COLLECTION_TYPE => metadata,
+		_ => return Ok(HttpResponse::NotFound().body("No such document")),
+	};
+
+	let data = match thumbnails::get_cached(&id, metadata.version, query.page, size, format, &db_pool).await? {
+		Some(data) => data,
+		None => {
+			let (_, document) = match rmdoc::load_document(&id, Some(metadata.version), &db_pool).await? {
+				Some(document) => document,
+				None => return Ok(HttpResponse::NotFound().body("No such document")),
+			};
+
+			if document.file_type() == FileType::Epub {
+				return Ok(HttpResponse::BadRequest().body("Thumbnails are only available for PDFs and notebooks"));
+			}
+
+			match thumbnails::generate(document, metadata.version, query.page, size, format, &db_pool).await? {
+				Some(data) => data,
+				None => return Ok(HttpResponse::NotFound().body("No such page")),
+			}
+		}
+	};
+
+	Ok(HttpResponse::Ok().content_type(format.content_type()).body(data))
+}
+
+
+#[derive(Deserialize)]
+struct AddQuery {
+	name: String,
+	/// ID of the folder to add the document to; the root by default
+	folder: Option<String>,
+}
+
+
+/// Adds a file (the request body) to the library as a new document, the way uploading it from the tablet would.  Files the tablet
+/// can't open are converted first: see `library::archive_file`.  PDFs and EPUBs are named from their metadata, by
+/// `--name-template`.
+#[actix_web::post("/documents")]
+async fn add(
+	_admin_token: ValidatedAdminToken,
+	req: HttpRequest,
+	query: web::Query<AddQuery>,
+	body: web::Bytes,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+	server_config: web::Data<ServerConfig>,
+) -> Result<HttpResponse, ServerError> {
+	let parent = query.folder.as_deref().unwrap_or(tree::ROOT);
+	let content_type = req.content_type().to_owned();
+	let name = query.name.clone();
+	let layout = server_config.options.image_page_layout();
+
+	// Converting can be CPU heavy, so it's done on a blocking thread
+	let document = web::block(move || {
+		library::archive_file(body.to_vec(), &content_type, &name, &layout)
+			.map(|document| document.map(|document| (DocumentInfo::from_document(&document), document)))
+	})
+	.await
+	.context("Converting document")??;
+	let (document_info, document) = match document {
+		Ok(document) => document,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+	};
+	let name = library::name_from_template(&server_config.options.name_template, &document_info, &query.name);
+
+	let event = match library::add_document(&document, &name, parent, EventSource::admin(), &db_pool).await? {
+		Ok(event) => event,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid folder: {}", err))),
+	};
+	// Saved before the event is published, so that search indexes the title and authors along with the name
+	if !document_info.is_empty() {
+		library::save_document_info(&document.id, &document_info, &db_pool).await?;
+	}
+
+	let response = json!({
+		"id": event.document.id,
+		"version": event.document.version,
+		"name": event.document.name,
+		"parent": event.document.parent,
+		"file_type": document.file_type().as_str(),
+		"page_count": document.pages.len(),
+	});
+	event.publish(&event_bus);
+
+	Ok(HttpResponse::Ok().json(response))
+}
+
+
+#[derive(Deserialize)]
+struct CreateFolderQuery {
+	/// Folder names separated by slashes, e.g. "Family/Alex/Notes"
+	path: String,
+	/// ID of the folder the path starts in; the root by default
+	parent: Option<String>,
+}
+
+
+/// Creates the folder at a path, along with any folders on the way there that don't exist yet.  Folders that already exist are
+/// reused, so setting up the same layout twice is harmless.
+#[actix_web::post("/folders")]
+async fn create_folder(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<CreateFolderQuery>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	if query.path.split('/').all(|name| name.trim().is_empty()) {
+		return Ok(HttpResponse::BadRequest().body("The path has no folder names"));
+	}
+
+	let parent = query.parent.as_deref().unwrap_or(tree::ROOT);
+	let (id, events) = match library::folder_by_path(&query.path, parent, EventSource::admin(), &db_pool).await? {
+		Ok(folder) => folder,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid parent: {}", err))),
+	};
+
+	let response = json!({
+		"id": id,
+		"created": events.iter().map(|event| &event.document.id).collect::<Vec<_>>(),
+	});
+	for event in events {
+		event.publish(&event_bus);
+	}
+
+	Ok(HttpResponse::Ok().json(response))
+}
+
+
+#[derive(Deserialize)]
+struct CreateNotebookQuery {
+	name: String,
+	/// ID of the folder to add the notebook to; the root by default
+	folder: Option<String>,
+	/// 1 by default
+	pages: Option<usize>,
+	/// The template every page starts with, by the name the tablet uses (e.g. "P Lines medium"); "Blank" by default
+	template: Option<String>,
+}
+
+
+/// Creates a notebook of blank pages
+#[actix_web::post("/notebooks")]
+async fn create_notebook(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<CreateNotebookQuery>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let pages = query.pages.unwrap_or(1);
+	let template = query.template.as_deref().unwrap_or("Blank").trim();
+
+	if pages == 0 || pages > MAX_NEW_NOTEBOOK_PAGES {
+		return Ok(HttpResponse::BadRequest().body(format!("A notebook needs between 1 and {} pages", MAX_NEW_NOTEBOOK_PAGES)));
+	}
+	// The .pagedata has a line per page
+	if template.is_empty() || template.chars().any(char::is_control) {
+		return Ok(HttpResponse::BadRequest().body("Invalid template name"));
+	}
+
+	let document = library::blank_notebook(pages, template);
+	let parent = query.folder.as_deref().unwrap_or(tree::ROOT);
+	let event = match library::add_document(&document, &query.name, parent, EventSource::admin(), &db_pool).await? {
+		Ok(event) => event,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid folder: {}", err))),
+	};
+
+	let response = json!({
+		"id": event.document.id,
+		"version": event.document.version,
+		"name": event.document.name,
+		"parent": event.document.parent,
+		"page_count": document.pages.len(),
+		"template": template,
+	});
+	event.publish(&event_bus);
+
+	Ok(HttpResponse::Ok().json(response))
+}
@@ -0,0 +1,58 @@
+use crate::{
+	auth::ValidatedUserToken,
+	error::ServerError,
+	hwr::{self, HwrEngine, PageRequest},
+};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use log::{debug, warn};
+
+
+/// The tablet's handwriting recognition ("Convert to text").  The strokes of a page come in MyScript's batch format, and the text
+/// goes back as JIIX, or as plain text if that's what the tablet accepts.
+#[actix_web::post("/api/v1/page")]
+async fn recognize_page(
+	token: ValidatedUserToken,
+	req: HttpRequest,
+	body: web::Bytes,
+	engine: web::Data<Option<Box<dyn HwrEngine>>>,
+) -> Result<HttpResponse, ServerError> {
+	let engine = match engine.as_ref() {
+		Some(engine) => engine,
+		None => return Ok(HttpResponse::NotImplemented().body("Handwriting recognition isn't enabled on this server")),
+	};
+	let page: PageRequest = match serde_json::from_slice(&body) {
+		Ok(page) => page,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(format!("Invalid request: {}", err))),
+	};
+	let input = match page.into_input() {
+		Ok(input) => input,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+	};
+
+	debug!(
+		"Recognizing {} strokes from {} with the {} engine",
+		input.strokes.len(),
+		token.0.device_desc,
+		engine.name()
+	);
+	let recognition = match engine.recognize(&input).await {
+		Ok(recognition) => recognition,
+		Err(err) => {
+			warn!("Handwriting recognition failed: {:?}", err);
+			return Ok(HttpResponse::BadGateway().body("Handwriting recognition failed"));
+		}
+	};
+
+	let accept = req
+		.headers()
+		.get(header::ACCEPT)
+		.and_then(|accept| accept.to_str().ok())
+		.unwrap_or_default();
+	if accept.contains("text/plain") && !accept.contains("jiix") {
+		return Ok(HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(recognition.text));
+	}
+
+	Ok(HttpResponse::Ok()
+		.content_type("application/vnd.myscript.jiix")
+		.json(hwr::to_jiix(&recognition, &input.content_type)))
+}
@@ -1,6 +1,16 @@
 pub mod admin;
 pub mod auth;
+pub mod clip;
+pub mod deliveries;
+pub mod documents;
+pub mod hwr;
+pub mod pages;
+pub mod rules;
+pub mod search;
 pub mod storage;
+pub mod tags;
+pub mod trash;
+pub mod webhooks;
 
 
 use crate::config::ServerConfig;
@@ -0,0 +1,279 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	database::DbFileMetadata,
+	error::ServerError,
+	events::{EventBus, EventSource},
+	library,
+	rmdoc::{
+		self,
+		pages::{self, SourcePage},
+		FileType, RmDocument,
+	},
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+
+
+#[derive(Deserialize)]
+struct MergeRequest {
+	/// IDs of the documents, in order
+	documents: Vec<String>,
+	name: String,
+	/// ID of the folder to add the merged document to; the first document's folder by default
+	folder: Option<String>,
+}
+
+
+/// Merges PDF documents, one after the other, into a new document.  The originals are left as they are.
+#[actix_web::post("/documents/merge")]
+async fn merge(
+	_admin_token: ValidatedAdminToken,
+	payload: web::Json<MergeRequest>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let payload = payload.into_inner();
+	let mut sources = Vec::new();
+	let mut folder = payload.folder;
+
+	for id in &payload.documents {
+		let (metadata, document) = match load_pdf(id, &db_pool).await? {
+			Ok(document) => document,
+			Err(response) => return Ok(response),
+		};
+		folder.get_or_insert(metadata.parent);
+		sources.push(document);
+	}
+
+	let folder = match folder {
+		Some(folder) => folder,
+		None => return Ok(HttpResponse::BadRequest().body("No documents to merge")),
+	};
+	let pages = sources
+		.iter()
+		.enumerate()
+		.flat_map(|(document, source)| (0..source.pages.len()).map(move |index| SourcePage { document, index }))
+		.collect();
+
+	match add_documents(sources, vec![(payload.name, pages)], &folder, &db_pool, &event_bus).await? {
+		Ok(mut documents) => Ok(HttpResponse::Ok().json(documents.remove(0))),
+		Err(err) => Ok(HttpResponse::BadRequest().body(err)),
+	}
+}
+
+
+#[derive(Deserialize)]
+struct SplitRequest {
+	/// Pages for each new document, e.g. ["1-10", "11-20"]; see `pages::parse_pages`
+	ranges: Option<Vec<String>>,
+	/// Or: split into documents of this many pages
+	every: Option<usize>,
+	/// ID of the folder to add the new documents to; the document's folder by default
+	folder: Option<String>,
+}
+
+
+/// Splits a PDF document into new documents, e.g. a scanned book into its chapters.  The original is left as it is.
+#[actix_web::post("/documents/{id}/split")]
+async fn split(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	payload: web::Json<SplitRequest>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match load_pdf(&id, &db_pool).await? {
+		Ok(document) => document,
+		Err(response) => return Ok(response),
+	};
+	let page_count = document.pages.len();
+
+	let ranges = match (&payload.ranges, payload.every) {
+		(Some(ranges), None) if !ranges.is_empty() => ranges.iter().map(|range| range.trim().to_owned()).collect(),
+		(None, Some(every)) if every > 0 => (0..page_count)
+			.step_by(every)
+			.map(|start| format!("{}-{}", start + 1, (start + every).min(page_count)))
+			.collect::<Vec<_>>(),
+		_ => return Ok(HttpResponse::BadRequest().body("Either ranges or every is required")),
+	};
+
+	let mut outputs = Vec::new();
+	for range in ranges {
+		let pages = match pages::parse_pages(&range, page_count) {
+			Ok(pages) => pages,
+			Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+		};
+		outputs.push((
+			format!("{} (pages {})", metadata.name, range),
+			pages.into_iter().map(|index| SourcePage { document: 0, index }).collect(),
+		));
+	}
+
+	let folder = payload.folder.clone().unwrap_or(metadata.parent);
+	match add_documents(vec![document], outputs, &folder, &db_pool, &event_bus).await? {
+		Ok(documents) => Ok(HttpResponse::Ok().json(documents)),
+		Err(err) => Ok(HttpResponse::BadRequest().body(err)),
+	}
+}
+
+
+#[derive(Deserialize)]
+struct ExtractRequest {
+	/// e.g. "2,5-7"; see `pages::parse_pages`
+	pages: String,
+	/// The document's name followed by the pages by default
+	name: Option<String>,
+	/// ID of the folder to add the new document to; the document's folder by default
+	folder: Option<String>,
+}
+
+
+/// Copies some of a PDF document's pages to a new document.  The original is left as it is.
+#[actix_web::post("/documents/{id}/extract")]
+async fn extract(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	payload: web::Json<ExtractRequest>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match load_pdf(&id, &db_pool).await? {
+		Ok(document) => document,
+		Err(response) => return Ok(response),
+	};
+	let pages = match pages::parse_pages(&payload.pages, document.pages.len()) {
+		Ok(pages) => pages,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+	};
+
+	let name = payload
+		.name
+		.clone()
+		.unwrap_or_else(|| format!("{} (pages {})", metadata.name, payload.pages.trim()));
+	let folder = payload.folder.clone().unwrap_or(metadata.parent);
+	let pages = pages.into_iter().map(|index| SourcePage { document: 0, index }).collect();
+
+	match add_documents(vec![document], vec![(name, pages)], &folder, &db_pool, &event_bus).await? {
+		Ok(mut documents) => Ok(HttpResponse::Ok().json(documents.remove(0))),
+		Err(err) => Ok(HttpResponse::BadRequest().body(err)),
+	}
+}
+
+
+#[derive(Deserialize)]
+struct ReorderRequest {
+	/// Every page, in its new order, e.g. "3,1-2,4-"; see `pages::parse_pages`
+	order: String,
+}
+
+
+/// Reorders the pages of a PDF document, as a new version of it
+#[actix_web::post("/documents/{id}/reorder")]
+async fn reorder(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	payload: web::Json<ReorderRequest>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, document) = match load_pdf(&id, &db_pool).await? {
+		Ok(document) => document,
+		Err(response) => return Ok(response),
+	};
+	let order = match pages::parse_pages(&payload.order, document.pages.len()) {
+		Ok(order) => order,
+		Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+	};
+
+	let mut sorted = order.clone();
+	sorted.sort_unstable();
+	if !sorted.into_iter().eq(0..document.pages.len()) {
+		return Ok(HttpResponse::BadRequest().body("The order has to have every page exactly once"));
+	}
+
+	let pages: Vec<_> = order.into_iter().map(|index| SourcePage { document: 0, index }).collect();
+	let id = document.id.clone();
+	let reordered = web::block(move || pages::assemble(&id, &[document], &pages))
+		.await
+		.context("Reordering pages")??;
+
+	let event = match library::update_document(&reordered, metadata.version, EventSource::admin(), &db_pool).await? {
+		Some(event) => event,
+		None => return Ok(HttpResponse::Conflict().body("The document changed while its pages were being reordered")),
+	};
+
+	let response = json!({
+		"id": event.document.id,
+		"version": event.document.version,
+		"page_count": reordered.pages.len(),
+	});
+	event.publish(&event_bus);
+
+	Ok(HttpResponse::Ok().json(response))
+}
+
+
+/// The latest version of a document, or the response to give if it isn't a PDF
+async fn load_pdf(id: &str, db: &SqlitePool) -> Result<Result<(DbFileMetadata, RmDocument), HttpResponse>, ServerError> {
+	let (metadata, document) = match rmdoc::load_document(id, None, db).await? {
+		Some(document) => document,
+		None => return Ok(Err(HttpResponse::NotFound().body(format!("No such document: {}", id)))),
+	};
+
+	if document.file_type() != FileType::Pdf || document.pdf.is_none() {
+		return Ok(Err(HttpResponse::BadRequest().body(format!("{} isn't a PDF", metadata.name))));
+	}
+
+	Ok(Ok((metadata, document)))
+}
+
+
+/// Assembles each of `outputs` (a name, and its pages of `sources`) as a new document in `folder`.  Returns a description of each
+/// new document, or why they can't go in `folder`.
+async fn add_documents(
+	sources: Vec<RmDocument>,
+	outputs: Vec<(String, Vec<SourcePage>)>,
+	folder: &str,
+	db: &SqlitePool,
+	event_bus: &Addr<EventBus>,
+) -> Result<Result<Vec<Value>, String>, ServerError> {
+	// Rewriting PDFs can be CPU heavy, so it's done on a blocking thread
+	let documents = web::block(move || {
+		outputs
+			.into_iter()
+			.map(|(name, pages)| Ok((name, pages::assemble(&uuid::Uuid::new_v4().to_string(), &sources, &pages)?)))
+			.collect::<anyhow::Result<Vec<_>>>()
+	})
+	.await
+	.context("Assembling documents")??;
+
+	let mut events = Vec::new();
+	let mut response = Vec::new();
+
+	for (name, document) in documents {
+		let event = match library::add_document(&document, &name, folder, EventSource::admin(), db).await? {
+			Ok(event) => event,
+			// Only the first can fail, before anything's been added
+			Err(err) => return Ok(Err(format!("Invalid folder: {}", err))),
+		};
+
+		response.push(json!({
+			"id": event.document.id,
+			"version": event.document.version,
+			"name": event.document.name,
+			"parent": event.document.parent,
+			"page_count": document.pages.len(),
+		}));
+		events.push(event);
+	}
+
+	for event in events {
+		event.publish(event_bus);
+	}
+
+	Ok(Ok(response))
+}
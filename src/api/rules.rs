@@ -0,0 +1,124 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	error::ServerError,
+	rules::{self, Action, Conditions, Rule, RuleEngine, RunScheduled},
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+
+#[actix_web::get("/rules")]
+async fn list(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(rules::list_rules(&db_pool).await?))
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleRequest {
+	name: String,
+	#[serde(default = "default_enabled")]
+	enabled: bool,
+	/// Event names to run on (e.g. created, moved, or "scheduled" to run periodically).  Missing or empty means every event.
+	#[serde(default)]
+	events: Vec<String>,
+	#[serde(default)]
+	conditions: Conditions,
+	actions: Vec<Action>,
+}
+
+fn default_enabled() -> bool {
+	true
+}
+
+impl RuleRequest {
+	fn into_rule(self, id: i64) -> Rule {
+		Rule {
+			id,
+			name: self.name.trim().to_owned(),
+			enabled: self.enabled,
+			events: self.events.into_iter().map(|event| event.trim().to_owned()).collect(),
+			conditions: self.conditions,
+			actions: self.actions,
+			date_created: Utc::now().timestamp(),
+		}
+	}
+}
+
+
+/// Adds a filing rule.  See `rules` for what rules can match on and do.
+#[actix_web::post("/rules")]
+async fn create(
+	_admin_token: ValidatedAdminToken,
+	payload: web::Json<RuleRequest>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let mut rule = payload.into_inner().into_rule(0);
+	if let Err(err) = rule.validate_with(&db_pool).await? {
+		return Ok(HttpResponse::BadRequest().body(err));
+	}
+
+	rule.id = rules::add_rule(&rule, &db_pool).await?;
+
+	Ok(HttpResponse::Ok().json(rule))
+}
+
+
+#[actix_web::get("/rules/{id}")]
+async fn get(_admin_token: ValidatedAdminToken, id: web::Path<i64>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	match rules::get_rule(*id, &db_pool).await? {
+		Some(rule) => Ok(HttpResponse::Ok().json(rule)),
+		None => Ok(HttpResponse::NotFound().body("Not Found")),
+	}
+}
+
+
+/// Replaces a rule, e.g. to change its conditions or disable it
+#[actix_web::put("/rules/{id}")]
+async fn update(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<i64>,
+	payload: web::Json<RuleRequest>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let existing = match rules::get_rule(*id, &db_pool).await? {
+		Some(rule) => rule,
+		None => return Ok(HttpResponse::NotFound().body("Not Found")),
+	};
+
+	let rule = Rule {
+		date_created: existing.date_created,
+		..payload.into_inner().into_rule(*id)
+	};
+	if let Err(err) = rule.validate_with(&db_pool).await? {
+		return Ok(HttpResponse::BadRequest().body(err));
+	}
+
+	if !rules::update_rule(&rule, &db_pool).await? {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	Ok(HttpResponse::Ok().json(rule))
+}
+
+
+#[actix_web::delete("/rules/{id}")]
+async fn delete(_admin_token: ValidatedAdminToken, id: web::Path<i64>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	if !rules::delete_rule(*id, &db_pool).await? {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	Ok(HttpResponse::Ok().finish())
+}
+
+
+/// Checks the scheduled rules now, rather than waiting for the next time they're due
+#[actix_web::post("/rules/run")]
+async fn run(_admin_token: ValidatedAdminToken, rule_engine: web::Data<Addr<RuleEngine>>) -> Result<HttpResponse, ServerError> {
+	rule_engine.do_send(RunScheduled);
+
+	Ok(HttpResponse::Ok().finish())
+}
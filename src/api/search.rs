@@ -0,0 +1,27 @@
+use crate::{auth::ValidatedAdminToken, error::ServerError};
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+	q: String,
+	limit: Option<i64>,
+}
+
+/// Full-text search over document names, folders, PDF and EPUB text, and typed notes.  Hits are ranked best first.
+#[actix_web::get("/search")]
+async fn search(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<SearchQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	if query.q.trim().is_empty() {
+		return Ok(HttpResponse::BadRequest().body("Empty query"));
+	}
+
+	let limit = query.limit.unwrap_or(20).clamp(1, 100);
+
+	Ok(HttpResponse::Ok().json(crate::search::search(&query.q, limit, &db_pool).await?))
+}
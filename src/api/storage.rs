@@ -1,14 +1,17 @@
 use crate::{
 	auth::{FileAccessClaims, ValidatedUserToken},
-	config::ServerConfig,
+	config::{InvalidParentPolicy, ServerConfig},
 	database,
 	error::ServerError,
-	notifications::{Notification, NotificationServer},
+	events::{DocumentEvent, EventBus, EventSource},
+	rmdoc::validate,
+	tree::{self, FolderTree},
 	FILE_ACCESS_EXPIRATION,
 };
 use actix_web::{web, HttpResponse};
+use anyhow::Context;
 use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Utc};
-use log::info;
+use log::{info, warn};
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::SqlitePool;
@@ -24,7 +27,7 @@ struct ListDocumentsQuery {
 /// List files
 #[actix_web::get("/document-storage/json/2/docs")]
 async fn list(
-	_user_token: ValidatedUserToken,
+	user_token: ValidatedUserToken,
 	query: web::Query<ListDocumentsQuery>,
 	db_pool: web::Data<SqlitePool>,
 	server_config: web::Data<ServerConfig>,
@@ -40,7 +43,7 @@ async fn list(
 			}])));
 		}
 	} else {
-		database::list_metadata(&db_pool).await?
+		database::list_metadata(&**db_pool).await?
 	};
 
 	let with_blob = query.with_blob.unwrap_or(false);
@@ -51,7 +54,7 @@ async fn list(
 		.into_iter()
 		.map(|x| {
 			let blob_url_get = if with_blob {
-				let token = FileAccessClaims::new(exp.timestamp(), x.id.clone(), x.version, &server_config);
+				let token = FileAccessClaims::new(exp.timestamp(), x.id.clone(), x.version, &user_token.0, &server_config);
 
 				format!("https://{}/storage/{}", server_config.server_host, token)
 			} else {
@@ -91,7 +94,7 @@ struct UploadRequest {
 /// This API is used during both the creation of a new document and updating an existing document.
 #[actix_web::put("/document-storage/json/2/upload/request")]
 async fn upload_request(
-	_user_token: ValidatedUserToken,
+	user_token: ValidatedUserToken,
 	payload: web::Json<Vec<UploadRequest>>,
 	db_pool: web::Data<SqlitePool>,
 	server_config: web::Data<ServerConfig>,
@@ -118,7 +121,7 @@ async fn upload_request(
 		}
 
 		let exp = Utc::now() + Duration::seconds(FILE_ACCESS_EXPIRATION);
-		let token = FileAccessClaims::new(exp.timestamp(), req.id.clone(), req.version, &server_config);
+		let token = FileAccessClaims::new(exp.timestamp(), req.id.clone(), req.version, &user_token.0, &server_config);
 
 		results.push(json!({
 			"ID": req.id,
@@ -151,10 +154,32 @@ async fn upload(
 	// Log request
 	info!("upload_document: {:?}", claims);
 
+	if let Some(limits) = server_config.options.upload_limits() {
+		let (id, data) = (claims.file_id.clone(), body.clone());
+		let result = web::block(move || validate::validate_upload(&id, &data, &limits))
+			.await
+			.context("Validating upload")?;
+
+		if let Err(err) = result {
+			warn!(
+				"Rejected upload of {} version {} from {} ({}): {}",
+				claims.file_id, claims.file_version, claims.device_desc, claims.device_id, err
+			);
+			let mut response = if err.is_too_large() {
+				HttpResponse::PayloadTooLarge()
+			} else {
+				HttpResponse::BadRequest()
+			};
+			return Ok(response.body(err.to_string()));
+		}
+	}
+
 	// Store in database
-	if database::put_data(claims.file_id, claims.file_version, &body, &db_pool).await? == false {
+	let mut tx = database::begin_immediate_transaction(&db_pool).await?;
+	if database::put_data(claims.file_id, claims.file_version, &body, &mut tx).await? == false {
 		Ok(HttpResponse::Conflict().body("URL expired"))
 	} else {
+		tx.commit().await.context("Database TX")?;
 		Ok(HttpResponse::Ok().finish())
 	}
 }
@@ -203,7 +228,8 @@ async fn update_status(
 	user_token: ValidatedUserToken,
 	payload: web::Json<Vec<UpdateRequest>>,
 	db_pool: web::Data<SqlitePool>,
-	notification_server: web::Data<actix::Addr<NotificationServer>>,
+	event_bus: web::Data<actix::Addr<EventBus>>,
+	server_config: web::Data<ServerConfig>,
 ) -> Result<HttpResponse, ServerError> {
 	// Log request
 	info!("payload: {:?}", payload);
@@ -212,12 +238,61 @@ async fn update_status(
 	database::clean_deleted_files(&db_pool).await?;
 
 	let mut results = Vec::new();
-	let mut notifications = Vec::new();
+	let mut events = Vec::new();
+	let source = EventSource::new(&user_token.0.device_desc, &user_token.0.device_id);
 
 	let mut tx = database::begin_immediate_transaction(&db_pool).await?;
 
+	let mut tree = FolderTree::from_metadata(&database::list_metadata(&mut tx).await?);
+
+	// Folders created in this batch are added up front, so that items can be moved into them whatever the order.  Everything
+	// else only reaches the tree once its update has been accepted.
+	let new_folders: Vec<&str> = payload
+		.iter()
+		.filter(|request| request.file_type.as_deref() == Some(tree::COLLECTION_TYPE) && tree.parent(&request.id).is_none())
+		.map(|request| request.id.as_str())
+		.collect();
+	for id in &new_folders {
+		tree.set(id, Some(tree::COLLECTION_TYPE), Some(tree::ROOT));
+	}
+
 	for request in &*payload {
-		let updated_metadata = database::put_metadata(
+		let mut parent = request.parent.clone();
+		let is_new_folder = new_folders.contains(&request.id.as_str());
+
+		// The tablet sends the parent with every update, so only a move is checked; an item that's already somewhere it
+		// shouldn't be can still be updated
+		let moved = match (&parent, tree.parent(&request.id)) {
+			(Some(requested_parent), Some(current_parent)) => requested_parent != current_parent || is_new_folder,
+			(Some(_), None) => true,
+			(None, _) => false,
+		};
+
+		if let (Some(requested_parent), true) = (&parent, moved) {
+			if let Err(err) = tree.validate_parent(&request.id, requested_parent) {
+				match server_config.options.invalid_parent_policy {
+					InvalidParentPolicy::Reject => {
+						info!("Rejecting update of {} with parent {}: {}", request.id, requested_parent, err);
+						if is_new_folder {
+							tree.remove(&request.id);
+						}
+						results.push(json!({
+							"ID": request.id,
+							"Version": request.version,
+							"Message": format!("Invalid parent: {}", err),
+							"Success": false,
+						}));
+						continue;
+					}
+					InvalidParentPolicy::MoveToRoot => {
+						info!("Moving {} to the root instead of {}: {}", request.id, requested_parent, err);
+						parent = Some(tree::ROOT.to_owned());
+					}
+				}
+			}
+		}
+
+		let change = database::put_metadata(
 			request.id.clone(),
 			request.version,
 			request.modified_client.timestamp(),
@@ -225,33 +300,33 @@ async fn update_status(
 			request.visible_name.clone(),
 			request.current_page,
 			request.bookmarked,
-			request.parent.clone(),
+			parent.clone(),
 			&mut tx,
 		)
 		.await?;
 
+		if change.is_some() {
+			tree.set(&request.id, request.file_type.as_deref(), parent.as_deref());
+		} else if is_new_folder {
+			tree.remove(&request.id);
+		}
+
 		results.push(json!({
 			"ID": request.id,
 			"Version": request.version,
-			"Message": if updated_metadata.is_some() { "" } else { "Version on server is not -1 of what you supplied" },
-			"Success": updated_metadata.is_some(),
+			"Message": if change.is_some() { "" } else { "Version on server is not -1 of what you supplied" },
+			"Success": change.is_some(),
 		}));
 
-		if let Some(updated_metadata) = updated_metadata {
-			// Yes, all changes have an event type of "DocAdded"
-			notifications.push(Notification::from_metadata(
-				"DocAdded",
-				&updated_metadata,
-				&user_token.0.device_desc,
-				&user_token.0.device_id,
-			));
+		if let Some(change) = change {
+			events.push(DocumentEvent::from_change(change, source.clone()));
 		}
 	}
 
 	tx.commit().await?;
 
-	for notification in notifications {
-		notification.broadcast(&notification_server);
+	for event in events {
+		event.publish(&event_bus);
 	}
 
 	Ok(HttpResponse::Ok().json(results))
@@ -272,13 +347,14 @@ pub async fn delete(
 	user_token: ValidatedUserToken,
 	payload: web::Json<Vec<DeleteRequest>>,
 	db_pool: web::Data<SqlitePool>,
-	notification_server: web::Data<actix::Addr<NotificationServer>>,
+	event_bus: web::Data<actix::Addr<EventBus>>,
 ) -> Result<HttpResponse, ServerError> {
 	// Log request
 	info!("payload: {:?}", payload);
 
 	let mut results = Vec::new();
-	let mut notifications = Vec::new();
+	let mut events = Vec::new();
+	let source = EventSource::new(&user_token.0.device_desc, &user_token.0.device_id);
 
 	let mut tx = database::begin_immediate_transaction(&db_pool).await?;
 
@@ -293,19 +369,14 @@ pub async fn delete(
 		}));
 
 		if let Some(old_metadata) = old_metadata {
-			notifications.push(Notification::from_metadata(
-				"DocDeleted",
-				&old_metadata,
-				&user_token.0.device_desc,
-				&user_token.0.device_id,
-			));
+			events.push(DocumentEvent::deleted(old_metadata, source.clone()));
 		}
 	}
 
 	tx.commit().await?;
 
-	for notification in notifications {
-		notification.broadcast(&notification_server);
+	for event in events {
+		event.publish(&event_bus);
 	}
 
 	Ok(HttpResponse::Ok().json(results))
@@ -0,0 +1,109 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	error::ServerError,
+	events::{EventBus, EventSource},
+	library, rmdoc, tags,
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+/// Every tag, with how many documents and pages have it
+#[actix_web::get("/tags")]
+async fn list(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(tags::list_tags(&db_pool).await?))
+}
+
+
+#[derive(Deserialize)]
+struct TaggedQuery {
+	tag: String,
+}
+
+
+/// The documents, and the pages, with a tag
+#[actix_web::get("/tagged")]
+async fn tagged(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<TaggedQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(tags::tagged(&query.tag, &db_pool).await?))
+}
+
+
+#[derive(Deserialize)]
+struct ChangeTagsRequest {
+	#[serde(default)]
+	add: Vec<String>,
+	#[serde(default)]
+	remove: Vec<String>,
+	/// The page (counting from 0) to tag, rather than the whole document
+	page: Option<usize>,
+}
+
+
+/// Adds and removes tags on a document, or on one of its pages, as a new version of it that the tablets then sync
+#[actix_web::post("/documents/{id}/tags")]
+async fn change(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	payload: web::Json<ChangeTagsRequest>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let (metadata, mut document) = match rmdoc::load_document(&id, None, &db_pool).await? {
+		Some(document) => document,
+		None => return Ok(HttpResponse::NotFound().body(format!("No such document: {}", id))),
+	};
+
+	let add: Vec<String> = payload.add.iter().map(|tag| tag.trim().to_owned()).collect();
+	let remove: Vec<String> = payload.remove.iter().map(|tag| tag.trim().to_owned()).collect();
+	if add.iter().any(|tag| tag.is_empty() || tag.chars().any(char::is_control)) {
+		return Ok(HttpResponse::BadRequest().body("Tags can't be empty or contain control characters"));
+	}
+
+	let page_id = match payload.page {
+		Some(page) => match document.pages.get(page) {
+			Some(page) => Some(page.id.clone()),
+			None => return Ok(HttpResponse::NotFound().body(format!("No page {}; the document has {} pages", page, document.pages.len()))),
+		},
+		None => None,
+	};
+
+	let mut version = metadata.version;
+	if tags::change_tags(&mut document.content, page_id.as_deref(), &add, &remove) {
+		let event = match library::update_document(&document, metadata.version, EventSource::admin(), &db_pool).await? {
+			Some(event) => event,
+			None => {
+				return Ok(
+					HttpResponse::Conflict().body("The document changed, or a new version of it is being uploaded; try again once it has synced")
+				)
+			}
+		};
+		version = event.document.version;
+		event.publish(&event_bus);
+	}
+
+	let tags: Vec<&str> = match &page_id {
+		Some(page_id) => document
+			.content
+			.page_tags
+			.iter()
+			.flatten()
+			.filter(|tag| &tag.page_id == page_id)
+			.map(|tag| tag.name.as_str())
+			.collect(),
+		None => document.content.tags.iter().flatten().map(|tag| tag.name.as_str()).collect(),
+	};
+
+	Ok(HttpResponse::Ok().json(json!({
+		"id": metadata.id,
+		"version": version,
+		"page": payload.page,
+		"tags": tags,
+	})))
+}
@@ -0,0 +1,79 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	error::ServerError,
+	events::{EventBus, EventSource},
+	trash::{self, RestoreError},
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+/// Items directly in the trash, with where they'll be restored to and how much space they take up.
+#[actix_web::get("/trash")]
+async fn list(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(trash::list_trash(&db_pool).await?))
+}
+
+
+#[actix_web::get("/trash/stats")]
+async fn stats(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(trash::trash_stats(&db_pool).await?))
+}
+
+
+/// Move an item out of the trash, back to the folder it was trashed from.
+#[actix_web::post("/trash/{id}/restore")]
+async fn restore(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<String>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let event = match trash::restore(&id, EventSource::admin(), &db_pool).await? {
+		Ok(event) => event,
+		Err(err @ RestoreError::NotInTrash) => return Ok(HttpResponse::NotFound().body(err.to_string())),
+		Err(err @ RestoreError::UploadPending) => return Ok(HttpResponse::Conflict().body(err.to_string())),
+	};
+
+	let response = json!({
+		"id": event.document.id,
+		"version": event.document.version,
+		"parent": event.document.parent,
+	});
+	event.publish(&event_bus);
+
+	Ok(HttpResponse::Ok().json(response))
+}
+
+
+#[derive(Debug, Deserialize)]
+struct EmptyQuery {
+	/// Only delete items that have been in the trash for at least this many days
+	older_than_days: Option<u32>,
+}
+
+/// Permanently delete everything in the trash.
+#[actix_web::post("/trash/empty")]
+async fn empty(
+	_admin_token: ValidatedAdminToken,
+	query: web::Query<EmptyQuery>,
+	db_pool: web::Data<SqlitePool>,
+	event_bus: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, ServerError> {
+	let trashed_before = match query.older_than_days.map(trash::trashed_before) {
+		Some(None) => return Ok(HttpResponse::BadRequest().body("older_than_days is too large")),
+		Some(Some(trashed_before)) => Some(trashed_before),
+		None => None,
+	};
+	let events = trash::empty(trashed_before, EventSource::admin(), &db_pool).await?;
+	let deleted = events.len();
+
+	for event in events {
+		event.publish(&event_bus);
+	}
+
+	Ok(HttpResponse::Ok().json(json!({ "deleted": deleted })))
+}
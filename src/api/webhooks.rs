@@ -0,0 +1,134 @@
+use crate::{
+	auth::ValidatedAdminToken,
+	error::ServerError,
+	events::EVENT_NAMES,
+	webhooks::{self, ProcessPending, WebhookDelivery, WebhookDispatcher},
+};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use rand::{rngs::OsRng, Rng};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+#[actix_web::get("/webhooks")]
+async fn list(_admin_token: ValidatedAdminToken, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	Ok(HttpResponse::Ok().json(webhooks::list_webhooks(&db_pool).await?))
+}
+
+
+#[derive(Debug, Deserialize)]
+struct CreateWebhookRequest {
+	url: String,
+	/// Event types to deliver (e.g. DocAdded, DocDeleted).  Missing or empty means all events.
+	events: Option<Vec<String>>,
+	/// Only deliver events for documents under this folder ID
+	folder: Option<String>,
+	/// Used to sign payloads.  One is generated if not provided.
+	secret: Option<String>,
+}
+
+/// Subscribe a URL to document events.
+/// The response includes the secret, which is used to sign each payload with HMAC-SHA256 (X-Webhook-Signature header).
+#[actix_web::post("/webhooks")]
+async fn create(
+	_admin_token: ValidatedAdminToken,
+	payload: web::Json<CreateWebhookRequest>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	if !payload.url.starts_with("http://") && !payload.url.starts_with("https://") {
+		return Ok(HttpResponse::BadRequest().body("Webhook URL must be http or https"));
+	}
+	if let Some(event) = payload.events.iter().flatten().find(|event| !EVENT_NAMES.contains(&event.as_str())) {
+		return Ok(HttpResponse::BadRequest().body(format!("Unknown event {:?}; webhooks can receive {}", event, EVENT_NAMES.join(", "))));
+	}
+
+	let events = payload.events.as_deref().unwrap_or(&[]).join(",");
+	let secret = payload.secret.clone().unwrap_or_else(|| hex::encode(OsRng.gen::<[u8; 32]>()));
+
+	let result = sqlx::query("INSERT INTO webhooks (url,events,folder,secret,date_created) VALUES (?,?,?,?,?)")
+		.bind(&payload.url)
+		.bind(&events)
+		.bind(&payload.folder)
+		.bind(&secret)
+		.bind(Utc::now().timestamp())
+		.execute(&**db_pool)
+		.await?;
+
+	Ok(HttpResponse::Ok().json(json!({
+		"id": result.last_insert_rowid(),
+		"url": payload.url,
+		"events": events,
+		"folder": payload.folder,
+		"secret": secret,
+	})))
+}
+
+
+#[actix_web::delete("/webhooks/{id}")]
+async fn delete(_admin_token: ValidatedAdminToken, id: web::Path<i64>, db_pool: web::Data<SqlitePool>) -> Result<HttpResponse, ServerError> {
+	let result = sqlx::query("DELETE FROM webhooks WHERE id=?").bind(*id).execute(&**db_pool).await?;
+
+	if result.rows_affected() == 0 {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id=?")
+		.bind(*id)
+		.execute(&**db_pool)
+		.await?;
+
+	Ok(HttpResponse::Ok().finish())
+}
+
+
+#[derive(Debug, Deserialize)]
+struct DeliveriesQuery {
+	/// pending, delivered or failed
+	status: Option<String>,
+}
+
+/// Delivery log for a webhook, most recent first.
+#[actix_web::get("/webhooks/{id}/deliveries")]
+async fn deliveries(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<i64>,
+	query: web::Query<DeliveriesQuery>,
+	db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ServerError> {
+	let deliveries =
+		sqlx::query_as::<_, WebhookDelivery>("SELECT * FROM webhook_deliveries WHERE webhook_id=? AND (? IS NULL OR status=?) ORDER BY id DESC")
+			.bind(*id)
+			.bind(&query.status)
+			.bind(&query.status)
+			.fetch_all(&**db_pool)
+			.await?;
+
+	Ok(HttpResponse::Ok().json(deliveries))
+}
+
+
+/// Queue a delivery to be sent again, e.g. after it exhausted its retries.
+#[actix_web::post("/webhooks/deliveries/{id}/resend")]
+async fn resend(
+	_admin_token: ValidatedAdminToken,
+	id: web::Path<i64>,
+	db_pool: web::Data<SqlitePool>,
+	dispatcher: web::Data<Addr<WebhookDispatcher>>,
+) -> Result<HttpResponse, ServerError> {
+	let result = sqlx::query("UPDATE webhook_deliveries SET status='pending', attempts=0, next_attempt=? WHERE id=?")
+		.bind(Utc::now().timestamp())
+		.bind(*id)
+		.execute(&**db_pool)
+		.await?;
+
+	if result.rows_affected() == 0 {
+		return Ok(HttpResponse::NotFound().body("Not Found"));
+	}
+
+	dispatcher.do_send(ProcessPending);
+
+	Ok(HttpResponse::Ok().finish())
+}
@@ -11,6 +11,7 @@ use std::convert::TryInto;
 pub type ValidatedAdminToken = JWTAuthorization<AdminTokenClaims>;
 pub type ValidatedDeviceToken = JWTAuthorization<DeviceTokenClaims>;
 pub type ValidatedUserToken = JWTAuthorization<UserTokenClaims>;
+pub type ValidatedUserOrAdminToken = JWTAuthorization<UserOrAdminClaims>;
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -166,6 +167,11 @@ pub struct FileAccessClaims {
 	pub exp: u64,
 	pub file_id: String,
 	pub file_version: i64,
+	/// The device the URL was given to, for logs.  Empty in tokens issued before these were added.
+	#[serde(default)]
+	pub device_id: String,
+	#[serde(default)]
+	pub device_desc: String,
 }
 
 impl JWTValidation for FileAccessClaims {
@@ -177,11 +183,13 @@ impl JWTValidation for FileAccessClaims {
 }
 
 impl FileAccessClaims {
-	pub fn new(exp: i64, file_id: String, file_version: i64, server_config: &ServerConfig) -> String {
+	pub fn new(exp: i64, file_id: String, file_version: i64, user_token: &UserTokenClaims, server_config: &ServerConfig) -> String {
 		let claims = FileAccessClaims {
 			exp: exp.try_into().expect("overflow"),
 			file_id,
 			file_version,
+			device_id: user_token.device_id.clone(),
+			device_desc: user_token.device_desc.clone(),
 		};
 
 		jsonwebtoken::encode(
@@ -201,6 +209,47 @@ impl FileAccessClaims {
 }
 
 
+/// Accepts either a user token or an admin token, for APIs that serve both the tablet protocol and admin tooling.
+#[derive(Debug)]
+pub enum UserOrAdminClaims {
+	User(UserTokenClaims),
+	Admin,
+}
+
+impl UserOrAdminClaims {
+	pub fn device_desc(&self) -> &str {
+		match self {
+			UserOrAdminClaims::User(claims) => &claims.device_desc,
+			UserOrAdminClaims::Admin => "admin",
+		}
+	}
+}
+
+impl FromRequest for JWTAuthorization<UserOrAdminClaims> {
+	type Error = Error;
+	type Future = future::Ready<Result<Self, Error>>;
+
+	fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+		let server_config = ServerConfig::from_req(req);
+
+		let authtoken = match bearer_token(req) {
+			Ok(authtoken) => authtoken,
+			Err(err) => return future::err(err),
+		};
+
+		// Try the user token first, since that's the common case
+		let claims = decode_token::<UserTokenClaims>(authtoken, server_config)
+			.map(UserOrAdminClaims::User)
+			.or_else(|_| decode_token::<AdminTokenClaims>(authtoken, server_config).map(|_| UserOrAdminClaims::Admin));
+
+		match claims {
+			Ok(claims) => future::ok(Self(claims)),
+			Err(err) => future::err(err),
+		}
+	}
+}
+
+
 pub struct JWTAuthorization<T>(pub T);
 
 pub trait JWTValidation {
@@ -214,27 +263,44 @@ impl<T: DeserializeOwned + JWTValidation> FromRequest for JWTAuthorization<T> {
 	fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
 		let server_config = ServerConfig::from_req(req);
 
-		let auth_header = req.headers().get("Authorization").and_then(|auth| auth.to_str().ok());
-		let authtoken = match auth_header {
-			Some(auth_header) => {
-				let mut split = auth_header.split(" ");
-				let scheme = split.next().unwrap_or("").to_lowercase();
-				if scheme != "bearer" {
-					return future::err(ErrorUnauthorized("Expects Bearer Authorization"));
-				}
+		let authtoken = match bearer_token(req) {
+			Ok(authtoken) => authtoken,
+			Err(err) => return future::err(err),
+		};
+
+		match decode_token::<T>(authtoken, server_config) {
+			Ok(claims) => future::ok(Self(claims)),
+			Err(err) => future::err(err),
+		}
+	}
+}
+
 
-				split.next().unwrap_or("")
+/// Extracts the token from the request's Bearer Authorization header.
+fn bearer_token(req: &HttpRequest) -> Result<&str, Error> {
+	let auth_header = req.headers().get("Authorization").and_then(|auth| auth.to_str().ok());
+
+	match auth_header {
+		Some(auth_header) => {
+			let mut split = auth_header.split(" ");
+			let scheme = split.next().unwrap_or("").to_lowercase();
+			if scheme != "bearer" {
+				return Err(ErrorUnauthorized("Expects Bearer Authorization"));
 			}
-			None => return future::err(ErrorUnauthorized("Missing Authorization Header")),
-		};
 
-		// Decode and validate the JWT
-		let validation = T::validation();
-		let authtoken = match jsonwebtoken::decode::<T>(&authtoken, &DecodingKey::from_secret(&server_config.jwt_secret_key), &validation) {
-			Ok(authtoken) => authtoken,
-			Err(err) => return future::err(ErrorUnauthorized(format!("Bad JWT Token: {:?}", err.into_kind()))),
-		};
+			Ok(split.next().unwrap_or(""))
+		}
+		None => Err(ErrorUnauthorized("Missing Authorization Header")),
+	}
+}
+
+
+/// Decode and validate the JWT
+fn decode_token<T: DeserializeOwned + JWTValidation>(authtoken: &str, server_config: &ServerConfig) -> Result<T, Error> {
+	let validation = T::validation();
 
-		future::ok(Self(authtoken.claims))
+	match jsonwebtoken::decode::<T>(authtoken, &DecodingKey::from_secret(&server_config.jwt_secret_key), &validation) {
+		Ok(authtoken) => Ok(authtoken.claims),
+		Err(err) => Err(ErrorUnauthorized(format!("Bad JWT Token: {:?}", err.into_kind()))),
 	}
 }
@@ -0,0 +1,309 @@
+//! Clips web pages for reading on the tablet: the article in a page is saved as an EPUB, with its images.
+use crate::rmdoc::{
+	article, convert,
+	epub::Book,
+	html::{self, Element, Node},
+};
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use log::debug;
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	time::Duration,
+};
+use url::Url;
+
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+const MAX_PAGE_SIZE: usize = 10 * 1024 * 1024;
+const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+const MAX_IMAGES: usize = 100;
+/// How many images are downloaded at once
+const IMAGE_FETCH_CONCURRENCY: usize = 4;
+const USER_AGENT: &str = "Mozilla/5.0 (compatible; rm-personal-cloud)";
+
+
+#[derive(Debug)]
+pub enum ClipError {
+	NothingToClip,
+	InvalidUrl(String),
+	/// The server isn't allowed to fetch from the page's origin
+	ForbiddenOrigin(String),
+	FetchFailed(String),
+}
+
+impl fmt::Display for ClipError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ClipError::NothingToClip => write!(f, "Either the page's HTML or its URL is required"),
+			ClipError::InvalidUrl(err) => write!(f, "Invalid URL: {}", err),
+			ClipError::ForbiddenOrigin(origin) => write!(f, "Fetching from {} isn't allowed", origin),
+			ClipError::FetchFailed(err) => write!(f, "Unable to fetch {}", err),
+		}
+	}
+}
+
+
+/// A response, and where it ended up coming from after redirects
+struct Fetched {
+	url: Url,
+	data: Vec<u8>,
+	content_type: Option<String>,
+}
+
+
+pub struct Clipping {
+	pub title: String,
+	pub epub: Vec<u8>,
+}
+
+
+/// Fetches pages and images, but only from the configured origins, so that clipping can't be used to reach anything else the
+/// server can (e.g. services on its network).
+pub struct Fetcher {
+	client: awc::Client,
+	/// e.g. "https://example.com"; "*" allows any
+	origins: Vec<String>,
+}
+
+impl Fetcher {
+	pub fn new(origins: &[String]) -> Self {
+		Self {
+			// Redirects are followed by hand, so that each one can be checked
+			client: awc::Client::builder()
+				.timeout(FETCH_TIMEOUT)
+				.disable_redirects()
+				.add_default_header(("User-Agent", USER_AGENT))
+				.finish(),
+			origins: origins.iter().map(|origin| origin.trim_end_matches('/').to_owned()).collect(),
+		}
+	}
+
+	fn allows(&self, url: &Url) -> bool {
+		let origin = url.origin().ascii_serialization();
+		matches!(url.scheme(), "http" | "https") && self.origins.iter().any(|allowed| allowed == "*" || *allowed == origin)
+	}
+
+	/// Fetches a URL, following redirects as long as they stay on allowed origins.
+	async fn fetch(&self, url: &Url, limit: usize) -> Result<Fetched, ClipError> {
+		let mut url = url.clone();
+
+		for _ in 0..=MAX_REDIRECTS {
+			if !self.allows(&url) {
+				return Err(ClipError::ForbiddenOrigin(url.origin().ascii_serialization()));
+			}
+
+			// Pooled connections can go stale between requests, and there are only a few requests per clipping anyway
+			let mut response = self
+				.client
+				.get(url.as_str())
+				.force_close()
+				.send()
+				.await
+				.map_err(|err| ClipError::FetchFailed(format!("{}: {}", url, err)))?;
+
+			if response.status().is_redirection() {
+				let location = response.headers().get("Location").and_then(|location| location.to_str().ok());
+				url = match location.map(|location| url.join(location)) {
+					Some(Ok(location)) => location,
+					_ => return Err(ClipError::FetchFailed(format!("{}: Invalid redirect", url))),
+				};
+				continue;
+			}
+			if !response.status().is_success() {
+				return Err(ClipError::FetchFailed(format!("{}: {}", url, response.status())));
+			}
+
+			let content_type = response
+				.headers()
+				.get("Content-Type")
+				.and_then(|content_type| content_type.to_str().ok())
+				.map(str::to_owned);
+			let data = response
+				.body()
+				.limit(limit)
+				.await
+				.map_err(|err| ClipError::FetchFailed(format!("{}: {}", url, err)))?;
+
+			return Ok(Fetched {
+				data: data.to_vec(),
+				content_type,
+				url,
+			});
+		}
+
+		Err(ClipError::FetchFailed(format!("{}: Too many redirects", url)))
+	}
+}
+
+
+/// Makes an EPUB of the article in a page.  The page is fetched from `url` if its HTML isn't given; either way, relative links and
+/// images are resolved against `url`.  Images are downloaded if they're on an allowed origin, and left out otherwise (unless
+/// they're embedded in the page).
+pub async fn clip(url: Option<&str>, page: Option<String>, title: Option<String>, fetcher: &Fetcher) -> Result<Result<Clipping, ClipError>> {
+	let url = match url.map(Url::parse) {
+		Some(Ok(url)) => Some(url),
+		Some(Err(err)) => return Ok(Err(ClipError::InvalidUrl(err.to_string()))),
+		None => None,
+	};
+	let (page, url) = match (page, url) {
+		(Some(page), url) => (page, url),
+		(None, Some(url)) => match fetcher.fetch(&url, MAX_PAGE_SIZE).await {
+			Ok(fetched) => (String::from_utf8_lossy(&fetched.data).into_owned(), Some(fetched.url)),
+			Err(err) => return Ok(Err(err)),
+		},
+		(None, None) => return Ok(Err(ClipError::NothingToClip)),
+	};
+
+	let mut article = article::extract(&page);
+	let base = match (&url, &article.base) {
+		(Some(url), Some(base)) => url.join(base).ok(),
+		(Some(url), None) => Some(url.clone()),
+		(None, Some(base)) => Url::parse(base).ok(),
+		(None, None) => None,
+	};
+
+	resolve_links(&mut article.content, base.as_ref());
+	let images = fetch_images(&article.content, fetcher).await;
+	let resources = convert::embed_images(&mut article.content, &mut |src| {
+		images.get(src).cloned().or_else(|| convert::data_uri(src))
+	});
+
+	let title = title
+		.or(article.title)
+		.or_else(|| url.as_ref().map(Url::to_string))
+		.unwrap_or_else(|| "Untitled article".to_owned());
+	remove_heading(&mut article.content, &title);
+
+	// The title, and who wrote it where
+	let mut body = format!("<h1>{}</h1>\n", html::escape(&title));
+	let source = url.as_ref().and_then(|url| {
+		let host = url.host_str()?;
+		Some(format!("<a href=\"{}\">{}</a>", html::escape(url.as_str()), html::escape(host)))
+	});
+	let byline: Vec<String> = article.author.iter().map(|author| html::escape(author)).chain(source).collect();
+	if !byline.is_empty() {
+		body.push_str(&format!("<p><em>{}</em></p>\n", byline.join(" · ")));
+	}
+	body.push_str(&html::to_xhtml(&article.content));
+
+	let epub = Book {
+		title: title.clone(),
+		author: article.author,
+		language: article.language,
+		body,
+		resources,
+	}
+	.to_epub()?;
+
+	Ok(Ok(Clipping { title, epub }))
+}
+
+
+/// Makes links and image sources absolute, and finds the real source of lazy-loaded images
+fn resolve_links(content: &mut Element, base: Option<&Url>) {
+	let resolve = |link: &str| -> Option<String> {
+		match base {
+			_ if link.starts_with("data:") => Some(link.to_owned()),
+			Some(base) => base.join(link).ok().map(String::from),
+			None => Url::parse(link).ok().map(String::from),
+		}
+	};
+
+	content.visit_mut(&mut |element| match element.name.as_str() {
+		"img" => {
+			let src = ["data-src", "data-original", "data-lazy-src"]
+				.iter()
+				.find_map(|attribute| element.attribute(attribute))
+				.or_else(|| element.attribute("src").filter(|src| !src.is_empty() && !src.starts_with("data:")))
+				.or_else(|| element.attribute("srcset").and_then(|srcset| srcset.split_whitespace().next()))
+				.or_else(|| element.attribute("src"))
+				.and_then(resolve);
+
+			element.attributes.retain(|(name, _)| name != "src");
+			if let Some(src) = src {
+				element.set_attribute("src", &src);
+			}
+		}
+		"a" => {
+			if let Some(href) = element.attribute("href").and_then(resolve) {
+				element.set_attribute("href", &href);
+			}
+		}
+		_ => (),
+	});
+}
+
+
+/// Downloads the images in the article that are on allowed origins.  Returns their media types and data, by URL.
+async fn fetch_images(content: &Element, fetcher: &Fetcher) -> HashMap<String, (String, Vec<u8>)> {
+	let mut seen = HashSet::new();
+	let sources: Vec<String> = content
+		.elements()
+		.into_iter()
+		.filter(|element| element.name == "img")
+		.filter_map(|element| element.attribute("src"))
+		.filter(|src| !src.starts_with("data:") && seen.insert(*src))
+		.take(MAX_IMAGES)
+		.map(str::to_owned)
+		.collect();
+
+	stream::iter(sources)
+		.map(|src| async move {
+			let url = Url::parse(&src).ok()?;
+			match fetcher.fetch(&url, MAX_IMAGE_SIZE).await {
+				Ok(fetched) => Some((src, (image_type(&fetched.data, fetched.content_type.as_deref()), fetched.data))),
+				Err(err) => {
+					debug!("Leaving out image: {}", err);
+					None
+				}
+			}
+		})
+		.buffer_unordered(IMAGE_FETCH_CONCURRENCY)
+		.filter_map(|image| async move { image })
+		.collect()
+		.await
+}
+
+
+/// Servers don't always say what type an image is, or say it correctly
+fn image_type(data: &[u8], content_type: Option<&str>) -> String {
+	let sniffed = if data.starts_with(b"\x89PNG") {
+		Some("image/png")
+	} else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+		Some("image/jpeg")
+	} else if data.starts_with(b"GIF8") {
+		Some("image/gif")
+	} else if data.len() > 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+		Some("image/webp")
+	} else {
+		None
+	};
+
+	match (sniffed, content_type) {
+		(Some(sniffed), _) => sniffed.to_owned(),
+		(None, Some(content_type)) => content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase(),
+		(None, None) => String::new(),
+	}
+}
+
+
+/// Removes the article's own heading if it repeats the title, since the title is added as a heading anyway
+fn remove_heading(element: &mut Element, title: &str) -> bool {
+	let is_title = |node: &Node| match node {
+		Node::Element(x) if x.name == "h1" || x.name == "h2" => x.text().split_whitespace().collect::<Vec<_>>().join(" ") == title,
+		_ => false,
+	};
+
+	if let Some(i) = element.children.iter().position(is_title) {
+		element.children.remove(i);
+		return true;
+	}
+
+	element.children.iter_mut().any(|child| match child {
+		Node::Element(child) => remove_heading(child, title),
+		Node::Text(_) => false,
+	})
+}
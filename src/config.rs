@@ -1,18 +1,172 @@
+use crate::{
+	cron::Schedule,
+	rmdoc::{images::PageLayout, validate::UploadLimits},
+};
 use actix_web::{web, HttpRequest};
 use anyhow::Result;
+use chrono::format::{Item, StrftimeItems};
+use clap::{Args, ValueEnum};
 use rand::{rngs::OsRng, Rng};
 use sqlx::SqlitePool;
 use std::convert::TryInto;
 
 
+/// Server behavior that can be tweaked from the command line.
+#[derive(Clone, Debug, Args)]
+pub struct ServerOptions {
+	/// What to do when a client tries to move an item into a folder that doesn't exist, into a document, or into itself
+	#[clap(long = "invalid-parent-policy", value_enum, default_value = "reject")]
+	pub invalid_parent_policy: InvalidParentPolicy,
+
+	/// Permanently delete items that have been in the trash for this many days
+	#[clap(long = "trash-auto-empty-days", value_parser = clap::value_parser!(u32).range(..=crate::MAX_TRASH_DAYS as i64))]
+	pub trash_auto_empty_days: Option<u32>,
+
+	/// Width of the pages of PDFs made from uploaded images, in millimetres.  The tablet's screen by default.
+	#[clap(long = "image-page-width", value_parser, default_value_t = 157.8)]
+	pub image_page_width: f64,
+
+	/// Height of the pages of PDFs made from uploaded images, in millimetres.  The tablet's screen by default.
+	#[clap(long = "image-page-height", value_parser, default_value_t = 210.6)]
+	pub image_page_height: f64,
+
+	/// Space to leave around the images on those pages, in millimetres
+	#[clap(long = "image-page-margin", value_parser, default_value_t = 0.0)]
+	pub image_page_margin: f64,
+
+	/// Name of the top-level folder clipped web pages are saved to.  It's created the first time something is clipped.
+	#[clap(long = "clip-folder", value_parser, default_value = "Articles")]
+	pub clip_folder: String,
+
+	/// An origin (e.g. https://example.com) the web clipper may fetch pages and images from, or * for any.  Can be given more than
+	/// once.  By default nothing is fetched, so pages have to be sent with their HTML, and only their embedded images are kept.
+	#[clap(long = "clip-fetch-origin", value_parser)]
+	pub clip_fetch_origins: Vec<String>,
+
+	/// Check that documents clients upload are well-formed archives before storing them, and reject those that aren't
+	#[clap(long = "validate-uploads", value_parser)]
+	pub validate_uploads: bool,
+
+	/// With --validate-uploads, how much an upload may unpack to, in megabytes
+	#[clap(long = "upload-max-unpacked-size", value_parser, default_value_t = 1024)]
+	pub upload_max_unpacked_size: u64,
+
+	/// With --validate-uploads, how many times larger than itself an upload may unpack to
+	#[clap(long = "upload-max-compression-ratio", value_parser, default_value_t = 100.0)]
+	pub upload_max_compression_ratio: f64,
+
+	/// How documents added through the admin API are named, from their PDF or EPUB metadata: {title}, {authors}, {author} (the
+	/// first), {year}, {date} and {name} (the name they were added with), e.g. "{author} - {title} ({year})".  If something it
+	/// uses is missing, the name they were added with is kept.
+	#[clap(long = "name-template", value_parser, default_value = "{title}")]
+	pub name_template: String,
+
+	/// Create a notebook for each day (a journal) in the folder at this path, e.g. "Journal".  The folder is created if need be.
+	#[clap(long = "journal-folder", value_parser)]
+	pub journal_folder: Option<String>,
+
+	/// When to create the day's notebook, as a cron expression in the server's local time (see README)
+	#[clap(long = "journal-schedule", value_parser, default_value = "0 6 * * *")]
+	pub journal_schedule: Schedule,
+
+	/// How to name the day's notebook, with strftime fields, e.g. "%A %e %B"
+	#[clap(long = "journal-name", value_parser = date_format, default_value = "%Y-%m-%d")]
+	pub journal_name: String,
+
+	/// The template for the pages of the day's notebook, by the name the tablet uses, e.g. "P Lines medium"
+	#[clap(long = "journal-template", value_parser, default_value = "Blank")]
+	pub journal_template: String,
+
+	/// How many blank pages the day's notebook starts with
+	#[clap(long = "journal-pages", value_parser = clap::value_parser!(u64).range(1..=crate::MAX_NEW_NOTEBOOK_PAGES as u64), default_value_t = 1)]
+	pub journal_pages: u64,
+
+	/// Copy the pages of the previous day's notebook that have unfinished to-dos (typed checkboxes that aren't ticked, or the
+	/// --journal-todo-tag page tag) to the start of the new day's
+	#[clap(long = "journal-carry-todos", value_parser)]
+	pub journal_carry_todos: bool,
+
+	/// With --journal-carry-todos, the page tag that marks a page as having unfinished to-dos
+	#[clap(long = "journal-todo-tag", value_parser, default_value = "todo")]
+	pub journal_todo_tag: String,
+
+	/// What recognizes handwriting for the tablet's "Convert to text"
+	#[clap(long = "hwr-engine", value_enum, default_value = "disabled")]
+	pub hwr_engine: HwrEngineKind,
+
+	/// URL of the recognition service the http engine sends strokes to
+	#[clap(long = "hwr-url", value_parser, required_if_eq("hwr-engine", "http"))]
+	pub hwr_url: Option<String>,
+
+	/// Sent to the recognition service as a bearer token
+	#[clap(long = "hwr-api-key", value_parser)]
+	pub hwr_api_key: Option<String>,
+
+	/// What the fixture engine recognizes, whatever is written
+	#[clap(long = "hwr-fixture-text", value_parser, default_value = "hello world")]
+	pub hwr_fixture_text: String,
+}
+
+impl ServerOptions {
+	pub fn image_page_layout(&self) -> PageLayout {
+		let points = |millimetres: f64| millimetres * 72.0 / 25.4;
+
+		PageLayout {
+			width: points(self.image_page_width),
+			height: points(self.image_page_height),
+			margin: points(self.image_page_margin),
+		}
+	}
+
+	/// None if uploads aren't validated
+	pub fn upload_limits(&self) -> Option<UploadLimits> {
+		self.validate_uploads.then(|| UploadLimits {
+			max_unpacked_size: self.upload_max_unpacked_size.saturating_mul(1024 * 1024),
+			max_compression_ratio: self.upload_max_compression_ratio,
+		})
+	}
+}
+
+
+/// Rejects strftime formats chrono can't use, which would otherwise only fail when they're used
+fn date_format(format: &str) -> Result<String, String> {
+	if StrftimeItems::new(format).any(|item| item == Item::Error) {
+		return Err("Invalid strftime format".to_owned());
+	}
+
+	Ok(format.to_owned())
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InvalidParentPolicy {
+	/// Fail the update for that item
+	Reject,
+	/// Apply the update, but put the item at the root instead
+	MoveToRoot,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HwrEngineKind {
+	/// The tablet is told recognition isn't available
+	Disabled,
+	/// Send the strokes to a recognition service (see --hwr-url)
+	Http,
+	/// Always recognize the same text (see --hwr-fixture-text), for testing
+	Fixture,
+}
+
+
 #[derive(Clone)]
 pub struct ServerConfig {
 	pub jwt_secret_key: [u8; 32],
 	pub server_host: String,
+	pub options: ServerOptions,
 }
 
 impl ServerConfig {
-	pub async fn load_config(db: &SqlitePool, server_host: String) -> Result<Self> {
+	pub async fn load_config(db: &SqlitePool, server_host: String, options: ServerOptions) -> Result<Self> {
 		let jwt_secret_key: [u8; 32] = {
 			// Create an encoding key if one doesn't exist
 			sqlx::query("INSERT OR IGNORE INTO config (key,value) VALUES (?,?)")
@@ -31,7 +185,11 @@ impl ServerConfig {
 			secret.try_into().expect("Corrupt jwt_secret_key in database.")
 		};
 
-		Ok(ServerConfig { jwt_secret_key, server_host })
+		Ok(ServerConfig {
+			jwt_secret_key,
+			server_host,
+			options,
+		})
 	}
 
 	pub fn from_req(req: &HttpRequest) -> &Self {
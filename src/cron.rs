@@ -0,0 +1,153 @@
+//! Cron-style schedules: "minute hour day-of-month month day-of-week", e.g. "0 6 * * mon" for 6am every Monday.  Fields take
+//! `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/15`, `8-18/2`), and months and days of the week can be given by
+//! their first three letters.  `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` work too.
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike};
+use std::str::FromStr;
+
+
+const MONTH_NAMES: &[&str] = &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+
+/// Each field is a bitset of the values it allows
+#[derive(Clone, Debug)]
+pub struct Schedule {
+	minutes: u64,
+	hours: u64,
+	days: u64,
+	months: u64,
+	weekdays: u64,
+	/// When both the day of the month and the day of the week are restricted, a day matching either will do, as in cron
+	either_day: bool,
+}
+
+impl Schedule {
+	/// The first minute after `after` that the schedule runs at, or None if it never does (e.g. "0 0 30 2 *").  Times that don't
+	/// exist locally, because the clocks go forward, are skipped.
+	pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+		let timezone = after.timezone();
+		let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+		let mut date = start.date();
+
+		// Every day of the month falls on every day of the week within 28 years, but Feb 29 on a given day can take longer
+		for _ in 0..366 * 40 {
+			if self.runs_on(date) {
+				for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+					for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+						let time = date.and_hms(hour, minute, 0);
+						if time < start {
+							continue;
+						}
+						if let Some(time) = timezone.from_local_datetime(&time).earliest() {
+							return Some(time);
+						}
+					}
+				}
+			}
+			date = date.succ_opt()?;
+		}
+
+		None
+	}
+
+	fn runs_on(&self, date: NaiveDate) -> bool {
+		if self.months & (1 << date.month()) == 0 {
+			return false;
+		}
+
+		let day = self.days & (1 << date.day()) != 0;
+		let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+		if self.either_day {
+			day || weekday
+		} else {
+			day && weekday
+		}
+	}
+}
+
+impl FromStr for Schedule {
+	type Err = String;
+
+	fn from_str(expression: &str) -> Result<Self, Self::Err> {
+		let expression = match expression.trim().to_ascii_lowercase().as_str() {
+			"@hourly" => "0 * * * *".to_owned(),
+			"@daily" | "@midnight" => "0 0 * * *".to_owned(),
+			"@weekly" => "0 0 * * 0".to_owned(),
+			"@monthly" => "0 0 1 * *".to_owned(),
+			"@yearly" | "@annually" => "0 0 1 1 *".to_owned(),
+			expression => expression.to_owned(),
+		};
+
+		let fields: Vec<&str> = expression.split_whitespace().collect();
+		if fields.len() != 5 {
+			return Err(format!(
+				"A schedule has 5 fields (minute, hour, day of the month, month, day of the week), not {}",
+				fields.len()
+			));
+		}
+
+		// Sunday is 0 or 7
+		let mut weekdays = parse_field(fields[4], 0, 7, WEEKDAY_NAMES)?;
+		if weekdays & (1 << 7) != 0 {
+			weekdays = (weekdays | 1) & !(1 << 7);
+		}
+
+		Ok(Self {
+			minutes: parse_field(fields[0], 0, 59, &[])?,
+			hours: parse_field(fields[1], 0, 23, &[])?,
+			days: parse_field(fields[2], 1, 31, &[])?,
+			months: parse_field(fields[3], 1, 12, MONTH_NAMES)?,
+			weekdays,
+			either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+		})
+	}
+}
+
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+	let mut values = 0;
+
+	for part in field.split(',') {
+		let (range, step) = match part.split_once('/') {
+			Some((range, step)) => match step.parse::<u32>() {
+				Ok(step) if step > 0 => (range, step),
+				_ => return Err(format!("Invalid step in {:?}", part)),
+			},
+			None => (part, 1),
+		};
+
+		let (start, end) = match range.split_once('-') {
+			_ if range == "*" => (min, max),
+			Some((start, end)) => (parse_value(start, min, max, names)?, parse_value(end, min, max, names)?),
+			// "5/15" means from 5 onwards, every 15
+			None if step > 1 => (parse_value(range, min, max, names)?, max),
+			None => {
+				let value = parse_value(range, min, max, names)?;
+				(value, value)
+			}
+		};
+		if start > end {
+			return Err(format!("The range {:?} runs backwards", range));
+		}
+
+		for value in (start..=end).step_by(step as usize) {
+			values |= 1 << value;
+		}
+	}
+
+	Ok(values)
+}
+
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+	let number = match names.iter().position(|name| name.eq_ignore_ascii_case(value)) {
+		Some(index) => index as u32 + min,
+		None => value.parse().map_err(|_| format!("Invalid value {:?}", value))?,
+	};
+
+	if number < min || number > max {
+		return Err(format!("{} is out of range ({}-{})", number, min, max));
+	}
+
+	Ok(number)
+}
@@ -1,10 +1,12 @@
-use crate::DELETED_FILE_EXPIRATION;
+use crate::{tree, DELETED_FILE_EXPIRATION};
 use anyhow::{Context, Result};
 use chrono::Utc;
+use serde::Serialize;
 use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+use std::collections::HashMap;
 
 
-#[derive(sqlx::FromRow, Default)]
+#[derive(sqlx::FromRow, Default, Clone, Debug, Serialize)]
 pub struct DbFileMetadata {
 	pub id: String,
 	pub version: i64,
@@ -14,11 +16,21 @@ pub struct DbFileMetadata {
 	pub current_page: i64,
 	pub bookmarked: bool,
 	pub parent: String,
+	#[serde(skip)]
 	pub committed: bool,
+	#[serde(skip)]
 	pub deleted: i64,
 }
 
 
+/// The result of a successful put_metadata.
+pub struct MetadataChange {
+	/// The last committed version before this change, if the file existed
+	pub previous: Option<DbFileMetadata>,
+	pub current: DbFileMetadata,
+}
+
+
 pub async fn get_metadata_by_id<'c, E: sqlx::Executor<'c, Database = sqlx::Sqlite>>(id: &str, db: E) -> Result<Option<DbFileMetadata>> {
 	sqlx::query_as::<_, DbFileMetadata>("SELECT MAX(version) AS version,id,client_date_modified,file_type,name,current_page,bookmarked,parent,committed,deleted FROM files WHERE id=? AND committed=1 AND deleted=0 GROUP BY id")
 		.bind(id)
@@ -27,6 +39,15 @@ pub async fn get_metadata_by_id<'c, E: sqlx::Executor<'c, Database = sqlx::Sqlit
 		.context("Database")
 }
 
+pub async fn get_metadata_by_id_version(id: &str, version: i64, db: &SqlitePool) -> Result<Option<DbFileMetadata>> {
+	sqlx::query_as::<_, DbFileMetadata>("SELECT version,id,client_date_modified,file_type,name,current_page,bookmarked,parent,committed,deleted FROM files WHERE id=? AND version=? AND committed=1 AND deleted=0")
+		.bind(id)
+		.bind(version)
+		.fetch_optional(db)
+		.await
+		.context("Database")
+}
+
 pub async fn get_data_by_id_version(id: &str, version: i64, db: &SqlitePool) -> Result<Option<Vec<u8>>> {
 	sqlx::query("SELECT data FROM files WHERE id=? AND version=? AND committed=1 AND deleted=0")
 		.bind(id)
@@ -42,7 +63,7 @@ pub async fn get_data_by_id_version(id: &str, version: i64, db: &SqlitePool) ->
 }
 
 
-pub async fn list_metadata(db: &SqlitePool) -> Result<Vec<DbFileMetadata>> {
+pub async fn list_metadata<'c, E: sqlx::Executor<'c, Database = sqlx::Sqlite>>(db: E) -> Result<Vec<DbFileMetadata>> {
 	sqlx::query_as::<_, DbFileMetadata>("SELECT MAX(version) AS version,id,client_date_modified,file_type,name,current_page,bookmarked,parent,committed,deleted FROM files WHERE committed=1 AND deleted=0 GROUP BY id")
 		.fetch_all(db)
 		.await
@@ -50,17 +71,25 @@ pub async fn list_metadata(db: &SqlitePool) -> Result<Vec<DbFileMetadata>> {
 }
 
 
+/// Size of each file's latest data, in bytes.  Files without data are omitted.
+pub async fn list_data_sizes<'c, E: sqlx::Executor<'c, Database = sqlx::Sqlite>>(db: E) -> Result<HashMap<String, i64>> {
+	let rows: Vec<(String, i64)> = sqlx::query_as("SELECT id,LENGTH(data) FROM files AS f WHERE committed=1 AND deleted=0 AND data IS NOT NULL AND version=(SELECT MAX(version) FROM files WHERE id=f.id AND committed=1 AND deleted=0)")
+		.fetch_all(db)
+		.await
+		.context("Database")?;
+
+	Ok(rows.into_iter().collect())
+}
+
+
 /// Returns Ok(true) if the data has been successfully added to the database.
 /// Returns Ok(false) when version is not correct.
 /// Returns an error for things like Sqlite errors.
-pub async fn put_data(id: String, version: i64, data: &[u8], db: &SqlitePool) -> Result<bool> {
-	// Start a transaction
-	let mut tx = begin_immediate_transaction(db).await?;
-
+pub async fn put_data(id: String, version: i64, data: &[u8], tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<bool> {
 	// Find the latest version of the file, even if it isn't committed yet.
 	let row = sqlx::query_as::<_, DbFileMetadata>("SELECT MAX(version) AS version,id,client_date_modified,file_type,name,current_page,bookmarked,parent,committed,deleted FROM files WHERE id=? GROUP BY id")
 		.bind(&id)
-		.fetch_optional(&mut tx)
+		.fetch_optional(&mut *tx)
 		.await?;
 
 	// If the file doesn't exist yet we create a "ghost" metadata that allows the rest of this code to work.
@@ -88,7 +117,7 @@ pub async fn put_data(id: String, version: i64, data: &[u8], db: &SqlitePool) ->
 			.bind(Utc::now().timestamp())
 			.bind(&metadata.id)
 			.bind(version)
-			.execute(&mut tx)
+			.execute(&mut *tx)
 			.await
 			.context("Update next version's file data")?;
 	}
@@ -106,19 +135,16 @@ pub async fn put_data(id: String, version: i64, data: &[u8], db: &SqlitePool) ->
 			.bind(false)
 			.bind(data)
 			.bind(0)
-			.execute(&mut tx)
+			.execute(&mut *tx)
 			.await
 			.context("Insert next version's file data")?;
 	}
 
-	// Commit
-	tx.commit().await.context("Database TX")?;
-
 	Ok(true)
 }
 
 
-/// Returns Ok(Some(new_metadata)) if the metadata has been successfully updated.
+/// Returns Ok(Some(change)) if the metadata has been successfully updated.
 /// Returns Ok(None) on failure (either bad version or some kind of conflict).
 /// Returns an error for things like Sqlite errors.
 pub async fn put_metadata(
@@ -131,7 +157,7 @@ pub async fn put_metadata(
 	bookmarked: Option<bool>,
 	parent: Option<String>,
 	tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
-) -> Result<Option<DbFileMetadata>> {
+) -> Result<Option<MetadataChange>> {
 	// Find the latest version of the file, even if it isn't committed yet.
 	let row = sqlx::query_as::<_, DbFileMetadata>("SELECT MAX(version) AS version,id,client_date_modified,file_type,name,current_page,bookmarked,parent,committed,deleted FROM files WHERE id=? AND deleted=0 GROUP BY id")
 		.bind(&id)
@@ -156,6 +182,20 @@ pub async fn put_metadata(
 		return Ok(None);
 	}
 
+	// Remember what the file looked like before this change.
+	// An uncommitted record carries the previous version's metadata (see put_data).
+	let previous = if metadata.committed && metadata.version > 0 {
+		Some(metadata.clone())
+	} else if !metadata.committed && metadata.version > 1 {
+		Some(DbFileMetadata {
+			version: metadata.version - 1,
+			committed: true,
+			..metadata.clone()
+		})
+	} else {
+		None
+	};
+
 	// Modify metadata
 	metadata.client_date_modified = client_date_modified;
 	metadata.file_type = file_type.unwrap_or(metadata.file_type);
@@ -178,7 +218,7 @@ pub async fn put_metadata(
 		.bind(true)
 		.bind(&metadata.id)
 		.bind(version)
-		.execute(tx)
+		.execute(&mut *tx)
 		.await
 		.context("Update next version's metadata")?;
 	}
@@ -206,13 +246,32 @@ pub async fn put_metadata(
 			.bind(data.0)
 			.bind(true)
 			.bind(0)
-			.execute(tx)
+			.execute(&mut *tx)
 			.await.context("Insert next version's file metadata")?;
 	}
 
+	// Remember where items in the trash came from, so they can be restored there
+	let previous_parent = previous.as_ref().map(|x| x.parent.as_str()).unwrap_or(tree::ROOT);
+
+	if metadata.parent == tree::TRASH && previous_parent != tree::TRASH {
+		sqlx::query("INSERT OR REPLACE INTO trash (id,previous_parent,date_trashed) VALUES (?,?,?)")
+			.bind(&metadata.id)
+			.bind(previous_parent)
+			.bind(Utc::now().timestamp())
+			.execute(&mut *tx)
+			.await
+			.context("Insert trash record")?;
+	} else if metadata.parent != tree::TRASH && previous_parent == tree::TRASH {
+		sqlx::query("DELETE FROM trash WHERE id=?")
+			.bind(&metadata.id)
+			.execute(&mut *tx)
+			.await
+			.context("Delete trash record")?;
+	}
+
 	metadata.version = version;
 
-	Ok(Some(metadata))
+	Ok(Some(MetadataChange { previous, current: metadata }))
 }
 
 
@@ -262,9 +321,11 @@ pub async fn delete_file(id: &str, version: i64, tx: &mut sqlx::Transaction<'_,
 			sqlx::query("UPDATE files SET deleted=? WHERE id=?")
 				.bind(Utc::now().timestamp())
 				.bind(id)
-				.execute(tx)
+				.execute(&mut *tx)
 				.await?;
 
+			sqlx::query("DELETE FROM trash WHERE id=?").bind(id).execute(tx).await?;
+
 			return Ok(Some(server_metadata));
 		}
 	}
@@ -0,0 +1,475 @@
+//! Documents delivered at a set time, e.g. tomorrow's agenda at 6am, rather than as soon as they're added.  A delivery is either
+//! a file uploaded along with it, or a file on the server that's read when the delivery runs.  Deliveries can recur on a cron
+//! schedule (see `cron`), e.g. a report every Monday: each run reads the file again and replaces the document the last run
+//! delivered, as a new version of it, so it keeps its place and the tablets just sync the change.
+//!
+//! A delivery can also be a planner made from calendars (see `rmdoc::planner`), which is made again on each run.  If it comes
+//! out with as many pages as before, the new version keeps the pages of the old one, so whatever was written on them stays put.
+use crate::{
+	config::ServerConfig,
+	cron::Schedule,
+	database,
+	events::{EventBus, EventSource},
+	library,
+	rmdoc::{
+		self,
+		document_info::DocumentInfo,
+		planner::{self, PlannerLayout},
+		FileType, RmDocument,
+	},
+	tree::FolderTree,
+	DELIVERY_CHECK_INTERVAL,
+};
+use actix::prelude::*;
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{Local, NaiveDate, Utc};
+use log::{debug, info, warn};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{
+	convert::TryFrom,
+	path::{Path, PathBuf},
+};
+
+
+/// Where a delivery's file comes from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+	/// Uploaded along with the delivery, and kept with it
+	Upload { content_type: String },
+	/// A file on the server, read again on each run
+	File { path: PathBuf },
+	/// A planner covering `days` days from `start`, made from an .ics file, or every .ics file in a directory (by name), on the
+	/// server.  Without a path, from the calendar uploaded along with the delivery.
+	Planner {
+		path: Option<PathBuf>,
+		layout: PlannerLayout,
+		start: NaiveDate,
+		days: u32,
+	},
+}
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Delivery {
+	pub id: i64,
+	/// What to name the document, unless `--name-template` names it from its metadata
+	pub name: String,
+	/// ID of the folder to add the document to
+	pub folder: String,
+	pub source: Source,
+	/// A cron expression, for deliveries that recur
+	pub schedule: Option<String>,
+	/// When it's next due (Unix time); None once a delivery that doesn't recur has run
+	pub next_run: Option<i64>,
+	/// The document the last run delivered, which the next run replaces
+	pub document_id: Option<String>,
+	pub last_run: Option<i64>,
+	pub last_error: Option<String>,
+	/// SHA256 of the file the last run delivered, so a run can tell it hasn't changed
+	#[serde(skip)]
+	pub data_hash: Option<String>,
+	pub date_created: i64,
+}
+
+
+#[derive(sqlx::FromRow)]
+struct DeliveryRow {
+	id: i64,
+	name: String,
+	folder: String,
+	/// JSON
+	source: String,
+	schedule: Option<String>,
+	next_run: Option<i64>,
+	document_id: Option<String>,
+	last_run: Option<i64>,
+	last_error: Option<String>,
+	data_hash: Option<String>,
+	date_created: i64,
+}
+
+impl TryFrom<DeliveryRow> for Delivery {
+	type Error = anyhow::Error;
+
+	fn try_from(row: DeliveryRow) -> Result<Self> {
+		let id = row.id;
+
+		Ok(Delivery {
+			id,
+			name: row.name,
+			folder: row.folder,
+			source: serde_json::from_str(&row.source).with_context(|| format!("Source of delivery {}", id))?,
+			schedule: row.schedule,
+			next_run: row.next_run,
+			document_id: row.document_id,
+			last_run: row.last_run,
+			last_error: row.last_error,
+			data_hash: row.data_hash,
+			date_created: row.date_created,
+		})
+	}
+}
+
+
+/// Everything but the uploaded file
+const DELIVERY_COLUMNS: &str = "id,name,folder,source,schedule,next_run,document_id,last_run,last_error,data_hash,date_created";
+
+
+pub async fn list_deliveries(db: &SqlitePool) -> Result<Vec<Delivery>> {
+	sqlx::query_as::<_, DeliveryRow>(&format!("SELECT {} FROM deliveries ORDER BY id", DELIVERY_COLUMNS))
+		.fetch_all(db)
+		.await
+		.context("Database")?
+		.into_iter()
+		.map(Delivery::try_from)
+		.collect()
+}
+
+
+pub async fn get_delivery(id: i64, db: &SqlitePool) -> Result<Option<Delivery>> {
+	sqlx::query_as::<_, DeliveryRow>(&format!("SELECT {} FROM deliveries WHERE id=?", DELIVERY_COLUMNS))
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")?
+		.map(Delivery::try_from)
+		.transpose()
+}
+
+
+/// Adds a delivery, ignoring its ID, along with the uploaded file for `Source::Upload`.  Returns the ID it's given.
+pub async fn add_delivery(delivery: &Delivery, data: Option<&[u8]>, db: &SqlitePool) -> Result<i64> {
+	let result = sqlx::query("INSERT INTO deliveries (name,folder,source,data,schedule,next_run,date_created) VALUES (?,?,?,?,?,?,?)")
+		.bind(&delivery.name)
+		.bind(&delivery.folder)
+		.bind(serde_json::to_string(&delivery.source)?)
+		.bind(data)
+		.bind(&delivery.schedule)
+		.bind(delivery.next_run)
+		.bind(delivery.date_created)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.last_insert_rowid())
+}
+
+
+/// Deletes a delivery, leaving any document it delivered alone.  Returns false if there isn't one.
+pub async fn delete_delivery(id: i64, db: &SqlitePool) -> Result<bool> {
+	let result = sqlx::query("DELETE FROM deliveries WHERE id=?")
+		.bind(id)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+
+/// Makes a delivery due now.  Returns false if there isn't one.
+pub async fn run_now(id: i64, db: &SqlitePool) -> Result<bool> {
+	let result = sqlx::query("UPDATE deliveries SET next_run=? WHERE id=?")
+		.bind(Utc::now().timestamp())
+		.bind(id)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+
+/// When a recurring delivery is next due after now, or None if its schedule never comes round
+pub fn next_run(schedule: &Schedule) -> Option<i64> {
+	schedule.next_after(&Local::now()).map(|time| time.timestamp())
+}
+
+
+/// The content type `library::archive_file` needs to recognise a text file, going by its extension.  Other files are
+/// recognised by their contents.
+fn content_type_of(path: &Path) -> &'static str {
+	let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+
+	match extension.to_ascii_lowercase().as_str() {
+		"md" | "markdown" => "text/markdown",
+		"html" | "htm" | "xhtml" => "text/html",
+		"txt" => "text/plain",
+		_ => "application/octet-stream",
+	}
+}
+
+
+/// Run the deliveries that are due
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RunDue;
+
+
+/// Runs deliveries when they're due, checking every DELIVERY_CHECK_INTERVAL.  Deliveries run one at a time.
+pub struct DeliveryScheduler {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	server_config: ServerConfig,
+}
+
+impl DeliveryScheduler {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>, server_config: ServerConfig) -> Self {
+		Self {
+			db,
+			event_bus,
+			server_config,
+		}
+	}
+}
+
+impl Actor for DeliveryScheduler {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		// Catch up on anything that came due while the server was down
+		ctx.notify(RunDue);
+		ctx.run_interval(DELIVERY_CHECK_INTERVAL, |_act, ctx| ctx.notify(RunDue));
+	}
+}
+
+impl Handler<RunDue> for DeliveryScheduler {
+	type Result = ();
+
+	fn handle(&mut self, _: RunDue, ctx: &mut Self::Context) {
+		let runner = Runner {
+			db: self.db.clone(),
+			event_bus: self.event_bus.clone(),
+			server_config: self.server_config.clone(),
+		};
+
+		ctx.wait(
+			async move {
+				if let Err(err) = runner.run_due().await {
+					warn!("Unable to run deliveries: {:?}", err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+
+struct Runner {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	server_config: ServerConfig,
+}
+
+impl Runner {
+	async fn run_due(&self) -> Result<()> {
+		let due: Vec<Delivery> = sqlx::query_as::<_, DeliveryRow>(&format!(
+			"SELECT {} FROM deliveries WHERE next_run<=? ORDER BY next_run",
+			DELIVERY_COLUMNS
+		))
+		.bind(Utc::now().timestamp())
+		.fetch_all(&self.db)
+		.await
+		.context("Database")?
+		.into_iter()
+		.map(Delivery::try_from)
+		.collect::<Result<_>>()?;
+
+		for delivery in due {
+			self.run(&delivery).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Runs a delivery and works out when it's next due.  A run that fails is recorded against the delivery rather than retried;
+	/// recurring deliveries try again on their next run.
+	async fn run(&self, delivery: &Delivery) -> Result<()> {
+		let next_run = match &delivery.schedule {
+			Some(schedule) => schedule.parse::<Schedule>().ok().as_ref().and_then(next_run),
+			None => None,
+		};
+
+		let (document_id, data_hash, last_error) = match self.deliver(delivery).await {
+			Ok((document_id, data_hash)) => (Some(document_id), Some(data_hash), None),
+			Err(err) => {
+				warn!("Delivery {} ({}) failed: {:#}", delivery.id, delivery.name, err);
+				(delivery.document_id.clone(), delivery.data_hash.clone(), Some(format!("{:#}", err)))
+			}
+		};
+
+		sqlx::query("UPDATE deliveries SET next_run=?,document_id=?,data_hash=?,last_run=?,last_error=? WHERE id=?")
+			.bind(next_run)
+			.bind(document_id)
+			.bind(data_hash)
+			.bind(Utc::now().timestamp())
+			.bind(last_error)
+			.bind(delivery.id)
+			.execute(&self.db)
+			.await
+			.context("Database")?;
+
+		Ok(())
+	}
+
+	/// Adds the delivery's file to the library, or replaces the document the last run delivered with it if that's still around
+	/// (and not in the trash).  A document a tablet is uploading isn't replaced; the run fails, and keeps the last run's hash so
+	/// that the next run tries again.  Returns the document's ID and the file's hash.
+	async fn deliver(&self, delivery: &Delivery) -> Result<(String, String)> {
+		let (data, content_type) = self.read_source(delivery).await?;
+		let data_hash = hex::encode(digest::digest(&digest::SHA256, &data));
+
+		let metadata = database::list_metadata(&self.db).await?;
+		let tree = FolderTree::from_metadata(&metadata);
+		let previous = delivery
+			.document_id
+			.as_deref()
+			.and_then(|id| metadata.iter().find(|x| x.id == id))
+			.filter(|previous| !tree.is_trashed(&previous.id));
+
+		if let Some(previous) = previous {
+			if delivery.data_hash.as_deref() == Some(data_hash.as_str()) {
+				debug!("Delivery {} ({}) hasn't changed since its last run", delivery.id, delivery.name);
+				return Ok((previous.id.clone(), data_hash));
+			}
+		}
+
+		let name = delivery.name.clone();
+		let layout = self.server_config.options.image_page_layout();
+		let (document_info, mut document) = actix_web::web::block(move || {
+			library::archive_file(data, &content_type, &name, &layout)
+				.map(|document| document.map(|document| (DocumentInfo::from_document(&document), document)))
+		})
+		.await
+		.context("Converting document")??
+		.map_err(|err| anyhow!("{}", err))?;
+
+		let event = match previous {
+			Some(previous) => {
+				if let Source::Planner { .. } = delivery.source {
+					if let Some((_, old)) = rmdoc::load_document(&previous.id, None, &self.db).await? {
+						document = keep_pages(old, document);
+					}
+				}
+				document.id = previous.id.clone();
+				library::update_document(&document, previous.version, EventSource::server(), &self.db)
+					.await?
+					.context("The document changed, or a tablet is uploading a new version of it, while it was being replaced")?
+			}
+			None => {
+				let name = library::name_from_template(&self.server_config.options.name_template, &document_info, &delivery.name);
+				library::add_document(&document, &name, &delivery.folder, EventSource::server(), &self.db)
+					.await?
+					.map_err(|err| anyhow!("Invalid folder: {}", err))?
+			}
+		};
+		if !document_info.is_empty() {
+			library::save_document_info(&document.id, &document_info, &self.db).await?;
+		}
+
+		info!(
+			"Delivered {} ({}) as version {} of {}",
+			delivery.id, delivery.name, event.document.version, document.id
+		);
+		event.publish(&self.event_bus);
+
+		Ok((document.id, data_hash))
+	}
+
+	async fn read_source(&self, delivery: &Delivery) -> Result<(Vec<u8>, String)> {
+		match &delivery.source {
+			Source::Upload { content_type } => {
+				let data: Option<Vec<u8>> = sqlx::query_scalar("SELECT data FROM deliveries WHERE id=?")
+					.bind(delivery.id)
+					.fetch_one(&self.db)
+					.await
+					.context("Database")?;
+
+				Ok((data.context("The uploaded file is missing")?, content_type.clone()))
+			}
+			Source::File { path } => {
+				let data = {
+					let path = path.clone();
+					actix_web::web::block(move || std::fs::read(path)).await.context("Reading file")?
+				};
+
+				Ok((
+					data.with_context(|| format!("Unable to read {}", path.display()))?,
+					content_type_of(path).to_owned(),
+				))
+			}
+			Source::Planner { path, layout, start, days } => {
+				let calendar = match path {
+					Some(path) => {
+						let path = path.clone();
+						actix_web::web::block(move || read_calendars(&path))
+							.await
+							.context("Reading calendars")??
+					}
+					None => sqlx::query_scalar::<_, Option<Vec<u8>>>("SELECT data FROM deliveries WHERE id=?")
+						.bind(delivery.id)
+						.fetch_one(&self.db)
+						.await
+						.context("Database")?
+						.context("The uploaded calendar is missing")?,
+				};
+
+				let (layout, start, days) = (*layout, *start, *days);
+				let pdf = actix_web::web::block(move || planner::planner_pdf(&String::from_utf8_lossy(&calendar), layout, start, days))
+					.await
+					.context("Making planner")??;
+
+				Ok((pdf, "application/pdf".to_owned()))
+			}
+		}
+	}
+}
+
+
+/// The .ics file at `path`, or every .ics file in the directory at `path`, in order of their names, one after the other
+fn read_calendars(path: &Path) -> Result<Vec<u8>> {
+	if !path.is_dir() {
+		return std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()));
+	}
+
+	let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+		.with_context(|| format!("Unable to read {}", path.display()))?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|file| {
+			let extension = file.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+			extension.eq_ignore_ascii_case("ics") && file.is_file()
+		})
+		.collect();
+	files.sort();
+
+	let mut calendars = Vec::new();
+	for file in files {
+		calendars.extend(std::fs::read(&file).with_context(|| format!("Unable to read {}", file.display()))?);
+		calendars.push(b'\n');
+	}
+
+	Ok(calendars)
+}
+
+
+/// The previous version of a planner with the pages of the new one's PDF, if it has as many, so that the pages keep their IDs
+/// and whatever was written on them.  Otherwise the new one as it is.
+fn keep_pages(mut previous: RmDocument, planner: RmDocument) -> RmDocument {
+	let previous_pages = previous
+		.pdf
+		.clone()
+		.and_then(|pdf| hayro::hayro_syntax::Pdf::new(pdf).ok())
+		.map(|pdf| pdf.pages().len());
+	if previous.file_type() != FileType::Pdf || previous_pages != Some(planner.pages.len()) {
+		return planner;
+	}
+
+	previous.pdf = planner.pdf;
+	// They're of the old pages, and the tablet makes them again
+	let thumbnails = format!("{}.thumbnails/", previous.id);
+	previous.other_files.retain(|path, _| !path.starts_with(&thumbnails));
+	previous
+}
@@ -0,0 +1,279 @@
+use crate::{
+	database::{DbFileMetadata, MetadataChange},
+	tree, EVENT_BACKLOG_LEN,
+};
+use actix::prelude::*;
+use futures::channel::mpsc;
+use log::debug;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Arc};
+
+
+/// The names event filters accept (see DocumentEvent::matches_name): the tablet's event types, then the event kinds
+pub const EVENT_NAMES: &[&str] = &[
+	"DocAdded",
+	"DocDeleted",
+	"created",
+	"deleted",
+	"trashed",
+	"restored",
+	"moved",
+	"renamed",
+	"updated",
+];
+
+
+/// What happened to a document.
+/// A single mutation can change several things at once (e.g. rename and move); the event's kind is the most significant of them,
+/// in the order they're listed here.  Subscribers that care about the details can diff `previous` and `document`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentEventKind {
+	Created,
+	Deleted,
+	Trashed,
+	Restored,
+	Moved,
+	Renamed,
+	Updated,
+}
+
+impl DocumentEventKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			DocumentEventKind::Created => "created",
+			DocumentEventKind::Deleted => "deleted",
+			DocumentEventKind::Trashed => "trashed",
+			DocumentEventKind::Restored => "restored",
+			DocumentEventKind::Moved => "moved",
+			DocumentEventKind::Renamed => "renamed",
+			DocumentEventKind::Updated => "updated",
+		}
+	}
+}
+
+
+/// Who made a change.
+#[derive(Clone, Debug, Serialize)]
+pub struct EventSource {
+	pub device_desc: String,
+	pub device_id: String,
+}
+
+impl EventSource {
+	pub fn new(device_desc: &str, device_id: &str) -> Self {
+		Self {
+			device_desc: device_desc.to_owned(),
+			device_id: device_id.to_owned(),
+		}
+	}
+
+	/// Changes made through the admin API or CLI
+	pub fn admin() -> Self {
+		Self::new("admin", "admin")
+	}
+
+	/// Changes the server makes on its own (maintenance, etc)
+	pub fn server() -> Self {
+		Self::new("rm-personal-cloud", "rm-personal-cloud")
+	}
+
+	/// Changes made by a filing rule (see `rules`)
+	pub fn rule(id: i64) -> Self {
+		Self::new("rules", &format!("rule-{}", id))
+	}
+
+	pub fn is_rule(&self) -> bool {
+		self.device_desc == "rules" && self.device_id.starts_with("rule-")
+	}
+}
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DocumentEvent {
+	pub kind: DocumentEventKind,
+	/// The document as it is after the change (or, for deletes, as it was when deleted)
+	pub document: DbFileMetadata,
+	/// The last committed version before the change, if there was one
+	pub previous: Option<DbFileMetadata>,
+	pub source: EventSource,
+}
+
+impl DocumentEvent {
+	/// Derives the event for a metadata update by diffing the old and new metadata.
+	pub fn from_change(change: MetadataChange, source: EventSource) -> Self {
+		let kind = match &change.previous {
+			None => DocumentEventKind::Created,
+			Some(previous) if previous.parent != change.current.parent => {
+				if change.current.parent == tree::TRASH {
+					DocumentEventKind::Trashed
+				} else if previous.parent == tree::TRASH {
+					DocumentEventKind::Restored
+				} else {
+					DocumentEventKind::Moved
+				}
+			}
+			Some(previous) if previous.name != change.current.name => DocumentEventKind::Renamed,
+			Some(_) => DocumentEventKind::Updated,
+		};
+
+		DocumentEvent {
+			kind,
+			document: change.current,
+			previous: change.previous,
+			source,
+		}
+	}
+
+	pub fn deleted(metadata: DbFileMetadata, source: EventSource) -> Self {
+		DocumentEvent {
+			kind: DocumentEventKind::Deleted,
+			document: metadata,
+			previous: None,
+			source,
+		}
+	}
+
+	/// The event type the tablet knows about.
+	/// Yes, all changes have an event type of "DocAdded"
+	pub fn tablet_event(&self) -> &'static str {
+		match self.kind {
+			DocumentEventKind::Deleted => "DocDeleted",
+			_ => "DocAdded",
+		}
+	}
+
+	/// Event filters can name either the tablet's event type (DocAdded, DocDeleted) or an event kind (moved, trashed, ...)
+	pub fn matches_name(&self, name: &str) -> bool {
+		name == self.tablet_event() || name == self.kind.as_str()
+	}
+
+	pub fn publish(self, event_bus: &Addr<EventBus>) {
+		event_bus.do_send(Publish(self));
+	}
+}
+
+
+#[derive(Clone)]
+pub struct SequencedEvent {
+	pub sequence: u64,
+	pub event: Arc<DocumentEvent>,
+}
+
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish(pub DocumentEvent);
+
+/// Sent to subscribed actors for every published event
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EventMessage(pub SequencedEvent);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe(pub Recipient<EventMessage>);
+
+/// Subscribes to events through a channel, rather than as an actor.
+/// If `since` is given, any events after that sequence number that are still in the backlog are returned as well.
+#[derive(Message)]
+#[rtype(result = "StreamSubscription")]
+pub struct SubscribeStream {
+	pub since: Option<u64>,
+}
+
+pub struct StreamSubscription {
+	pub backlog: Vec<SequencedEvent>,
+	pub receiver: mpsc::UnboundedReceiver<SequencedEvent>,
+	/// Sequence number of the most recent event
+	pub latest: u64,
+}
+
+
+/// Every mutation path publishes its DocumentEvents here, and everything that reacts to changes (the tablet's notification websocket,
+/// SSE/long-poll clients, webhooks, ...) subscribes to it.
+pub struct EventBus {
+	subscriptions: Vec<Recipient<EventMessage>>,
+	streams: Vec<mpsc::UnboundedSender<SequencedEvent>>,
+	/// The last EVENT_BACKLOG_LEN events, so stream clients can catch up after reconnecting
+	backlog: VecDeque<SequencedEvent>,
+	latest_sequence: u64,
+}
+
+impl EventBus {
+	pub fn new() -> Self {
+		Self {
+			subscriptions: Vec::new(),
+			streams: Vec::new(),
+			backlog: VecDeque::new(),
+			latest_sequence: 0,
+		}
+	}
+}
+
+impl Actor for EventBus {
+	type Context = Context<Self>;
+}
+
+impl Handler<Subscribe> for EventBus {
+	type Result = ();
+
+	fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+		self.subscriptions.push(msg.0);
+		debug!("EventBus: Total Subscriptions: {}", self.subscriptions.len());
+	}
+}
+
+impl Handler<SubscribeStream> for EventBus {
+	type Result = MessageResult<SubscribeStream>;
+
+	fn handle(&mut self, msg: SubscribeStream, _: &mut Context<Self>) -> Self::Result {
+		let (sender, receiver) = mpsc::unbounded();
+		self.streams.push(sender);
+		debug!("EventBus: Total Streams: {}", self.streams.len());
+
+		let backlog = match msg.since {
+			Some(since) => self.backlog.iter().filter(|event| event.sequence > since).cloned().collect(),
+			None => Vec::new(),
+		};
+
+		MessageResult(StreamSubscription {
+			backlog,
+			receiver,
+			latest: self.latest_sequence,
+		})
+	}
+}
+
+impl Handler<Publish> for EventBus {
+	type Result = ();
+
+	fn handle(&mut self, msg: Publish, _: &mut Context<Self>) -> Self::Result {
+		debug!("Publishing event: {:?} {}", msg.0.kind, msg.0.document.id);
+
+		self.latest_sequence += 1;
+		let event = SequencedEvent {
+			sequence: self.latest_sequence,
+			event: Arc::new(msg.0),
+		};
+
+		self.subscriptions.retain(|addr| {
+			// Remove dead subscriptions
+			if !addr.connected() {
+				debug!("EventBus: Removing dead subscription");
+				return false;
+			}
+
+			addr.do_send(EventMessage(event.clone()));
+			true
+		});
+
+		// Dead streams are removed when their receiver has been dropped
+		self.streams.retain(|stream| stream.unbounded_send(event.clone()).is_ok());
+
+		if self.backlog.len() >= EVENT_BACKLOG_LEN {
+			self.backlog.pop_front();
+		}
+		self.backlog.push_back(event);
+	}
+}
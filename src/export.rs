@@ -0,0 +1,85 @@
+//! Exports of whole folders, for reading or archiving elsewhere.
+use crate::{
+	database::{self, DbFileMetadata},
+	rmdoc::{self, markdown},
+	tree::{self, FolderTree},
+};
+use anyhow::Result;
+use log::debug;
+use sqlx::SqlitePool;
+use std::{
+	collections::{HashMap, HashSet},
+	io::{Cursor, Write},
+};
+use zip::{write::FileOptions, ZipWriter};
+
+
+/// A zip with the Markdown export of every document in a folder (at any depth), laid out in the same folders.  Returns None if
+/// there's no such folder.  Documents that can't be read are left out.
+pub async fn folder_markdown(folder: &str, db: &SqlitePool) -> Result<Option<Vec<u8>>> {
+	let metadata = database::list_metadata(db).await?;
+	let items: HashMap<&str, &DbFileMetadata> = metadata.iter().map(|x| (x.id.as_str(), x)).collect();
+
+	if folder != tree::ROOT && items.get(folder).is_none_or(|x| x.file_type != tree::COLLECTION_TYPE) {
+		return Ok(None);
+	}
+
+	let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+	let mut used_paths = HashSet::new();
+
+	for id in FolderTree::from_metadata(&metadata).descendants(folder) {
+		let item = items[id.as_str()];
+		if item.file_type != tree::DOCUMENT_TYPE {
+			continue;
+		}
+
+		let document = match rmdoc::load_document(&id, Some(item.version), db).await {
+			Ok(Some((_, document))) => document,
+			Ok(None) => continue,
+			Err(err) => {
+				debug!("Leaving {} out of Markdown export: {:?}", id, err);
+				continue;
+			}
+		};
+
+		// Where the document is, relative to the folder
+		let mut path = vec![file_name(&item.name)];
+		let mut parent = item.parent.as_str();
+		while parent != folder {
+			match items.get(parent) {
+				Some(x) if path.len() < 256 => {
+					path.push(file_name(&x.name));
+					parent = x.parent.as_str();
+				}
+				_ => break,
+			}
+		}
+		path.reverse();
+		let path = path.join("/");
+
+		// Documents can share a name; zip entries can't
+		let mut unique_path = format!("{}.md", path);
+		for i in 2.. {
+			if used_paths.insert(unique_path.to_lowercase()) {
+				break;
+			}
+			unique_path = format!("{} ({}).md", path, i);
+		}
+
+		writer.start_file(unique_path, FileOptions::default())?;
+		writer.write_all(markdown::export_markdown(&document, &item.name).as_bytes())?;
+	}
+
+	Ok(Some(writer.finish()?.into_inner()))
+}
+
+
+/// A name that's safe to use as a file name in the zip
+fn file_name(name: &str) -> String {
+	let name = name.trim().replace(|c: char| c == '/' || c == '\\' || c.is_control(), "_");
+
+	match name.as_str() {
+		"" | "." | ".." => "Untitled".to_owned(),
+		_ => name,
+	}
+}
@@ -0,0 +1,280 @@
+//! Handwriting recognition, for the tablet's "Convert to text".
+//!
+//! The tablet sends its strokes in MyScript's batch format and expects MyScript's JIIX back.  Recognition itself is left to an
+//! `HwrEngine`; the API handler translates between the tablet's formats and the engine's.
+use crate::{
+	config::{HwrEngineKind, ServerOptions},
+	HWR_MAX_RESPONSE_SIZE, HWR_TIMEOUT,
+};
+use anyhow::{bail, Context, Result};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+
+/// What to recognize.  This is also the body of the request the HTTP engine sends.
+#[derive(Debug, Serialize)]
+pub struct HwrInput {
+	/// What the strokes are: "Text", "Math" or "Diagram"
+	pub content_type: String,
+	/// e.g. "en_US"
+	pub language: String,
+	/// The size of the page, in pixels
+	pub width: f64,
+	pub height: f64,
+	/// Pixels per inch
+	pub dpi: f64,
+	/// In the order they were written
+	pub strokes: Vec<Stroke>,
+}
+
+
+/// The points of a stroke, as parallel arrays.  `t` (milliseconds since the epoch) and `p` (pressure, from 0 to 1) are empty if
+/// the tablet didn't send them.
+#[derive(Debug, Serialize)]
+pub struct Stroke {
+	pub x: Vec<f64>,
+	pub y: Vec<f64>,
+	pub t: Vec<i64>,
+	pub p: Vec<f64>,
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct Recognition {
+	pub text: String,
+	/// The words of `text`, with the alternatives the engine considered.  Worked out from `text` if the engine doesn't give them.
+	#[serde(default)]
+	pub words: Vec<Word>,
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct Word {
+	pub label: String,
+	/// Best first
+	#[serde(default)]
+	pub candidates: Vec<String>,
+}
+
+
+pub trait HwrEngine {
+	/// For logs
+	fn name(&self) -> &'static str;
+
+	fn recognize<'a>(&'a self, input: &'a HwrInput) -> LocalBoxFuture<'a, Result<Recognition>>;
+}
+
+
+/// The engine chosen on the command line, if any
+pub fn engine(options: &ServerOptions) -> Option<Box<dyn HwrEngine>> {
+	match options.hwr_engine {
+		HwrEngineKind::Disabled => None,
+		HwrEngineKind::Http => Some(Box::new(HttpEngine::new(
+			// clap makes sure there's a URL
+			options.hwr_url.clone().unwrap_or_default(),
+			options.hwr_api_key.clone(),
+		))),
+		HwrEngineKind::Fixture => Some(Box::new(FixtureEngine {
+			text: options.hwr_fixture_text.clone(),
+		})),
+	}
+}
+
+
+/// POSTs the `HwrInput` as JSON to a recognition service, which responds with a `Recognition` as JSON.
+pub struct HttpEngine {
+	url: String,
+	/// Sent as a bearer token
+	api_key: Option<String>,
+	client: awc::Client,
+}
+
+impl HttpEngine {
+	pub fn new(url: String, api_key: Option<String>) -> Self {
+		Self {
+			url,
+			api_key,
+			client: awc::Client::builder().timeout(HWR_TIMEOUT).finish(),
+		}
+	}
+
+	async fn send(&self, input: &HwrInput) -> Result<Recognition> {
+		let mut request = self.client.post(&self.url);
+		if let Some(api_key) = &self.api_key {
+			request = request.bearer_auth(api_key);
+		}
+
+		let mut response = request
+			.send_json(input)
+			.await
+			.map_err(|err| anyhow::anyhow!("Sending to {}: {}", self.url, err))?;
+		let body = response
+			.body()
+			.limit(HWR_MAX_RESPONSE_SIZE)
+			.await
+			.with_context(|| format!("Reading response from {}", self.url))?;
+
+		if !response.status().is_success() {
+			bail!("{} responded {}: {}", self.url, response.status(), String::from_utf8_lossy(&body));
+		}
+
+		serde_json::from_slice(&body).with_context(|| format!("Invalid response from {}", self.url))
+	}
+}
+
+impl HwrEngine for HttpEngine {
+	fn name(&self) -> &'static str {
+		"http"
+	}
+
+	fn recognize<'a>(&'a self, input: &'a HwrInput) -> LocalBoxFuture<'a, Result<Recognition>> {
+		self.send(input).boxed_local()
+	}
+}
+
+
+/// Recognizes the same text whatever is written, for testing the tablet's side of things without a recognition service.
+pub struct FixtureEngine {
+	pub text: String,
+}
+
+impl HwrEngine for FixtureEngine {
+	fn name(&self) -> &'static str {
+		"fixture"
+	}
+
+	fn recognize<'a>(&'a self, _input: &'a HwrInput) -> LocalBoxFuture<'a, Result<Recognition>> {
+		future::ready(Ok(Recognition {
+			text: self.text.clone(),
+			words: Vec::new(),
+		}))
+		.boxed_local()
+	}
+}
+
+
+/// The batch request the tablet sends, as far as we need it
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageRequest {
+	#[serde(default)]
+	configuration: PageConfiguration,
+	content_type: String,
+	#[serde(default)]
+	width: f64,
+	#[serde(default)]
+	height: f64,
+	#[serde(rename = "xDPI")]
+	x_dpi: Option<f64>,
+	stroke_groups: Vec<StrokeGroup>,
+}
+
+#[derive(Default, Deserialize)]
+struct PageConfiguration {
+	lang: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StrokeGroup {
+	strokes: Vec<PageStroke>,
+}
+
+#[derive(Deserialize)]
+struct PageStroke {
+	x: Vec<f64>,
+	y: Vec<f64>,
+	#[serde(default)]
+	t: Vec<i64>,
+	#[serde(default)]
+	p: Vec<f64>,
+}
+
+
+impl PageRequest {
+	/// The engine's input.  Errors if the strokes don't make sense.
+	pub fn into_input(self) -> Result<HwrInput, String> {
+		let mut strokes = Vec::new();
+
+		for stroke in self.stroke_groups.into_iter().flat_map(|group| group.strokes) {
+			let points = stroke.x.len();
+			if stroke.y.len() != points || (!stroke.t.is_empty() && stroke.t.len() != points) || (!stroke.p.is_empty() && stroke.p.len() != points) {
+				return Err(format!(
+					"Stroke {} has a different number of values for each of its points",
+					strokes.len()
+				));
+			}
+
+			// Taps without any points don't help anyone
+			if points > 0 {
+				strokes.push(Stroke {
+					x: stroke.x,
+					y: stroke.y,
+					t: stroke.t,
+					p: stroke.p,
+				});
+			}
+		}
+
+		Ok(HwrInput {
+			content_type: self.content_type,
+			language: self.configuration.lang.unwrap_or_else(|| "en_US".to_owned()),
+			width: self.width,
+			height: self.height,
+			// The reMarkable's screen
+			dpi: self.x_dpi.unwrap_or(226.0),
+			strokes,
+		})
+	}
+}
+
+
+/// The recognized text in JIIX, as the tablet asks for it
+pub fn to_jiix(recognition: &Recognition, content_type: &str) -> serde_json::Value {
+	let words: Vec<_> = if recognition.words.is_empty() {
+		split_words(&recognition.text).into_iter().map(|word| json!({ "label": word })).collect()
+	} else {
+		recognition
+			.words
+			.iter()
+			.map(|word| {
+				if word.candidates.is_empty() {
+					json!({ "label": word.label })
+				} else {
+					json!({ "label": word.label, "candidates": word.candidates })
+				}
+			})
+			.collect()
+	};
+
+	json!({
+		"type": content_type,
+		"label": recognition.text,
+		"words": words,
+		"version": "3",
+		"id": "MainBlock",
+	})
+}
+
+
+/// JIIX treats the spaces and line breaks between words as words of their own
+fn split_words(text: &str) -> Vec<&str> {
+	let mut words = Vec::new();
+	let mut start = 0;
+
+	for (i, c) in text.char_indices() {
+		if c == ' ' || c == '\n' {
+			if start < i {
+				words.push(&text[start..i]);
+			}
+			words.push(&text[i..i + 1]);
+			start = i + 1;
+		}
+	}
+	if start < text.len() {
+		words.push(&text[start..]);
+	}
+
+	words
+}
@@ -0,0 +1,414 @@
+//! Reads the events out of iCalendar (`.ics`) files, for planners (see `rmdoc::planner`).
+//!
+//! Times are read as the server's local time: times in UTC are converted, and times in a named time zone (TZID) are taken as
+//! they are.  Recurring events are expanded for the common rules (FREQ, INTERVAL, COUNT, UNTIL, BYDAY and BYMONTHDAY), less
+//! the dates in EXDATE and those that have been moved or cancelled individually (RECURRENCE-ID).
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use std::{collections::HashSet, convert::TryFrom};
+
+
+/// Stops a recurring event with no end from being expanded forever
+const MAX_OCCURRENCES: usize = 10_000;
+/// Longer INTERVALs are taken as this
+const MAX_INTERVAL: i64 = 10_000;
+
+
+#[derive(Clone, Debug)]
+pub struct Event {
+	pub summary: String,
+	pub location: Option<String>,
+	pub start: NaiveDateTime,
+	pub end: NaiveDateTime,
+	pub all_day: bool,
+}
+
+impl Event {
+	/// Whether any of the event falls on `date`
+	pub fn is_on(&self, date: NaiveDate) -> bool {
+		let day_start = date.and_hms(0, 0, 0);
+		let day_end = day_start + Duration::days(1);
+
+		self.start < day_end && (self.end > day_start || (self.end == self.start && self.start >= day_start))
+	}
+}
+
+
+/// A VEVENT as it's written, before any recurrence is expanded
+#[derive(Debug, Default)]
+struct RawEvent {
+	uid: Option<String>,
+	summary: Option<String>,
+	location: Option<String>,
+	start: Option<(NaiveDateTime, bool)>,
+	end: Option<NaiveDateTime>,
+	duration: Option<Duration>,
+	rrule: Option<String>,
+	exdates: Vec<NaiveDateTime>,
+	recurrence_id: Option<NaiveDateTime>,
+	cancelled: bool,
+}
+
+
+/// The events in `calendar` (one or more VCALENDARs, one after the other) that fall between `from` and `to` (inclusive),
+/// sorted by when they start.  Anything that can't be read is skipped.
+pub fn events_between(calendar: &str, from: NaiveDate, to: NaiveDate) -> Vec<Event> {
+	let raw_events = parse(calendar);
+	let range_start = from.and_hms(0, 0, 0);
+	let range_end = to.and_hms(0, 0, 0) + Duration::days(1);
+
+	// Occurrences of recurring events that have been changed have an event of their own, which replaces them
+	let moved: HashSet<(&str, NaiveDateTime)> = raw_events
+		.iter()
+		.filter_map(|event| Some((event.uid.as_deref()?, event.recurrence_id?)))
+		.collect();
+
+	let mut events = Vec::new();
+
+	for raw in raw_events.iter().filter(|event| !event.cancelled) {
+		let (start, all_day) = match raw.start {
+			Some(start) => start,
+			None => continue,
+		};
+		let length = match (raw.end, raw.duration) {
+			(Some(end), _) if end >= start => end - start,
+			(_, Some(duration)) => duration,
+			_ if all_day => Duration::days(1),
+			_ => Duration::zero(),
+		};
+
+		let starts = match raw.rrule.as_deref() {
+			Some(rrule) if raw.recurrence_id.is_none() => expand(rrule, start, range_end),
+			_ => vec![start],
+		};
+
+		for occurrence in starts {
+			let end = occurrence + length;
+			if end < range_start || occurrence >= range_end || raw.exdates.contains(&occurrence) {
+				continue;
+			}
+			if raw.recurrence_id.is_none() && raw.rrule.is_some() {
+				if let Some(uid) = raw.uid.as_deref() {
+					if moved.contains(&(uid, occurrence)) {
+						continue;
+					}
+				}
+			}
+
+			events.push(Event {
+				summary: raw.summary.clone().unwrap_or_default(),
+				location: raw.location.clone(),
+				start: occurrence,
+				end,
+				all_day,
+			});
+		}
+	}
+
+	events.sort_by(|a, b| (a.start, !a.all_day, &a.summary).cmp(&(b.start, !b.all_day, &b.summary)));
+	events
+}
+
+
+fn parse(calendar: &str) -> Vec<RawEvent> {
+	let mut events = Vec::new();
+	let mut current: Option<RawEvent> = None;
+	// Nested components (e.g. VALARM) have properties of their own, which mustn't be taken as the event's
+	let mut depth = 0;
+
+	for line in unfold(calendar) {
+		let (name, params, value) = match split_property(&line) {
+			Some(property) => property,
+			None => continue,
+		};
+
+		match (name.as_str(), value) {
+			("BEGIN", "VEVENT") if current.is_none() => current = Some(RawEvent::default()),
+			("BEGIN", _) if current.is_some() => depth += 1,
+			("END", "VEVENT") if depth == 0 => events.extend(current.take()),
+			("END", _) if depth > 0 => depth -= 1,
+			_ => (),
+		}
+
+		let event = match &mut current {
+			Some(event) if depth == 0 => event,
+			_ => continue,
+		};
+
+		match name.as_str() {
+			"UID" => event.uid = Some(value.to_owned()),
+			"SUMMARY" => event.summary = Some(unescape(value)),
+			"LOCATION" => event.location = Some(unescape(value)).filter(|location| !location.is_empty()),
+			"DTSTART" => event.start = parse_time(value, &params),
+			"DTEND" => event.end = parse_time(value, &params).map(|(end, _)| end),
+			"DURATION" => event.duration = parse_duration(value),
+			"RRULE" => event.rrule = Some(value.to_owned()),
+			"EXDATE" => event
+				.exdates
+				.extend(value.split(',').filter_map(|value| parse_time(value, &params)).map(|(time, _)| time)),
+			"RECURRENCE-ID" => event.recurrence_id = parse_time(value, &params).map(|(time, _)| time),
+			"STATUS" => event.cancelled = value.eq_ignore_ascii_case("CANCELLED"),
+			_ => (),
+		}
+	}
+
+	events
+}
+
+
+/// Lines, with long lines that were folded onto several put back together
+fn unfold(calendar: &str) -> Vec<String> {
+	let mut lines: Vec<String> = Vec::new();
+
+	for line in calendar.lines() {
+		let line = line.trim_end_matches('\r');
+		match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+			(Some(continuation), Some(last)) => last.push_str(continuation),
+			_ => lines.push(line.to_owned()),
+		}
+	}
+
+	lines
+}
+
+
+/// A content line's name (upper case), parameters (e.g. "VALUE=DATE", "TZID=Europe/London") and value
+fn split_property(line: &str) -> Option<(String, Vec<String>, &str)> {
+	let (head, value) = line.split_once(':')?;
+	let mut parts = head.split(';');
+	let name = parts.next()?.trim().to_ascii_uppercase();
+	let params = parts.map(|param| param.trim().to_ascii_uppercase()).collect();
+
+	Some((name, params, value.trim()))
+}
+
+
+/// A DATE or DATE-TIME, as local time, and whether it's a date (i.e. all day)
+fn parse_time(value: &str, params: &[String]) -> Option<(NaiveDateTime, bool)> {
+	let value = value.trim();
+
+	if params.iter().any(|param| param == "VALUE=DATE") || value.len() == 8 {
+		return NaiveDate::parse_from_str(value, "%Y%m%d").ok().map(|date| (date.and_hms(0, 0, 0), true));
+	}
+
+	match value.strip_suffix('Z') {
+		Some(utc) => {
+			let utc = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+			Some((Utc.from_utc_datetime(&utc).with_timezone(&Local).naive_local(), false))
+		}
+		None => NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok().map(|time| (time, false)),
+	}
+}
+
+
+/// e.g. "PT1H30M", "P1D", "P2W"
+fn parse_duration(value: &str) -> Option<Duration> {
+	let (negative, value) = match value.strip_prefix('-') {
+		Some(value) => (true, value),
+		None => (false, value.strip_prefix('+').unwrap_or(value)),
+	};
+	let mut rest = value.strip_prefix('P')?;
+	let mut duration = Duration::zero();
+
+	while !rest.is_empty() {
+		rest = rest.strip_prefix('T').unwrap_or(rest);
+		let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+		let number: i64 = rest[..digits].parse().ok()?;
+		duration = duration
+			+ match rest[digits..].chars().next()? {
+				'W' => Duration::weeks(number),
+				'D' => Duration::days(number),
+				'H' => Duration::hours(number),
+				'M' => Duration::minutes(number),
+				'S' => Duration::seconds(number),
+				_ => return None,
+			};
+		rest = &rest[digits + 1..];
+	}
+
+	Some(if negative { -duration } else { duration })
+}
+
+
+fn unescape(value: &str) -> String {
+	let mut text = String::with_capacity(value.len());
+	let mut chars = value.chars();
+
+	while let Some(c) = chars.next() {
+		match (c, c == '\\') {
+			(_, true) => match chars.next() {
+				Some('n') | Some('N') => text.push('\n'),
+				Some(escaped) => text.push(escaped),
+				None => (),
+			},
+			(c, false) => text.push(c),
+		}
+	}
+
+	text
+}
+
+
+/// When a recurring event starting at `start` happens, up to `until`
+fn expand(rrule: &str, start: NaiveDateTime, until: NaiveDateTime) -> Vec<NaiveDateTime> {
+	let rule: Vec<(String, String)> = rrule
+		.split(';')
+		.filter_map(|part| part.split_once('='))
+		.map(|(name, value)| (name.trim().to_ascii_uppercase(), value.trim().to_ascii_uppercase()))
+		.collect();
+	let get = |name: &str| rule.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+	// Anything longer repeats too rarely to matter in a planner, and would overflow when multiplied out
+	let interval = get("INTERVAL")
+		.and_then(|interval| interval.parse::<i64>().ok())
+		.filter(|interval| *interval > 0)
+		.unwrap_or(1)
+		.min(MAX_INTERVAL);
+	let count = get("COUNT").and_then(|count| count.parse::<usize>().ok());
+	let until = match get("UNTIL").and_then(|value| parse_time(value, &[])) {
+		// An UNTIL that's a date includes that whole day
+		Some((rule_until, true)) => until.min(rule_until + Duration::days(1)),
+		Some((rule_until, false)) => until.min(rule_until + Duration::seconds(1)),
+		None => until,
+	};
+	let by_day: Vec<(Option<i64>, Weekday)> = get("BYDAY").map(parse_by_day).unwrap_or_default();
+	let by_month_day: Vec<i64> = get("BYMONTHDAY")
+		.map(|days| days.split(',').filter_map(|day| day.parse().ok()).collect())
+		.unwrap_or_default();
+
+	let time = start.time();
+	let start_date = start.date();
+	let mut occurrences = Vec::new();
+
+	// The dates each period (day, week, month or year) of the rule has, in order.  None once they're past what dates can represent.
+	let period_dates = |period: i64| -> Option<Vec<NaiveDate>> {
+		let offset = period.checked_mul(interval)?;
+
+		Some(match get("FREQ") {
+			Some("DAILY") => vec![start_date.checked_add_signed(Duration::days(offset))?],
+			Some("WEEKLY") => {
+				let monday = start_date
+					.checked_sub_signed(Duration::days(start_date.weekday().num_days_from_monday() as i64))?
+					.checked_add_signed(Duration::weeks(offset))?;
+				let mut days: Vec<Weekday> = by_day.iter().map(|(_, day)| *day).collect();
+				if days.is_empty() {
+					days.push(start_date.weekday());
+				}
+				let mut dates: Vec<NaiveDate> = days
+					.into_iter()
+					.map(|day| monday.checked_add_signed(Duration::days(day.num_days_from_monday() as i64)))
+					.collect::<Option<_>>()?;
+				dates.sort();
+				dates.dedup();
+				dates
+			}
+			Some("MONTHLY") => {
+				let months = (start_date.year() as i64 * 12 + start_date.month0() as i64).checked_add(offset)?;
+				let (year, month) = (i32::try_from(months.div_euclid(12)).ok()?, months.rem_euclid(12) as u32 + 1);
+				NaiveDate::from_ymd_opt(year, month, 1)?;
+				let mut dates = if !by_day.is_empty() {
+					by_day
+						.iter()
+						.flat_map(|&(ordinal, day)| weekdays_in_month(year, month, day, ordinal))
+						.collect()
+				} else if !by_month_day.is_empty() {
+					by_month_day.iter().filter_map(|&day| month_day(year, month, day)).collect()
+				} else {
+					month_day(year, month, start_date.day() as i64).into_iter().collect::<Vec<_>>()
+				};
+				dates.sort();
+				dates.dedup();
+				dates
+			}
+			Some("YEARLY") => {
+				let year = start_date.year().checked_add(i32::try_from(offset).ok()?)?;
+				NaiveDate::from_ymd_opt(year, 1, 1)?;
+				// Nothing in years without the start's day, e.g. 29 February
+				NaiveDate::from_ymd_opt(year, start_date.month(), start_date.day()).into_iter().collect()
+			}
+			_ => Vec::new(),
+		})
+	};
+
+	for period in 0.. {
+		if get("FREQ").is_none() || occurrences.len() >= MAX_OCCURRENCES || period > MAX_OCCURRENCES as i64 {
+			break;
+		}
+		let dates = match period_dates(period) {
+			Some(dates) => dates,
+			None => break,
+		};
+
+		for date in dates {
+			let occurrence = date.and_time(time);
+			if occurrence < start {
+				continue;
+			}
+			if occurrence >= until || count.into_iter().any(|count| occurrences.len() >= count) {
+				return occurrences;
+			}
+			occurrences.push(occurrence);
+		}
+	}
+
+	occurrences
+}
+
+
+/// e.g. "MO,WE" or "1MO,-1FR" (the first Monday and last Friday)
+fn parse_by_day(value: &str) -> Vec<(Option<i64>, Weekday)> {
+	value
+		.split(',')
+		.filter_map(|day| {
+			let day = day.trim();
+			let split = day.len().checked_sub(2)?;
+			let weekday = match &day[split..] {
+				"MO" => Weekday::Mon,
+				"TU" => Weekday::Tue,
+				"WE" => Weekday::Wed,
+				"TH" => Weekday::Thu,
+				"FR" => Weekday::Fri,
+				"SA" => Weekday::Sat,
+				"SU" => Weekday::Sun,
+				_ => return None,
+			};
+			let ordinal = match &day[..split] {
+				"" => None,
+				ordinal => Some(ordinal.trim_start_matches('+').parse().ok()?),
+			};
+			Some((ordinal, weekday))
+		})
+		.collect()
+}
+
+
+/// Every `weekday` in the month, or with `ordinal`, just the nth (counting back from the end if negative)
+fn weekdays_in_month(year: i32, month: u32, weekday: Weekday, ordinal: Option<i64>) -> Vec<NaiveDate> {
+	let days: Vec<NaiveDate> = (1..=31)
+		.filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+		.filter(|date| date.weekday() == weekday)
+		.collect();
+
+	match ordinal {
+		None => days,
+		Some(ordinal) if ordinal > 0 => days.get(ordinal as usize - 1).copied().into_iter().collect(),
+		Some(ordinal) => days
+			.len()
+			.checked_sub((-ordinal) as usize)
+			.and_then(|index| days.get(index))
+			.copied()
+			.into_iter()
+			.collect(),
+	}
+}
+
+
+/// Day `day` of the month, counting back from the end if negative
+fn month_day(year: i32, month: u32, day: i64) -> Option<NaiveDate> {
+	let days_in_month = (28..=31).rev().find(|&day| NaiveDate::from_ymd_opt(year, month, day).is_some())? as i64;
+	let day = if day < 0 { days_in_month + day + 1 } else { day };
+
+	if day < 1 || day > days_in_month {
+		return None;
+	}
+	NaiveDate::from_ymd_opt(year, month, day as u32)
+}
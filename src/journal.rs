@@ -0,0 +1,224 @@
+//! A notebook for each day (e.g. "2026-10-17"), created in `--journal-folder` on `--journal-schedule`.  It's added like any other
+//! document, so it shows up on the tablets without anyone having to make it.  With `--journal-carry-todos`, the pages of the
+//! previous day's notebook that still have to-dos on them are copied to the start of the new one.
+use crate::{
+	config::ServerConfig,
+	database,
+	events::{EventBus, EventSource},
+	library,
+	rmdoc::{
+		self,
+		lines::{Lines, ParagraphStyle},
+		FileType, PageTag, RmDocument,
+	},
+	tree::{self, FolderTree},
+	JOURNAL_CHECK_INTERVAL,
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use chrono::{Duration, Local, TimeZone};
+use log::{debug, info, warn};
+use sqlx::SqlitePool;
+
+
+/// Keys in the config table
+const LAST_RUN_KEY: &str = "journal_last_run";
+const LAST_DOCUMENT_KEY: &str = "journal_last_document";
+
+
+/// Create the day's notebook if it's due
+#[derive(Message)]
+#[rtype(result = "()")]
+struct CheckDue;
+
+
+/// Creates the day's notebook when `--journal-schedule` comes round, checking every JOURNAL_CHECK_INTERVAL.  If the server was
+/// down when it was due, it's created when the server starts.
+pub struct Journal {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	server_config: ServerConfig,
+}
+
+impl Journal {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>, server_config: ServerConfig) -> Self {
+		Self {
+			db,
+			event_bus,
+			server_config,
+		}
+	}
+}
+
+impl Actor for Journal {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		if self.server_config.options.journal_folder.is_none() {
+			ctx.stop();
+			return;
+		}
+
+		ctx.notify(CheckDue);
+		ctx.run_interval(JOURNAL_CHECK_INTERVAL, |_act, ctx| ctx.notify(CheckDue));
+	}
+}
+
+impl Handler<CheckDue> for Journal {
+	type Result = ();
+
+	fn handle(&mut self, _: CheckDue, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+		let event_bus = self.event_bus.clone();
+		let server_config = self.server_config.clone();
+
+		ctx.wait(
+			async move {
+				if let Err(err) = check_due(&db, &event_bus, &server_config).await {
+					warn!("Unable to create the day's journal notebook: {:?}", err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+
+async fn check_due(db: &SqlitePool, event_bus: &Addr<EventBus>, server_config: &ServerConfig) -> Result<()> {
+	let options = &server_config.options;
+	let now = Local::now();
+
+	// The first time, a run in the last day counts, so that the day's notebook is there straight away
+	let last_run = match get_config(LAST_RUN_KEY, db).await?.and_then(|last_run| last_run.parse().ok()) {
+		Some(last_run) => Local.timestamp(last_run, 0),
+		None => now - Duration::days(1),
+	};
+	match options.journal_schedule.next_after(&last_run) {
+		Some(due) if due <= now => (),
+		_ => return Ok(()),
+	}
+
+	let folder_path = options.journal_folder.as_deref().unwrap_or_default();
+	let (folder, folder_events) = library::folder_by_path(folder_path, tree::ROOT, EventSource::server(), db)
+		.await?
+		.map_err(|err| anyhow::anyhow!("Invalid journal folder: {}", err))?;
+	for event in folder_events {
+		event.publish(event_bus);
+	}
+
+	let name = now.format(&options.journal_name).to_string();
+	let metadata = database::list_metadata(db).await?;
+	let tree = FolderTree::from_metadata(&metadata);
+	let exists = metadata
+		.iter()
+		.any(|x| x.parent == folder && x.name == name && x.file_type != tree::COLLECTION_TYPE);
+
+	if exists {
+		debug!("The journal notebook {} already exists", name);
+	} else {
+		let mut document = library::blank_notebook(options.journal_pages as usize, &options.journal_template);
+
+		if options.journal_carry_todos {
+			let previous = match get_config(LAST_DOCUMENT_KEY, db).await? {
+				Some(id) if metadata.iter().any(|x| x.id == id) && !tree.is_trashed(&id) => rmdoc::load_document(&id, None, db).await?,
+				_ => None,
+			};
+			if let Some((_, previous)) = previous {
+				document = carry_todos(&previous, document, &options.journal_todo_tag);
+			}
+		}
+
+		let event = library::add_document(&document, &name, &folder, EventSource::server(), db)
+			.await?
+			.map_err(|err| anyhow::anyhow!("Invalid journal folder: {}", err))?;
+		info!("Created the journal notebook {} with {} pages", name, document.pages.len());
+		event.publish(event_bus);
+
+		set_config(LAST_DOCUMENT_KEY, &document.id, db).await?;
+	}
+
+	set_config(LAST_RUN_KEY, &now.timestamp().to_string(), db).await?;
+
+	Ok(())
+}
+
+
+/// Puts copies of the pages of `previous` that have unfinished to-dos in front of the pages of `notebook`.  A page has
+/// unfinished to-dos if its typed text has a checkbox that isn't ticked, or it has `todo_tag`.
+fn carry_todos(previous: &RmDocument, notebook: RmDocument, todo_tag: &str) -> RmDocument {
+	if previous.file_type() != FileType::Notebook {
+		return notebook;
+	}
+
+	let todos: Vec<_> = previous
+		.pages
+		.iter()
+		.filter(|page| {
+			let tagged = previous
+				.content
+				.page_tags
+				.iter()
+				.flatten()
+				.any(|tag| tag.page_id == page.id && tag.name.eq_ignore_ascii_case(todo_tag));
+			let unticked = page
+				.lines
+				.as_deref()
+				.and_then(|data| Lines::parse(data).ok())
+				.and_then(|lines| lines.text)
+				.into_iter()
+				.flat_map(|text| text.paragraphs)
+				.any(|paragraph| paragraph.style == ParagraphStyle::Checkbox && !paragraph.text.trim().is_empty());
+			tagged || unticked
+		})
+		.collect();
+	if todos.is_empty() {
+		return notebook;
+	}
+
+	let mut document = RmDocument::new(&notebook.id, FileType::Notebook, todos.len() + notebook.pages.len());
+	let mut page_tags = Vec::new();
+
+	for (page, source) in document.pages.iter_mut().zip(todos.iter().copied().chain(&notebook.pages)) {
+		page.template = source.template.clone();
+		page.lines = source.lines.clone();
+		page.metadata = source.metadata.clone();
+		page_tags.extend(
+			previous
+				.content
+				.page_tags
+				.iter()
+				.flatten()
+				.filter(|tag| tag.page_id == source.id)
+				.map(|tag| PageTag {
+					page_id: page.id.clone(),
+					..tag.clone()
+				}),
+		);
+	}
+	if !page_tags.is_empty() {
+		document.content.page_tags = Some(page_tags);
+	}
+
+	document
+}
+
+
+async fn get_config(key: &str, db: &SqlitePool) -> Result<Option<String>> {
+	sqlx::query_scalar("SELECT value FROM config WHERE key=?")
+		.bind(key)
+		.fetch_optional(db)
+		.await
+		.context("Database")
+}
+
+
+async fn set_config(key: &str, value: &str, db: &SqlitePool) -> Result<()> {
+	sqlx::query("INSERT OR REPLACE INTO config (key,value) VALUES (?,?)")
+		.bind(key)
+		.bind(value)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(())
+}
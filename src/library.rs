@@ -0,0 +1,515 @@
+//! Changes the server makes to the library itself, rather than on behalf of the tablet: adding documents, etc.
+use crate::{
+	database,
+	events::{DocumentEvent, EventSource},
+	rmdoc::{
+		self, convert,
+		document_info::{Cover, DocumentInfo},
+		images::{self, ImageType, PageLayout},
+		FileType, RmDocument,
+	},
+	tree::{self, FolderTree, InvalidParent},
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use sqlx::SqlitePool;
+use std::{
+	fmt,
+	io::{Cursor, Read, Write},
+};
+use zip::{write::FileOptions, ZipWriter};
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnsupportedFile {
+	InvalidPdf,
+	InvalidImage(String),
+	UnknownType,
+	TooLarge,
+}
+
+impl fmt::Display for UnsupportedFile {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			UnsupportedFile::InvalidPdf => write!(f, "The PDF is damaged or encrypted"),
+			UnsupportedFile::InvalidImage(err) => write!(f, "Unable to read the image: {}", err),
+			UnsupportedFile::UnknownType => write!(f, "Only PDF, EPUB, Markdown, HTML, text, JPEG, PNG and TIFF files are supported"),
+			UnsupportedFile::TooLarge => write!(f, "The zip unpacks to more than {} bytes", rmdoc::MAX_UNPACKED_SIZE),
+		}
+	}
+}
+
+
+/// The kinds of file we can add to the library, once converted
+enum SourceFile {
+	Pdf(Vec<u8>),
+	Epub(Vec<u8>),
+	/// One or more JPEGs, PNGs or TIFFs, to become the pages of a PDF
+	Images(Vec<Vec<u8>>),
+	Markdown(String),
+	Html(String),
+	Text(String),
+}
+
+
+/// Works out what kind of file `data` is, converts it to a PDF or EPUB if the tablet can't open it, and wraps it in a new
+/// document archive.  Binary formats are recognised by their contents; text formats need their `content_type`, except HTML documents.
+/// `name` is used as the title of converted EPUBs.
+pub fn archive_file(data: Vec<u8>, content_type: &str, name: &str, layout: &PageLayout) -> Result<Result<RmDocument, UnsupportedFile>> {
+	let (pdf, epub) = match detect(data, content_type)? {
+		Ok(SourceFile::Pdf(pdf)) => (Some(pdf), None),
+		Ok(SourceFile::Epub(epub)) => (None, Some(epub)),
+		Ok(SourceFile::Images(images)) => match images::images_to_pdf(&images, layout) {
+			Ok(pdf) => (Some(pdf), None),
+			Err(err) => return Ok(Err(UnsupportedFile::InvalidImage(format!("{:#}", err)))),
+		},
+		Ok(SourceFile::Markdown(markdown)) => (None, Some(convert::markdown_to_epub(name, &markdown)?)),
+		Ok(SourceFile::Html(html)) => (None, Some(convert::html_to_epub(name, &html)?)),
+		Ok(SourceFile::Text(text)) => (None, Some(convert::text_to_epub(name, &text)?)),
+		Err(err) => return Ok(Err(err)),
+	};
+
+	if let Some(pdf) = pdf {
+		let page_count = match hayro::hayro_syntax::Pdf::new(pdf.clone()) {
+			Ok(parsed) => parsed.pages().len(),
+			Err(_) => return Ok(Err(UnsupportedFile::InvalidPdf)),
+		};
+		let mut document = RmDocument::new(&uuid::Uuid::new_v4().to_string(), FileType::Pdf, page_count);
+		document.pdf = Some(pdf);
+		return Ok(Ok(document));
+	}
+
+	Ok(Ok(archive_epub(epub.unwrap_or_default())))
+}
+
+
+/// Wraps an EPUB in a new document archive
+pub fn archive_epub(epub: Vec<u8>) -> RmDocument {
+	// The tablet doesn't know an EPUB's page count until it renders it
+	let mut document = RmDocument::new(&uuid::Uuid::new_v4().to_string(), FileType::Epub, 1);
+	document.epub = Some(epub);
+	document
+}
+
+
+fn detect(data: Vec<u8>, content_type: &str) -> Result<Result<SourceFile, UnsupportedFile>> {
+	if data.starts_with(b"%PDF-") {
+		return Ok(Ok(SourceFile::Pdf(data)));
+	}
+	if ImageType::detect(&data).is_some() {
+		return Ok(Ok(SourceFile::Images(vec![data])));
+	}
+
+	if let Ok(mut archive) = zip::ZipArchive::new(Cursor::new(&data)) {
+		if is_epub(&mut archive) {
+			return Ok(Ok(SourceFile::Epub(data)));
+		}
+		return Ok(match zipped_images(&mut archive)? {
+			Some(images) if !images.is_empty() => Ok(SourceFile::Images(images)),
+			Some(_) => Err(UnsupportedFile::UnknownType),
+			None => Err(UnsupportedFile::TooLarge),
+		});
+	}
+
+	let text = String::from_utf8_lossy(&data);
+	let text = text.trim_start_matches('\u{feff}');
+	let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+	let looks_like_html = ["<!doctype html", "<html"]
+		.iter()
+		.any(|start| text.trim_start().to_ascii_lowercase().starts_with(start));
+
+	Ok(match essence.as_str() {
+		"text/markdown" | "text/x-markdown" => Ok(SourceFile::Markdown(text.to_owned())),
+		"text/html" | "application/xhtml+xml" => Ok(SourceFile::Html(text.to_owned())),
+		_ if looks_like_html => Ok(SourceFile::Html(text.to_owned())),
+		"text/plain" => Ok(SourceFile::Text(text.to_owned())),
+		_ => Err(UnsupportedFile::UnknownType),
+	})
+}
+
+
+/// EPUBs are zips with a `mimetype` file containing "application/epub+zip"
+fn is_epub(archive: &mut zip::ZipArchive<Cursor<&Vec<u8>>>) -> bool {
+	let mut mimetype = String::new();
+	let has_mimetype = match archive.by_name("mimetype") {
+		// Only the start matters, however much the entry unpacks to
+		Ok(file) => file.take(64).read_to_string(&mut mimetype).is_ok(),
+		Err(_) => false,
+	};
+
+	has_mimetype && mimetype.trim() == "application/epub+zip"
+}
+
+
+/// The images in a zip, in order of their names (so that "page 2" comes before "page 10").  Anything else in the zip is ignored.
+/// `None` if the zip unpacks to more than `MAX_UNPACKED_SIZE`, like the document archives `RmDocument::from_zip` reads.
+fn zipped_images(archive: &mut zip::ZipArchive<Cursor<&Vec<u8>>>) -> Result<Option<Vec<Vec<u8>>>> {
+	let mut images = Vec::new();
+	let mut unpacked = 0;
+
+	for i in 0..archive.len() {
+		let mut file = archive.by_index(i).context("Reading zip")?;
+		let name = file.name().to_owned();
+		let hidden = name.split('/').any(|part| part.starts_with('.') || part == "__MACOSX");
+
+		if file.is_dir() || hidden {
+			continue;
+		}
+
+		let mut data = Vec::new();
+		let read = (&mut file)
+			.take(rmdoc::MAX_UNPACKED_SIZE - unpacked + 1)
+			.read_to_end(&mut data)
+			.context("Reading zip")?;
+		unpacked += read as u64;
+		if unpacked > rmdoc::MAX_UNPACKED_SIZE {
+			return Ok(None);
+		}
+		if ImageType::detect(&data).is_some() {
+			images.push((natural_sort_key(&name), data));
+		}
+	}
+
+	images.sort_by(|a, b| a.0.cmp(&b.0));
+	Ok(Some(images.into_iter().map(|(_, data)| data).collect()))
+}
+
+
+/// Pads numbers with zeros so that names sort the way people expect
+fn natural_sort_key(name: &str) -> String {
+	let mut key = String::new();
+	let mut digits = String::new();
+
+	for c in name.chars().chain(std::iter::once('\0')) {
+		if c.is_ascii_digit() {
+			digits.push(c);
+			continue;
+		}
+		if !digits.is_empty() {
+			key.push_str(&format!("{:0>20}", digits));
+			digits.clear();
+		}
+		key.push(c);
+	}
+
+	key
+}
+
+
+/// Uploads a new document as version 1 and commits it, the same way the tablet would.
+/// Returns the event for the caller to publish, or why `parent` can't hold the document.
+pub async fn add_document(
+	document: &RmDocument,
+	name: &str,
+	parent: &str,
+	source: EventSource,
+	db: &SqlitePool,
+) -> Result<Result<DocumentEvent, InvalidParent>> {
+	add_item(&document.id, &document.to_zip()?, tree::DOCUMENT_TYPE, name, parent, source, db).await
+}
+
+
+/// Uploads `document` as the version after `version` and commits it, keeping its name and folder.  Returns the event for the
+/// caller to publish, or None if there's already a newer version (e.g. the tablet uploaded one in the meantime), or a tablet is
+/// part way through uploading one.
+pub async fn update_document(document: &RmDocument, version: i64, source: EventSource, db: &SqlitePool) -> Result<Option<DocumentEvent>> {
+	let data = document.to_zip()?;
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	if !is_latest(&document.id, version, &mut tx).await? || !database::put_data(document.id.clone(), version + 1, &data, &mut tx).await? {
+		return Ok(None);
+	}
+
+	let change = database::put_metadata(
+		document.id.clone(),
+		version + 1,
+		Utc::now().timestamp(),
+		None,
+		None,
+		None,
+		None,
+		None,
+		&mut tx,
+	)
+	.await?;
+	tx.commit().await.context("Database TX")?;
+
+	Ok(change.map(|change| DocumentEvent::from_change(change, source)))
+}
+
+
+/// Renames, moves or (un)bookmarks an item as the version after `version`, the same way the tablet would.  Returns the event for
+/// the caller to publish, or None if there's already a newer version, or a tablet is part way through uploading one.
+pub async fn update_metadata(
+	id: &str,
+	version: i64,
+	name: Option<String>,
+	parent: Option<String>,
+	bookmarked: Option<bool>,
+	source: EventSource,
+	db: &SqlitePool,
+) -> Result<Option<DocumentEvent>> {
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	if !is_latest(id, version, &mut tx).await? {
+		return Ok(None);
+	}
+
+	let change = database::put_metadata(
+		id.to_owned(),
+		version + 1,
+		Utc::now().timestamp(),
+		None,
+		name,
+		None,
+		bookmarked,
+		parent,
+		&mut tx,
+	)
+	.await?;
+	tx.commit().await.context("Database TX")?;
+
+	Ok(change.map(|change| DocumentEvent::from_change(change, source)))
+}
+
+
+/// Whether `version` is the latest version of `id`.  A tablet uploads the next version before committing it, and writing over
+/// that (or committing it along with our change) would lose what the tablet uploaded.
+async fn is_latest(id: &str, version: i64, tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<bool> {
+	let latest: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM files WHERE id=? AND deleted=0")
+		.bind(id)
+		.fetch_one(&mut *tx)
+		.await
+		.context("Database")?;
+
+	Ok(latest == Some(version))
+}
+
+
+/// A new notebook with `page_count` blank pages, all using `template` (by the name the tablet uses, e.g. "P Lines medium")
+pub fn blank_notebook(page_count: usize, template: &str) -> RmDocument {
+	let mut document = RmDocument::new(&uuid::Uuid::new_v4().to_string(), FileType::Notebook, page_count);
+	for page in &mut document.pages {
+		page.template = template.to_owned();
+	}
+	document
+}
+
+
+/// The ID of the folder at `path` (folder names separated by slashes, e.g. "Family/Alex/Notes") under `parent`, creating the
+/// folders along it that don't exist.  The events for those are returned for the caller to publish.
+pub async fn folder_by_path(
+	path: &str,
+	parent: &str,
+	source: EventSource,
+	db: &SqlitePool,
+) -> Result<Result<(String, Vec<DocumentEvent>), InvalidParent>> {
+	let mut folder = parent.to_owned();
+	let mut events = Vec::new();
+
+	for name in path.split('/').map(str::trim).filter(|name| !name.is_empty()) {
+		match folder_by_name(name, &folder, source.clone(), db).await? {
+			Ok((id, event)) => {
+				folder = id;
+				events.extend(event);
+			}
+			// Only the first folder can fail, since the rest are in folders we just found or made
+			Err(err) => return Ok(Err(err)),
+		}
+	}
+
+	Ok(Ok((folder, events)))
+}
+
+
+/// The ID of the folder called `name` in `parent`, creating it if there isn't one.  If it was created, the event is returned
+/// for the caller to publish.
+pub async fn folder_by_name(
+	name: &str,
+	parent: &str,
+	source: EventSource,
+	db: &SqlitePool,
+) -> Result<Result<(String, Option<DocumentEvent>), InvalidParent>> {
+	// Looking for the folder in the transaction that creates it means two ingests into the same folder can't both create it
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	let metadata = database::list_metadata(&mut tx).await?;
+	let existing = metadata
+		.iter()
+		.find(|x| x.file_type == tree::COLLECTION_TYPE && x.parent == parent && x.name == name);
+
+	if let Some(folder) = existing {
+		return Ok(Ok((folder.id.clone(), None)));
+	}
+
+	// Folders are uploaded with an empty .content
+	let id = uuid::Uuid::new_v4().to_string();
+	let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+	writer.start_file(format!("{}.content", id), FileOptions::default())?;
+	writer.write_all(b"{}")?;
+	let data = writer.finish()?.into_inner();
+
+	let tree = FolderTree::from_metadata(&metadata);
+	let change = match insert_item(&id, &data, tree::COLLECTION_TYPE, name, parent, &tree, &mut tx).await? {
+		Ok(change) => change,
+		Err(err) => return Ok(Err(err)),
+	};
+	tx.commit().await.context("Database TX")?;
+
+	Ok(Ok((id, Some(DocumentEvent::from_change(change, source)))))
+}
+
+
+async fn add_item(
+	id: &str,
+	data: &[u8],
+	file_type: &str,
+	name: &str,
+	parent: &str,
+	source: EventSource,
+	db: &SqlitePool,
+) -> Result<Result<DocumentEvent, InvalidParent>> {
+	// Checking the parent in the same transaction means it can't be deleted or moved before the item is added
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	let tree = FolderTree::from_metadata(&database::list_metadata(&mut tx).await?);
+	let change = match insert_item(id, data, file_type, name, parent, &tree, &mut tx).await? {
+		Ok(change) => change,
+		Err(err) => return Ok(Err(err)),
+	};
+	tx.commit().await.context("Database TX")?;
+
+	Ok(Ok(DocumentEvent::from_change(change, source)))
+}
+
+
+/// Adds the first version of an item in `tx`, which `tree` was read in
+async fn insert_item(
+	id: &str,
+	data: &[u8],
+	file_type: &str,
+	name: &str,
+	parent: &str,
+	tree: &FolderTree,
+	tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<Result<database::MetadataChange, InvalidParent>> {
+	if let Err(err) = tree.validate_parent(id, parent) {
+		return Ok(Err(err));
+	}
+
+	if !database::put_data(id.to_owned(), 1, data, tx).await? {
+		anyhow::bail!("Conflict while adding {}", id);
+	}
+
+	let change = database::put_metadata(
+		id.to_owned(),
+		1,
+		Utc::now().timestamp(),
+		Some(file_type.to_owned()),
+		Some(name.to_owned()),
+		Some(0),
+		Some(false),
+		Some(parent.to_owned()),
+		tx,
+	)
+	.await?
+	.with_context(|| format!("Conflict while adding {}", id))?;
+
+	Ok(Ok(change))
+}
+
+
+/// Names a document by `template` (see `--name-template`), from what its PDF or EPUB says about itself.  If the template uses
+/// anything `info` doesn't have, the document keeps `name`, the name it was added with.
+pub fn name_from_template(template: &str, info: &DocumentInfo, name: &str) -> String {
+	let mut output = String::new();
+	let mut rest = template;
+
+	while let Some(start) = rest.find('{') {
+		output.push_str(&rest[..start]);
+		let placeholder = match rest[start..].find('}') {
+			Some(end) => &rest[start + 1..start + end],
+			None => {
+				rest = &rest[start..];
+				break;
+			}
+		};
+
+		let value = match placeholder {
+			"title" => info.title.clone(),
+			"authors" => (!info.authors.is_empty()).then(|| info.authors.join(", ")),
+			"author" => info.authors.first().cloned(),
+			"year" => info.year().map(str::to_owned),
+			"date" => info.date.clone(),
+			"name" => Some(name.to_owned()),
+			// Not a placeholder, so it's kept as it is
+			_ => Some(format!("{{{}}}", placeholder)),
+		};
+		match value {
+			Some(value) => output.push_str(&value),
+			None => return name.to_owned(),
+		}
+
+		rest = &rest[start + placeholder.len() + 2..];
+	}
+	output.push_str(rest);
+
+	let output = output.trim();
+	if output.is_empty() {
+		name.to_owned()
+	} else {
+		output.to_owned()
+	}
+}
+
+
+/// Keeps what a document's PDF or EPUB says about itself, for search and the admin API
+pub async fn save_document_info(id: &str, info: &DocumentInfo, db: &SqlitePool) -> Result<()> {
+	sqlx::query("INSERT OR REPLACE INTO document_info (id,title,authors,date,cover,cover_type) VALUES (?,?,?,?,?,?)")
+		.bind(id)
+		.bind(&info.title)
+		.bind(serde_json::to_string(&info.authors)?)
+		.bind(&info.date)
+		.bind(info.cover.as_ref().map(|cover| &cover.data))
+		.bind(info.cover.as_ref().map(|cover| &cover.content_type))
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(())
+}
+
+
+#[derive(sqlx::FromRow)]
+struct DocumentInfoRow {
+	title: Option<String>,
+	/// JSON array
+	authors: String,
+	date: Option<String>,
+	cover: Option<Vec<u8>>,
+	cover_type: Option<String>,
+}
+
+
+/// What was read from a document's PDF or EPUB when it was added, if it was added through the admin API
+pub async fn get_document_info(id: &str, db: &SqlitePool) -> Result<Option<DocumentInfo>> {
+	let row: Option<DocumentInfoRow> = sqlx::query_as("SELECT title,authors,date,cover,cover_type FROM document_info WHERE id=?")
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")?;
+
+	Ok(row.map(|row| DocumentInfo {
+		title: row.title,
+		authors: serde_json::from_str(&row.authors).unwrap_or_default(),
+		date: row.date,
+		cover: row.cover.zip(row.cover_type).map(|(data, content_type)| Cover { data, content_type }),
+	}))
+}
+
+
+/// Forgets what was read from documents that have since been deleted for good
+pub async fn clean_document_info(db: &SqlitePool) -> Result<()> {
+	sqlx::query("DELETE FROM document_info WHERE id NOT IN (SELECT id FROM files)")
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(())
+}
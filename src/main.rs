@@ -1,10 +1,28 @@
 mod api;
 mod auth;
+mod clipper;
 mod config;
+mod cron;
 mod database;
+mod deliveries;
 mod error;
+mod events;
+mod export;
+mod hwr;
+mod ical;
+mod journal;
+mod library;
+mod maintenance;
 mod notifications;
 mod request_logger;
+mod rmdoc;
+mod rules;
+mod search;
+mod tags;
+mod thumbnails;
+mod trash;
+mod tree;
+mod webhooks;
 
 
 use crate::auth::AdminTokenClaims;
@@ -14,16 +32,24 @@ use actix_web::{
 	web::{self, Data},
 	App, HttpServer,
 };
-use anyhow::Result;
-use clap::Parser;
-use config::ServerConfig;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use config::{ServerConfig, ServerOptions};
+use deliveries::DeliveryScheduler;
 use env_logger::Env;
+use events::EventBus;
+use journal::Journal;
 use log::{error, info};
-use notifications::NotificationServer;
+use maintenance::Maintenance;
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use rules::RuleEngine;
 use rustls::{Certificate, PrivateKey};
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use search::SearchIndexer;
+use sqlx::{
+	sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+	SqlitePool,
+};
 use std::{
 	fs::File,
 	io::BufReader,
@@ -32,6 +58,8 @@ use std::{
 	thread,
 	time::Duration,
 };
+use tags::TagIndexer;
+use webhooks::WebhookDispatcher;
 
 
 // TODO: Hopefully at some point chrono updates and we can use fancy const fn Duration::seconds() here
@@ -45,35 +73,88 @@ const DEVICE_CODE_EXPIRATION: i64 = 5 * 60; // secs
 const REQUEST_LOG_EXPIRATION: i64 = 30 * 24 * 60 * 60; // secs
 /// How long to keep deleted files around for
 const DELETED_FILE_EXPIRATION: i64 = 30 * 24 * 60 * 60; // secs
+/// How often to run periodic maintenance (purging deleted files, emptying the trash, etc)
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 /// The official API uses this charset: b"abcdefghijklmnopqrstuvwxyz";
 const DEVICE_CODE_CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 const DEVICE_CODE_LEN: usize = 8;
 const MAXIMUM_REQUEST_SIZE: usize = 256 * 1024 * 1024; // bytes
 const WEBSOCKET_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
 const WEBSOCKET_CLIENT_TIMEOUT: Duration = Duration::from_secs(40);
+const SSE_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+/// How many recent events to keep so SSE/long-poll clients can catch up after reconnecting
+const EVENT_BACKLOG_LEN: usize = 1024;
+const LONG_POLL_DEFAULT_TIMEOUT: u64 = 30; // secs
+const LONG_POLL_MAX_TIMEOUT: u64 = 120; // secs
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to look for webhook deliveries that are due for a retry
+const WEBHOOK_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// Delay before the first retry of a failed webhook delivery; doubles with each attempt
+const WEBHOOK_RETRY_BASE_DELAY: i64 = 30; // secs
+const WEBHOOK_MAX_ATTEMPTS: i64 = 8;
+/// How long to keep the webhook delivery log around
+const WEBHOOK_DELIVERY_EXPIRATION: i64 = 30 * 24 * 60 * 60; // secs
+const HWR_TIMEOUT: Duration = Duration::from_secs(30);
+const HWR_MAX_RESPONSE_SIZE: usize = 1024 * 1024; // bytes
+/// The most pages a notebook created through the admin API can start with
+const MAX_NEW_NOTEBOOK_PAGES: usize = 1000;
+/// How often rules that run on a schedule are checked
+const RULES_SCHEDULE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often to look for deliveries that are due
+const DELIVERY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often to check whether the day's journal notebook is due (see --journal-schedule)
+const JOURNAL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// The most days a planner can cover
+const MAX_PLANNER_DAYS: u32 = 366;
+/// The most days --trash-auto-empty-days can keep items in the trash for (about a century)
+const MAX_TRASH_DAYS: u32 = 36_600;
 
 
 #[derive(Clone, Debug, Parser)]
-#[clap(name = "rm-personal-cloud", version, about, long_about = None)]
+#[clap(name = "rm-personal-cloud", version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Opt {
 	#[clap(long = "db", value_parser)]
 	db_path: PathBuf,
 
-	#[clap(long = "ssl-cert", value_parser)]
-	ssl_cert_path: PathBuf,
+	#[clap(long = "ssl-cert", value_parser, required = true)]
+	ssl_cert_path: Option<PathBuf>,
 
-	#[clap(long = "ssl-key", value_parser)]
-	ssl_key_path: PathBuf,
+	#[clap(long = "ssl-key", value_parser, required = true)]
+	ssl_key_path: Option<PathBuf>,
 
 	#[clap(long = "hostname", value_parser, default_value = "local.appspot.com")]
 	hostname: String,
 
 	/// Where to listen on (e.g. 0.0.0.0)
-	#[clap(long = "bind", value_parser)]
-	bind_address: IpAddr,
+	#[clap(long = "bind", value_parser, required = true)]
+	bind_address: Option<IpAddr>,
 
 	#[clap(long = "https-port", default_value = "8084", value_parser)]
 	https_port: u16,
+
+	#[clap(flatten)]
+	server_options: ServerOptions,
+
+	/// Run a command against the database instead of starting the server
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+	/// Export a PDF or notebook as a PDF, with the handwriting drawn onto its pages
+	ExportPdf {
+		#[clap(long = "id", value_parser)]
+		id: String,
+
+		/// Defaults to the latest version
+		#[clap(long = "version", value_parser)]
+		version: Option<i64>,
+
+		#[clap(long = "output", short = 'o', value_parser)]
+		output_path: PathBuf,
+	},
 }
 
 
@@ -83,10 +164,26 @@ async fn main() -> Result<(), anyhow::Error> {
 
 	let opt = Opt::from_args();
 
+	// TODO: Some kind of weird bug in sqlx is causing database open errors for anything more than 2 connections.
+	let pool_options = SqlitePoolOptions::new().max_connections(2);
+	let db_pool = pool_options
+		.connect_with(SqliteConnectOptions::new().filename(opt.db_path).create_if_missing(true))
+		.await?;
+	sqlx::query(include_str!("../schema.sql")).execute(&db_pool).await?;
+
+	if let Some(command) = opt.command {
+		return run_command(command, &db_pool).await;
+	}
+
+	// These are required unless there's a command, which clap enforces
+	let ssl_cert_path = opt.ssl_cert_path.expect("unexpected");
+	let ssl_key_path = opt.ssl_key_path.expect("unexpected");
+	let bind_address = opt.bind_address.expect("unexpected");
+
 	// Load SSL keys
 	let ssl_config = {
-		let cert_file = &mut BufReader::new(File::open(&opt.ssl_cert_path).expect("Unable to read SSL cert"));
-		let key_file = &mut BufReader::new(File::open(&opt.ssl_key_path).expect("Unable to read SSL key"));
+		let cert_file = &mut BufReader::new(File::open(&ssl_cert_path).expect("Unable to read SSL cert"));
+		let key_file = &mut BufReader::new(File::open(&ssl_key_path).expect("Unable to read SSL key"));
 
 		let cert_chain = certs(cert_file).expect("Invalid SSL cert").into_iter().map(Certificate).collect();
 		let mut keys: Vec<PrivateKey> = pkcs8_private_keys(key_file)
@@ -102,15 +199,16 @@ async fn main() -> Result<(), anyhow::Error> {
 			.expect("Invalid SSL key")
 	};
 
-	// TODO: Some kind of weird bug in sqlx is causing database open errors for anything more than 2 connections.
-	let pool_options = SqlitePoolOptions::new().max_connections(2);
-	let db_pool = pool_options
-		.connect_with(SqliteConnectOptions::new().filename(opt.db_path).create_if_missing(true))
-		.await?;
-	sqlx::query(include_str!("../schema.sql")).execute(&db_pool).await?;
 
-	let server_config = ServerConfig::load_config(&db_pool, opt.hostname).await?;
-	let notification_server_addr = NotificationServer::new().start();
+	let server_config = ServerConfig::load_config(&db_pool, opt.hostname, opt.server_options).await?;
+	let event_bus_addr = EventBus::new().start();
+	let webhook_dispatcher_addr = WebhookDispatcher::new(db_pool.clone(), event_bus_addr.clone()).start();
+	SearchIndexer::new(db_pool.clone(), event_bus_addr.clone()).start();
+	TagIndexer::new(db_pool.clone(), event_bus_addr.clone()).start();
+	let rule_engine_addr = RuleEngine::new(db_pool.clone(), event_bus_addr.clone(), webhook_dispatcher_addr.clone()).start();
+	let delivery_scheduler_addr = DeliveryScheduler::new(db_pool.clone(), event_bus_addr.clone(), server_config.clone()).start();
+	Journal::new(db_pool.clone(), event_bus_addr.clone(), server_config.clone()).start();
+	Maintenance::new(db_pool.clone(), event_bus_addr.clone(), server_config.clone()).start();
 
 	println!(
 		"Admin URL: https://{}/admin/#{}",
@@ -126,8 +224,12 @@ async fn main() -> Result<(), anyhow::Error> {
 			.app_data(web::JsonConfig::default().content_type(|_| true)) // The tablet sends some odd content-types for JSON requests, so just accept any
 			.app_data(web::PayloadConfig::default().limit(MAXIMUM_REQUEST_SIZE))
 			.app_data(Data::new(db_pool.clone()))
-			.app_data(Data::new(notification_server_addr.clone()))
+			.app_data(Data::new(event_bus_addr.clone()))
+			.app_data(Data::new(webhook_dispatcher_addr.clone()))
+			.app_data(Data::new(rule_engine_addr.clone()))
+			.app_data(Data::new(delivery_scheduler_addr.clone()))
 			.app_data(Data::new(server_config.clone()))
+			.app_data(Data::new(hwr::engine(&server_config.options)))
 			.service(api::settings_v1_beta)
 			.service(api::v1_reports)
 			.service(api::service_discovery)
@@ -140,14 +242,18 @@ async fn main() -> Result<(), anyhow::Error> {
 			.service(api::storage::download)
 			.service(api::storage::update_status)
 			.service(api::storage::delete)
+			.service(api::clip::clip)
+			.service(api::hwr::recognize_page)
 			.service(notifications::ws_notifications)
+			.service(notifications::sse_notifications)
+			.service(notifications::poll_notifications)
 			.service(api::admin::service())
 			.default_service(web::route().to(request_logger::default_service))
 	})
-	.bind_rustls(SocketAddr::new(opt.bind_address, opt.https_port), ssl_config)?
+	.bind_rustls(SocketAddr::new(bind_address, opt.https_port), ssl_config)?
 	.run();
 
-	cert_watcher(opt.ssl_cert_path.clone(), server.handle());
+	cert_watcher(ssl_cert_path, server.handle());
 
 	server.await?;
 
@@ -155,6 +261,20 @@ async fn main() -> Result<(), anyhow::Error> {
 }
 
 
+async fn run_command(command: Command, db_pool: &SqlitePool) -> Result<()> {
+	match command {
+		Command::ExportPdf { id, version, output_path } => {
+			let (_, document) = rmdoc::load_document(&id, version, db_pool)
+				.await?
+				.context("No such document or version")?;
+			std::fs::write(&output_path, rmdoc::pdf::export_pdf(&document)?).context("Unable to write output")?;
+		}
+	}
+
+	Ok(())
+}
+
+
 /// Watches the SSL certificate file and causes the HttpServer to exit when it changes.
 /// We expect some extenral management (e.g. systemd) to restart us, allowing us to reload the cert.
 fn cert_watcher(filepath: PathBuf, server: actix_web::dev::ServerHandle) {
@@ -0,0 +1,72 @@
+use crate::{
+	config::ServerConfig,
+	database,
+	events::{EventBus, EventSource},
+	library, thumbnails, trash, MAINTENANCE_INTERVAL,
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use log::{info, warn};
+use sqlx::SqlitePool;
+
+
+/// Periodic housekeeping: purging old deleted files, auto-emptying the trash and keeping thumbnails up to date.
+pub struct Maintenance {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	server_config: ServerConfig,
+}
+
+impl Maintenance {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>, server_config: ServerConfig) -> Self {
+		Self {
+			db,
+			event_bus,
+			server_config,
+		}
+	}
+
+	fn run(&mut self, _: &mut Context<Self>) {
+		let db = self.db.clone();
+		let event_bus = self.event_bus.clone();
+		let server_config = self.server_config.clone();
+
+		actix_web::rt::spawn(async move {
+			if let Err(err) = run_maintenance(&db, &event_bus, &server_config).await {
+				warn!("Error during maintenance: {:?}", err);
+			}
+		});
+	}
+}
+
+impl Actor for Maintenance {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		self.run(ctx);
+		ctx.run_interval(MAINTENANCE_INTERVAL, Self::run);
+	}
+}
+
+
+async fn run_maintenance(db: &SqlitePool, event_bus: &Addr<EventBus>, server_config: &ServerConfig) -> Result<()> {
+	database::clean_deleted_files(db).await?;
+
+	if let Some(days) = server_config.options.trash_auto_empty_days {
+		let trashed_before = trash::trashed_before(days).context("--trash-auto-empty-days is too large")?;
+		let events = trash::empty(Some(trashed_before), EventSource::server(), db).await?;
+
+		if !events.is_empty() {
+			info!("Auto-emptied {} items from the trash", events.len());
+		}
+
+		for event in events {
+			event.publish(event_bus);
+		}
+	}
+
+	thumbnails::refresh(db).await?;
+	library::clean_document_info(db).await?;
+
+	Ok(())
+}
@@ -1,8 +1,14 @@
-use crate::{auth::ValidatedUserToken, database::DbFileMetadata, WEBSOCKET_CLIENT_TIMEOUT, WEBSOCKET_HEARTBEAT_INTERVAL};
+use crate::{
+	auth::{ValidatedUserOrAdminToken, ValidatedUserToken},
+	events::{DocumentEvent, EventBus, EventMessage, SequencedEvent, Subscribe, SubscribeStream},
+	LONG_POLL_DEFAULT_TIMEOUT, LONG_POLL_MAX_TIMEOUT, SSE_KEEPALIVE_INTERVAL, WEBSOCKET_CLIENT_TIMEOUT, WEBSOCKET_HEARTBEAT_INTERVAL,
+};
 use actix::prelude::*;
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{rt::time, web, web::Bytes, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
+use futures::{stream, StreamExt};
 use log::debug;
+use serde::Deserialize;
 use serde_json::json;
 use std::time::Instant;
 
@@ -12,7 +18,7 @@ pub async fn ws_notifications(
 	_user_token: ValidatedUserToken,
 	req: HttpRequest,
 	stream: web::Payload,
-	srv: web::Data<Addr<NotificationServer>>,
+	srv: web::Data<Addr<EventBus>>,
 ) -> Result<HttpResponse, actix_web::Error> {
 	ws::start(
 		WsNotificationSession {
@@ -25,40 +31,192 @@ pub async fn ws_notifications(
 }
 
 
-pub struct Notification(String);
-
-impl Notification {
-	pub fn from_metadata(event: &str, metadata: &DbFileMetadata, source_device_desc: &str, source_device_id: &str) -> Self {
-		Notification(
-			serde_json::to_string(&json!({
-				"message": {
-					"attributes": {
-						"bookmarked": if metadata.bookmarked { "true".to_owned() } else { "false".to_owned() },
-						"event": event.to_owned(),
-						"id": metadata.id.clone(),
-						"parent": metadata.parent.clone(),
-						"sourceDeviceDesc": source_device_desc.to_owned(),
-						"sourceDeviceID": source_device_id.to_owned(),
-						"type": metadata.file_type.clone(),
-						"version": metadata.version.to_string(),
-						"vissibleName": metadata.name.clone(),
-					}
+#[derive(Debug, Deserialize)]
+struct NotificationQuery {
+	/// Comma separated list of event types (e.g. DocAdded,DocDeleted) and/or event kinds (e.g. moved,trashed)
+	event: Option<String>,
+	/// Comma separated list of document IDs
+	doc: Option<String>,
+	/// Only return notifications with a sequence number greater than this
+	since: Option<u64>,
+	/// How long a long-poll request waits for a notification (secs)
+	timeout: Option<u64>,
+}
+
+/// Server-Sent Events version of the notification stream.
+/// Each event's data is the same JSON the tablet receives over the websocket; the SSE id is the event's sequence number,
+/// so clients that reconnect with Last-Event-ID (or ?since=) get any notifications they missed that are still in the backlog.
+#[actix_web::get("/notifications/sse/json/1")]
+pub async fn sse_notifications(
+	token: ValidatedUserOrAdminToken,
+	req: HttpRequest,
+	query: web::Query<NotificationQuery>,
+	srv: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, actix_web::Error> {
+	debug!("SSE notification stream opened by {}", token.0.device_desc());
+
+	let filter = NotificationFilter::from_query(&query);
+	let since = query.since.or_else(|| {
+		req.headers()
+			.get("Last-Event-ID")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.parse().ok())
+	});
+
+	let subscription = srv
+		.send(SubscribeStream { since })
+		.await
+		.map_err(actix_web::error::ErrorInternalServerError)?;
+
+	let events = stream::iter(subscription.backlog)
+		.chain(subscription.receiver)
+		.filter(move |event| futures::future::ready(filter.matches(&event.event)))
+		.map(|event| {
+			format!(
+				"id: {}\nevent: {}\ndata: {}\n\n",
+				event.sequence,
+				event.event.tablet_event(),
+				tablet_notification(&event.event)
+			)
+		});
+	let keepalive = stream::unfold(time::interval(SSE_KEEPALIVE_INTERVAL), |mut interval| async {
+		interval.tick().await;
+		Some((": keepalive\n\n".to_owned(), interval))
+	});
+
+	Ok(HttpResponse::Ok()
+		.content_type("text/event-stream")
+		.insert_header(("Cache-Control", "no-cache"))
+		.streaming(stream::select(events, keepalive).map(|chunk| Ok::<_, actix_web::Error>(Bytes::from(chunk)))))
+}
+
+
+/// Long-poll version of the notification stream.
+/// Returns immediately if there are matching notifications after `since`, otherwise waits up to `timeout` seconds for one to arrive.
+/// The response's `next` should be passed as `since` on the following request.
+#[actix_web::get("/notifications/poll/json/1")]
+pub async fn poll_notifications(
+	_token: ValidatedUserOrAdminToken,
+	query: web::Query<NotificationQuery>,
+	srv: web::Data<Addr<EventBus>>,
+) -> Result<HttpResponse, actix_web::Error> {
+	let filter = NotificationFilter::from_query(&query);
+	let timeout = std::time::Duration::from_secs(query.timeout.unwrap_or(LONG_POLL_DEFAULT_TIMEOUT).min(LONG_POLL_MAX_TIMEOUT));
+
+	let subscription = srv
+		.send(SubscribeStream { since: query.since })
+		.await
+		.map_err(actix_web::error::ErrorInternalServerError)?;
+	let mut receiver = subscription.receiver;
+	let mut events: Vec<SequencedEvent> = subscription.backlog.into_iter().filter(|event| filter.matches(&event.event)).collect();
+
+	if events.is_empty() {
+		// Wait for the first matching notification, then grab anything else that has already arrived
+		let first = time::timeout(timeout, async {
+			while let Some(event) = receiver.next().await {
+				if filter.matches(&event.event) {
+					return Some(event);
+				}
+			}
+			None
+		})
+		.await;
+
+		if let Ok(Some(event)) = first {
+			events.push(event);
+
+			while let Ok(Some(event)) = receiver.try_next() {
+				if filter.matches(&event.event) {
+					events.push(event);
 				}
-			}))
-			.expect("Failed to serialize"),
-		)
+			}
+		}
 	}
 
-	pub fn broadcast(self, notification_server: &Addr<NotificationServer>) {
-		notification_server.do_send(Message(self.0));
+	let next = events
+		.last()
+		.map(|event| event.sequence)
+		.unwrap_or(subscription.latest.max(query.since.unwrap_or(0)));
+	let events: Vec<_> = events
+		.into_iter()
+		.map(|event| {
+			json!({
+				"sequence": event.sequence,
+				"event": event.event.tablet_event(),
+				"kind": event.event.kind,
+				"id": event.event.document.id,
+				"notification": tablet_notification(&event.event),
+				"change": &*event.event,
+			})
+		})
+		.collect();
+
+	Ok(HttpResponse::Ok().json(json!({
+		"next": next,
+		"events": events,
+	})))
+}
+
+
+/// Builds the notification the tablet expects for a document event.
+pub fn tablet_notification(event: &DocumentEvent) -> serde_json::Value {
+	json!({
+		"message": {
+			"attributes": tablet_attributes(event),
+		}
+	})
+}
+
+/// The notification's attributes (document ID, name, type, version, etc), as the tablet receives them.
+pub fn tablet_attributes(event: &DocumentEvent) -> serde_json::Value {
+	let metadata = &event.document;
+
+	json!({
+		"bookmarked": if metadata.bookmarked { "true".to_owned() } else { "false".to_owned() },
+		"event": event.tablet_event(),
+		"id": metadata.id.clone(),
+		"parent": metadata.parent.clone(),
+		"sourceDeviceDesc": event.source.device_desc.clone(),
+		"sourceDeviceID": event.source.device_id.clone(),
+		"type": metadata.file_type.clone(),
+		"version": metadata.version.to_string(),
+		"vissibleName": metadata.name.clone(),
+	})
+}
+
+
+/// Restricts a notification stream to certain event types and/or documents.
+struct NotificationFilter {
+	events: Option<Vec<String>>,
+	ids: Option<Vec<String>>,
+}
+
+impl NotificationFilter {
+	fn from_query(query: &NotificationQuery) -> Self {
+		let split = |list: &Option<String>| {
+			list.as_ref()
+				.map(|list| list.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect())
+		};
+
+		NotificationFilter {
+			events: split(&query.event),
+			ids: split(&query.doc),
+		}
+	}
+
+	fn matches(&self, event: &DocumentEvent) -> bool {
+		self.events
+			.as_ref()
+			.is_none_or(|events| events.iter().any(|name| event.matches_name(name)))
+			&& self.ids.as_ref().is_none_or(|ids| ids.contains(&event.document.id))
 	}
 }
 
 
 struct WsNotificationSession {
 	last_heartbeat: Instant,
-	/// Address of the NotificationServer actor
-	server_addr: Addr<NotificationServer>,
+	/// Address of the EventBus actor
+	server_addr: Addr<EventBus>,
 }
 
 impl Actor for WsNotificationSession {
@@ -68,7 +226,7 @@ impl Actor for WsNotificationSession {
 		// Start heartbeat task
 		self.heartbeat(ctx);
 
-		// Send a Subscribe message to the NotificationServer actor so we'll receive notifications
+		// Send a Subscribe message to the EventBus actor so we'll receive notifications
 		let my_addr = ctx.address();
 		self.server_addr
 			.send(Subscribe(my_addr.recipient()))
@@ -84,12 +242,12 @@ impl Actor for WsNotificationSession {
 	}
 }
 
-impl Handler<Message> for WsNotificationSession {
+impl Handler<EventMessage> for WsNotificationSession {
 	type Result = ();
 
-	fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+	fn handle(&mut self, msg: EventMessage, ctx: &mut Self::Context) {
 		// Write message to the websocket connection
-		ctx.text(msg.0);
+		ctx.text(tablet_notification(&msg.0.event).to_string());
 	}
 }
 
@@ -138,61 +296,3 @@ impl WsNotificationSession {
 		});
 	}
 }
-
-
-#[derive(Message)]
-#[rtype(result = "()")]
-struct Message(pub String);
-
-#[derive(Message)]
-#[rtype(result = "()")]
-struct Subscribe(pub Recipient<Message>);
-
-
-pub struct NotificationServer {
-	subscriptions: Vec<Recipient<Message>>,
-}
-
-impl NotificationServer {
-	pub fn new() -> Self {
-		Self { subscriptions: Vec::new() }
-	}
-
-	fn send_message(&mut self, message: &str) {
-		self.subscriptions.retain(|addr| {
-			// Remove dead subscriptions
-			if !addr.connected() {
-				debug!("NotificationServer: Removing dead websocket connection");
-				return false;
-			}
-
-			let _ = addr.do_send(Message(message.to_owned()));
-			return true;
-		});
-	}
-}
-
-impl Actor for NotificationServer {
-	type Context = Context<Self>;
-}
-
-impl Handler<Subscribe> for NotificationServer {
-	type Result = ();
-
-	fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
-		debug!("Someone subscribed");
-
-		self.subscriptions.push(msg.0);
-		debug!("NotificationServer: Total Connections: {}", self.subscriptions.len());
-	}
-}
-
-impl Handler<Message> for NotificationServer {
-	type Result = ();
-
-	fn handle(&mut self, msg: Message, _: &mut Context<Self>) -> Self::Result {
-		debug!("Broadcasting message: {}", msg.0);
-
-		self.send_message(&msg.0);
-	}
-}
@@ -0,0 +1,290 @@
+//! Finds the article in a web page: its main text, without the navigation, sidebars, comments, ads, etc. around it.
+//!
+//! This is a simplified version of what Readability (Firefox's reader view) does.  Paragraphs are scored by how much prose they
+//! contain, and their scores are given to their parent and grandparent.  The best scoring element, discounted by how much of its
+//! text is links, is taken to be the article, along with any siblings that look like they're part of it.
+use super::html::{self, Element, Node};
+use std::collections::HashMap;
+
+
+/// Elements that are never part of an article
+const REMOVED_ELEMENTS: &[&str] = &[
+	"aside", "button", "footer", "form", "iframe", "nav", "noscript", "script", "select", "style", "textarea",
+];
+
+/// Classes and IDs of elements that probably aren't part of the article...
+const UNLIKELY: &[&str] = &[
+	"-ad-",
+	"advert",
+	"banner",
+	"breadcrumb",
+	"combx",
+	"comment",
+	"cookie",
+	"disqus",
+	"footer",
+	"gdpr",
+	"masthead",
+	"menu",
+	"modal",
+	"newsletter",
+	"outbrain",
+	"pager",
+	"popup",
+	"promo",
+	"related",
+	"share",
+	"sidebar",
+	"skyscraper",
+	"social",
+	"sponsor",
+	"subscribe",
+	"taboola",
+];
+
+/// ...and of those that probably are
+const LIKELY: &[&str] = &["article", "body", "content", "entry", "main", "post", "story", "text"];
+
+/// Elements whose text counts as a paragraph
+const PARAGRAPHS: &[&str] = &["p", "pre", "td", "blockquote"];
+
+/// Paragraphs shorter than this (in characters) are ignored
+const MIN_PARAGRAPH_LENGTH: usize = 25;
+
+
+pub struct Article {
+	pub title: Option<String>,
+	pub author: Option<String>,
+	pub language: Option<String>,
+	/// What the page's relative links are relative to, if it says
+	pub base: Option<String>,
+	/// The element holding the article
+	pub content: Element,
+}
+
+
+pub fn extract(page: &str) -> Article {
+	let document = html::parse(page);
+	let title = meta(&document, "og:title")
+		.or_else(|| document.find("title").map(|title| title.text()))
+		.map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "))
+		.filter(|title| !title.is_empty());
+	let author = meta(&document, "author").filter(|author| !author.is_empty());
+	let language = document.find("html").and_then(|root| root.attribute("lang")).map(str::to_owned);
+	let base = document.find("base").and_then(|base| base.attribute("href")).map(str::to_owned);
+
+	let mut body = match document.find("body") {
+		Some(body) => body.clone(),
+		None => document,
+	};
+	remove_unlikely(&mut body);
+
+	Article {
+		title,
+		author,
+		language,
+		base,
+		content: best_candidate(&body).unwrap_or(body),
+	}
+}
+
+
+/// The content of a `<meta>` tag, by its name or property
+fn meta(document: &Element, name: &str) -> Option<String> {
+	document
+		.elements()
+		.into_iter()
+		.filter(|element| element.name == "meta")
+		.find(|element| element.attribute("name") == Some(name) || element.attribute("property") == Some(name))
+		.and_then(|element| element.attribute("content"))
+		.map(|content| content.trim().to_owned())
+}
+
+
+fn remove_unlikely(element: &mut Element) {
+	element.children.retain_mut(|child| {
+		let child = match child {
+			Node::Element(child) => child,
+			Node::Text(_) => return true,
+		};
+		let names = format!(
+			"{} {}",
+			child.attribute("class").unwrap_or_default(),
+			child.attribute("id").unwrap_or_default()
+		)
+		.to_ascii_lowercase();
+		let hidden = child.attribute("hidden").is_some()
+			|| child.attribute("aria-hidden") == Some("true")
+			|| child
+				.attribute("style")
+				.is_some_and(|style| style.replace(' ', "").contains("display:none"));
+		let unlikely = UNLIKELY.iter().any(|x| names.contains(x))
+			&& !LIKELY.iter().any(|x| names.contains(x))
+			&& !["body", "article", "main"].contains(&child.name.as_str());
+
+		if REMOVED_ELEMENTS.contains(&child.name.as_str()) || hidden || unlikely {
+			return false;
+		}
+		remove_unlikely(child);
+		true
+	});
+}
+
+
+/// The element that most looks like the article, and its siblings that look like they're part of it
+fn best_candidate(body: &Element) -> Option<Element> {
+	let mut scores = HashMap::new();
+	score_paragraphs(body, body, &mut Vec::new(), &mut scores);
+
+	let (path, score) = scores
+		.iter()
+		.map(|(path, score)| (path, score * (1.0 - link_density(element_at(body, path)))))
+		.max_by(|a, b| a.1.total_cmp(&b.1))?;
+	let (index, parent_path) = match path.split_last() {
+		Some((index, parent_path)) => (*index, parent_path),
+		None => return Some(body.clone()),
+	};
+
+	// Articles are often split across several elements (e.g. the text either side of an ad that's been removed)
+	let threshold = (score * 0.2).max(10.0);
+	let mut article = Element::new("div");
+
+	for (i, sibling) in element_at(body, parent_path).children.iter().enumerate() {
+		let sibling = match sibling {
+			Node::Element(sibling) => sibling,
+			Node::Text(_) => continue,
+		};
+		let mut sibling_path = parent_path.to_vec();
+		sibling_path.push(i);
+
+		let is_part = i == index
+			|| scores.get(&sibling_path).is_some_and(|score| *score >= threshold)
+			|| (sibling.name == "p" && sibling.text().trim().chars().count() > 80 && link_density(sibling) < 0.25);
+
+		if is_part {
+			article.children.push(Node::Element(sibling.clone()));
+		}
+	}
+
+	Some(article)
+}
+
+
+/// Scores each paragraph, adding its score to its parent's and half of it to its grandparent's.
+/// Elements are identified by their path from the body: the index of each child on the way down.
+fn score_paragraphs(body: &Element, element: &Element, path: &mut Vec<usize>, scores: &mut HashMap<Vec<usize>, f64>) {
+	for (i, child) in element.children.iter().enumerate() {
+		let child = match child {
+			Node::Element(child) => child,
+			Node::Text(_) => continue,
+		};
+		path.push(i);
+
+		// Plenty of sites use divs for paragraphs
+		let is_paragraph = PARAGRAPHS.contains(&child.name.as_str())
+			|| (child.name == "div" && !child.children.iter().any(|x| matches!(x, Node::Element(x) if is_block(x))));
+
+		if is_paragraph {
+			let text = child.text();
+			let length = text.trim().chars().count();
+
+			if length >= MIN_PARAGRAPH_LENGTH {
+				let score = 1.0 + text.matches([',', '，']).count() as f64 + (length as f64 / 100.0).min(3.0);
+
+				for (ancestor, share) in [(1, 1.0), (2, 0.5)] {
+					if path.len() >= ancestor {
+						let ancestor_path = path[..path.len() - ancestor].to_vec();
+						let initial = initial_score(element_at(body, &ancestor_path));
+						*scores.entry(ancestor_path).or_insert(initial) += score * share;
+					}
+				}
+			}
+		} else {
+			score_paragraphs(body, child, path, scores);
+		}
+
+		path.pop();
+	}
+}
+
+
+fn is_block(element: &Element) -> bool {
+	[
+		"article",
+		"blockquote",
+		"div",
+		"dl",
+		"figure",
+		"h1",
+		"h2",
+		"h3",
+		"h4",
+		"h5",
+		"h6",
+		"ol",
+		"p",
+		"pre",
+		"section",
+		"table",
+		"ul",
+	]
+	.contains(&element.name.as_str())
+}
+
+
+/// Some elements are more likely to hold articles than others, as are elements with some classes or IDs
+fn initial_score(element: &Element) -> f64 {
+	let tag_score = match element.name.as_str() {
+		"article" | "main" => 10.0,
+		"div" => 5.0,
+		"pre" | "td" | "blockquote" => 3.0,
+		"address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" => -3.0,
+		"h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+		_ => 0.0,
+	};
+	let names = format!(
+		"{} {}",
+		element.attribute("class").unwrap_or_default(),
+		element.attribute("id").unwrap_or_default()
+	)
+	.to_ascii_lowercase();
+	let class_score = if LIKELY.iter().any(|x| names.contains(x)) {
+		25.0
+	} else if UNLIKELY.iter().any(|x| names.contains(x)) {
+		-25.0
+	} else {
+		0.0
+	};
+
+	tag_score + class_score
+}
+
+
+/// How much of an element's text is links, from 0 to 1
+fn link_density(element: &Element) -> f64 {
+	let length = element.text().chars().count();
+	if length == 0 {
+		return 0.0;
+	}
+
+	let link_length: usize = element
+		.elements()
+		.into_iter()
+		.filter(|x| x.name == "a")
+		.map(|x| x.text().chars().count())
+		.sum();
+
+	link_length as f64 / length as f64
+}
+
+
+fn element_at<'a>(body: &'a Element, path: &[usize]) -> &'a Element {
+	let mut element = body;
+	for &i in path {
+		match &element.children[i] {
+			Node::Element(child) => element = child,
+			Node::Text(_) => unreachable!("Paths only lead to elements"),
+		}
+	}
+	element
+}
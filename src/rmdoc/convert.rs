@@ -0,0 +1,140 @@
+//! Turns Markdown, HTML and plain text into EPUBs, which the tablet reflows to suit its screen and the reader's settings.
+use super::{
+	epub::{Book, Resource},
+	html::{self, Element, Node},
+};
+use anyhow::Result;
+use base64::Engine;
+use pulldown_cmark::{Options, Parser};
+use std::collections::HashMap;
+
+
+/// The image types EPUB readers have to support, and the extensions we give them
+const IMAGE_TYPES: &[(&str, &str)] = &[
+	("image/gif", "gif"),
+	("image/jpeg", "jpg"),
+	("image/png", "png"),
+	("image/svg+xml", "svg"),
+	("image/webp", "webp"),
+];
+
+
+/// Blank lines separate paragraphs; other line breaks are kept as they are.
+pub fn text_to_epub(title: &str, text: &str) -> Result<Vec<u8>> {
+	let mut body = String::new();
+	let mut paragraph: Vec<String> = Vec::new();
+
+	for line in text.lines().chain(std::iter::once("")) {
+		if !line.trim().is_empty() {
+			paragraph.push(html::escape(line.trim_end()));
+		} else if !paragraph.is_empty() {
+			body.push_str(&format!("<p>{}</p>\n", paragraph.join("<br/>\n")));
+			paragraph.clear();
+		}
+	}
+
+	Book {
+		title: title.to_owned(),
+		author: None,
+		language: None,
+		body,
+		resources: Vec::new(),
+	}
+	.to_epub()
+}
+
+
+/// CommonMark, plus tables, strikethrough and footnotes.
+pub fn markdown_to_epub(title: &str, markdown: &str) -> Result<Vec<u8>> {
+	let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_FOOTNOTES | Options::ENABLE_SMART_PUNCTUATION;
+	let mut converted = String::new();
+	pulldown_cmark::html::push_html(&mut converted, Parser::new_ext(markdown, options));
+
+	html_to_epub(title, &converted)
+}
+
+
+/// Keeps the text and its structure (headings, lists, tables, links, ...) but not the page's styling.  Images are only kept if
+/// they're embedded in the page as `data:` URIs, as there's nothing to resolve relative links against.
+pub fn html_to_epub(title: &str, page: &str) -> Result<Vec<u8>> {
+	let document = html::parse(page);
+	let language = document.find("html").and_then(|root| root.attribute("lang")).map(str::to_owned);
+	let mut body = match document.find("body") {
+		Some(body) => body.clone(),
+		None => document,
+	};
+	let resources = embed_images(&mut body, &mut data_uri);
+
+	Book {
+		title: title.to_owned(),
+		author: None,
+		language,
+		body: html::to_xhtml(&body),
+		resources,
+	}
+	.to_epub()
+}
+
+
+/// Moves the images an element shows into the book.  `load` gets the media type and data of an image from its `src`; images it
+/// can't load, or that EPUB readers don't support, are left out.
+pub fn embed_images(element: &mut Element, load: &mut impl FnMut(&str) -> Option<(String, Vec<u8>)>) -> Vec<Resource> {
+	let mut resources = Vec::new();
+	embed_images_in(element, load, &mut resources, &mut HashMap::new());
+	resources
+}
+
+
+/// `paths` remembers where each `src` went, so an image shown more than once is only included once
+fn embed_images_in(
+	element: &mut Element,
+	load: &mut impl FnMut(&str) -> Option<(String, Vec<u8>)>,
+	resources: &mut Vec<Resource>,
+	paths: &mut HashMap<String, Option<String>>,
+) {
+	element.children.retain_mut(|child| {
+		let child = match child {
+			Node::Element(child) => child,
+			Node::Text(_) => return true,
+		};
+		if child.name != "img" {
+			embed_images_in(child, load, resources, paths);
+			return true;
+		}
+
+		let src = child.attribute("src").unwrap_or_default().to_owned();
+		let path = paths.entry(src).or_insert_with_key(|src| {
+			let (media_type, data) = load(src)?;
+			let extension = IMAGE_TYPES.iter().find(|(t, _)| *t == media_type)?.1;
+			let path = format!("images/{}.{}", resources.len() + 1, extension);
+
+			resources.push(Resource {
+				path: path.clone(),
+				media_type,
+				data,
+			});
+			Some(path)
+		});
+
+		match path {
+			Some(path) => {
+				child.set_attribute("src", path);
+				true
+			}
+			None => false,
+		}
+	});
+}
+
+
+/// The media type and data of a base64 `data:` URI
+pub fn data_uri(uri: &str) -> Option<(String, Vec<u8>)> {
+	let (header, data) = uri.strip_prefix("data:")?.split_once(',')?;
+	let media_type = header.strip_suffix(";base64")?;
+	let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+
+	Some((
+		media_type.to_ascii_lowercase(),
+		base64::engine::general_purpose::STANDARD.decode(data).ok()?,
+	))
+}
@@ -0,0 +1,258 @@
+//! Compares two versions of a document: which pages were added, removed or moved, and which strokes were added or erased on
+//! each page.
+//!
+//! Pages are matched by their ID.  Strokes are matched by their contents, since erasing part of a stroke replaces it with new
+//! strokes for the parts that are left: a stroke is unchanged if the other version has an identical one.
+use super::{
+	lines::{Color, Line, Lines, Pen},
+	style,
+	svg::{self, SvgOptions},
+	Page, RmDocument,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+
+/// Strokes that are in both versions are faded, so the changes stand out
+const UNCHANGED_COLOR: &str = "#c0c0c0";
+const ADDED_COLOR: &str = "#2ca02c";
+const REMOVED_COLOR: &str = "#d62728";
+
+
+#[derive(Serialize)]
+pub struct DocumentDiff {
+	/// Indexes are in the newer version
+	pub pages_added: Vec<PageRef>,
+	/// Indexes are in the older version
+	pub pages_removed: Vec<PageRef>,
+	/// Pages that are in both versions, but in a different order relative to the others
+	pub pages_moved: Vec<PageMove>,
+	pub pages_changed: Vec<PageChange>,
+}
+
+
+#[derive(Serialize)]
+pub struct PageRef {
+	pub id: String,
+	/// Counting from 0
+	pub index: usize,
+}
+
+
+#[derive(Serialize)]
+pub struct PageMove {
+	pub id: String,
+	pub from: usize,
+	pub to: usize,
+}
+
+
+#[derive(Serialize)]
+pub struct PageChange {
+	pub id: String,
+	/// In the newer version
+	pub index: usize,
+	pub strokes_added: usize,
+	pub strokes_removed: usize,
+	pub text_changed: bool,
+	/// The page with added strokes in green, removed strokes in red, and the rest in grey
+	pub svg: String,
+}
+
+
+/// Which strokes are the same.  Floats are compared by their bits: a stroke that hasn't changed is stored exactly as it was.
+#[derive(PartialEq, Eq, Hash)]
+struct StrokeKey {
+	pen: Pen,
+	color: Color,
+	thickness: u32,
+	argb: Option<u32>,
+	points: Vec<[u32; 6]>,
+}
+
+impl StrokeKey {
+	fn new(line: &Line) -> Self {
+		StrokeKey {
+			pen: line.pen,
+			color: line.color,
+			thickness: line.thickness.to_bits(),
+			argb: line.argb,
+			points: line
+				.points
+				.iter()
+				.map(|p| [p.x, p.y, p.speed, p.direction, p.width, p.pressure].map(f32::to_bits))
+				.collect(),
+		}
+	}
+}
+
+
+pub fn diff(old: &RmDocument, new: &RmDocument) -> Result<DocumentDiff> {
+	let old_indexes: HashMap<&str, usize> = old.pages.iter().enumerate().map(|(i, page)| (page.id.as_str(), i)).collect();
+	let new_indexes: HashMap<&str, usize> = new.pages.iter().enumerate().map(|(i, page)| (page.id.as_str(), i)).collect();
+
+	let pages_added = new
+		.pages
+		.iter()
+		.enumerate()
+		.filter(|(_, page)| !old_indexes.contains_key(page.id.as_str()))
+		.map(|(index, page)| PageRef { id: page.id.clone(), index })
+		.collect();
+	let pages_removed = old
+		.pages
+		.iter()
+		.enumerate()
+		.filter(|(_, page)| !new_indexes.contains_key(page.id.as_str()))
+		.map(|(index, page)| PageRef { id: page.id.clone(), index })
+		.collect();
+
+	// The pages in both versions, in each version's order.  The fewest pages that have to move to turn one order into the other
+	// are those that aren't in the longest common subsequence.
+	let old_order: Vec<&str> = old
+		.pages
+		.iter()
+		.map(|page| page.id.as_str())
+		.filter(|id| new_indexes.contains_key(id))
+		.collect();
+	let new_order: Vec<&str> = new
+		.pages
+		.iter()
+		.map(|page| page.id.as_str())
+		.filter(|id| old_indexes.contains_key(id))
+		.collect();
+	let in_order = longest_common_subsequence(&old_order, &new_order);
+	let pages_moved = new_order
+		.iter()
+		.filter(|id| !in_order.contains(*id))
+		.map(|id| PageMove {
+			id: id.to_string(),
+			from: old_indexes[id],
+			to: new_indexes[id],
+		})
+		.collect();
+
+	let mut pages_changed = Vec::new();
+	for (index, page) in new.pages.iter().enumerate() {
+		if let Some(&old_index) = old_indexes.get(page.id.as_str()) {
+			if let Some(change) = compare_page(&old.pages[old_index], page, index)? {
+				pages_changed.push(change);
+			}
+		}
+	}
+
+	Ok(DocumentDiff {
+		pages_added,
+		pages_removed,
+		pages_moved,
+		pages_changed,
+	})
+}
+
+
+fn compare_page(old: &Page, new: &Page, index: usize) -> Result<Option<PageChange>> {
+	if old.lines == new.lines {
+		return Ok(None);
+	}
+
+	let parse = |page: &Page| -> Result<Lines> {
+		match &page.lines {
+			Some(data) => Lines::parse(data).with_context(|| format!("Parsing page {}", page.id)),
+			None => Ok(Lines::default()),
+		}
+	};
+	let (old_lines, new_lines) = (parse(old)?, parse(new)?);
+
+	let added = unmatched(&new_lines, &old_lines);
+	let removed = unmatched(&old_lines, &new_lines);
+	let text_changed = old_lines.text.as_ref().map(|text| &text.paragraphs) != new_lines.text.as_ref().map(|text| &text.paragraphs);
+
+	// e.g. the page was only saved again
+	if added.is_empty() && removed.is_empty() && !text_changed {
+		return Ok(None);
+	}
+
+	let unchanged: Vec<&Line> = drawn_lines(&new_lines)
+		.filter(|line| !added.iter().any(|added| std::ptr::eq(*added, *line)))
+		.collect();
+	let (old_offset, new_offset) = (
+		style::x_offset(&old_lines, style::PAGE_WIDTH),
+		style::x_offset(&new_lines, style::PAGE_WIDTH),
+	);
+	let svg = svg::render_colored_svg(
+		&[
+			(&unchanged, new_offset, UNCHANGED_COLOR),
+			(&removed, old_offset, REMOVED_COLOR),
+			(&added, new_offset, ADDED_COLOR),
+		],
+		style::page_height(&old_lines).max(style::page_height(&new_lines)),
+		&SvgOptions::default(),
+	);
+
+	Ok(Some(PageChange {
+		id: new.id.clone(),
+		index,
+		strokes_added: added.len(),
+		strokes_removed: removed.len(),
+		text_changed,
+		svg,
+	}))
+}
+
+
+/// The strokes the page shows.  Eraser strokes only hide other strokes, so they aren't changes in themselves.
+fn drawn_lines(lines: &Lines) -> impl Iterator<Item = &Line> {
+	lines
+		.visible_lines()
+		.filter(|line| line.pen != Pen::Eraser && style::stroke_style(line).is_some())
+}
+
+
+/// The strokes of `lines` that `other` doesn't have (as many times)
+fn unmatched<'a>(lines: &'a Lines, other: &Lines) -> Vec<&'a Line> {
+	let mut counts: HashMap<StrokeKey, usize> = HashMap::new();
+	for line in drawn_lines(other) {
+		*counts.entry(StrokeKey::new(line)).or_default() += 1;
+	}
+
+	drawn_lines(lines)
+		.filter(|line| match counts.get_mut(&StrokeKey::new(line)) {
+			Some(count) if *count > 0 => {
+				*count -= 1;
+				false
+			}
+			_ => true,
+		})
+		.collect()
+}
+
+
+fn longest_common_subsequence<'a>(a: &[&'a str], b: &[&'a str]) -> HashSet<&'a str> {
+	// lengths[i][j] is the length of the LCS of a[i..] and b[j..]
+	let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+	for i in (0..a.len()).rev() {
+		for j in (0..b.len()).rev() {
+			lengths[i][j] = if a[i] == b[j] {
+				lengths[i + 1][j + 1] + 1
+			} else {
+				lengths[i + 1][j].max(lengths[i][j + 1])
+			};
+		}
+	}
+
+	let mut result = HashSet::new();
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() && j < b.len() {
+		if a[i] == b[j] {
+			result.insert(a[i]);
+			i += 1;
+			j += 1;
+		} else if lengths[i + 1][j] >= lengths[i][j + 1] {
+			i += 1;
+		} else {
+			j += 1;
+		}
+	}
+
+	result
+}
@@ -0,0 +1,203 @@
+//! Reads what PDFs and EPUBs say about themselves: their title, authors, date and cover.
+//!
+//! PDFs describe themselves in their XMP metadata and their Info dictionary; XMP is preferred, since tools tend to keep it more
+//! accurate.  EPUBs describe themselves in their package document (OPF).
+use super::{
+	epub::{self, attribute, element_contents, element_texts, tags},
+	FileType, RmDocument,
+};
+use anyhow::{Context, Result};
+use log::debug;
+use lopdf::{Document, Object};
+use serde::Serialize;
+use std::io::Cursor;
+
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DocumentInfo {
+	pub title: Option<String>,
+	pub authors: Vec<String>,
+	/// e.g. "2021-01-04", or less precise ("2021-01", "2021") if that's all the document says
+	pub date: Option<String>,
+	#[serde(skip)]
+	pub cover: Option<Cover>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Cover {
+	pub data: Vec<u8>,
+	/// e.g. "image/jpeg"
+	pub content_type: String,
+}
+
+
+impl DocumentInfo {
+	/// What a document's PDF or EPUB says about itself.  Anything that can't be read is left out.
+	pub fn from_document(document: &RmDocument) -> Self {
+		let info = match (document.file_type(), &document.pdf, &document.epub) {
+			(FileType::Pdf, Some(pdf), _) => from_pdf(pdf),
+			(FileType::Epub, _, Some(epub)) => from_epub(epub),
+			_ => return DocumentInfo::default(),
+		};
+
+		info.unwrap_or_else(|err| {
+			debug!("Unable to read the metadata of {}: {:?}", document.id, err);
+			DocumentInfo::default()
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.title.is_none() && self.authors.is_empty() && self.date.is_none() && self.cover.is_none()
+	}
+
+	/// The year, from the date
+	pub fn year(&self) -> Option<&str> {
+		self.date.as_deref().and_then(|date| date.get(..4))
+	}
+}
+
+
+fn from_pdf(data: &[u8]) -> Result<DocumentInfo> {
+	let pdf = Document::load_mem(data).context("Invalid PDF")?;
+	let mut info = DocumentInfo::default();
+
+	// XMP
+	let xmp = pdf
+		.catalog()
+		.and_then(|catalog| catalog.get(b"Metadata"))
+		.and_then(Object::as_reference)
+		.and_then(|id| pdf.get_object(id))
+		.and_then(Object::as_stream)
+		.and_then(|stream| stream.get_plain_content())
+		.map(|xmp| String::from_utf8_lossy(&xmp).into_owned());
+
+	if let Ok(xmp) = xmp {
+		// Titles are a list of translations (rdf:Alt), and authors an ordered list (rdf:Seq)
+		info.title = element_contents(&xmp, "title")
+			.first()
+			.and_then(|title| element_texts(title, "li").into_iter().next())
+			.and_then(|title| plausible_title(&title));
+		info.authors = element_contents(&xmp, "creator")
+			.first()
+			.map(|creators| element_texts(creators, "li"))
+			.unwrap_or_default()
+			.into_iter()
+			.filter_map(|author| non_empty(&author))
+			.collect();
+		// Dates can be elements or attributes of the rdf:Description
+		info.date = element_texts(&xmp, "CreateDate")
+			.into_iter()
+			.next()
+			.or_else(|| tags(&xmp, "Description").into_iter().find_map(|tag| attribute(tag, "xmp:CreateDate")))
+			.and_then(|date| normalize_date(&date));
+	}
+
+	// The Info dictionary, for whatever XMP didn't have
+	let dictionary = pdf
+		.trailer
+		.get(b"Info")
+		.and_then(Object::as_reference)
+		.and_then(|id| pdf.get_dictionary(id));
+
+	if let Ok(dictionary) = dictionary {
+		let text = |key: &[u8]| dictionary.get(key).ok().and_then(|value| lopdf::decode_text_string(value).ok());
+
+		if info.title.is_none() {
+			info.title = text(b"Title").and_then(|title| plausible_title(&title));
+		}
+		if info.authors.is_empty() {
+			info.authors = text(b"Author").unwrap_or_default().split(';').filter_map(non_empty).collect();
+		}
+		if info.date.is_none() {
+			info.date = text(b"CreationDate").and_then(|date| normalize_date(&date));
+		}
+	}
+
+	Ok(info)
+}
+
+
+fn from_epub(data: &[u8]) -> Result<DocumentInfo> {
+	let mut archive = zip::ZipArchive::new(Cursor::new(data)).context("Invalid EPUB")?;
+	let (opf, base) = epub::package(&mut archive)?;
+	let metadata = element_contents(&opf, "metadata").first().copied().unwrap_or(&opf);
+
+	let title = element_texts(metadata, "title")
+		.into_iter()
+		.next()
+		.and_then(|title| plausible_title(&title));
+	let authors = element_texts(metadata, "creator")
+		.into_iter()
+		.filter_map(|author| non_empty(&author))
+		.collect();
+	let date = element_texts(metadata, "date").into_iter().next().and_then(|date| normalize_date(&date));
+
+	// EPUB 3 marks the cover in the manifest; EPUB 2 names it in a <meta>
+	let items = tags(&opf, "item");
+	let cover_id = tags(metadata, "meta")
+		.into_iter()
+		.find(|tag| attribute(tag, "name").as_deref() == Some("cover"))
+		.and_then(|tag| attribute(tag, "content"));
+	let cover_item = items
+		.iter()
+		.find(|tag| attribute(tag, "properties").is_some_and(|properties| properties.split_whitespace().any(|p| p == "cover-image")))
+		.or_else(|| items.iter().find(|tag| cover_id.is_some() && attribute(tag, "id") == cover_id));
+
+	let cover = cover_item.and_then(|tag| {
+		let href = attribute(tag, "href")?;
+		let content_type = attribute(tag, "media-type").filter(|media_type| media_type.starts_with("image/"))?;
+		let path = format!("{}{}", base, epub::percent_decode(&href));
+
+		match epub::read_bytes(&mut archive, &path) {
+			Ok(data) => Some(Cover { data, content_type }),
+			Err(err) => {
+				debug!("Unable to read EPUB cover {}: {:?}", path, err);
+				None
+			}
+		}
+	});
+
+	Ok(DocumentInfo { title, authors, date, cover })
+}
+
+
+/// Leaves out titles that are just a placeholder or the name of the file the document was made from (e.g. "Microsoft Word -
+/// report.docx"), which are no better than the name it was uploaded with.
+fn plausible_title(title: &str) -> Option<String> {
+	let title = title.trim();
+	let title = title.strip_prefix("Microsoft Word - ").unwrap_or(title);
+	let lowercase = title.to_lowercase();
+	let file_extensions = [".pdf", ".doc", ".docx", ".odt", ".rtf", ".tex", ".dvi", ".ps", ".indd", ".qxd", ".epub"];
+
+	if lowercase.is_empty() || lowercase == "untitled" || file_extensions.iter().any(|extension| lowercase.ends_with(extension)) {
+		None
+	} else {
+		Some(title.to_owned())
+	}
+}
+
+
+fn non_empty(text: &str) -> Option<String> {
+	let text = text.trim();
+	(!text.is_empty()).then(|| text.to_owned())
+}
+
+
+/// Dates as "YYYY-MM-DD" (or the start of it), from PDF dates ("D:20210104123456+01'00'") and ISO 8601 dates
+/// ("2021-01-04T12:34:56Z")
+fn normalize_date(date: &str) -> Option<String> {
+	let date = date.trim();
+
+	if let Some(digits) = date.strip_prefix("D:") {
+		let digits: String = digits.chars().take_while(char::is_ascii_digit).take(8).collect();
+		let parts: Vec<&str> = vec![digits.get(..4), digits.get(4..6), digits.get(6..8)].into_iter().flatten().collect();
+		return (!parts.is_empty()).then(|| parts.join("-"));
+	}
+
+	let prefix: String = date.chars().take_while(|c| c.is_ascii_digit() || *c == '-').take(10).collect();
+	let prefix = prefix.trim_end_matches('-');
+	let valid = prefix.len() >= 4 && prefix.as_bytes()[..4].iter().all(u8::is_ascii_digit);
+
+	valid.then(|| prefix.to_owned())
+}
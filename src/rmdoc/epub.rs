@@ -0,0 +1,365 @@
+//! Just enough of EPUB to get the text out of one (the package document's spine gives the chapters, in reading order), and to make
+//! simple ones: a single chapter of XHTML, and the images it shows.
+use super::html::{self, decode_entities};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use std::{
+	collections::HashMap,
+	io::{Cursor, Read, Write},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+
+/// Styling for the books we make.  The tablet sets the font, margins and line spacing itself.
+const STYLESHEET: &str = "img { max-width: 100%; height: auto; }
+pre { white-space: pre-wrap; }
+blockquote { margin-left: 1.5em; margin-right: 0; font-style: italic; }
+table { border-collapse: collapse; }
+td, th { border: 1px solid #888; padding: 0.2em 0.4em; }
+";
+
+
+/// A book with a single chapter, to be written as an EPUB
+pub struct Book {
+	pub title: String,
+	pub author: Option<String>,
+	/// e.g. "en"; undetermined if not known
+	pub language: Option<String>,
+	/// The XHTML of the chapter's body
+	pub body: String,
+	/// Files the body refers to, e.g. images
+	pub resources: Vec<Resource>,
+}
+
+
+pub struct Resource {
+	/// Relative to the chapter, e.g. "images/1.png"
+	pub path: String,
+	pub media_type: String,
+	pub data: Vec<u8>,
+}
+
+
+impl Book {
+	pub fn to_epub(&self) -> Result<Vec<u8>> {
+		let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+		let identifier = format!("urn:uuid:{}", uuid::Uuid::new_v4());
+		let title = html::escape(&self.title);
+		let language = html::escape(self.language.as_deref().unwrap_or("und"));
+
+		// The mimetype has to come first, uncompressed, so that the file can be recognised from its first bytes
+		writer.start_file("mimetype", FileOptions::default().compression_method(CompressionMethod::Stored))?;
+		writer.write_all(b"application/epub+zip")?;
+
+		let mut add = |name: &str, data: &[u8]| -> Result<()> {
+			writer.start_file(name, FileOptions::default())?;
+			writer.write_all(data)?;
+			Ok(())
+		};
+
+		add(
+			"META-INF/container.xml",
+			br#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>
+"#,
+		)?;
+
+		let creator = match &self.author {
+			Some(author) => format!("<dc:creator>{}</dc:creator>\n", html::escape(author)),
+			None => String::new(),
+		};
+		let resources: String = self
+			.resources
+			.iter()
+			.enumerate()
+			.map(|(i, resource)| {
+				format!(
+					"<item id=\"resource-{}\" href=\"{}\" media-type=\"{}\"/>\n",
+					i,
+					html::escape(&resource.path),
+					html::escape(&resource.media_type)
+				)
+			})
+			.collect();
+		add(
+			"OEBPS/content.opf",
+			format!(
+				r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="book-id">{identifier}</dc:identifier>
+<dc:title>{title}</dc:title>
+{creator}<dc:language>{language}</dc:language>
+<meta property="dcterms:modified">{modified}</meta>
+</metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+<item id="style" href="style.css" media-type="text/css"/>
+<item id="text" href="text.xhtml" media-type="application/xhtml+xml"/>
+{resources}</manifest>
+<spine toc="ncx"><itemref idref="text"/></spine>
+</package>
+"#,
+				modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+			)
+			.as_bytes(),
+		)?;
+
+		// EPUB 3 readers use the nav document, older ones the NCX
+		add(
+			"OEBPS/nav.xhtml",
+			xhtml_document(
+				&title,
+				&language,
+				&format!(r#"<nav epub:type="toc"><ol><li><a href="text.xhtml">{}</a></li></ol></nav>"#, title),
+			)
+			.as_bytes(),
+		)?;
+		add(
+			"OEBPS/toc.ncx",
+			format!(
+				r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head><meta name="dtb:uid" content="{identifier}"/></head>
+<docTitle><text>{title}</text></docTitle>
+<navMap><navPoint id="text" playOrder="1"><navLabel><text>{title}</text></navLabel><content src="text.xhtml"/></navPoint></navMap>
+</ncx>
+"#
+			)
+			.as_bytes(),
+		)?;
+		add("OEBPS/style.css", STYLESHEET.as_bytes())?;
+		add("OEBPS/text.xhtml", xhtml_document(&title, &language, &self.body).as_bytes())?;
+
+		for resource in &self.resources {
+			add(&format!("OEBPS/{}", resource.path), &resource.data)?;
+		}
+
+		Ok(writer.finish()?.into_inner())
+	}
+}
+
+
+/// `title` and `language` are already escaped
+fn xhtml_document(title: &str, language: &str, body: &str) -> String {
+	format!(
+		r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{language}" lang="{language}">
+<head><title>{title}</title><link rel="stylesheet" type="text/css" href="style.css"/></head>
+<body>
+{body}
+</body>
+</html>
+"#
+	)
+}
+
+
+/// The text of each chapter, in reading order.
+pub fn chapter_text(epub: &[u8]) -> Result<Vec<String>> {
+	let mut archive = zip::ZipArchive::new(Cursor::new(epub)).context("Invalid EPUB")?;
+	let (opf, base) = package(&mut archive)?;
+
+	let manifest: HashMap<String, String> = tags(&opf, "item")
+		.into_iter()
+		.filter_map(|tag| Some((attribute(tag, "id")?, attribute(tag, "href")?)))
+		.collect();
+	let mut chapters = Vec::new();
+
+	for idref in tags(&opf, "itemref").into_iter().filter_map(|tag| attribute(tag, "idref")) {
+		let href = match manifest.get(&idref) {
+			Some(href) => href,
+			None => continue,
+		};
+		let path = format!("{}{}", base, percent_decode(href.split('#').next().unwrap_or_default()));
+
+		chapters.push(html_to_text(&read_file(&mut archive, &path)?));
+	}
+
+	Ok(chapters)
+}
+
+
+/// The readable text of an (X)HTML document: tags are dropped, block elements become line breaks, and entities are decoded.
+pub fn html_to_text(html: &str) -> String {
+	let body = match html.find("<body") {
+		Some(i) => &html[i..],
+		None => html,
+	};
+	let mut text = String::new();
+	let mut rest = body;
+
+	while let Some(start) = rest.find('<') {
+		text.push_str(&decode_entities(&rest[..start]));
+		rest = &rest[start..];
+
+		let end = match rest.find('>') {
+			Some(end) => end,
+			None => break,
+		};
+		let tag = rest[1..end].trim_start_matches('/').to_ascii_lowercase();
+		let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+		rest = &rest[end + 1..];
+
+		match name {
+			// Skip their contents entirely
+			"script" | "style" | "head" => {
+				if let Some(close) = rest.to_ascii_lowercase().find(&format!("</{}", name)) {
+					rest = &rest[close..];
+				}
+			}
+			"p" | "div" | "br" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "blockquote" | "section" | "pre" => text.push('\n'),
+			_ => (),
+		}
+	}
+	text.push_str(&decode_entities(rest));
+
+	// Collapse the whitespace left behind by the markup
+	text.lines()
+		.map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+		.filter(|line| !line.is_empty())
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+
+/// The package document (the OPF, which lists the book's metadata and files), and the directory it's in (e.g. "OEBPS/"), which
+/// the paths in it are relative to
+pub(super) fn package(archive: &mut zip::ZipArchive<Cursor<&[u8]>>) -> Result<(String, String)> {
+	let container = read_file(archive, "META-INF/container.xml")?;
+	let opf_path = match tags(&container, "rootfile").into_iter().find_map(|tag| attribute(tag, "full-path")) {
+		Some(path) => path,
+		None => bail!("EPUB container doesn't name a package document"),
+	};
+	let opf = read_file(archive, &opf_path)?;
+	let base = match opf_path.rfind('/') {
+		Some(i) => opf_path[..=i].to_owned(),
+		None => String::new(),
+	};
+
+	Ok((opf, base))
+}
+
+
+fn read_file(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, path: &str) -> Result<String> {
+	Ok(String::from_utf8_lossy(&read_bytes(archive, path)?).into_owned())
+}
+
+
+pub(super) fn read_bytes(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, path: &str) -> Result<Vec<u8>> {
+	let file = archive.by_name(path).with_context(|| format!("EPUB is missing {}", path))?;
+	let mut data = Vec::new();
+	file.take(super::MAX_UNPACKED_SIZE + 1).read_to_end(&mut data)?;
+	if data.len() as u64 > super::MAX_UNPACKED_SIZE {
+		bail!("{} unpacks to more than {} bytes", path, super::MAX_UNPACKED_SIZE);
+	}
+	Ok(data)
+}
+
+
+/// The text inside every element with the given name, ignoring namespace prefixes, with any markup in it dropped.  Elements
+/// with the same name can't be nested.
+pub(super) fn element_texts(xml: &str, name: &str) -> Vec<String> {
+	element_contents(xml, name)
+		.into_iter()
+		.map(|inner| html_to_text(inner).replace('\n', " "))
+		.collect()
+}
+
+
+/// Everything between the start and end tags of every element with the given name, ignoring namespace prefixes
+pub(super) fn element_contents<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+	let mut found = Vec::new();
+	let mut rest = xml;
+
+	while let Some(start) = rest.find('<') {
+		rest = &rest[start + 1..];
+		let end = match rest.find('>') {
+			Some(end) => end,
+			None => break,
+		};
+		let tag = &rest[..end];
+		let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+		rest = &rest[end + 1..];
+
+		if tag_name.rsplit(':').next() != Some(name) || tag.ends_with('/') {
+			continue;
+		}
+
+		let close = format!("</{}>", tag_name);
+		if let Some(close_start) = rest.find(&close) {
+			found.push(&rest[..close_start]);
+			rest = &rest[close_start + close.len()..];
+		}
+	}
+
+	found
+}
+
+
+/// The insides (everything between the name and the closing `>`) of every tag with the given name, ignoring namespace prefixes.
+pub(super) fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+	let mut found = Vec::new();
+	let mut rest = xml;
+
+	while let Some(start) = rest.find('<') {
+		rest = &rest[start + 1..];
+		let end = rest.find('>').unwrap_or(rest.len());
+		let tag = &rest[..end];
+		let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+		let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+
+		if local_name == name {
+			found.push(&tag[tag_name.len()..]);
+		}
+		rest = &rest[end..];
+	}
+
+	found
+}
+
+
+pub(super) fn attribute(tag: &str, name: &str) -> Option<String> {
+	for quote in &['"', '\''] {
+		let pattern = format!("{}={}", name, quote);
+		let mut search = tag;
+
+		while let Some(i) = search.find(&pattern) {
+			let preceded_by_space = !search[..i].ends_with(|c: char| !c.is_whitespace());
+			let value = &search[i + pattern.len()..];
+
+			if preceded_by_space {
+				return value.find(*quote).map(|end| decode_entities(&value[..end]));
+			}
+			search = value;
+		}
+	}
+
+	None
+}
+
+
+pub(super) fn percent_decode(text: &str) -> String {
+	let bytes = text.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+
+	while i < bytes.len() {
+		match (bytes[i], text.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+			(b'%', Some(byte)) => {
+				decoded.push(byte);
+				i += 3;
+			}
+			(byte, _) => {
+				decoded.push(byte);
+				i += 1;
+			}
+		}
+	}
+
+	String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,582 @@
+//! A forgiving HTML parser, for turning web pages and converted Markdown into the XHTML that EPUBs are made of.
+//!
+//! It knows just enough of HTML's rules to build a sensible tree from real-world markup: void elements, elements whose contents
+//! aren't markup, and the end tags that are usually left out.  The tree can be written back out as well-formed XHTML, keeping only
+//! the markup that makes sense in a book.
+
+
+#[derive(Clone, Debug)]
+pub enum Node {
+	Element(Element),
+	Text(String),
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct Element {
+	/// Lowercase
+	pub name: String,
+	/// Names are lowercase; values have had their entities decoded
+	pub attributes: Vec<(String, String)>,
+	pub children: Vec<Node>,
+}
+
+impl Element {
+	pub fn new(name: &str) -> Self {
+		Element {
+			name: name.to_owned(),
+			..Default::default()
+		}
+	}
+
+	pub fn attribute(&self, name: &str) -> Option<&str> {
+		self.attributes.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+	}
+
+	pub fn set_attribute(&mut self, name: &str, value: &str) {
+		match self.attributes.iter_mut().find(|(n, _)| n == name) {
+			Some(attribute) => attribute.1 = value.to_owned(),
+			None => self.attributes.push((name.to_owned(), value.to_owned())),
+		}
+	}
+
+	/// The first element with the given name, searching depth first
+	pub fn find(&self, name: &str) -> Option<&Element> {
+		self.children.iter().find_map(|child| match child {
+			Node::Element(element) if element.name == name => Some(element),
+			Node::Element(element) => element.find(name),
+			Node::Text(_) => None,
+		})
+	}
+
+	/// Every element inside this one, depth first
+	pub fn elements(&self) -> Vec<&Element> {
+		let mut elements = Vec::new();
+		for child in &self.children {
+			if let Node::Element(element) = child {
+				elements.push(element);
+				elements.extend(element.elements());
+			}
+		}
+		elements
+	}
+
+	/// Calls `f` on every element inside this one, depth first
+	pub fn visit_mut(&mut self, f: &mut impl FnMut(&mut Element)) {
+		for child in &mut self.children {
+			if let Node::Element(element) = child {
+				f(element);
+				element.visit_mut(f);
+			}
+		}
+	}
+
+	/// All the text inside the element, without any markup
+	pub fn text(&self) -> String {
+		let mut text = String::new();
+		for child in &self.children {
+			match child {
+				Node::Element(element) => text.push_str(&element.text()),
+				Node::Text(t) => text.push_str(t),
+			}
+		}
+		text
+	}
+}
+
+
+/// Elements that never have contents or an end tag
+const VOID_ELEMENTS: &[&str] = &[
+	"area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Elements whose contents are text rather than markup
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Elements that end a paragraph that's still open when they start
+const BLOCK_ELEMENTS: &[&str] = &[
+	"address",
+	"article",
+	"aside",
+	"blockquote",
+	"div",
+	"dl",
+	"fieldset",
+	"figure",
+	"footer",
+	"form",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"header",
+	"hr",
+	"main",
+	"nav",
+	"ol",
+	"p",
+	"pre",
+	"section",
+	"table",
+	"ul",
+];
+
+/// Elements that are dropped along with everything in them when writing XHTML
+const DROPPED_ELEMENTS: &[&str] = &[
+	"applet", "audio", "button", "canvas", "embed", "form", "head", "iframe", "input", "map", "noscript", "object", "script", "select", "style",
+	"svg", "template", "textarea", "title", "video",
+];
+
+/// Elements that are kept when writing XHTML.  Anything not listed here or in DROPPED_ELEMENTS is replaced by its contents.
+const KEPT_ELEMENTS: &[&str] = &[
+	"a",
+	"abbr",
+	"b",
+	"blockquote",
+	"br",
+	"caption",
+	"cite",
+	"code",
+	"dd",
+	"del",
+	"div",
+	"dl",
+	"dt",
+	"em",
+	"figcaption",
+	"figure",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"hr",
+	"i",
+	"img",
+	"ins",
+	"kbd",
+	"li",
+	"mark",
+	"ol",
+	"p",
+	"pre",
+	"q",
+	"s",
+	"samp",
+	"small",
+	"strong",
+	"sub",
+	"sup",
+	"table",
+	"tbody",
+	"td",
+	"tfoot",
+	"th",
+	"thead",
+	"tr",
+	"u",
+	"ul",
+];
+
+/// The attributes that are kept when writing XHTML, by element
+const KEPT_ATTRIBUTES: &[(&str, &str)] = &[
+	("a", "href"),
+	("img", "src"),
+	("img", "alt"),
+	("ol", "start"),
+	("td", "colspan"),
+	("td", "rowspan"),
+	("th", "colspan"),
+	("th", "rowspan"),
+];
+
+
+/// Parses a document or fragment.  Returns a root element (named "#document") holding whatever was found.
+pub fn parse(html: &str) -> Element {
+	let mut stack = vec![Element::new("#document")];
+	let mut rest = html;
+
+	while let Some(start) = rest.find('<') {
+		append_text(&mut stack, &decode_entities(&rest[..start]));
+		rest = &rest[start..];
+
+		if rest.starts_with("<!--") {
+			rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+			continue;
+		}
+		// Doctypes, CDATA and processing instructions
+		if rest.starts_with("<!") || rest.starts_with("<?") {
+			rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+			continue;
+		}
+
+		let is_end_tag = rest.starts_with("</");
+		let tag_start = if is_end_tag { 2 } else { 1 };
+
+		// A '<' that doesn't start a tag is just text
+		if !rest[tag_start..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+			append_text(&mut stack, "<");
+			rest = &rest[1..];
+			continue;
+		}
+
+		let (element, self_closing, after) = read_tag(&rest[tag_start..]);
+		rest = after;
+
+		if is_end_tag {
+			close(&mut stack, &element.name);
+			continue;
+		}
+
+		close_implied(&mut stack, &element.name);
+
+		if VOID_ELEMENTS.contains(&element.name.as_str()) || self_closing {
+			stack.last_mut().unwrap().children.push(Node::Element(element));
+			continue;
+		}
+
+		if RAW_TEXT_ELEMENTS.contains(&element.name.as_str()) {
+			let end = rest.to_ascii_lowercase().find(&format!("</{}", element.name)).unwrap_or(rest.len());
+			let mut element = element;
+			if end > 0 {
+				element.children.push(Node::Text(decode_entities(&rest[..end])));
+			}
+			stack.last_mut().unwrap().children.push(Node::Element(element));
+			rest = rest[end..].find('>').map_or("", |close| &rest[end + close + 1..]);
+			continue;
+		}
+
+		stack.push(element);
+	}
+	append_text(&mut stack, &decode_entities(rest));
+
+	while stack.len() > 1 {
+		pop(&mut stack);
+	}
+	stack.pop().unwrap()
+}
+
+
+/// Reads a start or end tag, from just after its `<` (or `</`).  Returns the element, whether it closed itself (`<br/>`),
+/// and what comes after the tag.
+fn read_tag(tag: &str) -> (Element, bool, &str) {
+	let bytes = tag.as_bytes();
+	let is_name_end = |b: u8| b.is_ascii_whitespace() || b == b'/' || b == b'>';
+	let mut i = bytes.iter().position(|&b| is_name_end(b)).unwrap_or(bytes.len());
+	let mut element = Element::new(&tag[..i].to_ascii_lowercase());
+	let mut self_closing = false;
+
+	while i < bytes.len() {
+		match bytes[i] {
+			b'>' => return (element, self_closing, &tag[i + 1..]),
+			b'/' => {
+				self_closing = true;
+				i += 1;
+				continue;
+			}
+			b if b.is_ascii_whitespace() => {
+				i += 1;
+				continue;
+			}
+			_ => self_closing = false,
+		}
+
+		let name_start = i;
+		while i < bytes.len() && !is_name_end(bytes[i]) && bytes[i] != b'=' {
+			i += 1;
+		}
+		let name = tag[name_start..i].to_ascii_lowercase();
+
+		while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+			i += 1;
+		}
+		if i == bytes.len() || bytes[i] != b'=' {
+			element.attributes.push((name, String::new()));
+			continue;
+		}
+		i += 1;
+		while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+			i += 1;
+		}
+
+		let value = match bytes.get(i) {
+			Some(&quote) if quote == b'"' || quote == b'\'' => {
+				let end = tag[i + 1..].find(quote as char).map_or(tag.len(), |end| i + 1 + end);
+				let value = &tag[i + 1..end];
+				i = (end + 1).min(bytes.len());
+				value
+			}
+			_ => {
+				let start = i;
+				while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+					i += 1;
+				}
+				&tag[start..i]
+			}
+		};
+
+		if !element.attributes.iter().any(|(n, _)| *n == name) {
+			element.attributes.push((name, decode_entities(value)));
+		}
+	}
+
+	(element, self_closing, "")
+}
+
+
+fn append_text(stack: &mut [Element], text: &str) {
+	if text.is_empty() {
+		return;
+	}
+
+	let children = &mut stack.last_mut().unwrap().children;
+	match children.last_mut() {
+		Some(Node::Text(previous)) => previous.push_str(text),
+		_ => children.push(Node::Text(text.to_owned())),
+	}
+}
+
+
+/// Closes the innermost open element, adding it to its parent.
+fn pop(stack: &mut Vec<Element>) {
+	let element = stack.pop().unwrap();
+	stack.last_mut().unwrap().children.push(Node::Element(element));
+}
+
+
+/// Handles an end tag: closes the element it names, and anything left open inside it.  End tags for elements that aren't open are ignored.
+fn close(stack: &mut Vec<Element>, name: &str) {
+	if let Some(index) = stack.iter().skip(1).rposition(|element| element.name == name) {
+		while stack.len() > index + 1 {
+			pop(stack);
+		}
+	}
+}
+
+
+/// Closes the elements whose end tags are implied by the start of `name`, e.g. a paragraph is ended by the start of a list,
+/// and a list item by the start of the next one.
+fn close_implied(stack: &mut Vec<Element>, name: &str) {
+	let (closes, boundaries): (&[&str], &[&str]) = match name {
+		"li" => (&["li"], &["ul", "ol"]),
+		"dt" | "dd" => (&["dt", "dd"], &["dl"]),
+		"tr" => (&["tr"], &["table", "thead", "tbody", "tfoot"]),
+		"td" | "th" => (&["td", "th"], &["tr", "table"]),
+		"thead" | "tbody" | "tfoot" => (&["thead", "tbody", "tfoot"], &["table"]),
+		_ if BLOCK_ELEMENTS.contains(&name) => (&["p"], &["div", "li", "dd", "td", "th", "blockquote", "section", "article", "body"]),
+		_ => return,
+	};
+
+	for (index, element) in stack.iter().enumerate().skip(1).rev() {
+		if closes.contains(&element.name.as_str()) {
+			while stack.len() > index {
+				pop(stack);
+			}
+			return;
+		}
+		if boundaries.contains(&element.name.as_str()) {
+			return;
+		}
+	}
+}
+
+
+/// Writes the contents of an element as XHTML, leaving out anything that doesn't belong in a book: scripts, forms, styling, etc.
+pub fn to_xhtml(element: &Element) -> String {
+	let mut xhtml = String::new();
+	for child in &element.children {
+		write_node(&mut xhtml, child);
+	}
+	xhtml
+}
+
+
+fn write_node(xhtml: &mut String, node: &Node) {
+	let element = match node {
+		Node::Text(text) => return xhtml.push_str(&escape(text)),
+		Node::Element(element) => element,
+	};
+	let name = element.name.as_str();
+
+	if DROPPED_ELEMENTS.contains(&name) || (name == "img" && element.attribute("src").is_none()) {
+		return;
+	}
+	if !KEPT_ELEMENTS.contains(&name) {
+		for child in &element.children {
+			write_node(xhtml, child);
+		}
+		return;
+	}
+
+	xhtml.push('<');
+	xhtml.push_str(name);
+	for (attribute, value) in &element.attributes {
+		let is_unsafe_link = attribute == "href" && !["http:", "https:", "mailto:"].iter().any(|scheme| value.starts_with(scheme));
+
+		if KEPT_ATTRIBUTES.contains(&(name, attribute.as_str())) && !is_unsafe_link {
+			xhtml.push_str(&format!(" {}=\"{}\"", attribute, escape(value)));
+		}
+	}
+
+	if VOID_ELEMENTS.contains(&name) {
+		xhtml.push_str("/>");
+		return;
+	}
+
+	xhtml.push('>');
+	for child in &element.children {
+		write_node(xhtml, child);
+	}
+	xhtml.push_str(&format!("</{}>", name));
+}
+
+
+/// Escapes text for use in XML content or attributes, dropping the control characters XML doesn't allow.
+pub fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\t' | '\n' | '\r' => escaped.push(c),
+			_ if c.is_control() && (c as u32) < 0x20 => (),
+			_ => escaped.push(c),
+		}
+	}
+
+	escaped
+}
+
+
+/// Decodes character references, and the named entities commonly found in prose.  Anything else is left as it is.
+pub fn decode_entities(text: &str) -> String {
+	if !text.contains('&') {
+		return text.to_owned();
+	}
+
+	let mut decoded = String::new();
+	let mut rest = text;
+
+	while let Some(start) = rest.find('&') {
+		decoded.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		let entity = rest.find(';').filter(|end| *end <= 10).map(|end| &rest[1..end]);
+		let character = entity.and_then(|entity| match entity {
+			_ if entity.starts_with("#x") || entity.starts_with("#X") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+			_ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+			_ => named_entity(entity),
+		});
+
+		match (entity, character) {
+			(Some(entity), Some(character)) => {
+				decoded.push(character);
+				rest = &rest[entity.len() + 2..];
+			}
+			_ => {
+				decoded.push('&');
+				rest = &rest[1..];
+			}
+		}
+	}
+	decoded.push_str(rest);
+
+	decoded
+}
+
+
+fn named_entity(name: &str) -> Option<char> {
+	let c = match name {
+		"amp" => '&',
+		"lt" => '<',
+		"gt" => '>',
+		"quot" => '"',
+		"apos" => '\'',
+		"nbsp" => '\u{a0}',
+		"shy" => '\u{ad}',
+		"ensp" => '\u{2002}',
+		"emsp" => '\u{2003}',
+		"thinsp" => '\u{2009}',
+		"zwnj" => '\u{200c}',
+		"zwj" => '\u{200d}',
+		"ndash" => '–',
+		"mdash" => '—',
+		"lsquo" => '‘',
+		"rsquo" => '’',
+		"sbquo" => '‚',
+		"ldquo" => '“',
+		"rdquo" => '”',
+		"bdquo" => '„',
+		"laquo" => '«',
+		"raquo" => '»',
+		"lsaquo" => '‹',
+		"rsaquo" => '›',
+		"hellip" => '…',
+		"bull" => '•',
+		"middot" => '·',
+		"dagger" => '†',
+		"Dagger" => '‡',
+		"prime" => '′',
+		"Prime" => '″',
+		"copy" => '©',
+		"reg" => '®',
+		"trade" => '™',
+		"deg" => '°',
+		"plusmn" => '±',
+		"times" => '×',
+		"divide" => '÷',
+		"minus" => '−',
+		"frac12" => '½',
+		"frac14" => '¼',
+		"frac34" => '¾',
+		"sup2" => '²',
+		"sup3" => '³',
+		"micro" => 'µ',
+		"para" => '¶',
+		"sect" => '§',
+		"cent" => '¢',
+		"pound" => '£',
+		"euro" => '€',
+		"yen" => '¥',
+		"iexcl" => '¡',
+		"iquest" => '¿',
+		"larr" => '←',
+		"rarr" => '→',
+		"uarr" => '↑',
+		"darr" => '↓',
+		"agrave" => 'à',
+		"aacute" => 'á',
+		"acirc" => 'â',
+		"auml" => 'ä',
+		"aring" => 'å',
+		"aelig" => 'æ',
+		"ccedil" => 'ç',
+		"egrave" => 'è',
+		"eacute" => 'é',
+		"ecirc" => 'ê',
+		"euml" => 'ë',
+		"iacute" => 'í',
+		"iuml" => 'ï',
+		"ntilde" => 'ñ',
+		"oacute" => 'ó',
+		"ocirc" => 'ô',
+		"ouml" => 'ö',
+		"oslash" => 'ø',
+		"uacute" => 'ú',
+		"uuml" => 'ü',
+		"szlig" => 'ß',
+		"Auml" => 'Ä',
+		"Eacute" => 'É',
+		"Ouml" => 'Ö',
+		"Uuml" => 'Ü',
+		_ => return None,
+	};
+
+	Some(c)
+}
@@ -0,0 +1,270 @@
+//! Makes PDFs of images, one image per page, e.g. for scans.
+//!
+//! JPEGs are embedded as they are, so photos don't lose any more quality; everything else is decoded and compressed losslessly.
+//! Images are scaled to fit the page (less its margins), centered, and turned the right way up if they say they're rotated.
+use anyhow::{bail, Context, Result};
+use image::{codecs::jpeg::JpegDecoder, metadata::Orientation, DynamicImage, ExtendedColorType, ImageDecoder, ImageFormat};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream};
+use std::io::Cursor;
+use tiff::{decoder::DecodingResult, tags::Tag, ColorType};
+
+
+/// The size of each page, and the space to leave around its image, in points
+#[derive(Clone, Copy, Debug)]
+pub struct PageLayout {
+	pub width: f64,
+	pub height: f64,
+	pub margin: f64,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageType {
+	Jpeg,
+	Png,
+	Tiff,
+}
+
+impl ImageType {
+	/// Recognises an image from its first bytes
+	pub fn detect(data: &[u8]) -> Option<Self> {
+		if data.starts_with(&[0xff, 0xd8, 0xff]) {
+			Some(ImageType::Jpeg)
+		} else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+			Some(ImageType::Png)
+		} else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+			Some(ImageType::Tiff)
+		} else {
+			None
+		}
+	}
+}
+
+
+/// An image ready to be embedded in a PDF
+struct PdfImage {
+	width: u32,
+	height: u32,
+	color_space: &'static str,
+	bits_per_component: u8,
+	/// The data of JPEGs is embedded as it is; everything else is raw pixels
+	is_jpeg: bool,
+	data: Vec<u8>,
+	orientation: Orientation,
+}
+
+
+/// Makes a PDF with a page for each image, in order.  Every page of a multi-page TIFF is included.
+pub fn images_to_pdf(images: &[Vec<u8>], layout: &PageLayout) -> Result<Vec<u8>> {
+	let mut pdf = Document::with_version("1.5");
+	let pages_id = pdf.new_object_id();
+	let mut kids = Vec::new();
+
+	for (i, data) in images.iter().enumerate() {
+		let decoded = match ImageType::detect(data) {
+			Some(ImageType::Jpeg) => decode_jpeg(data),
+			Some(ImageType::Png) => decode_png(data),
+			Some(ImageType::Tiff) => decode_tiff(data),
+			None => bail!("Image {} isn't a JPEG, PNG or TIFF", i + 1),
+		}
+		.with_context(|| format!("Reading image {}", i + 1))?;
+
+		for image in decoded {
+			kids.push(Object::Reference(add_page(&mut pdf, pages_id, image, layout)));
+		}
+	}
+
+	if kids.is_empty() {
+		bail!("No images");
+	}
+
+	let count = kids.len() as i64;
+	pdf.objects.insert(
+		pages_id,
+		Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }),
+	);
+	let catalog_id = pdf.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+	pdf.trailer.set("Root", catalog_id);
+	pdf.compress();
+
+	let mut output = Vec::new();
+	pdf.save_to(&mut output)?;
+	Ok(output)
+}
+
+
+fn add_page(pdf: &mut Document, pages_id: ObjectId, image: PdfImage, layout: &PageLayout) -> ObjectId {
+	let mut dict = dictionary! {
+		"Type" => "XObject",
+		"Subtype" => "Image",
+		"Width" => image.width as i64,
+		"Height" => image.height as i64,
+		"ColorSpace" => image.color_space,
+		"BitsPerComponent" => image.bits_per_component as i64,
+	};
+	if image.is_jpeg {
+		dict.set("Filter", "DCTDecode");
+	}
+	let image_id = pdf.add_object(Stream::new(dict, image.data));
+
+	// The size the image is shown at, the right way up
+	let (width, height) = match image.orientation {
+		Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH => (image.height, image.width),
+		_ => (image.width, image.height),
+	};
+	let scale = ((layout.width - 2.0 * layout.margin) / width as f64).min((layout.height - 2.0 * layout.margin) / height as f64);
+	let (width, height) = (width as f64 * scale, height as f64 * scale);
+	let (x, y) = ((layout.width - width) / 2.0, (layout.height - height) / 2.0);
+
+	// Images are drawn into a unit square; this maps it onto where the image goes on the page, turning it as it goes
+	let [a, b, c, d, e, f] = match image.orientation {
+		Orientation::NoTransforms => [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+		Orientation::Rotate90 => [0.0, -1.0, 1.0, 0.0, 0.0, 1.0],
+		Orientation::Rotate180 => [-1.0, 0.0, 0.0, -1.0, 1.0, 1.0],
+		Orientation::Rotate270 => [0.0, 1.0, -1.0, 0.0, 1.0, 0.0],
+		Orientation::FlipHorizontal => [-1.0, 0.0, 0.0, 1.0, 1.0, 0.0],
+		Orientation::FlipVertical => [1.0, 0.0, 0.0, -1.0, 0.0, 1.0],
+		Orientation::Rotate90FlipH => [0.0, -1.0, -1.0, 0.0, 1.0, 1.0],
+		Orientation::Rotate270FlipH => [0.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+	};
+	let content = format!(
+		"q {:.4} {:.4} {:.4} {:.4} {:.4} {:.4} cm /Im0 Do Q",
+		width * a,
+		height * b,
+		width * c,
+		height * d,
+		width * e + x,
+		height * f + y
+	);
+	let content_id = pdf.add_object(Stream::new(dictionary! {}, content.into_bytes()));
+
+	pdf.add_object(dictionary! {
+		"Type" => "Page",
+		"Parent" => pages_id,
+		"MediaBox" => vec![0.into(), 0.into(), Object::Real(layout.width as f32), Object::Real(layout.height as f32)],
+		"Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } },
+		"Contents" => content_id,
+	})
+}
+
+
+fn decode_jpeg(data: &[u8]) -> Result<Vec<PdfImage>> {
+	let mut decoder = JpegDecoder::new(Cursor::new(data))?;
+	let (width, height) = decoder.dimensions();
+	let orientation = decoder.orientation()?;
+
+	let color_space = match decoder.original_color_type() {
+		ExtendedColorType::L8 => "DeviceGray",
+		ExtendedColorType::Rgb8 => "DeviceRGB",
+		// e.g. CMYK, which PDF viewers disagree on how to read
+		_ => {
+			let mut image = pixels(DynamicImage::from_decoder(decoder)?);
+			image.orientation = orientation;
+			return Ok(vec![image]);
+		}
+	};
+
+	Ok(vec![PdfImage {
+		width,
+		height,
+		color_space,
+		bits_per_component: 8,
+		is_jpeg: true,
+		data: data.to_vec(),
+		orientation,
+	}])
+}
+
+
+fn decode_png(data: &[u8]) -> Result<Vec<PdfImage>> {
+	Ok(vec![pixels(image::load_from_memory_with_format(data, ImageFormat::Png)?)])
+}
+
+
+/// Each page of a TIFF.  Black and white scans stay one bit per pixel.
+fn decode_tiff(data: &[u8]) -> Result<Vec<PdfImage>> {
+	let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data))?;
+	let mut images = Vec::new();
+
+	loop {
+		let (width, height) = decoder.dimensions()?;
+		let color_type = decoder.colortype()?;
+		let orientation = decoder
+			.find_tag_unsigned::<u8>(Tag::Orientation)?
+			.and_then(Orientation::from_exif)
+			.unwrap_or(Orientation::NoTransforms);
+		if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2) {
+			bail!("Planar TIFFs aren't supported");
+		}
+
+		// 16 bit samples are cut down to 8 bits
+		let samples = match decoder.read_image()? {
+			DecodingResult::U8(samples) => samples,
+			DecodingResult::U16(samples) => samples.into_iter().map(|sample| (sample >> 8) as u8).collect(),
+			_ => bail!("Unsupported TIFF sample format"),
+		};
+
+		let (color_space, bits_per_component, data) = match color_type {
+			ColorType::Gray(1) => ("DeviceGray", 1, samples),
+			ColorType::Gray(8) | ColorType::Gray(16) => ("DeviceGray", 8, samples),
+			ColorType::RGB(8) | ColorType::RGB(16) => ("DeviceRGB", 8, samples),
+			ColorType::CMYK(8) | ColorType::CMYK(16) => ("DeviceCMYK", 8, samples),
+			ColorType::GrayA(8) | ColorType::GrayA(16) => ("DeviceGray", 8, flatten_alpha(&samples, 1)),
+			ColorType::RGBA(8) | ColorType::RGBA(16) => ("DeviceRGB", 8, flatten_alpha(&samples, 3)),
+			other => bail!("Unsupported TIFF color type {:?}", other),
+		};
+
+		images.push(PdfImage {
+			width,
+			height,
+			color_space,
+			bits_per_component,
+			is_jpeg: false,
+			data,
+			orientation,
+		});
+
+		if !decoder.more_images() {
+			break;
+		}
+		decoder.next_image()?;
+	}
+
+	Ok(images)
+}
+
+
+/// The raw pixels of a decoded image; transparent images are put on a white background.
+fn pixels(image: DynamicImage) -> PdfImage {
+	let (width, height) = (image.width(), image.height());
+	let (color_space, data) = match (image.color().has_color(), image.color().has_alpha()) {
+		(false, false) => ("DeviceGray", image.into_luma8().into_raw()),
+		(false, true) => ("DeviceGray", flatten_alpha(&image.into_luma_alpha8().into_raw(), 1)),
+		(true, false) => ("DeviceRGB", image.into_rgb8().into_raw()),
+		(true, true) => ("DeviceRGB", flatten_alpha(&image.into_rgba8().into_raw(), 3)),
+	};
+
+	PdfImage {
+		width,
+		height,
+		color_space,
+		bits_per_component: 8,
+		is_jpeg: false,
+		data,
+		orientation: Orientation::NoTransforms,
+	}
+}
+
+
+/// Blends pixels with an alpha channel onto white, dropping the alpha channel
+fn flatten_alpha(samples: &[u8], channels: usize) -> Vec<u8> {
+	samples
+		.chunks_exact(channels + 1)
+		.flat_map(|pixel| {
+			let alpha = pixel[channels] as u32;
+			pixel[..channels]
+				.iter()
+				.map(move |&value| ((value as u32 * alpha + 255 * (255 - alpha)) / 255) as u8)
+		})
+		.collect()
+}
@@ -0,0 +1,908 @@
+//! Parser for the tablet's `.rm` stroke files.
+//!
+//! Versions 3 and 5 are a simple list of layers, each a list of strokes, each a list of points.
+//! Version 6 (firmware 3.x) is a sequence of blocks describing a scene tree (layers are groups under the root), with strokes and
+//! highlights as items in those groups, plus the page's typed text.  Items are CRDTs, so edits are appended as new blocks
+//! referencing earlier items by ID.
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use std::{
+	collections::{HashMap, HashSet},
+	convert::TryInto,
+	f32::consts::PI,
+};
+
+
+const HEADER_PREFIX: &[u8] = b"reMarkable .lines file, version=";
+const HEADER_LEN: usize = 43;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pen {
+	Brush,
+	Pencil,
+	Ballpoint,
+	Marker,
+	Fineliner,
+	Highlighter,
+	Eraser,
+	MechanicalPencil,
+	EraseArea,
+	Calligraphy,
+	Shader,
+	Unknown(u32),
+}
+
+impl Pen {
+	fn from_id(id: u32) -> Self {
+		// Newer firmware added a second set of IDs for the same tools
+		match id {
+			0 | 12 => Pen::Brush,
+			1 | 14 => Pen::Pencil,
+			2 | 15 => Pen::Ballpoint,
+			3 | 16 => Pen::Marker,
+			4 | 17 => Pen::Fineliner,
+			5 | 18 => Pen::Highlighter,
+			6 => Pen::Eraser,
+			7 | 13 => Pen::MechanicalPencil,
+			8 => Pen::EraseArea,
+			21 => Pen::Calligraphy,
+			23 => Pen::Shader,
+			_ => Pen::Unknown(id),
+		}
+	}
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Color {
+	Black,
+	Grey,
+	White,
+	Yellow,
+	Green,
+	Pink,
+	Blue,
+	Red,
+	GreyOverlap,
+	/// The actual colour is given by the stroke's ARGB value
+	Highlight,
+	Green2,
+	Cyan,
+	Magenta,
+	Yellow2,
+	Unknown(u32),
+}
+
+impl Color {
+	fn from_id(id: u32) -> Self {
+		match id {
+			0 => Color::Black,
+			1 => Color::Grey,
+			2 => Color::White,
+			3 => Color::Yellow,
+			4 => Color::Green,
+			5 => Color::Pink,
+			6 => Color::Blue,
+			7 => Color::Red,
+			8 => Color::GreyOverlap,
+			9 => Color::Highlight,
+			10 => Color::Green2,
+			11 => Color::Cyan,
+			12 => Color::Magenta,
+			13 => Color::Yellow2,
+			_ => Color::Unknown(id),
+		}
+	}
+}
+
+
+/// A point, in the units used by version 5 files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+	pub x: f32,
+	pub y: f32,
+	pub speed: f32,
+	/// Tilt, in radians
+	pub direction: f32,
+	pub width: f32,
+	/// 0 to 1
+	pub pressure: f32,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+	pub pen: Pen,
+	pub color: Color,
+	/// Base width of the stroke (the tablet's thickness setting)
+	pub thickness: f32,
+	pub points: Vec<Point>,
+	/// Colour for Color::Highlight strokes
+	pub argb: Option<u32>,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+	pub x: f64,
+	pub y: f64,
+	pub w: f64,
+	pub h: f64,
+}
+
+
+/// A text highlight on a PDF or EPUB page (version 6)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Highlight {
+	pub color: Color,
+	pub text: String,
+	/// Character offset into the page's text, if known
+	pub start: Option<u32>,
+	pub rectangles: Vec<Rect>,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+	/// Only version 6 files name their layers; older versions keep names in the page's `-metadata.json`
+	pub name: Option<String>,
+	pub visible: bool,
+	pub lines: Vec<Line>,
+	pub highlights: Vec<Highlight>,
+}
+
+impl Layer {
+	fn new(name: Option<String>) -> Self {
+		Layer {
+			name,
+			visible: true,
+			lines: Vec::new(),
+			highlights: Vec::new(),
+		}
+	}
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParagraphStyle {
+	Basic,
+	Plain,
+	Heading,
+	Bold,
+	Bullet,
+	Bullet2,
+	Checkbox,
+	CheckboxChecked,
+}
+
+impl ParagraphStyle {
+	fn from_id(id: u8) -> Self {
+		match id {
+			1 => ParagraphStyle::Plain,
+			2 => ParagraphStyle::Heading,
+			3 => ParagraphStyle::Bold,
+			4 => ParagraphStyle::Bullet,
+			5 => ParagraphStyle::Bullet2,
+			6 => ParagraphStyle::Checkbox,
+			7 => ParagraphStyle::CheckboxChecked,
+			_ => ParagraphStyle::Basic,
+		}
+	}
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paragraph {
+	pub style: ParagraphStyle,
+	pub text: String,
+}
+
+
+/// Typed text on a page (version 6)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+	pub x: f64,
+	pub y: f64,
+	pub width: f32,
+	pub paragraphs: Vec<Paragraph>,
+}
+
+
+/// A parsed `.rm` file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Lines {
+	pub version: u32,
+	pub layers: Vec<Layer>,
+	pub text: Option<Text>,
+}
+
+impl Lines {
+	pub fn parse(data: &[u8]) -> Result<Self> {
+		if data.len() < HEADER_LEN || !data.starts_with(HEADER_PREFIX) {
+			bail!("Not a .rm file");
+		}
+
+		let version = match data[HEADER_PREFIX.len()] {
+			b'3' => 3,
+			b'5' => 5,
+			b'6' => 6,
+			_ => bail!("Unsupported .rm version: {}", String::from_utf8_lossy(&data[..HEADER_LEN]).trim()),
+		};
+
+		let mut reader = Reader::new(&data[HEADER_LEN..]);
+
+		if version == 6 {
+			parse_v6(&mut reader)
+		} else {
+			parse_v5(&mut reader, version)
+		}
+	}
+
+	pub fn visible_lines(&self) -> impl Iterator<Item = &Line> {
+		self.layers.iter().filter(|layer| layer.visible).flat_map(|layer| layer.lines.iter())
+	}
+}
+
+
+fn parse_v5(reader: &mut Reader, version: u32) -> Result<Lines> {
+	let mut layers = Vec::new();
+
+	for _ in 0..reader.u32()? {
+		let mut layer = Layer::new(None);
+
+		for _ in 0..reader.u32()? {
+			let pen = Pen::from_id(reader.u32()?);
+			let color = Color::from_id(reader.u32()?);
+			reader.u32()?;
+			let thickness = reader.f32()?;
+			if version >= 5 {
+				reader.u32()?;
+			}
+
+			let mut points = Vec::new();
+
+			for _ in 0..reader.u32()? {
+				points.push(Point {
+					x: reader.f32()?,
+					y: reader.f32()?,
+					speed: reader.f32()?,
+					direction: reader.f32()?,
+					width: reader.f32()?,
+					pressure: reader.f32()?,
+				});
+			}
+
+			layer.lines.push(Line {
+				pen,
+				color,
+				thickness,
+				points,
+				argb: None,
+			});
+		}
+
+		layers.push(layer);
+	}
+
+	Ok(Lines { version, layers, text: None })
+}
+
+
+/// Item IDs in version 6 files: (author, counter)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CrdtId(pub u8, pub u64);
+
+/// The scene's root group; layers are its children
+const ROOT_ID: CrdtId = CrdtId(0, 1);
+/// Marks the start (or end) of a CRDT sequence
+const END_ID: CrdtId = CrdtId(0, 0);
+/// The most characters (including deleted ones) a page's text can have, so that a bad length can't exhaust memory
+const MAX_TEXT_LENGTH: usize = 1024 * 1024;
+
+const BLOCK_SCENE_TREE: u8 = 0x01;
+const BLOCK_TREE_NODE: u8 = 0x02;
+const BLOCK_GLYPH_ITEM: u8 = 0x03;
+const BLOCK_GROUP_ITEM: u8 = 0x04;
+const BLOCK_LINE_ITEM: u8 = 0x05;
+const BLOCK_ROOT_TEXT: u8 = 0x07;
+const BLOCK_TOMBSTONE_ITEM: u8 = 0x08;
+
+const TAG_BYTE1: u8 = 0x1;
+const TAG_BYTE4: u8 = 0x4;
+const TAG_BYTE8: u8 = 0x8;
+const TAG_LENGTH4: u8 = 0xC;
+const TAG_ID: u8 = 0xF;
+
+
+/// An item in one of the scene's groups
+struct SceneItem<T> {
+	parent: CrdtId,
+	id: CrdtId,
+	/// None if the item has been deleted
+	value: Option<T>,
+}
+
+
+#[derive(Default)]
+struct Scene {
+	/// Node -> parent node
+	parents: HashMap<CrdtId, CrdtId>,
+	/// Children of the root, in the order we saw them
+	layers: Vec<CrdtId>,
+	/// Node -> (label, visible)
+	nodes: HashMap<CrdtId, (Option<String>, bool)>,
+	lines: Vec<SceneItem<Line>>,
+	highlights: Vec<SceneItem<Highlight>>,
+	tombstones: HashSet<CrdtId>,
+	text: Option<Text>,
+}
+
+impl Scene {
+	fn add_child(&mut self, node: CrdtId, parent: CrdtId) {
+		self.parents.insert(node, parent);
+
+		if parent == ROOT_ID && !self.layers.contains(&node) {
+			self.layers.push(node);
+		}
+	}
+
+	/// The layer a node belongs to, by walking up the tree
+	fn layer_of(&self, node: CrdtId) -> Option<CrdtId> {
+		let mut current = node;
+
+		for _ in 0..=self.parents.len() {
+			if self.layers.contains(&current) {
+				return Some(current);
+			}
+
+			current = *self.parents.get(&current)?;
+		}
+
+		None
+	}
+
+	fn into_lines(self) -> Lines {
+		let mut layers: Vec<Layer> = self
+			.layers
+			.iter()
+			.map(|id| {
+				let (name, visible) = self.nodes.get(id).cloned().unwrap_or((None, true));
+				Layer { visible, ..Layer::new(name) }
+			})
+			.collect();
+
+		// Items without a layer go into a layer of their own
+		let mut orphans = Layer::new(None);
+
+		// Later blocks for the same item replace earlier ones
+		let mut seen = HashSet::new();
+		let mut lines: Vec<_> = self.lines.iter().rev().filter(|item| seen.insert(item.id)).collect();
+		lines.reverse();
+		seen.clear();
+		let mut highlights: Vec<_> = self.highlights.iter().rev().filter(|item| seen.insert(item.id)).collect();
+		highlights.reverse();
+
+		for item in lines {
+			if let (Some(line), false) = (&item.value, self.tombstones.contains(&item.id)) {
+				match self.layer_of(item.parent).and_then(|layer| self.layers.iter().position(|x| *x == layer)) {
+					Some(i) => layers[i].lines.push(line.clone()),
+					None => orphans.lines.push(line.clone()),
+				}
+			}
+		}
+
+		for item in highlights {
+			if let (Some(highlight), false) = (&item.value, self.tombstones.contains(&item.id)) {
+				match self.layer_of(item.parent).and_then(|layer| self.layers.iter().position(|x| *x == layer)) {
+					Some(i) => layers[i].highlights.push(highlight.clone()),
+					None => orphans.highlights.push(highlight.clone()),
+				}
+			}
+		}
+
+		if layers.is_empty() || !orphans.lines.is_empty() || !orphans.highlights.is_empty() {
+			layers.push(orphans);
+		}
+
+		Lines {
+			version: 6,
+			layers,
+			text: self.text,
+		}
+	}
+}
+
+
+fn parse_v6(reader: &mut Reader) -> Result<Lines> {
+	let mut scene = Scene::default();
+
+	while reader.remaining() > 0 {
+		let length = reader.u32()? as usize;
+		reader.u8()?;
+		let _min_version = reader.u8()?;
+		let current_version = reader.u8()?;
+		let block_type = reader.u8()?;
+		let mut block = Reader::new(reader.bytes(length)?);
+
+		// A block we can't make sense of shouldn't stop us from rendering the rest of the page
+		if let Err(err) = parse_block(&mut block, block_type, current_version, &mut scene) {
+			debug!("Skipping malformed .rm block of type {}: {:?}", block_type, err);
+		}
+	}
+
+	Ok(scene.into_lines())
+}
+
+
+fn parse_block(reader: &mut Reader, block_type: u8, version: u8, scene: &mut Scene) -> Result<()> {
+	match block_type {
+		BLOCK_SCENE_TREE => {
+			let node = reader.tagged_id(1)?;
+			reader.tagged_id(2)?;
+			reader.tagged_bool(3)?;
+			let parent = reader.subblock(4, |r| r.tagged_id(1))?;
+			scene.add_child(node, parent);
+		}
+		BLOCK_TREE_NODE => {
+			let node = reader.tagged_id(1)?;
+			let label = reader.lww(2, |r| r.tagged_string(2))?;
+			let visible = reader.lww(3, |r| r.tagged_bool(2))?;
+			scene.nodes.insert(node, (Some(label), visible));
+		}
+		BLOCK_GROUP_ITEM => {
+			let item = read_scene_item(reader, |r| r.tagged_id(2))?;
+			if let Some(node) = item.value {
+				scene.add_child(node, item.parent);
+			}
+		}
+		BLOCK_LINE_ITEM => scene.lines.push(read_scene_item(reader, |r| read_line(r, version))?),
+		BLOCK_GLYPH_ITEM => scene.highlights.push(read_scene_item(reader, read_highlight)?),
+		BLOCK_TOMBSTONE_ITEM => {
+			reader.tagged_id(1)?;
+			scene.tombstones.insert(reader.tagged_id(2)?);
+		}
+		BLOCK_ROOT_TEXT => scene.text = Some(read_text(reader)?),
+		_ => (),
+	}
+
+	Ok(())
+}
+
+
+fn read_scene_item<T>(reader: &mut Reader, read_value: impl FnOnce(&mut Reader) -> Result<T>) -> Result<SceneItem<T>> {
+	let parent = reader.tagged_id(1)?;
+	let id = reader.tagged_id(2)?;
+	reader.tagged_id(3)?;
+	reader.tagged_id(4)?;
+	reader.tagged_u32(5)?;
+
+	let value = if reader.has_tag(6, TAG_LENGTH4) {
+		Some(reader.subblock(6, |r| {
+			let _item_type = r.u8()?;
+			read_value(r)
+		})?)
+	} else {
+		None
+	};
+
+	Ok(SceneItem { parent, id, value })
+}
+
+
+fn read_line(reader: &mut Reader, version: u8) -> Result<Line> {
+	let pen = Pen::from_id(reader.tagged_u32(1)?);
+	let color = Color::from_id(reader.tagged_u32(2)?);
+	let thickness = reader.tagged_f64(3)? as f32;
+	reader.tagged_f32(4)?;
+
+	let points = reader.subblock(5, |r| {
+		let mut points = Vec::new();
+
+		while r.remaining() > 0 {
+			points.push(if version == 1 {
+				Point {
+					x: r.f32()?,
+					y: r.f32()?,
+					speed: r.f32()?,
+					direction: r.f32()?,
+					width: r.f32()?,
+					pressure: r.f32()?,
+				}
+			} else {
+				// Version 2 packs everything but the position into integers
+				Point {
+					x: r.f32()?,
+					y: r.f32()?,
+					speed: r.u16()? as f32 / 4.0,
+					width: r.u16()? as f32 / 4.0,
+					direction: r.u8()? as f32 * 2.0 * PI / 255.0,
+					pressure: r.u8()? as f32 / 255.0,
+				}
+			});
+		}
+
+		Ok(points)
+	})?;
+
+	// The rest (timestamp, move ID) we don't need, except for the colour of highlighter strokes
+	let mut argb = None;
+
+	while let Some((index, tag_type)) = reader.read_tag()? {
+		if index == 8 && tag_type == TAG_BYTE4 {
+			argb = Some(reader.u32()?);
+		} else {
+			reader.skip_value(tag_type)?;
+		}
+	}
+
+	Ok(Line {
+		pen,
+		color,
+		thickness,
+		points,
+		argb,
+	})
+}
+
+
+fn read_highlight(reader: &mut Reader) -> Result<Highlight> {
+	let start = if reader.has_tag(2, TAG_BYTE4) {
+		Some(reader.tagged_u32(2)?)
+	} else {
+		None
+	};
+	if reader.has_tag(3, TAG_BYTE4) {
+		reader.tagged_u32(3)?;
+	}
+	let color = Color::from_id(reader.tagged_u32(4)?);
+	let text = reader.tagged_string(5)?;
+	let rectangles = reader.subblock(6, |r| {
+		let mut rectangles = Vec::new();
+
+		for _ in 0..r.varuint()? {
+			rectangles.push(Rect {
+				x: r.f64()?,
+				y: r.f64()?,
+				w: r.f64()?,
+				h: r.f64()?,
+			});
+		}
+
+		Ok(rectangles)
+	})?;
+
+	Ok(Highlight {
+		color,
+		text,
+		start,
+		rectangles,
+	})
+}
+
+
+/// A run of characters in the text's CRDT sequence
+struct TextItem {
+	id: CrdtId,
+	left: CrdtId,
+	deleted_length: u32,
+	/// None for formatting markers, which take up an ID but aren't text
+	text: Option<String>,
+}
+
+
+fn read_text(reader: &mut Reader) -> Result<Text> {
+	reader.tagged_id(1)?;
+
+	let (items, styles) = reader.subblock(2, |r| {
+		let items = r.subblock(1, |r| {
+			r.subblock(1, |r| {
+				let mut items = Vec::new();
+
+				// Every item takes up at least one character
+				let count = r.varuint()?;
+				if count > MAX_TEXT_LENGTH as u64 {
+					bail!("Text has more than {} items", MAX_TEXT_LENGTH);
+				}
+
+				for _ in 0..count {
+					items.push(r.subblock(0, |r| {
+						let id = r.tagged_id(2)?;
+						let left = r.tagged_id(3)?;
+						r.tagged_id(4)?;
+						let deleted_length = r.tagged_u32(5)?;
+						let text = if r.has_tag(6, TAG_LENGTH4) {
+							r.subblock(6, |r| {
+								let text = r.string()?;
+								// Formatting markers have an empty string and a format code
+								Ok(if text.is_empty() && r.has_tag(2, TAG_BYTE4) { None } else { Some(text) })
+							})?
+						} else {
+							None
+						};
+
+						Ok(TextItem {
+							id,
+							left,
+							deleted_length,
+							text,
+						})
+					})?);
+				}
+
+				Ok(items)
+			})
+		})?;
+
+		let styles = r.subblock(2, |r| {
+			r.subblock(1, |r| {
+				let mut styles = HashMap::new();
+
+				for _ in 0..r.varuint()? {
+					let char_id = r.id()?;
+					r.tagged_id(1)?;
+					let style = r.subblock(2, |r| {
+						r.u8()?;
+						r.u8()
+					})?;
+					styles.insert(char_id, ParagraphStyle::from_id(style));
+				}
+
+				Ok(styles)
+			})
+		})?;
+
+		Ok((items, styles))
+	})?;
+
+	let (x, y) = reader.subblock(3, |r| Ok((r.f64()?, r.f64()?)))?;
+	let width = reader.tagged_f32(4)?;
+
+	Ok(Text {
+		x,
+		y,
+		width,
+		paragraphs: build_paragraphs(&items, &styles)?,
+	})
+}
+
+
+/// Puts the text's characters in order and splits them into paragraphs.
+/// Each item is inserted after its left neighbour (or at the end, if that isn't there); items inserted later at the same spot
+/// come first, as the tablet does.
+fn build_paragraphs(items: &[TextItem], styles: &HashMap<CrdtId, ParagraphStyle>) -> Result<Vec<Paragraph>> {
+	// The characters are a linked list, from END_ID (standing in for the start) through each one's successor, so that inserting
+	// a run doesn't have to search for its neighbour or shift what comes after it
+	let mut chars: HashMap<CrdtId, Option<char>> = HashMap::new();
+	let mut next: HashMap<CrdtId, CrdtId> = HashMap::new();
+	let mut last = END_ID;
+
+	for item in items {
+		let left = if item.left == END_ID || chars.contains_key(&item.left) {
+			item.left
+		} else {
+			last
+		};
+
+		// Deleted runs only store their length, so check it before making room for them
+		let length = match &item.text {
+			Some(text) => text.chars().count(),
+			None => item.deleted_length.max(1) as usize,
+		};
+		if chars.len() + length > MAX_TEXT_LENGTH {
+			bail!("Text is longer than {} characters", MAX_TEXT_LENGTH);
+		}
+
+		let after = next.get(&left).copied();
+		let mut previous = left;
+		let run = (0..length).zip(item.text.iter().flat_map(|text| text.chars().map(Some)).chain(std::iter::repeat(None)));
+		for (i, c) in run {
+			let counter = item.id.1.checked_add(i as u64).ok_or_else(|| anyhow!("Text item ID out of range"))?;
+			let id = CrdtId(item.id.0, counter);
+			// A repeated ID would tie the list in a loop
+			if id == END_ID || chars.insert(id, if item.deleted_length == 0 { c } else { None }).is_some() {
+				bail!("Text item ID {:?} is used more than once", id);
+			}
+			next.insert(previous, id);
+			previous = id;
+		}
+		match after {
+			Some(after) => {
+				next.insert(previous, after);
+			}
+			None => last = previous,
+		}
+	}
+
+	let mut paragraphs = vec![Paragraph {
+		style: styles.get(&END_ID).copied().unwrap_or(ParagraphStyle::Plain),
+		text: String::new(),
+	}];
+
+	let mut current = END_ID;
+	while let Some(&id) = next.get(&current) {
+		current = id;
+		match chars[&id] {
+			Some('\n') => paragraphs.push(Paragraph {
+				style: styles.get(&id).copied().unwrap_or(ParagraphStyle::Plain),
+				text: String::new(),
+			}),
+			Some(c) => paragraphs.last_mut().expect("unexpected").text.push(c),
+			None => (),
+		}
+	}
+
+	Ok(paragraphs)
+}
+
+
+/// Reads the little-endian, tagged encoding used by version 6 files.
+/// Tags are a varuint of (index << 4 | type); subblocks are length-prefixed and limit reads to their contents.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	end: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn new(data: &'a [u8]) -> Self {
+		Reader {
+			data,
+			pos: 0,
+			end: data.len(),
+		}
+	}
+
+	fn remaining(&self) -> usize {
+		self.end.saturating_sub(self.pos)
+	}
+
+	fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+		if n > self.remaining() {
+			bail!("Unexpected end of data");
+		}
+
+		let bytes = &self.data[self.pos..self.pos + n];
+		self.pos += n;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+	}
+
+	fn f32(&mut self) -> Result<f32> {
+		Ok(f32::from_le_bytes(self.bytes(4)?.try_into()?))
+	}
+
+	fn f64(&mut self) -> Result<f64> {
+		Ok(f64::from_le_bytes(self.bytes(8)?.try_into()?))
+	}
+
+	fn varuint(&mut self) -> Result<u64> {
+		let mut result = 0u64;
+
+		for shift in (0..64).step_by(7) {
+			let byte = self.u8()?;
+			result |= ((byte & 0x7f) as u64) << shift;
+
+			if byte & 0x80 == 0 {
+				return Ok(result);
+			}
+		}
+
+		bail!("Varuint too long")
+	}
+
+	fn id(&mut self) -> Result<CrdtId> {
+		Ok(CrdtId(self.u8()?, self.varuint()?))
+	}
+
+	fn string(&mut self) -> Result<String> {
+		let length = self.varuint()? as usize;
+		let _is_ascii = self.u8()?;
+		Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+	}
+
+	/// The next tag, or None at the end of the block
+	fn read_tag(&mut self) -> Result<Option<(u64, u8)>> {
+		if self.remaining() == 0 {
+			return Ok(None);
+		}
+
+		let tag = self.varuint()?;
+		Ok(Some((tag >> 4, (tag & 0xf) as u8)))
+	}
+
+	fn has_tag(&self, index: u64, tag_type: u8) -> bool {
+		let mut peek = *self;
+		matches!(peek.read_tag(), Ok(Some(tag)) if tag == (index, tag_type))
+	}
+
+	fn expect_tag(&mut self, index: u64, tag_type: u8) -> Result<()> {
+		match self.read_tag()? {
+			Some(tag) if tag == (index, tag_type) => Ok(()),
+			tag => bail!("Expected tag {} of type {:x}, found {:?}", index, tag_type, tag),
+		}
+	}
+
+	fn skip_value(&mut self, tag_type: u8) -> Result<()> {
+		match tag_type {
+			TAG_ID => drop(self.id()?),
+			TAG_BYTE1 => drop(self.bytes(1)?),
+			TAG_BYTE4 => drop(self.bytes(4)?),
+			TAG_BYTE8 => drop(self.bytes(8)?),
+			TAG_LENGTH4 => {
+				let length = self.u32()? as usize;
+				self.bytes(length)?;
+			}
+			_ => bail!("Unknown tag type {:x}", tag_type),
+		}
+
+		Ok(())
+	}
+
+	fn tagged_id(&mut self, index: u64) -> Result<CrdtId> {
+		self.expect_tag(index, TAG_ID)?;
+		self.id()
+	}
+
+	fn tagged_bool(&mut self, index: u64) -> Result<bool> {
+		self.expect_tag(index, TAG_BYTE1)?;
+		Ok(self.u8()? != 0)
+	}
+
+	fn tagged_u32(&mut self, index: u64) -> Result<u32> {
+		self.expect_tag(index, TAG_BYTE4)?;
+		self.u32()
+	}
+
+	fn tagged_f32(&mut self, index: u64) -> Result<f32> {
+		self.expect_tag(index, TAG_BYTE4)?;
+		self.f32()
+	}
+
+	fn tagged_f64(&mut self, index: u64) -> Result<f64> {
+		self.expect_tag(index, TAG_BYTE8)?;
+		self.f64()
+	}
+
+	fn tagged_string(&mut self, index: u64) -> Result<String> {
+		self.subblock(index, |r| r.string())
+	}
+
+	/// A last-writer-wins value: a timestamp followed by the value
+	fn lww<T>(&mut self, index: u64, read_value: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+		self.subblock(index, |r| {
+			r.tagged_id(1)?;
+			read_value(r)
+		})
+	}
+
+	/// Reads a subblock with `read_contents`, which can't read past its end.  Anything it leaves unread is skipped.
+	fn subblock<T>(&mut self, index: u64, read_contents: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+		self.expect_tag(index, TAG_LENGTH4)?;
+		let length = self.u32()? as usize;
+
+		if length > self.remaining() {
+			bail!("Subblock longer than its parent");
+		}
+
+		let outer_end = self.end;
+		self.end = self.pos + length;
+		let result = read_contents(self);
+		self.pos = self.end;
+		self.end = outer_end;
+
+		result
+	}
+}
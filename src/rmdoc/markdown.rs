@@ -0,0 +1,153 @@
+//! Exports what's been typed and highlighted in a document as Markdown, with a section for each page.
+//!
+//! Typed text comes from version 6 `.rm` files.  Highlights come from those too, or on older firmware from the
+//! `{id}.highlights/{page}.json` files alongside them.
+use super::{
+	lines::{Lines, Paragraph, ParagraphStyle},
+	RmDocument,
+};
+use log::debug;
+use serde::Deserialize;
+
+
+/// Highlights as older firmware stores them: a list per layer
+#[derive(Deserialize)]
+struct HighlightsFile {
+	highlights: Vec<Vec<HighlightEntry>>,
+}
+
+#[derive(Deserialize)]
+struct HighlightEntry {
+	text: String,
+	start: Option<u32>,
+}
+
+
+/// The document's typed text and highlights, under its title.  Each page that has any gets a section, starting with a `page-N`
+/// anchor (counting from 1, as the tablet numbers pages) so it can be linked to.
+pub fn export_markdown(document: &RmDocument, title: &str) -> String {
+	let mut markdown = format!(
+		"# {}
+",
+		escape(title)
+	);
+
+	for (index, page) in document.pages.iter().enumerate() {
+		let lines = page.lines.as_deref().and_then(|data| {
+			Lines::parse(data)
+				.map_err(|err| debug!("Unable to parse page {} of {} for Markdown export: {:?}", index, document.id, err))
+				.ok()
+		});
+
+		let mut section = String::new();
+
+		if let Some(text) = lines.as_ref().and_then(|lines| lines.text.as_ref()) {
+			section.push_str(&paragraphs_to_markdown(&text.paragraphs));
+		}
+
+		for highlight in page_highlights(document, index, lines.as_ref()) {
+			section.push_str(&format!("> {}\n\n", escape(&highlight).replace('\n', "\n> ")));
+		}
+
+		if !section.is_empty() {
+			markdown.push_str(&format!("\n<a id=\"page-{0}\"></a>\n\n## Page {0}\n\n{1}", index + 1, section.trim_end()));
+			markdown.push('\n');
+		}
+	}
+
+	markdown
+}
+
+
+fn paragraphs_to_markdown(paragraphs: &[Paragraph]) -> String {
+	let mut markdown = String::new();
+	let mut in_list = false;
+
+	for paragraph in paragraphs {
+		let text = escape(paragraph.text.trim());
+		if text.is_empty() {
+			continue;
+		}
+
+		let (line, is_list_item) = match paragraph.style {
+			ParagraphStyle::Heading => (format!("### {}", text), false),
+			ParagraphStyle::Bold => (format!("**{}**", text), false),
+			ParagraphStyle::Bullet => (format!("- {}", text), true),
+			ParagraphStyle::Bullet2 => (format!("  - {}", text), true),
+			ParagraphStyle::Checkbox => (format!("- [ ] {}", text), true),
+			ParagraphStyle::CheckboxChecked => (format!("- [x] {}", text), true),
+			ParagraphStyle::Basic | ParagraphStyle::Plain => (text, false),
+		};
+
+		// Items of the same list go on consecutive lines; everything else is separated by a blank line
+		if in_list && !is_list_item {
+			markdown.push('\n');
+		}
+		markdown.push_str(&line);
+		markdown.push_str(if is_list_item { "\n" } else { "\n\n" });
+		in_list = is_list_item;
+	}
+
+	if in_list {
+		markdown.push('\n');
+	}
+
+	markdown
+}
+
+
+/// The text of a page's highlights, in the order they appear on the page where that's known
+fn page_highlights(document: &RmDocument, index: usize, lines: Option<&Lines>) -> Vec<String> {
+	let mut highlights: Vec<(Option<u32>, String)> = lines
+		.into_iter()
+		.flat_map(|lines| &lines.layers)
+		.flat_map(|layer| &layer.highlights)
+		.map(|highlight| (highlight.start, highlight.text.clone()))
+		.collect();
+
+	let path = format!("{}.highlights/{}.json", document.id, document.pages[index].id);
+	if let Some(data) = document.other_files.get(&path) {
+		match serde_json::from_slice::<HighlightsFile>(data) {
+			Ok(file) => highlights.extend(file.highlights.into_iter().flatten().map(|entry| (entry.start, entry.text))),
+			Err(err) => debug!("Invalid highlights in {}: {:?}", path, err),
+		}
+	}
+
+	// Highlights without an offset stay where they are, after the rest
+	highlights.sort_by_key(|(start, _)| start.unwrap_or(u32::MAX));
+	highlights.dedup();
+
+	highlights
+		.into_iter()
+		.map(|(_, text)| text.trim().to_owned())
+		.filter(|text| !text.is_empty())
+		.collect()
+}
+
+
+/// Escapes the characters Markdown would otherwise treat as formatting
+fn escape(text: &str) -> String {
+	text.split('\n')
+		.map(|line| {
+			let mut escaped = String::with_capacity(line.len());
+			for c in line.trim_end().chars() {
+				if "\\`*_[]<>#|~".contains(c) {
+					escaped.push('\\');
+				}
+				escaped.push(c);
+			}
+
+			// Lines that would start a list
+			if escaped.starts_with("- ") || escaped.starts_with("+ ") {
+				escaped.insert(0, '\\');
+			} else if let Some((number, rest)) = escaped.split_once(". ") {
+				if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+					escaped = format!("{}\\. {}", number, rest);
+				}
+			}
+
+			escaped
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
@@ -0,0 +1,652 @@
+//! The tablet's document archive format.
+//!
+//! A document is uploaded as a zip containing `{id}.content` (JSON describing the document and its pages), `{id}.pagedata` (one
+//! template name per page), optionally `{id}.metadata`, `{id}.pdf` or `{id}.epub` for imported documents, and a directory `{id}/`
+//! with each page's strokes (`{page}.rm`) and layer names (`{page}-metadata.json`).
+//! Pages are named by their ID in the `.content`, or on older firmware by their index.
+use crate::database::{self, DbFileMetadata};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::SqlitePool;
+use std::{
+	collections::BTreeMap,
+	convert::TryFrom,
+	io::{Cursor, Read, Write},
+};
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+pub mod article;
+pub mod convert;
+pub mod diff;
+pub mod document_info;
+pub mod epub;
+pub mod html;
+pub mod images;
+pub mod lines;
+pub mod markdown;
+pub mod pages;
+pub mod pdf;
+pub mod planner;
+pub mod raster;
+pub mod style;
+pub mod svg;
+pub mod text;
+pub mod validate;
+
+
+/// The most a document archive may unpack to when it's loaded into memory
+pub(crate) const MAX_UNPACKED_SIZE: u64 = 1024 * 1024 * 1024;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+	Notebook,
+	Pdf,
+	Epub,
+}
+
+impl FileType {
+	pub fn from_content(file_type: &str) -> Self {
+		match file_type {
+			"pdf" => FileType::Pdf,
+			"epub" => FileType::Epub,
+			// Older firmware uses an empty string for notebooks
+			_ => FileType::Notebook,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FileType::Notebook => "notebook",
+			FileType::Pdf => "pdf",
+			FileType::Epub => "epub",
+		}
+	}
+}
+
+
+/// A value in the tablet's CRDT-style `.content` (firmware 3.x), e.g. {"timestamp": "1:2", "value": "ba"}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timestamped<T> {
+	pub timestamp: String,
+	pub value: T,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transform {
+	pub m11: f64,
+	pub m12: f64,
+	pub m13: f64,
+	pub m21: f64,
+	pub m22: f64,
+	pub m23: f64,
+	pub m31: f64,
+	pub m32: f64,
+	pub m33: f64,
+}
+
+impl Default for Transform {
+	fn default() -> Self {
+		Transform {
+			m11: 1.0,
+			m12: 0.0,
+			m13: 0.0,
+			m21: 0.0,
+			m22: 1.0,
+			m23: 0.0,
+			m31: 0.0,
+			m32: 0.0,
+			m33: 1.0,
+		}
+	}
+}
+
+
+/// A page entry in firmware 3.x's `cPages`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CPage {
+	pub id: String,
+	/// Sort key; pages are ordered by comparing these strings
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub idx: Option<Timestamped<String>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub template: Option<Timestamped<String>>,
+	/// Index of the page in the original PDF/EPUB; missing for pages inserted on the tablet
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub redir: Option<Timestamped<i64>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub deleted: Option<Timestamped<i64>>,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CPages {
+	pub pages: Vec<CPage>,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+impl CPages {
+	/// Updates the entries to list `pages` in order.  Entries for pages that are gone are kept but marked deleted, as the
+	/// tablet does, and anything changed gets a newer timestamp than any already there so that it wins when merged.
+	fn sync(&mut self, pages: &[Page]) {
+		let timestamp = self.next_timestamp();
+		let stamp = |value| Timestamped {
+			timestamp: timestamp.clone(),
+			value,
+		};
+
+		let mut old: Vec<CPage> = std::mem::take(&mut self.pages);
+
+		for page in pages {
+			let mut entry = match old.iter().position(|entry| entry.id == page.id) {
+				Some(position) => old.remove(position),
+				None => CPage {
+					id: page.id.clone(),
+					idx: None,
+					template: None,
+					redir: None,
+					deleted: None,
+					extra: Map::new(),
+				},
+			};
+
+			if entry.template.as_ref().map(|template| &template.value) != Some(&page.template) {
+				entry.template = Some(stamp(page.template.clone()));
+			}
+			if entry.deleted.is_some() {
+				entry.deleted = None;
+				entry.idx = None;
+			}
+			self.pages.push(entry);
+		}
+
+		// The sort keys are only renumbered if they no longer put the pages in order
+		let in_order = self.pages.iter().all(|entry| entry.idx.is_some())
+			&& self.pages.windows(2).all(|pair| match (&pair[0].idx, &pair[1].idx) {
+				(Some(a), Some(b)) => a.value < b.value,
+				_ => false,
+			});
+		if !in_order {
+			let width = idx_width(self.pages.len());
+			for (i, entry) in self.pages.iter_mut().enumerate() {
+				let idx = page_idx(i, width);
+				if entry.idx.as_ref().map(|idx| &idx.value) != Some(&idx) {
+					entry.idx = Some(stamp(idx));
+				}
+			}
+		}
+
+		for mut entry in old {
+			if entry.deleted.is_none() {
+				entry.deleted = Some(Timestamped {
+					timestamp: timestamp.clone(),
+					value: 1,
+				});
+			}
+			self.pages.push(entry);
+		}
+	}
+
+	/// Points each of `pages` at the page of the PDF/EPUB that `redirects` has for it (-1 for pages inserted on the tablet), as
+	/// `redirectionPageMap` does for older firmware
+	pub(super) fn redirect(&mut self, pages: &[Page], redirects: &[i64]) {
+		let timestamp = self.next_timestamp();
+
+		for (page, &redirect) in pages.iter().zip(redirects) {
+			let entry = match self.pages.iter_mut().find(|entry| entry.id == page.id) {
+				Some(entry) => entry,
+				None => continue,
+			};
+			let redir = (redirect >= 0).then_some(redirect);
+
+			if entry.redir.as_ref().map(|redir| redir.value) != redir {
+				entry.redir = redir.map(|value| Timestamped {
+					timestamp: timestamp.clone(),
+					value,
+				});
+			}
+		}
+	}
+
+	/// A timestamp newer than any of the entries' (e.g. "1:12" if the newest is "1:11")
+	fn next_timestamp(&self) -> String {
+		let counter = self
+			.pages
+			.iter()
+			.flat_map(|page| {
+				let idx = page.idx.as_ref().map(|idx| &idx.timestamp);
+				let template = page.template.as_ref().map(|template| &template.timestamp);
+				let redir = page.redir.as_ref().map(|redir| &redir.timestamp);
+				let deleted = page.deleted.as_ref().map(|deleted| &deleted.timestamp);
+				idx.into_iter().chain(template).chain(redir).chain(deleted)
+			})
+			.filter_map(|timestamp| timestamp.rsplit(':').next()?.parse::<u64>().ok())
+			.max()
+			.unwrap_or(0);
+
+		format!("1:{}", counter + 1)
+	}
+}
+
+
+/// How many letters each page's `idx` needs so that `count` pages sort in order
+fn idx_width(count: usize) -> u32 {
+	let mut width = 2;
+	while 26usize.saturating_pow(width) < count {
+		width += 1;
+	}
+	width
+}
+
+/// A sort key for page `index` out of pages that use `width` letters, e.g. "aa", "ab", ...
+fn page_idx(index: usize, width: u32) -> String {
+	(0..width)
+		.rev()
+		.map(|digit| (b'a' + (index / 26usize.pow(digit) % 26) as u8) as char)
+		.collect()
+}
+
+
+/// A tag on a whole document (firmware 2.6+)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tag {
+	pub name: String,
+	/// When it was added, in milliseconds since the epoch
+	#[serde(default)]
+	pub timestamp: i64,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+/// A tag on one page of a document (firmware 2.6+)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageTag {
+	pub name: String,
+	pub page_id: String,
+	/// When it was added, in milliseconds since the epoch
+	#[serde(default)]
+	pub timestamp: i64,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+
+/// The `.content` file.
+/// Fields the server doesn't use are kept in `extra` so they survive a round trip.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+	#[serde(default)]
+	pub file_type: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cover_page_number: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub dummy_document: Option<bool>,
+	#[serde(default)]
+	pub extra_metadata: Map<String, Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub font_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub line_height: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub margins: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub orientation: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub page_count: Option<i64>,
+	/// Page IDs in order (firmware < 3)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub pages: Option<Vec<String>>,
+	/// For each page, its index in the original PDF, or -1 for pages inserted on the tablet
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub redirection_page_map: Option<Vec<i64>>,
+	/// Pages (firmware 3.x)
+	#[serde(rename = "cPages", skip_serializing_if = "Option::is_none")]
+	pub c_pages: Option<CPages>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub text_alignment: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub text_scale: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub transform: Option<Transform>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tags: Option<Vec<Tag>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub page_tags: Option<Vec<PageTag>>,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+impl Content {
+	/// A fresh `.content` for a document with `page_count` pages, each given a new ID.
+	pub fn new(file_type: FileType, page_count: usize) -> Self {
+		let pages = (0..page_count).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+
+		Content {
+			file_type: file_type.as_str().to_owned(),
+			cover_page_number: Some(0),
+			dummy_document: Some(false),
+			extra_metadata: Map::new(),
+			font_name: Some(String::new()),
+			line_height: Some(-1.0),
+			margins: Some(100.0),
+			orientation: Some("portrait".to_owned()),
+			page_count: Some(page_count as i64),
+			pages: Some(pages),
+			redirection_page_map: None,
+			c_pages: None,
+			text_alignment: Some("left".to_owned()),
+			text_scale: Some(1.0),
+			transform: Some(Transform::default()),
+			tags: None,
+			page_tags: None,
+			extra: Map::new(),
+		}
+	}
+
+	/// Page IDs in display order, from firmware 3.x's `cPages` if there is one (which is what the tablet reads, even if
+	/// there's an older `pages` too), otherwise `pages`.
+	pub fn page_ids(&self) -> Vec<String> {
+		if let Some(c_pages) = &self.c_pages {
+			let mut pages: Vec<&CPage> = c_pages.pages.iter().filter(|page| page.deleted.is_none()).collect();
+			pages.sort_by(|a, b| {
+				let a = a.idx.as_ref().map(|idx| idx.value.as_str()).unwrap_or("");
+				let b = b.idx.as_ref().map(|idx| idx.value.as_str()).unwrap_or("");
+				a.cmp(b)
+			});
+
+			return pages.into_iter().map(|page| page.id.clone()).collect();
+		}
+
+		self.pages.clone().unwrap_or_default()
+	}
+}
+
+
+/// The `.metadata` file.  Archives uploaded by the tablet don't usually include it, since the same information goes through update-status.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+	#[serde(default)]
+	pub visible_name: String,
+	#[serde(rename = "type", default)]
+	pub file_type: String,
+	#[serde(default)]
+	pub parent: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub last_modified: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub last_opened_page: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub version: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub pinned: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub deleted: Option<bool>,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+
+/// A page's `-metadata.json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PageMetadata {
+	#[serde(default)]
+	pub layers: Vec<LayerMetadata>,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerMetadata {
+	pub name: String,
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Page {
+	pub id: String,
+	/// Template name from the `.pagedata` (e.g. "Blank", "P Lines medium")
+	pub template: String,
+	/// The raw `.rm` stroke file, if anything has been drawn on the page
+	pub lines: Option<Vec<u8>>,
+	pub metadata: Option<PageMetadata>,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct RmDocument {
+	pub id: String,
+	pub content: Content,
+	pub metadata: Option<Metadata>,
+	pub pages: Vec<Page>,
+	pub pdf: Option<Vec<u8>>,
+	pub epub: Option<Vec<u8>>,
+	/// Any other files in the archive (thumbnails, highlights, etc), keyed by their path, so they survive a round trip
+	pub other_files: BTreeMap<String, Vec<u8>>,
+	/// Older firmware names page files by their index rather than their ID
+	pub index_named_pages: bool,
+}
+
+impl RmDocument {
+	/// A new document with `page_count` blank pages.
+	pub fn new(id: &str, file_type: FileType, page_count: usize) -> Self {
+		let content = Content::new(file_type, page_count);
+		let pages = content
+			.page_ids()
+			.into_iter()
+			.map(|id| Page {
+				id,
+				template: "Blank".to_owned(),
+				lines: None,
+				metadata: None,
+			})
+			.collect();
+
+		RmDocument {
+			id: id.to_owned(),
+			content,
+			metadata: None,
+			pages,
+			pdf: None,
+			epub: None,
+			other_files: BTreeMap::new(),
+			index_named_pages: false,
+		}
+	}
+
+	pub fn from_zip(data: &[u8]) -> Result<Self> {
+		let mut archive = ZipArchive::new(Cursor::new(data)).context("Not a zip archive")?;
+		let mut files = BTreeMap::new();
+		let mut unpacked = 0;
+
+		for i in 0..archive.len() {
+			let mut file = archive.by_index(i).context("Corrupt zip archive")?;
+
+			if file.is_dir() {
+				continue;
+			}
+
+			// Archives are decompressed into memory, so don't trust the sizes they claim
+			let mut buffer = Vec::new();
+			let read = (&mut file)
+				.take(MAX_UNPACKED_SIZE - unpacked + 1)
+				.read_to_end(&mut buffer)
+				.context("Corrupt zip archive")?;
+			unpacked += read as u64;
+			if unpacked > MAX_UNPACKED_SIZE {
+				bail!("Archive unpacks to more than {} bytes", MAX_UNPACKED_SIZE);
+			}
+			files.insert(file.name().to_owned(), buffer);
+		}
+
+		let id = files
+			.keys()
+			.find_map(|name| name.strip_suffix(".content").filter(|id| !id.contains('/')))
+			.context("Archive has no .content")?
+			.to_owned();
+
+		let content: Content = serde_json::from_slice(&files.remove(&format!("{}.content", id)).expect("unexpected")).context("Invalid .content")?;
+		let metadata = match files.remove(&format!("{}.metadata", id)) {
+			Some(metadata) => Some(serde_json::from_slice(&metadata).context("Invalid .metadata")?),
+			None => None,
+		};
+		let pagedata = files
+			.remove(&format!("{}.pagedata", id))
+			.map(|pagedata| String::from_utf8_lossy(&pagedata).lines().map(|line| line.to_owned()).collect::<Vec<_>>())
+			.unwrap_or_default();
+		let pdf = files.remove(&format!("{}.pdf", id));
+		let epub = files.remove(&format!("{}.epub", id));
+
+		let mut page_ids = content.page_ids();
+		if page_ids.is_empty() {
+			page_ids = (0..content.page_count.unwrap_or(0).max(0)).map(|i| i.to_string()).collect();
+		}
+
+		let mut index_named_pages = false;
+		let mut pages = Vec::new();
+
+		for (i, page_id) in page_ids.into_iter().enumerate() {
+			let mut take = |suffix: &str| {
+				files.remove(&format!("{}/{}{}", id, page_id, suffix)).or_else(|| {
+					let file = files.remove(&format!("{}/{}{}", id, i, suffix));
+					index_named_pages |= file.is_some();
+					file
+				})
+			};
+
+			let lines = take(".rm");
+			let metadata = match take("-metadata.json") {
+				Some(metadata) => Some(serde_json::from_slice(&metadata).context("Invalid page metadata")?),
+				None => None,
+			};
+
+			pages.push(Page {
+				id: page_id,
+				template: pagedata.get(i).cloned().unwrap_or_else(|| "Blank".to_owned()),
+				lines,
+				metadata,
+			});
+		}
+
+		Ok(RmDocument {
+			id,
+			content,
+			metadata,
+			pages,
+			pdf,
+			epub,
+			other_files: files,
+			index_named_pages,
+		})
+	}
+
+	pub fn to_zip(&self) -> Result<Vec<u8>> {
+		let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+		let options = FileOptions::default();
+
+		// Keep the .content's page lists in sync with our pages; a document can have both
+		let mut content = self.content.clone();
+		content.page_count = Some(self.pages.len() as i64);
+		if let Some(c_pages) = &mut content.c_pages {
+			c_pages.sync(&self.pages);
+		}
+		if content.c_pages.is_none() || content.pages.is_some() {
+			content.pages = Some(self.pages.iter().map(|page| page.id.clone()).collect());
+		}
+
+		let mut add = |name: String, data: &[u8]| -> Result<()> {
+			writer.start_file(name, options)?;
+			writer.write_all(data)?;
+			Ok(())
+		};
+
+		add(format!("{}.content", self.id), &serde_json::to_vec_pretty(&content)?)?;
+
+		if let Some(metadata) = &self.metadata {
+			add(format!("{}.metadata", self.id), &serde_json::to_vec_pretty(metadata)?)?;
+		}
+
+		let pagedata: String = self.pages.iter().map(|page| format!("{}\n", page.template)).collect();
+		add(format!("{}.pagedata", self.id), pagedata.as_bytes())?;
+
+		if let Some(pdf) = &self.pdf {
+			add(format!("{}.pdf", self.id), pdf)?;
+		}
+
+		if let Some(epub) = &self.epub {
+			add(format!("{}.epub", self.id), epub)?;
+		}
+
+		for (i, page) in self.pages.iter().enumerate() {
+			let stem = if self.index_named_pages { i.to_string() } else { page.id.clone() };
+
+			if let Some(lines) = &page.lines {
+				add(format!("{}/{}.rm", self.id, stem), lines)?;
+			}
+
+			if let Some(metadata) = &page.metadata {
+				add(format!("{}/{}-metadata.json", self.id, stem), &serde_json::to_vec_pretty(metadata)?)?;
+			}
+		}
+
+		for (name, data) in &self.other_files {
+			add(name.clone(), data)?;
+		}
+
+		Ok(writer.finish()?.into_inner())
+	}
+
+	pub fn file_type(&self) -> FileType {
+		FileType::from_content(&self.content.file_type)
+	}
+
+	/// Which page of the embedded PDF/EPUB a page shows, or None for pages added on the tablet.
+	pub fn source_page(&self, index: usize) -> Option<usize> {
+		if let Some(map) = &self.content.redirection_page_map {
+			return map.get(index).and_then(|source| usize::try_from(*source).ok());
+		}
+
+		if let Some(c_pages) = &self.content.c_pages {
+			let page = self.pages.get(index)?;
+			return c_pages
+				.pages
+				.iter()
+				.find(|c_page| c_page.id == page.id)
+				.and_then(|c_page| c_page.redir.as_ref())
+				.and_then(|redir| usize::try_from(redir.value).ok());
+		}
+
+		// Older firmware can't add pages to a PDF
+		Some(index)
+	}
+}
+
+
+/// Loads and parses a committed version of a document (by default the latest).  Returns None if there is no such document or version.
+pub async fn load_document(id: &str, version: Option<i64>, db: &SqlitePool) -> Result<Option<(DbFileMetadata, RmDocument)>> {
+	let metadata = match version {
+		Some(version) => database::get_metadata_by_id_version(id, version, db).await?,
+		None => database::get_metadata_by_id(id, db).await?,
+	};
+	let metadata = match metadata {
+		Some(metadata) => metadata,
+		None => return Ok(None),
+	};
+
+	let data = match database::get_data_by_id_version(id, metadata.version, db).await? {
+		Some(data) => data,
+		None => return Ok(None),
+	};
+
+	let document = RmDocument::from_zip(&data).with_context(|| format!("Parsing archive for {} version {}", id, metadata.version))?;
+
+	Ok(Some((metadata, document)))
+}
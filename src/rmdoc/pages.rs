@@ -0,0 +1,218 @@
+//! Rearranges the pages of PDF documents: merging several into one, splitting one into several, and reordering or extracting pages.
+//!
+//! A page moves along with everything that belongs to it: the page of the PDF it shows (if it isn't a page added on the tablet),
+//! its strokes, its template, its tags, and its other files (thumbnails, highlights, etc).
+use super::{pdf::page_with_inherited_attributes, Content, FileType, Page, PageTag, RmDocument};
+use anyhow::{bail, Context, Result};
+use lopdf::{dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, HashSet};
+
+
+/// A page of one of the documents being rearranged
+#[derive(Clone, Copy, Debug)]
+pub struct SourcePage {
+	/// Which of the documents
+	pub document: usize,
+	/// Counting from 0
+	pub index: usize,
+}
+
+
+/// Makes document `id` out of `pages` of `sources`, which have to be PDFs.
+/// If `id` is that of the only source, the result is the next version of it, which keeps its page IDs and settings; otherwise
+/// it's a new document, whose pages get new IDs.
+pub fn assemble(id: &str, sources: &[RmDocument], pages: &[SourcePage]) -> Result<RmDocument> {
+	let in_place = matches!(sources, [source] if source.id == id);
+
+	// All the sources' objects go in one PDF, renumbered so they don't clash
+	let mut pdf: Option<Document> = None;
+	let mut pdf_pages: Vec<Vec<ObjectId>> = Vec::new();
+
+	for source in sources {
+		let data = match (source.file_type(), &source.pdf) {
+			(FileType::Pdf, Some(data)) => data,
+			_ => bail!("{} isn't a PDF", source.id),
+		};
+		let mut source_pdf = Document::load_mem(data).with_context(|| format!("Invalid PDF in {}", source.id))?;
+
+		match &mut pdf {
+			None => {
+				pdf_pages.push(source_pdf.get_pages().into_values().collect());
+				pdf = Some(source_pdf);
+			}
+			Some(pdf) => {
+				source_pdf.renumber_objects_with(pdf.max_id + 1);
+				pdf_pages.push(source_pdf.get_pages().into_values().collect());
+				pdf.max_id = source_pdf.max_id;
+				pdf.objects.extend(source_pdf.objects);
+			}
+		}
+	}
+
+	let mut pdf = pdf.context("Nothing to assemble")?;
+	let pages_id = pdf.new_object_id();
+	let mut used = HashSet::new();
+	let mut kids = Vec::new();
+	let mut redirection_page_map = Vec::new();
+	let mut new_pages = Vec::new();
+	let mut page_tags = Vec::new();
+
+	// Files that aren't any page's stay with the document they belong to
+	let mut other_files: BTreeMap<String, Vec<u8>> = match sources {
+		[source] if in_place => source
+			.other_files
+			.iter()
+			.filter(|(path, _)| !source.pages.iter().any(|page| page_file_suffix(path, &source.id, &page.id).is_some()))
+			.map(|(path, data)| (path.clone(), data.clone()))
+			.collect(),
+		_ => BTreeMap::new(),
+	};
+
+	for &SourcePage { document, index } in pages {
+		let source = &sources[document];
+		let page = source.pages.get(index).with_context(|| format!("{} has no page {}", source.id, index))?;
+
+		let redirect = match source.source_page(index).and_then(|i| pdf_pages[document].get(i)) {
+			Some(&source_id) => {
+				// The first time we see a page we use it as-is, so that links and outlines pointing at it still work
+				let page_id = if used.insert(source_id) {
+					let dict = page_with_inherited_attributes(&pdf, source_id)?;
+					pdf.set_object(source_id, dict);
+					source_id
+				} else {
+					let mut dict = page_with_inherited_attributes(&pdf, source_id)?;
+					dict.remove(b"Annots");
+					pdf.add_object(dict)
+				};
+				pdf.get_dictionary_mut(page_id)?.set("Parent", pages_id);
+				kids.push(Object::Reference(page_id));
+				kids.len() as i64 - 1
+			}
+			// Added on the tablet, so there's nothing in the PDF
+			None => -1,
+		};
+		redirection_page_map.push(redirect);
+
+		// Older firmware names page files by their index, which won't match once pages move, so those get IDs
+		let page_id = if in_place && !source.index_named_pages {
+			page.id.clone()
+		} else {
+			uuid::Uuid::new_v4().to_string()
+		};
+
+		for (path, data) in &source.other_files {
+			if let Some((directory, suffix)) = page_file_suffix(path, &source.id, &page.id) {
+				other_files.insert(format!("{}{}/{}{}", id, directory, page_id, suffix), data.clone());
+			}
+		}
+		page_tags.extend(
+			source
+				.content
+				.page_tags
+				.iter()
+				.flatten()
+				.filter(|tag| tag.page_id == page.id)
+				.map(|tag| PageTag {
+					page_id: page_id.clone(),
+					..tag.clone()
+				}),
+		);
+
+		new_pages.push(Page {
+			id: page_id,
+			template: page.template.clone(),
+			lines: page.lines.clone(),
+			metadata: page.metadata.clone(),
+		});
+	}
+
+	let pdf_page_count = kids.len() as i64;
+	pdf.set_object(pages_id, dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => pdf_page_count });
+
+	let mut content = if in_place {
+		sources[0].content.clone()
+	} else {
+		Content::new(FileType::Pdf, new_pages.len())
+	};
+	// A document from firmware 3.x keeps its format, with the PDF pages its pages show in `cPages`; `to_zip` puts the entries
+	// in the new order
+	match &mut content.c_pages {
+		Some(c_pages) => c_pages.redirect(&new_pages, &redirection_page_map),
+		None => content.pages = Some(new_pages.iter().map(|page| page.id.clone()).collect()),
+	}
+	if content.c_pages.is_none() || content.redirection_page_map.is_some() {
+		content.redirection_page_map = Some(redirection_page_map);
+	}
+	content.extra.insert("originalPageCount".to_owned(), pdf_page_count.into());
+	if !page_tags.is_empty() || content.page_tags.is_some() {
+		content.page_tags = Some(page_tags);
+	}
+
+	if in_place {
+		pdf.catalog_mut()?.set("Pages", pages_id);
+	} else {
+		// The first source's outline, named destinations, etc would point at pages that may not be there any more
+		let catalog_id = pdf.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+		pdf.trailer.set("Root", catalog_id);
+	}
+
+	// Drops the old page trees, and the pages that weren't picked
+	pdf.prune_objects();
+	pdf.compress();
+
+	let mut output = Vec::new();
+	pdf.save_to(&mut output)?;
+
+	Ok(RmDocument {
+		id: id.to_owned(),
+		content,
+		metadata: if in_place { sources[0].metadata.clone() } else { None },
+		pages: new_pages,
+		pdf: Some(output),
+		epub: None,
+		other_files,
+		index_named_pages: false,
+	})
+}
+
+
+/// Page numbers (counting from 1, as the tablet does) separated by commas, with ranges like "4-7", "7-4" (backwards) or "4-"
+/// (to the last page).  Returns the pages' indexes, or why `spec` doesn't make sense for a document with `page_count` pages.
+pub fn parse_pages(spec: &str, page_count: usize) -> Result<Vec<usize>, String> {
+	let number = |text: &str| -> Result<usize, String> {
+		match text.trim().parse::<usize>() {
+			Ok(number) if number >= 1 && number <= page_count => Ok(number - 1),
+			Ok(number) => Err(format!("There's no page {}; the document has {} pages", number, page_count)),
+			Err(_) => Err(format!("Invalid page number {:?}", text.trim())),
+		}
+	};
+
+	let mut pages = Vec::new();
+
+	for part in spec.split(',') {
+		match part.split_once('-') {
+			Some((start, end)) => {
+				let start = number(start)?;
+				let end = if end.trim().is_empty() { page_count - 1 } else { number(end)? };
+				if start <= end {
+					pages.extend(start..=end);
+				} else {
+					pages.extend((end..=start).rev());
+				}
+			}
+			None => pages.push(number(part)?),
+		}
+	}
+
+	Ok(pages)
+}
+
+
+/// If `path` is one of a page's files, like `{document}.thumbnails/{page}.jpg`, its directory (".thumbnails") and what follows
+/// the page's ID (".jpg").
+fn page_file_suffix<'a>(path: &'a str, document_id: &str, page_id: &str) -> Option<(&'a str, &'a str)> {
+	let (directory, file) = path.strip_prefix(document_id)?.split_once('/')?;
+	let suffix = file.strip_prefix(page_id)?;
+
+	(directory.starts_with('.') && (suffix.starts_with('.') || suffix.starts_with('-'))).then_some((directory, suffix))
+}
@@ -0,0 +1,342 @@
+//! Exports a document, with its annotations, as a PDF.
+//!
+//! Each of the tablet's pages becomes a page of the PDF: pages of an imported PDF keep their original content with the strokes
+//! drawn on top as vector paths, and pages added on the tablet (or every page, for notebooks) become blank pages the size of
+//! the screen.  Pages are mapped into PDF space the way the tablet displays them: scaled to fit the screen, from the top left.
+use super::{
+	lines::{Line, Lines},
+	style::{self, PAGE_HEIGHT, PAGE_WIDTH},
+	FileType, RmDocument,
+};
+use anyhow::{bail, Context, Result};
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+use std::{
+	collections::{BTreeMap, HashSet},
+	fmt::Write,
+};
+
+
+/// Points per `.rm` unit on notebook pages; the screen is 226 DPI
+const NOTEBOOK_SCALE: f64 = 72.0 / 226.0;
+
+/// Page attributes that pages can inherit from their ancestors in the page tree
+const INHERITED_ATTRIBUTES: &[&[u8]] = &[b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+
+pub fn export_pdf(document: &RmDocument) -> Result<Vec<u8>> {
+	let mut pdf = match (document.file_type(), &document.pdf) {
+		(FileType::Pdf, Some(data)) => Document::load_mem(data).context("Invalid PDF")?,
+		(FileType::Notebook, _) => {
+			let mut pdf = Document::with_version("1.7");
+			let catalog_id = pdf.add_object(dictionary! { "Type" => "Catalog" });
+			pdf.trailer.set("Root", catalog_id);
+			pdf
+		}
+		_ => bail!("Only PDFs and notebooks can be exported as PDF"),
+	};
+
+	let source_pages: Vec<ObjectId> = pdf.get_pages().into_values().collect();
+	let pages_id = pdf.new_object_id();
+	let mut used = HashSet::new();
+	let mut kids = Vec::new();
+
+	for (i, page) in document.pages.iter().enumerate() {
+		let lines = match &page.lines {
+			Some(data) => Lines::parse(data).with_context(|| format!("Parsing page {}", i))?,
+			None => Lines::default(),
+		};
+
+		let page_id = match document.source_page(i).and_then(|source| source_pages.get(source)) {
+			// The first time we see a page we use it as-is, so that links and outlines pointing at it still work
+			Some(&source) if used.insert(source) => {
+				let dict = page_with_inherited_attributes(&pdf, source)?;
+				pdf.set_object(source, dict);
+				source
+			}
+			Some(&source) => {
+				let mut dict = page_with_inherited_attributes(&pdf, source)?;
+				dict.remove(b"Annots");
+				pdf.add_object(dict)
+			}
+			None => add_blank_page(&mut pdf, &lines),
+		};
+
+		draw_overlay(&mut pdf, page_id, &lines)?;
+		pdf.get_dictionary_mut(page_id)?.set("Parent", pages_id);
+		kids.push(Object::Reference(page_id));
+	}
+
+	let count = kids.len() as i64;
+	pdf.set_object(pages_id, dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count });
+	pdf.catalog_mut()?.set("Pages", pages_id);
+
+	// Drops the old page tree, and any pages deleted on the tablet
+	pdf.prune_objects();
+	pdf.compress();
+
+	let mut output = Vec::new();
+	pdf.save_to(&mut output)?;
+	Ok(output)
+}
+
+
+/// A copy of a page's dictionary, with anything it inherits from the page tree copied in
+pub(super) fn page_with_inherited_attributes(pdf: &Document, page_id: ObjectId) -> Result<Dictionary> {
+	let mut dict = pdf.get_dictionary(page_id)?.clone();
+	let mut parent = dict.get(b"Parent").and_then(Object::as_reference).ok();
+
+	// Bounded, in case the page tree has a cycle
+	for _ in 0..64 {
+		let parent_dict = match parent.and_then(|id| pdf.get_dictionary(id).ok()) {
+			Some(parent_dict) => parent_dict,
+			None => break,
+		};
+
+		for key in INHERITED_ATTRIBUTES {
+			if !dict.has(key) {
+				if let Ok(value) = parent_dict.get(key) {
+					dict.set(key.to_vec(), value.clone());
+				}
+			}
+		}
+
+		parent = parent_dict.get(b"Parent").and_then(Object::as_reference).ok();
+	}
+
+	Ok(dict)
+}
+
+
+/// A blank page the size of the screen (or taller, for notebooks that scroll)
+fn add_blank_page(pdf: &mut Document, lines: &Lines) -> ObjectId {
+	let width = PAGE_WIDTH * NOTEBOOK_SCALE;
+	let height = style::page_height(lines) * NOTEBOOK_SCALE;
+
+	pdf.add_object(dictionary! {
+		"Type" => "Page",
+		"MediaBox" => vec![0.into(), 0.into(), Object::Real(width as f32), Object::Real(height as f32)],
+		"Resources" => Dictionary::new(),
+	})
+}
+
+
+fn draw_overlay(pdf: &mut Document, page_id: ObjectId, lines: &Lines) -> Result<()> {
+	if lines.layers.iter().all(|layer| layer.lines.is_empty() && layer.highlights.is_empty()) && lines.text.is_none() {
+		return Ok(());
+	}
+
+	let page = pdf.get_dictionary(page_id)?;
+	let page_box = match page.get(b"CropBox").or_else(|_| page.get(b"MediaBox")) {
+		Ok(page_box) => page_box_coordinates(pdf, page_box)?,
+		Err(_) => [0.0, 0.0, 612.0, 792.0],
+	};
+	let rotate = page.get(b"Rotate").and_then(Object::as_i64).unwrap_or(0).rem_euclid(360);
+	let [x0, y0, x1, y1] = page_box;
+
+	// Size of the page as displayed, in points
+	let (width, height) = if rotate == 90 || rotate == 270 {
+		(y1 - y0, x1 - x0)
+	} else {
+		(x1 - x0, y1 - y0)
+	};
+	// Points per .rm unit
+	let k = 1.0 / (PAGE_WIDTH / width).min(PAGE_HEIGHT / height);
+
+	// Maps .rm coordinates (origin top left, y down) into the page's coordinate space
+	let matrix = match rotate {
+		90 => [0.0, k, k, 0.0, x0, y0],
+		180 => [-k, 0.0, 0.0, k, x1, y0],
+		270 => [0.0, -k, -k, 0.0, x1, y1],
+		_ => [k, 0.0, 0.0, -k, x0, y1],
+	};
+	let x_offset = style::x_offset(lines, width / k);
+
+	let mut opacities: BTreeMap<String, f32> = BTreeMap::new();
+	let mut content = String::new();
+	let _ = writeln!(content, "q {} cm", format_numbers(&matrix));
+
+	let mut set_opacity = |content: &mut String, opacity: f32| {
+		let name = format!("RmGS{}", (opacity * 100.0).round() as u32);
+		let _ = writeln!(content, "/{} gs", name);
+		opacities.insert(name, opacity);
+	};
+
+	for layer in lines.layers.iter().filter(|layer| layer.visible) {
+		for highlight in &layer.highlights {
+			let (r, g, b) = style::rgb(style::highlighter_color(highlight.color));
+			set_opacity(&mut content, 0.3);
+			let _ = writeln!(content, "{} rg", format_numbers(&[r as f64, g as f64, b as f64]));
+
+			for rect in &highlight.rectangles {
+				let _ = writeln!(content, "{} re f", format_numbers(&[rect.x + x_offset, rect.y, rect.w, rect.h]));
+			}
+		}
+
+		for line in &layer.lines {
+			draw_line(&mut content, line, x_offset, &mut set_opacity);
+		}
+	}
+
+	let has_text = lines.text.is_some();
+	if let Some(text) = &lines.text {
+		set_opacity(&mut content, 1.0);
+		content.push_str("0 0 0 rg\n");
+
+		for line in style::layout_text(text, x_offset) {
+			// Flip the text back upright, since our y axis points down
+			let _ = writeln!(
+				content,
+				"BT /{} {} Tf 1 0 0 -1 {} Tm ({}) Tj ET",
+				if line.bold { "RmF2" } else { "RmF1" },
+				format_numbers(&[line.font_size]),
+				format_numbers(&[line.x, line.y]),
+				pdf_string(&line.text)
+			);
+		}
+	}
+
+	content.push_str("Q\n");
+
+	// Isolate the page's own content, so that any graphics state it leaves behind doesn't affect ours
+	let save_id = pdf.add_object(Stream::new(Dictionary::new(), b"q\n".to_vec()));
+	let restore_id = pdf.add_object(Stream::new(Dictionary::new(), b"Q\n".to_vec()));
+	let overlay_id = pdf.add_object(Stream::new(Dictionary::new(), content.into_bytes()));
+
+	let mut contents = vec![Object::Reference(save_id)];
+	contents.extend(page_contents(pdf, page_id));
+	contents.push(Object::Reference(restore_id));
+	contents.push(Object::Reference(overlay_id));
+
+	let mut resources = match pdf.get_dictionary(page_id)?.get(b"Resources") {
+		Ok(resources) => pdf.dereference(resources)?.1.as_dict().cloned().unwrap_or_default(),
+		Err(_) => Dictionary::new(),
+	};
+
+	let mut ext_g_state = sub_dictionary(pdf, &resources, b"ExtGState");
+	for (name, opacity) in opacities {
+		ext_g_state.set(name, dictionary! { "Type" => "ExtGState", "CA" => opacity, "ca" => opacity });
+	}
+	resources.set("ExtGState", ext_g_state);
+
+	if has_text {
+		let mut fonts = sub_dictionary(pdf, &resources, b"Font");
+		for (name, font) in [("RmF1", "Helvetica"), ("RmF2", "Helvetica-Bold")] {
+			fonts.set(
+				name,
+				dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => font, "Encoding" => "WinAnsiEncoding" },
+			);
+		}
+		resources.set("Font", fonts);
+	}
+
+	let page = pdf.get_dictionary_mut(page_id)?;
+	page.set("Contents", contents);
+	page.set("Resources", resources);
+
+	Ok(())
+}
+
+
+fn draw_line(content: &mut String, line: &Line, x_offset: f64, set_opacity: &mut impl FnMut(&mut String, f32)) {
+	let stroke_style = match style::stroke_style(line) {
+		Some(stroke_style) => stroke_style,
+		None => return,
+	};
+	let (r, g, b) = style::rgb(stroke_style.color);
+
+	let _ = writeln!(
+		content,
+		"{} RG {} J 1 j",
+		format_numbers(&[r as f64, g as f64, b as f64]),
+		if stroke_style.square_cap { 2 } else { 1 }
+	);
+
+	for run in style::stroke_runs(line) {
+		set_opacity(content, stroke_style.opacity * run.opacity);
+		let _ = write!(content, "{} w", format_numbers(&[run.width as f64]));
+
+		for (i, (x, y)) in run.points.iter().enumerate() {
+			let _ = write!(
+				content,
+				" {} {}",
+				format_numbers(&[*x as f64 + x_offset, *y as f64]),
+				if i == 0 { "m" } else { "l" }
+			);
+		}
+
+		content.push_str(" S\n");
+	}
+}
+
+
+/// [x0 y0 x1 y1] of a MediaBox/CropBox, normalized so that x0 < x1 and y0 < y1
+fn page_box_coordinates(pdf: &Document, page_box: &Object) -> Result<[f64; 4]> {
+	let values = pdf.dereference(page_box)?.1.as_array()?;
+	let mut numbers = [0.0; 4];
+
+	for (number, value) in numbers.iter_mut().zip(values) {
+		*number = match pdf.dereference(value)?.1 {
+			Object::Integer(value) => *value as f64,
+			Object::Real(value) => *value as f64,
+			_ => bail!("Invalid page box"),
+		};
+	}
+
+	let [a, b, c, d] = numbers;
+	Ok([a.min(c), b.min(d), a.max(c), b.max(d)])
+}
+
+
+/// The page's content streams, as a list of references
+fn page_contents(pdf: &Document, page_id: ObjectId) -> Vec<Object> {
+	let contents = match pdf.get_dictionary(page_id).and_then(|page| page.get(b"Contents")) {
+		Ok(contents) => contents,
+		Err(_) => return Vec::new(),
+	};
+
+	match contents {
+		Object::Reference(id) => match pdf.get_object(*id) {
+			Ok(Object::Array(array)) => array.clone(),
+			_ => vec![contents.clone()],
+		},
+		Object::Array(array) => array.clone(),
+		_ => Vec::new(),
+	}
+}
+
+
+/// A copy of one of the dictionaries in a resource dictionary (e.g. /Font), so we can add to it without affecting other pages
+fn sub_dictionary(pdf: &Document, resources: &Dictionary, key: &[u8]) -> Dictionary {
+	resources
+		.get(key)
+		.and_then(|value| pdf.dereference(value))
+		.and_then(|(_, value)| value.as_dict())
+		.cloned()
+		.unwrap_or_default()
+}
+
+
+pub(super) fn format_numbers(numbers: &[f64]) -> String {
+	numbers
+		.iter()
+		.map(|number| {
+			let formatted = format!("{:.3}", number);
+			formatted.trim_end_matches('0').trim_end_matches('.').to_owned()
+		})
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+
+/// Encodes text for a PDF string in WinAnsiEncoding; characters it doesn't have become '?'
+pub(super) fn pdf_string(text: &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'(' | ')' | '\\' => format!("\\{}", c),
+			' '..='~' => c.to_string(),
+			'•' => "\\225".to_owned(),
+			'\u{a0}'..='\u{ff}' => format!("\\{:o}", c as u32),
+			_ => "?".to_owned(),
+		})
+		.collect()
+}
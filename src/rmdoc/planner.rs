@@ -0,0 +1,486 @@
+//! Planners: PDFs the size of the tablet's screen with a page for each day or week, made from calendars (see `ical`).
+//!
+//! The first pages are an index linking to each day's (or week's) page, and every page links to the pages either side of it
+//! and back to the index.  Days have their events as blocks on an hourly grid next to space for notes; weeks have a row for
+//! each day.  Nothing else goes into the PDF, so the same calendar always makes the same PDF, page for page.
+use super::{
+	pdf::{format_numbers, pdf_string},
+	style::{PAGE_HEIGHT, PAGE_WIDTH},
+};
+use crate::ical::{self, Event};
+use anyhow::{bail, Result};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+
+/// The page size in points; the screen is 226 DPI
+const WIDTH: f64 = PAGE_WIDTH * 72.0 / 226.0;
+const HEIGHT: f64 = PAGE_HEIGHT * 72.0 / 226.0;
+const MARGIN: f64 = 24.0;
+
+/// The hours the grid on day pages covers.  Events outside them are listed with the all-day events.
+const FIRST_HOUR: u32 = 7;
+const LAST_HOUR: u32 = 22;
+
+/// How many all-day events there's room for above the grid
+const ALL_DAY_LINES: usize = 3;
+
+const NOTES_LINE_SPACING: f64 = 20.0;
+
+/// Month calendars on each index page of a daily planner
+const MONTHS_PER_INDEX_PAGE: usize = 6;
+
+/// Weeks in each of the two columns of the index of a weekly planner
+const WEEKS_PER_INDEX_COLUMN: usize = 27;
+
+const REGULAR: &str = "F1";
+const BOLD: &str = "F2";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannerLayout {
+	/// A page for each day
+	Daily,
+	/// A page for each week, starting with `start`
+	Weekly,
+}
+
+
+/// A planner covering `days` days from `start`, with the events in `calendar` (the text of one or more .ics files)
+pub fn planner_pdf(calendar: &str, layout: PlannerLayout, start: NaiveDate, days: u32) -> Result<Vec<u8>> {
+	if days == 0 {
+		bail!("A planner needs at least one day");
+	}
+
+	let pages = match layout {
+		PlannerLayout::Daily => {
+			let end = start + Duration::days(days as i64 - 1);
+			daily_pages(&ical::events_between(calendar, start, end), start, end)
+		}
+		PlannerLayout::Weekly => {
+			let weeks = (days as i64 + 6) / 7;
+			let end = start + Duration::weeks(weeks) - Duration::days(1);
+			weekly_pages(&ical::events_between(calendar, start, end), start, weeks)
+		}
+	};
+
+	write_pdf(pages)
+}
+
+
+/// What's drawn on a page, and the links on it
+#[derive(Default)]
+struct Canvas {
+	content: String,
+	/// Where each link is (x, y, width and height, from the top left) and the index of the page it goes to
+	links: Vec<([f64; 4], usize)>,
+}
+
+impl Canvas {
+	/// Text with its baseline at `y`, from the top of the page
+	fn text(&mut self, x: f64, y: f64, size: f64, font: &str, gray: f64, text: &str) {
+		writeln!(
+			self.content,
+			"BT /{} {} Tf {} g {} Td ({}) Tj ET",
+			font,
+			format_numbers(&[size]),
+			format_numbers(&[gray]),
+			format_numbers(&[x, HEIGHT - y]),
+			pdf_string(text)
+		)
+		.unwrap();
+	}
+
+	fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, width: f64, gray: f64) {
+		writeln!(
+			self.content,
+			"{} w {} G {} m {} l S",
+			format_numbers(&[width]),
+			format_numbers(&[gray]),
+			format_numbers(&[x1, HEIGHT - y1]),
+			format_numbers(&[x2, HEIGHT - y2])
+		)
+		.unwrap();
+	}
+
+	fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, gray: f64) {
+		writeln!(
+			self.content,
+			"{} g {} re f",
+			format_numbers(&[gray]),
+			format_numbers(&[x, HEIGHT - y - height, width, height])
+		)
+		.unwrap();
+	}
+
+	fn link(&mut self, x: f64, y: f64, width: f64, height: f64, page: usize) {
+		self.links.push(([x, y, width, height], page));
+	}
+
+	/// Ruled lines for notes, between `top` and `bottom`
+	fn notes(&mut self, x1: f64, x2: f64, top: f64, bottom: f64, spacing: f64) {
+		let mut y = top + spacing;
+		while y <= bottom {
+			self.line(x1, y, x2, y, 0.4, 0.75);
+			y += spacing;
+		}
+	}
+
+	/// The title, and links to the pages before and after this one and to the index (from pages that aren't the index), at the
+	/// top of the page
+	fn header(&mut self, title: &str, subtitle: &str, previous: Option<usize>, index: Option<usize>, next: Option<usize>) {
+		let mut x = WIDTH - MARGIN;
+		for (label, page) in [("Next >", next), ("Index", index), ("< Prev", previous)] {
+			let width = text_width(label, 9.0);
+			x -= width;
+			if let Some(page) = page {
+				self.text(x, MARGIN + 14.0, 9.0, REGULAR, 0.3, label);
+				self.link(x - 4.0, MARGIN, width + 8.0, 20.0, page);
+			}
+			x -= 14.0;
+		}
+
+		self.text(MARGIN, MARGIN + 16.0, 16.0, BOLD, 0.0, &fit(title, 16.0, x - MARGIN));
+		self.text(MARGIN, MARGIN + 30.0, 9.0, REGULAR, 0.4, subtitle);
+		self.line(MARGIN, MARGIN + 38.0, WIDTH - MARGIN, MARGIN + 38.0, 0.8, 0.2);
+	}
+}
+
+
+fn daily_pages(events: &[Event], start: NaiveDate, end: NaiveDate) -> Vec<Canvas> {
+	let dates: Vec<NaiveDate> = start.iter_days().take_while(|date| *date <= end).collect();
+	let mut months: Vec<(i32, u32)> = dates.iter().map(|date| (date.year(), date.month())).collect();
+	months.dedup();
+	let index_pages = months.len().div_ceil(MONTHS_PER_INDEX_PAGE);
+	let page_of = |date: NaiveDate| index_pages + (date - start).num_days() as usize;
+	let index_of =
+		|date: NaiveDate| months.iter().position(|&month| month == (date.year(), date.month())).unwrap_or_default() / MONTHS_PER_INDEX_PAGE;
+	let range = format!("{} - {}", long_date(start), long_date(end));
+
+	let mut pages = Vec::new();
+
+	for (i, page_months) in months.chunks(MONTHS_PER_INDEX_PAGE).enumerate() {
+		let mut page = Canvas::default();
+		page.header("Planner", &range, i.checked_sub(1), None, (i + 1 < index_pages).then(|| i + 1));
+
+		let column_width = (WIDTH - 2.0 * MARGIN) / 2.0;
+		for (j, &(year, month)) in page_months.iter().enumerate() {
+			let x = MARGIN + (j % 2) as f64 * column_width;
+			let y = 90.0 + (j / 2) as f64 * 160.0;
+			let first = NaiveDate::from_ymd(year, month, 1);
+			page.text(x, y, 11.0, BOLD, 0.0, &first.format("%B %Y").to_string());
+			for (k, day) in ["M", "T", "W", "T", "F", "S", "S"].iter().enumerate() {
+				page.text(x + k as f64 * 25.0 + 3.0, y + 16.0, 7.0, REGULAR, 0.5, day);
+			}
+
+			for date in first.iter_days().take_while(|date| date.month() == month) {
+				let cell = first.weekday().num_days_from_monday() + date.day() - 1;
+				let (cell_x, cell_y) = (x + (cell % 7) as f64 * 25.0, y + 34.0 + (cell / 7) as f64 * 18.0);
+				let label = date.day().to_string();
+				if date < start || date > end {
+					page.text(cell_x, cell_y, 9.0, REGULAR, 0.7, &label);
+				} else {
+					let busy = events.iter().any(|event| event.is_on(date));
+					page.text(cell_x, cell_y, 9.0, if busy { BOLD } else { REGULAR }, 0.0, &label);
+					page.link(cell_x - 4.0, cell_y - 12.0, 22.0, 16.0, page_of(date));
+				}
+			}
+		}
+
+		pages.push(page);
+	}
+
+	for &date in &dates {
+		let mut page = Canvas::default();
+		page.header(
+			&format!("{} {}", date.format("%A"), long_date(date)),
+			&format!("Week {}", date.iso_week().week()),
+			(date > start).then(|| page_of(date) - 1),
+			Some(index_of(date)),
+			(date < end).then(|| page_of(date) + 1),
+		);
+		draw_day(&mut page, events, date);
+		pages.push(page);
+	}
+
+	pages
+}
+
+
+/// Events as blocks on an hourly grid, with all-day events (and those outside the grid's hours) above it and space for notes
+/// beside it
+fn draw_day(page: &mut Canvas, events: &[Event], date: NaiveDate) {
+	let grid_top = MARGIN + 52.0 + ALL_DAY_LINES as f64 * 12.0 + 6.0;
+	let grid_bottom = HEIGHT - MARGIN;
+	let grid_right = MARGIN + (WIDTH - 2.0 * MARGIN) * 0.6;
+	let blocks_left = MARGIN + 28.0;
+	let hours = (LAST_HOUR - FIRST_HOUR) as f64;
+	let hour_height = (grid_bottom - grid_top) / hours;
+	let day_start = date.and_hms(0, 0, 0);
+	let grid_minutes = hours * 60.0;
+
+	for hour in FIRST_HOUR..=LAST_HOUR {
+		let y = grid_top + (hour - FIRST_HOUR) as f64 * hour_height;
+		page.line(blocks_left - 2.0, y, grid_right, y, 0.5, 0.6);
+		if hour < LAST_HOUR {
+			page.text(MARGIN, y + 8.0, 7.0, REGULAR, 0.4, &format!("{:02}:00", hour));
+			page.line(blocks_left, y + hour_height / 2.0, grid_right, y + hour_height / 2.0, 0.3, 0.85);
+		}
+	}
+
+	// Where each of the day's timed events is on the grid, in minutes from the top
+	let mut listed = Vec::new();
+	let mut blocks = Vec::new();
+	for event in events.iter().filter(|event| event.is_on(date)) {
+		let minutes = |time: NaiveDateTime| {
+			let minutes = (time - day_start).num_minutes().clamp(0, 24 * 60) as f64;
+			minutes - FIRST_HOUR as f64 * 60.0
+		};
+		let (top, bottom) = (minutes(event.start), minutes(event.end));
+		if event.all_day || bottom <= 0.0 || top >= grid_minutes || (top == bottom && top <= 0.0) {
+			listed.push(event);
+		} else {
+			blocks.push((event, top.max(0.0), bottom.min(grid_minutes)));
+		}
+	}
+
+	for (i, event) in listed.iter().enumerate().take(ALL_DAY_LINES) {
+		let y = MARGIN + 52.0 + i as f64 * 12.0;
+		let text = if i + 1 == ALL_DAY_LINES && listed.len() > ALL_DAY_LINES {
+			format!("+{} more", listed.len() - i)
+		} else {
+			label(event, date)
+		};
+		page.rect(MARGIN, y - 7.0, 3.0, 9.0, 0.3);
+		page.text(MARGIN + 7.0, y, 8.0, REGULAR, 0.0, &fit(&text, 8.0, grid_right - MARGIN - 7.0));
+	}
+
+	// Overlapping events share the width of the grid, each in the first lane that's free when it starts
+	let min_minutes = 10.0 / hour_height * 60.0;
+	let mut lanes: Vec<f64> = Vec::new();
+	let mut group = Vec::new();
+	let mut placed = Vec::new();
+	for (event, top, bottom) in blocks {
+		let bottom = bottom.max((top + min_minutes).min(grid_minutes)).max(top + 1.0);
+		if lanes.iter().all(|&end| end <= top) {
+			placed.extend(group.drain(..).map(|(event, top, bottom, lane)| (event, top, bottom, lane, lanes.len())));
+			lanes.clear();
+		}
+		let lane = match lanes.iter().position(|&end| end <= top) {
+			Some(lane) => lane,
+			None => {
+				lanes.push(0.0);
+				lanes.len() - 1
+			}
+		};
+		lanes[lane] = bottom;
+		group.push((event, top, bottom, lane));
+	}
+	placed.extend(group.drain(..).map(|(event, top, bottom, lane)| (event, top, bottom, lane, lanes.len())));
+
+	for (event, top, bottom, lane, lane_count) in placed {
+		let lane_width = (grid_right - blocks_left) / lane_count as f64;
+		let x = blocks_left + lane as f64 * lane_width;
+		let y = grid_top + top / 60.0 * hour_height;
+		let height = (bottom - top) / 60.0 * hour_height;
+		let text_width = lane_width - 8.0;
+
+		page.rect(x + 1.0, y + 0.5, lane_width - 2.0, height - 1.0, 0.88);
+		page.rect(x + 1.0, y + 0.5, 2.0, height - 1.0, 0.3);
+
+		let times = format!("{}-{}", event.start.format("%H:%M"), event.end.format("%H:%M"));
+		if height < 20.0 {
+			let text = format!("{} {}", event.start.format("%H:%M"), event.summary);
+			page.text(x + 6.0, y + height / 2.0 + 2.5, 7.0, BOLD, 0.0, &fit(&text, 7.0, text_width));
+		} else {
+			page.text(x + 6.0, y + 9.0, 7.0, BOLD, 0.0, &fit(&event.summary, 7.0, text_width));
+			let details = match &event.location {
+				Some(location) => format!("{}, {}", times, location),
+				None => times,
+			};
+			page.text(x + 6.0, y + 17.0, 6.5, REGULAR, 0.3, &fit(&details, 6.5, text_width));
+		}
+	}
+
+	let notes_left = grid_right + 12.0;
+	page.text(notes_left, MARGIN + 52.0, 9.0, BOLD, 0.4, "Notes");
+	page.notes(notes_left, WIDTH - MARGIN, MARGIN + 56.0, grid_bottom, NOTES_LINE_SPACING);
+}
+
+
+fn weekly_pages(events: &[Event], start: NaiveDate, weeks: i64) -> Vec<Canvas> {
+	let end = start + Duration::weeks(weeks) - Duration::days(1);
+	let mut index = Canvas::default();
+	index.header("Planner", &format!("{} - {}", long_date(start), long_date(end)), None, None, None);
+
+	let column_width = (WIDTH - 2.0 * MARGIN) / 2.0;
+	for week in 0..weeks as usize {
+		let week_start = start + Duration::weeks(week as i64);
+		let x = MARGIN + (week / WEEKS_PER_INDEX_COLUMN) as f64 * column_width;
+		let y = 80.0 + (week % WEEKS_PER_INDEX_COLUMN) as f64 * 18.0;
+		index.text(x, y, 9.0, BOLD, 0.0, &format!("Week {}", week_start.iso_week().week()));
+		index.text(x + 50.0, y, 9.0, REGULAR, 0.3, &week_range(week_start));
+		index.link(x - 4.0, y - 13.0, column_width - 8.0, 18.0, week + 1);
+	}
+
+	let mut pages = vec![index];
+	let rows_top = MARGIN + 46.0;
+	let row_height = (HEIGHT - MARGIN - rows_top) / 7.0;
+	let events_width = (WIDTH - 2.0 * MARGIN) * 0.55;
+	let notes_left = MARGIN + events_width + 8.0;
+
+	for week in 0..weeks {
+		let week_start = start + Duration::weeks(week);
+		let mut page = Canvas::default();
+		page.header(
+			&format!("Week {}", week_start.iso_week().week()),
+			&week_range(week_start),
+			(week > 0).then_some(week as usize),
+			Some(0),
+			(week + 1 < weeks).then(|| week as usize + 2),
+		);
+
+		for (day, date) in week_start.iter_days().take(7).enumerate() {
+			let y = rows_top + day as f64 * row_height;
+			if day > 0 {
+				page.line(MARGIN, y, WIDTH - MARGIN, y, 0.5, 0.5);
+			}
+			page.text(MARGIN, y + 14.0, 10.0, BOLD, 0.0, &format!("{} {}", date.format("%a"), date.day()));
+
+			let day_events: Vec<&Event> = events.iter().filter(|event| event.is_on(date)).collect();
+			let room = ((row_height - 24.0) / 10.0).floor().max(1.0) as usize;
+			for (i, event) in day_events.iter().enumerate().take(room) {
+				let text = if i + 1 == room && day_events.len() > room {
+					format!("+{} more", day_events.len() - i)
+				} else {
+					label(event, date)
+				};
+				page.text(
+					MARGIN + 4.0,
+					y + 26.0 + i as f64 * 10.0,
+					7.5,
+					REGULAR,
+					0.0,
+					&fit(&text, 7.5, events_width - 4.0),
+				);
+			}
+
+			page.notes(notes_left, WIDTH - MARGIN, y, y + row_height - 2.0, 16.0);
+		}
+
+		pages.push(page);
+	}
+
+	pages
+}
+
+
+fn write_pdf(pages: Vec<Canvas>) -> Result<Vec<u8>> {
+	let mut pdf = Document::with_version("1.5");
+	let pages_id = pdf.new_object_id();
+	// Allocated up front, so that links can go to pages that haven't been added yet
+	let page_ids: Vec<ObjectId> = pages.iter().map(|_| pdf.new_object_id()).collect();
+	let resources_id = pdf.add_object(dictionary! {
+		"Font" => dictionary! {
+			REGULAR => dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica", "Encoding" => "WinAnsiEncoding" },
+			BOLD => dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica-Bold", "Encoding" => "WinAnsiEncoding" },
+		},
+	});
+
+	for (page, &page_id) in pages.into_iter().zip(&page_ids) {
+		let content_id = pdf.add_object(Stream::new(dictionary! {}, page.content.into_bytes()));
+		let annotations: Vec<Object> = page
+			.links
+			.iter()
+			.map(|&([x, y, width, height], target)| {
+				let rect = [x, HEIGHT - y - height, x + width, HEIGHT - y]
+					.iter()
+					.map(|&n| Object::Real(n as f32))
+					.collect::<Vec<_>>();
+				Object::Reference(pdf.add_object(dictionary! {
+					"Type" => "Annot",
+					"Subtype" => "Link",
+					"Rect" => rect,
+					"Border" => vec![0.into(), 0.into(), 0.into()],
+					"Dest" => vec![Object::Reference(page_ids[target]), "Fit".into()],
+				}))
+			})
+			.collect();
+
+		pdf.objects.insert(
+			page_id,
+			Object::Dictionary(dictionary! {
+				"Type" => "Page",
+				"Parent" => pages_id,
+				"MediaBox" => vec![0.into(), 0.into(), Object::Real(WIDTH as f32), Object::Real(HEIGHT as f32)],
+				"Resources" => resources_id,
+				"Contents" => content_id,
+				"Annots" => annotations,
+			}),
+		);
+	}
+
+	let count = page_ids.len() as i64;
+	let kids: Vec<Object> = page_ids.into_iter().map(Object::Reference).collect();
+	pdf.objects.insert(
+		pages_id,
+		Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }),
+	);
+	let catalog_id = pdf.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+	pdf.trailer.set("Root", catalog_id);
+	pdf.compress();
+
+	let mut output = Vec::new();
+	pdf.save_to(&mut output)?;
+	Ok(output)
+}
+
+
+/// An event in a list of `date`'s events, with when it starts, or if it started the day before, when it ends
+fn label(event: &Event, date: NaiveDate) -> String {
+	let next_day = date.succ().and_hms(0, 0, 0);
+
+	match (event.start.date() < date, event.end < next_day) {
+		_ if event.all_day => event.summary.clone(),
+		(false, _) => format!("{} {}", event.start.format("%H:%M"), event.summary),
+		(true, true) => format!("Until {} {}", event.end.format("%H:%M"), event.summary),
+		(true, false) => event.summary.clone(),
+	}
+}
+
+
+/// e.g. "19 October 2026"
+fn long_date(date: NaiveDate) -> String {
+	format!("{} {}", date.day(), date.format("%B %Y"))
+}
+
+
+/// e.g. "19 Oct - 25 Oct 2026"
+fn week_range(start: NaiveDate) -> String {
+	let end = start + Duration::days(6);
+	format!("{} {} - {} {}", start.day(), start.format("%b"), end.day(), end.format("%b %Y"))
+}
+
+
+/// Roughly how wide text is in Helvetica, which is all that's needed to keep it in its box
+fn text_width(text: &str, size: f64) -> f64 {
+	text.chars().count() as f64 * size * 0.52
+}
+
+
+/// `text`, shortened with "..." if it's wider than `width`
+fn fit(text: &str, size: f64, width: f64) -> String {
+	let text = text.replace(|c: char| c.is_control(), " ");
+	if text_width(&text, size) <= width {
+		return text;
+	}
+
+	let chars = ((width / (size * 0.52)) as usize).saturating_sub(3);
+	let mut shortened: String = text.chars().take(chars).collect();
+	shortened.truncate(shortened.trim_end().len());
+	shortened.push_str("...");
+	shortened
+}
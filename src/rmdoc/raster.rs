@@ -0,0 +1,193 @@
+//! Renders pages as bitmaps, for thumbnails.
+//!
+//! Pages are drawn as the tablet shows them: PDF pages (rendered by hayro) scaled to fit the screen from the top left, with the
+//! strokes on top.  Only the first screen of a page is drawn, so every bitmap has the screen's proportions.  We don't have fonts
+//! for typed text, and it would be unreadable at thumbnail sizes anyway, so each line of it is drawn as a grey bar.
+use super::{
+	lines::{Line, Lines},
+	style::{self, PAGE_HEIGHT, PAGE_WIDTH},
+	FileType, RmDocument,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use hayro::{
+	hayro_interpret::{util::TransformExt, InterpreterSettings},
+	hayro_syntax::Pdf,
+	vello_cpu::{
+		color::{AlphaColor, Srgb},
+		kurbo::{Affine, BezPath, Cap, Join, Rect, Stroke},
+		Pixmap, RasterizerSettings, RenderContext, Resources, TargetInit,
+	},
+	RenderCache,
+};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+	Png,
+	Webp,
+}
+
+impl ImageFormat {
+	pub fn parse(format: &str) -> Option<Self> {
+		match format {
+			"png" => Some(ImageFormat::Png),
+			"webp" => Some(ImageFormat::Webp),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ImageFormat::Png => "png",
+			ImageFormat::Webp => "webp",
+		}
+	}
+
+	pub fn content_type(&self) -> &'static str {
+		match self {
+			ImageFormat::Png => "image/png",
+			ImageFormat::Webp => "image/webp",
+		}
+	}
+}
+
+
+/// Renders a page (counting from 0) of a PDF or notebook, `width` pixels wide.
+/// Returns None if the document doesn't have that page.
+pub fn render_page(document: &RmDocument, index: usize, width: u16) -> Result<Option<Pixmap>> {
+	let page = match document.pages.get(index) {
+		Some(page) => page,
+		None => return Ok(None),
+	};
+	let lines = match &page.lines {
+		Some(data) => Lines::parse(data).with_context(|| format!("Parsing page {}", index))?,
+		None => Lines::default(),
+	};
+
+	let height = (width as f64 * PAGE_HEIGHT / PAGE_WIDTH).round() as u16;
+	// Pixels per .rm unit
+	let scale = width as f64 / PAGE_WIDTH;
+	let mut ctx = RenderContext::new(width, height);
+	let mut page_width = PAGE_WIDTH;
+
+	match (document.file_type(), &document.pdf, document.source_page(index)) {
+		(FileType::Pdf, Some(data), Some(source)) => {
+			let pdf = Pdf::new(data.clone()).map_err(|err| anyhow!("Invalid PDF: {:?}", err))?;
+
+			if let Some(pdf_page) = pdf.pages().get(source) {
+				let (pdf_width, pdf_height) = pdf_page.render_dimensions();
+				// Points per .rm unit
+				let k = 1.0 / (PAGE_WIDTH / pdf_width as f64).min(PAGE_HEIGHT / pdf_height as f64);
+				let transform = Affine::scale(scale / k) * pdf_page.initial_transform(true).to_kurbo();
+				let cache = RenderCache::new();
+
+				hayro::render_into(
+					pdf_page,
+					&cache,
+					&InterpreterSettings::default(),
+					&hayro::RenderSettings::default(),
+					&mut ctx,
+					transform,
+				);
+				page_width = pdf_width as f64 / k;
+			}
+		}
+		// Pages added to a PDF on the tablet are blank
+		(FileType::Pdf, Some(_), None) | (FileType::Notebook, _, _) => (),
+		(FileType::Pdf, None, _) => bail!("Missing PDF"),
+		(FileType::Epub, _, _) => bail!("Only PDFs and notebooks can be rendered"),
+	}
+
+	draw_annotations(&mut ctx, &lines, scale, style::x_offset(&lines, page_width));
+
+	ctx.flush();
+	let mut pixmap = Pixmap::new(width, height);
+	ctx.render_with(
+		&mut pixmap,
+		&mut Resources::default(),
+		RasterizerSettings {
+			target_init: TargetInit::Clear(AlphaColor::WHITE),
+			..Default::default()
+		},
+	);
+
+	Ok(Some(pixmap))
+}
+
+
+pub fn encode(pixmap: Pixmap, format: ImageFormat) -> Result<Vec<u8>> {
+	match format {
+		ImageFormat::Png => pixmap.into_png().context("Encoding PNG"),
+		ImageFormat::Webp => {
+			let (width, height) = (pixmap.width() as u32, pixmap.height() as u32);
+			let data = pixmap.take_rgba8(hayro::vello_cpu::peniko::ImageAlphaType::Alpha);
+			let mut output = Vec::new();
+			image_webp::WebPEncoder::new(&mut output)
+				.encode(&data, width, height, image_webp::ColorType::Rgba8)
+				.context("Encoding WebP")?;
+			Ok(output)
+		}
+	}
+}
+
+
+fn draw_annotations(ctx: &mut RenderContext, lines: &Lines, scale: f64, x_offset: f64) {
+	ctx.reset_transform();
+	ctx.set_transform(Affine::scale(scale));
+
+	if let Some(text) = &lines.text {
+		ctx.set_paint(color("#b4b4b4", 1.0));
+
+		for line in style::layout_text(text, x_offset) {
+			let width = line.text.chars().count() as f64 * line.font_size * 0.5;
+			ctx.fill_rect(&Rect::new(line.x, line.y - line.font_size * 0.6, line.x + width, line.y));
+		}
+	}
+
+	for layer in lines.layers.iter().filter(|layer| layer.visible) {
+		for highlight in &layer.highlights {
+			ctx.set_paint(color(style::highlighter_color(highlight.color), 0.3));
+
+			for rect in &highlight.rectangles {
+				ctx.fill_rect(&Rect::new(rect.x + x_offset, rect.y, rect.x + x_offset + rect.w, rect.y + rect.h));
+			}
+		}
+
+		for line in &layer.lines {
+			draw_line(ctx, line, x_offset);
+		}
+	}
+}
+
+
+fn draw_line(ctx: &mut RenderContext, line: &Line, x_offset: f64) {
+	let stroke_style = match style::stroke_style(line) {
+		Some(stroke_style) => stroke_style,
+		None => return,
+	};
+	let cap = if stroke_style.square_cap { Cap::Square } else { Cap::Round };
+
+	for run in style::stroke_runs(line) {
+		let mut path = BezPath::new();
+
+		for (i, (x, y)) in run.points.iter().enumerate() {
+			let point = (*x as f64 + x_offset, *y as f64);
+
+			if i == 0 {
+				path.move_to(point);
+			} else {
+				path.line_to(point);
+			}
+		}
+
+		ctx.set_paint(color(stroke_style.color, stroke_style.opacity * run.opacity));
+		ctx.set_stroke(Stroke::new(run.width as f64).with_caps(cap).with_join(Join::Round));
+		ctx.stroke_path(&path);
+	}
+}
+
+
+fn color(hex: &str, opacity: f32) -> AlphaColor<Srgb> {
+	let (r, g, b) = style::rgb(hex);
+	AlphaColor::new([r, g, b, opacity])
+}
@@ -0,0 +1,273 @@
+//! How the tablet draws things, shared by the SVG and PDF renderers.
+//!
+//! Stroke widths follow the tablet's pens: pressure, tilt and speed all affect the width of pencils, brushes and ballpoints,
+//! while fineliners and highlighters draw at a fixed width.  Variable width strokes are split into runs of segments of equal width.
+use super::lines::{Color, Line, Lines, Paragraph, ParagraphStyle, Pen, Point, Text};
+
+
+/// Size of the tablet's screen, in the units used by `.rm` files
+pub const PAGE_WIDTH: f64 = 1404.0;
+pub const PAGE_HEIGHT: f64 = 1872.0;
+
+
+pub struct StrokeStyle {
+	/// e.g. "#000000"
+	pub color: &'static str,
+	pub opacity: f32,
+	/// Highlighters have square ends; everything else is round
+	pub square_cap: bool,
+}
+
+
+/// Consecutive segments of a stroke drawn with the same width and opacity
+pub struct Run {
+	pub width: f32,
+	pub opacity: f32,
+	pub points: Vec<(f32, f32)>,
+}
+
+
+/// Version 6 puts x=0 at the centre of the page, older versions at the left edge.
+pub fn x_offset(lines: &Lines, page_width: f64) -> f64 {
+	if lines.version >= 6 {
+		page_width / 2.0
+	} else {
+		0.0
+	}
+}
+
+
+/// The height of the page, which is taller than the screen if strokes were drawn below the bottom of it
+pub fn page_height(lines: &Lines) -> f64 {
+	lines
+		.visible_lines()
+		.flat_map(|line| line.points.iter())
+		.map(|point| point.y as f64)
+		.fold(PAGE_HEIGHT, |height, y| height.max(y.ceil()))
+}
+
+
+/// None for strokes that aren't drawn
+pub fn stroke_style(line: &Line) -> Option<StrokeStyle> {
+	let (color, opacity, square_cap) = match line.pen {
+		// Erased areas have already been removed from the other strokes
+		Pen::EraseArea => return None,
+		Pen::Eraser => ("#ffffff", 1.0, false),
+		Pen::Highlighter => (highlight_color(line), 0.3, true),
+		Pen::Shader => (pen_color(line), 0.1, false),
+		Pen::MechanicalPencil => (pen_color(line), 0.7, false),
+		_ => (pen_color(line), 1.0, false),
+	};
+
+	if line.points.is_empty() {
+		return None;
+	}
+
+	Some(StrokeStyle { color, opacity, square_cap })
+}
+
+
+/// Splits a stroke into runs of segments with the same (rounded) width and opacity.
+/// Each run starts where the previous one ended, so there are no gaps.  A single point (a dot) is a run with the point twice,
+/// which gives the line cap something to draw.
+pub fn stroke_runs(line: &Line) -> Vec<Run> {
+	let mut runs: Vec<Run> = Vec::new();
+	let mut last_width = line.thickness;
+
+	for (i, point) in line.points.iter().enumerate() {
+		let (width, opacity) = segment_style(line, point, last_width);
+		last_width = width;
+		let width = (width.max(0.2) * 10.0).round() / 10.0;
+		let opacity = (opacity * 10.0).round() / 10.0;
+
+		match runs.last_mut() {
+			Some(run) if run.width == width && run.opacity == opacity => run.points.push((point.x, point.y)),
+			_ => {
+				let start = match runs.last() {
+					Some(run) => *run.points.last().expect("unexpected"),
+					None => (point.x, point.y),
+				};
+				let mut points = vec![start];
+				if i > 0 {
+					points.push((point.x, point.y));
+				}
+				runs.push(Run { width, opacity, points });
+			}
+		}
+	}
+
+	for run in &mut runs {
+		if run.points.len() == 1 {
+			run.points.push(run.points[0]);
+		}
+	}
+
+	runs
+}
+
+
+/// Width and opacity of the segment ending at `point`, as the tablet draws it for each pen
+fn segment_style(line: &Line, point: &Point, last_width: f32) -> (f32, f32) {
+	let base = line.thickness;
+	let speed = point.speed / 50.0;
+	let tilt = point.direction;
+	let pressure = point.pressure;
+	let width = point.width;
+
+	match line.pen {
+		Pen::Ballpoint => (0.5 + pressure + width - 0.5 * speed, 1.0),
+		Pen::Fineliner => (base.powf(2.1) * 1.3, 1.0),
+		Pen::Marker => (0.9 * (width - 0.4 * tilt) + 0.1 * last_width, 1.0),
+		Pen::Pencil => {
+			let width = 0.7 * ((0.8 * base + 0.5 * pressure) * width - 0.25 * tilt.powf(1.8) - 0.6 * speed);
+			(width, (pressure - 0.1 * speed).clamp(0.2, 1.0))
+		}
+		Pen::MechanicalPencil => (base * base, 1.0),
+		Pen::Brush => (0.7 * ((1.0 + 1.4 * pressure) * width - 0.5 * tilt - 0.5 * speed), 1.0),
+		Pen::Calligraphy => (0.9 * ((1.0 + pressure) * width - 0.3 * tilt) + 0.1 * last_width, 1.0),
+		Pen::Highlighter => (15.0, 1.0),
+		Pen::Shader => (12.0, 1.0),
+		Pen::Eraser => (width, 1.0),
+		Pen::EraseArea | Pen::Unknown(_) => (base * 2.0, 1.0),
+	}
+}
+
+
+fn pen_color(line: &Line) -> &'static str {
+	match line.color {
+		Color::Black => "#000000",
+		Color::Grey | Color::GreyOverlap => "#8c8c8c",
+		Color::White => "#ffffff",
+		Color::Yellow | Color::Yellow2 => "#ffe600",
+		Color::Green | Color::Green2 => "#00b050",
+		Color::Pink => "#ff4fa3",
+		Color::Blue => "#2b5fd9",
+		Color::Red => "#e02424",
+		Color::Cyan => "#00b8d4",
+		Color::Magenta => "#c000c0",
+		Color::Highlight => highlight_color(line),
+		Color::Unknown(_) => "#000000",
+	}
+}
+
+
+/// Highlighter strokes use a lighter palette; "black" is the default yellow
+fn highlight_color(line: &Line) -> &'static str {
+	match (line.color, line.argb) {
+		(Color::Highlight, Some(argb)) => argb_color(argb),
+		(color, _) => highlighter_color(color),
+	}
+}
+
+
+pub fn highlighter_color(color: Color) -> &'static str {
+	match color {
+		Color::Green | Color::Green2 => "#a8f07a",
+		Color::Pink | Color::Magenta => "#f6a0d0",
+		Color::Blue | Color::Cyan => "#8fd4f0",
+		Color::Grey | Color::GreyOverlap => "#c8c8c8",
+		Color::Red => "#f79a9a",
+		_ => "#fff35a",
+	}
+}
+
+
+/// The nearest highlighter colour to an ARGB value
+fn argb_color(argb: u32) -> &'static str {
+	let (r, g, b) = ((argb >> 16) & 0xff, (argb >> 8) & 0xff, argb & 0xff);
+
+	if r > 200 && g > 200 && b < 150 {
+		highlighter_color(Color::Yellow)
+	} else if g > r && g > b {
+		highlighter_color(Color::Green)
+	} else if b > r && b > g {
+		highlighter_color(Color::Blue)
+	} else if r > g && b > g {
+		highlighter_color(Color::Pink)
+	} else if r > g {
+		highlighter_color(Color::Red)
+	} else {
+		highlighter_color(Color::Grey)
+	}
+}
+
+
+/// "#rrggbb" to 0-1 components
+pub fn rgb(color: &str) -> (f32, f32, f32) {
+	let component = |i: usize| u8::from_str_radix(color.get(i..i + 2).unwrap_or("00"), 16).unwrap_or(0) as f32 / 255.0;
+	(component(1), component(3), component(5))
+}
+
+
+/// A line of typed text, positioned on the page
+pub struct TextLine {
+	pub x: f64,
+	/// Baseline
+	pub y: f64,
+	pub font_size: f64,
+	pub bold: bool,
+	pub text: String,
+}
+
+
+/// Lays out typed text as lines, wrapping paragraphs to the text's width.
+/// We don't have font metrics, so wrapping is a rough guess based on the font size.
+pub fn layout_text(text: &Text, x_offset: f64) -> Vec<TextLine> {
+	let mut lines = Vec::new();
+	let mut y = text.y;
+
+	for paragraph in &text.paragraphs {
+		let (font_size, bold, indent) = match paragraph.style {
+			ParagraphStyle::Heading => (50.0, true, 0.0),
+			ParagraphStyle::Bold => (32.0, true, 0.0),
+			ParagraphStyle::Bullet2 => (32.0, false, 40.0),
+			_ => (32.0, false, 0.0),
+		};
+		let line_height = font_size * 1.5;
+		let max_chars = ((text.width as f64 - indent) / (font_size * 0.5)).max(10.0) as usize;
+
+		for line in wrap(&format!("{}{}", paragraph_prefix(paragraph), paragraph.text), max_chars) {
+			y += line_height;
+			lines.push(TextLine {
+				x: text.x + x_offset + indent,
+				y,
+				font_size,
+				bold,
+				text: line,
+			});
+		}
+	}
+
+	lines
+}
+
+
+fn paragraph_prefix(paragraph: &Paragraph) -> &'static str {
+	match paragraph.style {
+		ParagraphStyle::Bullet => "• ",
+		ParagraphStyle::Bullet2 => "◦ ",
+		ParagraphStyle::Checkbox => "☐ ",
+		ParagraphStyle::CheckboxChecked => "☑ ",
+		_ => "",
+	}
+}
+
+
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+	let mut lines = vec![String::new()];
+
+	for word in text.split(' ') {
+		let current = lines.last_mut().expect("unexpected");
+
+		if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > max_chars {
+			lines.push(word.to_owned());
+		} else {
+			if !current.is_empty() {
+				current.push(' ');
+			}
+			current.push_str(word);
+		}
+	}
+
+	lines
+}
@@ -0,0 +1,128 @@
+//! Renders parsed `.rm` pages as SVG.
+use super::{
+	lines::{Line, Lines},
+	style::{self, PAGE_WIDTH},
+};
+use std::fmt::Write;
+
+
+pub struct SvgOptions {
+	/// Fill the page with white, rather than leaving it transparent
+	pub background: bool,
+}
+
+impl Default for SvgOptions {
+	fn default() -> Self {
+		SvgOptions { background: true }
+	}
+}
+
+
+/// Renders the visible layers of a page.
+/// Notebooks that scroll are taller than a page; the SVG grows to fit.
+pub fn render_svg(lines: &Lines, options: &SvgOptions) -> String {
+	let x_offset = style::x_offset(lines, PAGE_WIDTH);
+	let height = style::page_height(lines);
+	let mut body = String::new();
+
+	if let Some(text) = &lines.text {
+		for line in style::layout_text(text, x_offset) {
+			let _ = writeln!(
+				body,
+				r#"<text x="{:.2}" y="{:.2}" font-family="sans-serif" font-size="{}" font-weight="{}">{}</text>"#,
+				line.x,
+				line.y,
+				line.font_size,
+				if line.bold { "bold" } else { "normal" },
+				escape_xml(&line.text)
+			);
+		}
+	}
+
+	for layer in lines.layers.iter().filter(|layer| layer.visible) {
+		for highlight in &layer.highlights {
+			let color = style::highlighter_color(highlight.color);
+
+			for rect in &highlight.rectangles {
+				let _ = writeln!(
+					body,
+					r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}" opacity="0.3"/>"#,
+					rect.x + x_offset,
+					rect.y,
+					rect.w,
+					rect.h,
+					color
+				);
+			}
+		}
+
+		for line in &layer.lines {
+			render_line(&mut body, line, x_offset, None);
+		}
+	}
+
+	svg_document(&body, height, options)
+}
+
+
+/// Renders strokes with each group in one colour, rather than their own, e.g. to show what changed between two versions of a
+/// page.  Each group has the x offset of the page it came from.
+pub fn render_colored_svg(groups: &[(&[&Line], f64, &str)], height: f64, options: &SvgOptions) -> String {
+	let mut body = String::new();
+
+	for (lines, x_offset, color) in groups {
+		for line in lines.iter() {
+			render_line(&mut body, line, *x_offset, Some(color));
+		}
+	}
+
+	svg_document(&body, height, options)
+}
+
+
+fn svg_document(body: &str, height: f64, options: &SvgOptions) -> String {
+	let background = if options.background {
+		format!(r#"<rect width="100%" height="100%" fill="white"/>{}"#, "\n")
+	} else {
+		String::new()
+	};
+
+	format!(
+		"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n{}{}</svg>\n",
+		background,
+		body,
+		w = PAGE_WIDTH,
+		h = height
+	)
+}
+
+
+/// `color` replaces the stroke's own colour
+fn render_line(out: &mut String, line: &Line, x_offset: f64, color: Option<&str>) {
+	let stroke_style = match style::stroke_style(line) {
+		Some(stroke_style) => stroke_style,
+		None => return,
+	};
+
+	for run in style::stroke_runs(line) {
+		let points: Vec<String> = run.points.iter().map(|(x, y)| format!("{:.2},{:.2}", *x as f64 + x_offset, y)).collect();
+
+		let _ = writeln!(
+			out,
+			r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="{}" stroke-linecap="{}" stroke-linejoin="round" opacity="{:.2}"/>"#,
+			points.join(" "),
+			color.unwrap_or(stroke_style.color),
+			run.width,
+			if stroke_style.square_cap { "square" } else { "round" },
+			stroke_style.opacity * run.opacity
+		);
+	}
+}
+
+
+pub fn escape_xml(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
@@ -0,0 +1,164 @@
+//! Extracts a document's searchable text: the text of an imported PDF's pages or an EPUB's chapters, and text typed on the tablet.
+use super::{epub, lines::Lines, FileType, RmDocument};
+use hayro::{
+	hayro_interpret::{
+		font::{Glyph, GlyphRun},
+		hayro_cmap::BfString,
+		interpret_page, BlendMode, ClipPath, Context, Device, DrawMode, DrawProps, Image, ImageDrawProps, InterpreterCache, InterpreterSettings,
+		RectExt, SoftMask,
+	},
+	hayro_syntax::Pdf,
+	kurbo::{Affine, BezPath, Point},
+};
+use log::debug;
+
+
+/// Where some text was found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+	/// A page of the document (counting from 0), as the tablet shows it
+	Page(usize),
+	/// A chapter of an EPUB (counting from 0).  EPUBs don't have pages until the tablet lays them out.
+	Chapter(usize),
+}
+
+
+pub struct ExtractedText {
+	pub location: Location,
+	pub text: String,
+}
+
+
+/// Everything readable in a document.  Text that can't be extracted (e.g. a page with an unsupported font encoding) is skipped.
+pub fn extract_text(document: &RmDocument) -> Vec<ExtractedText> {
+	let mut extracted = Vec::new();
+
+	let pdf = match (document.file_type(), &document.pdf) {
+		(FileType::Pdf, Some(data)) => Pdf::new(data.clone())
+			.map_err(|err| debug!("Unable to load PDF of {} for text extraction: {:?}", document.id, err))
+			.ok(),
+		_ => None,
+	};
+
+	for (index, page) in document.pages.iter().enumerate() {
+		let mut text = String::new();
+
+		if let (Some(pdf), Some(source)) = (&pdf, document.source_page(index)) {
+			text.push_str(&pdf_page_text(pdf, source));
+		}
+
+		if let Some(typed) = page
+			.lines
+			.as_deref()
+			.and_then(|data| Lines::parse(data).ok())
+			.and_then(|lines| lines.text)
+		{
+			for paragraph in typed.paragraphs {
+				text.push('\n');
+				text.push_str(&paragraph.text);
+			}
+		}
+
+		let text = text.trim();
+		if !text.is_empty() {
+			extracted.push(ExtractedText {
+				location: Location::Page(index),
+				text: text.to_owned(),
+			});
+		}
+	}
+
+	if let (FileType::Epub, Some(data)) = (document.file_type(), &document.epub) {
+		match epub::chapter_text(data) {
+			Ok(chapters) => extracted.extend(
+				chapters
+					.into_iter()
+					.enumerate()
+					.filter(|(_, text)| !text.is_empty())
+					.map(|(index, text)| ExtractedText {
+						location: Location::Chapter(index),
+						text,
+					}),
+			),
+			Err(err) => debug!("Unable to extract text from EPUB of {}: {:?}", document.id, err),
+		}
+	}
+
+	extracted
+}
+
+
+/// The text of a page of a PDF, including invisible text (e.g. from OCR).
+/// PDFs position each glyph rather than storing lines of text, so spaces and line breaks are guessed from the gaps between glyphs.
+fn pdf_page_text(pdf: &Pdf, index: usize) -> String {
+	let page = match pdf.pages().get(index) {
+		Some(page) => page,
+		None => return String::new(),
+	};
+	let cache = InterpreterCache::new();
+	let mut context = Context::new(
+		Affine::IDENTITY,
+		page.intersected_crop_box().to_kurbo(),
+		&cache,
+		page.xref(),
+		InterpreterSettings::default(),
+	);
+	let mut collector = TextCollector::default();
+
+	interpret_page(page, &mut context, &mut collector);
+
+	collector.text
+}
+
+
+#[derive(Default)]
+struct TextCollector {
+	text: String,
+	/// Where the last glyph ended, and its size
+	last: Option<(Point, f64)>,
+}
+
+impl<'a> Device<'a> for TextCollector {
+	fn draw_glyph_run(&mut self, glyph_run: &GlyphRun<'_, 'a>, props: DrawProps<'a>, _draw_mode: &DrawMode) {
+		for glyph in glyph_run.glyphs() {
+			let unicode = match glyph.as_unicode() {
+				Some(unicode) => unicode,
+				None => continue,
+			};
+			// Glyphs are in a 1000 unit em square
+			let transform = props.transform * glyph.transform();
+			let origin = transform * Point::ORIGIN;
+			let size = (transform * Point::new(0.0, 1000.0)).distance(origin);
+			let advance = match &**glyph {
+				Glyph::Outline(outline) => outline.advance_width().unwrap_or(500.0) as f64,
+				_ => 500.0,
+			};
+
+			if let Some((last_end, last_size)) = self.last {
+				if (origin.y - last_end.y).abs() > last_size.max(size) * 0.5 {
+					self.text.push('\n');
+				} else if origin.x - last_end.x > last_size.max(size) * 0.2 && !self.text.ends_with(' ') {
+					self.text.push(' ');
+				}
+			}
+
+			match unicode {
+				BfString::Char(c) => self.text.push(c),
+				BfString::String(s) => self.text.push_str(&s),
+			}
+			self.last = Some((transform * Point::new(advance, 0.0), size));
+		}
+	}
+
+	fn draw_path(&mut self, _path: &BezPath, _props: DrawProps<'a>, _draw_mode: &DrawMode) {}
+
+	fn push_clip_path(&mut self, _clip_path: &ClipPath) {}
+
+	fn push_transparency_group(&mut self, _opacity: f32, _mask: Option<SoftMask<'a>>, _blend_mode: BlendMode) {}
+
+	fn draw_image(&mut self, _image: Image<'a, '_>, _props: ImageDrawProps<'a>) {}
+
+	fn pop_clip(&mut self) {}
+
+	fn pop_transparency_group(&mut self) {}
+}
@@ -0,0 +1,118 @@
+//! Checks the archives clients upload before they're stored, so that a buggy client can't commit a document that breaks every
+//! device that syncs it.
+use std::{
+	fmt,
+	io::{self, Cursor, Read},
+};
+use zip::ZipArchive;
+
+
+pub struct UploadLimits {
+	/// How much an archive may unpack to, in bytes
+	pub max_unpacked_size: u64,
+	/// How much larger than the archive itself its contents may be
+	pub max_compression_ratio: f64,
+}
+
+
+#[derive(Debug)]
+pub enum UploadError {
+	NotAZip(String),
+	Corrupt(String),
+	/// A path that's absolute, escapes the archive, or is otherwise odd
+	InvalidPath(String),
+	/// A path that doesn't belong to the document being uploaded
+	WrongDocument(String),
+	MissingContent,
+	InvalidContent(String),
+	TooLarge(u64),
+	TooCompressed(f64),
+}
+
+impl UploadError {
+	/// Whether the upload was rejected for its size rather than its contents
+	pub fn is_too_large(&self) -> bool {
+		matches!(self, UploadError::TooLarge(_) | UploadError::TooCompressed(_))
+	}
+}
+
+impl fmt::Display for UploadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			UploadError::NotAZip(err) => write!(f, "Not a zip archive: {}", err),
+			UploadError::Corrupt(err) => write!(f, "Corrupt zip archive: {}", err),
+			UploadError::InvalidPath(path) => write!(f, "Invalid path in archive: {:?}", path),
+			UploadError::WrongDocument(path) => write!(f, "{:?} isn't part of this document", path),
+			UploadError::MissingContent => write!(f, "Archive has no .content"),
+			UploadError::InvalidContent(err) => write!(f, "Invalid .content: {}", err),
+			UploadError::TooLarge(limit) => write!(f, "Archive unpacks to more than {} bytes", limit),
+			UploadError::TooCompressed(limit) => write!(f, "Archive unpacks to more than {} times its size", limit),
+		}
+	}
+}
+
+
+/// Checks that `data` is a well-formed archive of document `id`, within the limits
+pub fn validate_upload(id: &str, data: &[u8], limits: &UploadLimits) -> Result<(), UploadError> {
+	let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|err| UploadError::NotAZip(err.to_string()))?;
+	let content_path = format!("{}.content", id);
+	let mut content = None;
+
+	let ratio_limit = (data.len() as f64 * limits.max_compression_ratio) as u64;
+	let limit = limits.max_unpacked_size.min(ratio_limit);
+	let mut unpacked = 0;
+
+	for i in 0..archive.len() {
+		let mut file = archive.by_index(i).map_err(|err| UploadError::Corrupt(err.to_string()))?;
+		let path = file.name().to_owned();
+		check_path(id, &path)?;
+
+		// The sizes the archive claims can't be trusted, so count what actually comes out.  Reading each file to the end also
+		// checks its CRC.
+		let mut reader = (&mut file).take(limit - unpacked + 1);
+		let read = if path == content_path {
+			let mut buffer = Vec::new();
+			let read = reader.read_to_end(&mut buffer);
+			content = Some(buffer);
+			read.map(|read| read as u64)
+		} else {
+			io::copy(&mut reader, &mut io::sink())
+		};
+		unpacked += read.map_err(|err| UploadError::Corrupt(format!("{}: {}", path, err)))?;
+
+		if unpacked > limit {
+			return Err(if unpacked > limits.max_unpacked_size {
+				UploadError::TooLarge(limits.max_unpacked_size)
+			} else {
+				UploadError::TooCompressed(limits.max_compression_ratio)
+			});
+		}
+	}
+
+	match serde_json::from_slice(&content.ok_or(UploadError::MissingContent)?) {
+		Ok(serde_json::Value::Object(_)) => Ok(()),
+		Ok(_) => Err(UploadError::InvalidContent("Not a JSON object".to_owned())),
+		Err(err) => Err(UploadError::InvalidContent(err.to_string())),
+	}
+}
+
+
+/// Paths have to be relative, stay within the archive, and start with the document's ID (e.g. `{id}.content` or `{id}/{page}.rm`)
+fn check_path(id: &str, path: &str) -> Result<(), UploadError> {
+	// Directories end with a slash
+	let sane = !path.contains('\\')
+		&& !path.chars().any(char::is_control)
+		&& path
+			.strip_suffix('/')
+			.unwrap_or(path)
+			.split('/')
+			.all(|part| !part.is_empty() && part != "." && part != "..");
+
+	if !sane {
+		Err(UploadError::InvalidPath(path.to_owned()))
+	} else if !path.strip_prefix(id).is_some_and(|rest| rest.starts_with(['.', '/'])) {
+		Err(UploadError::WrongDocument(path.to_owned()))
+	} else {
+		Ok(())
+	}
+}
@@ -0,0 +1,617 @@
+//! Rules that file documents automatically, e.g. "move new documents whose name starts with Invoice to /Finance/Invoices", or
+//! "move documents in /Inbox that haven't changed in 30 days to /Archive".
+//!
+//! A rule runs on the document events it names, or every RULES_SCHEDULE_INTERVAL against every document for rules that are
+//! "scheduled".  When a document matches all of a rule's conditions, the rule's actions are applied in order, each as a new
+//! version that the tablets are notified of.  Changes rules make don't trigger rules themselves, so rules can't loop.
+use crate::{
+	database::{self, DbFileMetadata},
+	events::{DocumentEvent, EventBus, EventMessage, EventSource, Subscribe},
+	library, rmdoc, tags, tree,
+	webhooks::{self, ProcessPending, WebhookDispatcher},
+	RULES_SCHEDULE_INTERVAL,
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use chrono::Utc;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::{collections::HashMap, convert::TryFrom};
+
+
+/// The event name for rules that run on a schedule rather than on document events
+pub const SCHEDULED: &str = "scheduled";
+
+/// Event names rules can run on: the tablet's (DocAdded) and the server's (created, moved, ...)
+const EVENT_NAMES: &[&str] = &["DocAdded", "created", "trashed", "restored", "moved", "renamed", "updated", SCHEDULED];
+
+/// The most days the age conditions can be given in (about a century), so that they can be counted in seconds
+const MAX_DAYS: i64 = 36_600;
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Rule {
+	pub id: i64,
+	pub name: String,
+	pub enabled: bool,
+	/// Event names (see EVENT_NAMES) the rule runs on.  Empty means every event, but not the schedule.
+	pub events: Vec<String>,
+	pub conditions: Conditions,
+	pub actions: Vec<Action>,
+	pub date_created: i64,
+}
+
+
+/// What a document has to be like for a rule to apply to it.  Every condition that's given has to match.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+	/// A regular expression the document's name has to match, e.g. "^Invoice"
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	/// "pdf", "epub" or "notebook"
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub file_type: Option<String>,
+	/// Path of a folder the document has to be somewhere under, e.g. "/Inbox"
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub folder: Option<String>,
+	/// Tags the document has to have, all of them
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub tags: Vec<String>,
+	/// In bytes
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub min_size: Option<i64>,
+	/// In bytes
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_size: Option<i64>,
+	/// The document hasn't changed for at least this many days
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub older_than_days: Option<i64>,
+	/// The document has changed in the last this many days
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub newer_than_days: Option<i64>,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+	/// Moves the document to the folder at a path, e.g. "/Finance/Invoices", creating the folders that don't exist
+	Move {
+		folder: String,
+	},
+	/// Renames the document.  `{name}` is replaced with its current name.
+	Rename {
+		name: String,
+	},
+	Tag {
+		tag: String,
+	},
+	Bookmark {
+		#[serde(default = "default_bookmarked")]
+		bookmarked: bool,
+	},
+	Trash,
+	/// Sends the rule, what set it off and the document to a webhook (see `webhooks`), which signs the delivery and retries it
+	/// like its own
+	Webhook {
+		webhook: i64,
+	},
+}
+
+fn default_bookmarked() -> bool {
+	true
+}
+
+
+impl Conditions {
+	/// The name condition, compiled
+	fn name_pattern(&self) -> Result<Option<Regex>> {
+		self.name.as_deref().map(Regex::new).transpose().context("Invalid name pattern")
+	}
+}
+
+
+impl Rule {
+	/// Why the rule can't work, if it can't
+	pub fn validate(&self) -> Result<(), String> {
+		if self.name.trim().is_empty() {
+			return Err("Rules need a name".to_owned());
+		}
+		if let Some(event) = self.events.iter().find(|event| !EVENT_NAMES.contains(&event.as_str())) {
+			return Err(format!("Unknown event {:?}; rules can run on {}", event, EVENT_NAMES.join(", ")));
+		}
+		if let Some(Err(err)) = self.conditions.name.as_deref().map(Regex::new) {
+			return Err(format!("Invalid name pattern: {}", err));
+		}
+		if let Some(file_type) = self
+			.conditions
+			.file_type
+			.as_deref()
+			.filter(|file_type| !["pdf", "epub", "notebook"].contains(file_type))
+		{
+			return Err(format!("Unknown file type {:?}; it can be pdf, epub or notebook", file_type));
+		}
+		for (condition, days) in [
+			("older_than_days", self.conditions.older_than_days),
+			("newer_than_days", self.conditions.newer_than_days),
+		] {
+			if days.is_some_and(|days| !(0..=MAX_DAYS).contains(&days)) {
+				return Err(format!("{} has to be from 0 to {}", condition, MAX_DAYS));
+			}
+		}
+		if self.actions.is_empty() {
+			return Err("Rules need at least one action".to_owned());
+		}
+
+		for action in &self.actions {
+			match action {
+				Action::Move { folder } if folder.chars().any(char::is_control) => return Err(format!("Invalid folder {:?}", folder)),
+				Action::Rename { name } if name.trim().is_empty() || name.chars().any(char::is_control) => {
+					return Err(format!("Invalid name {:?}", name))
+				}
+				Action::Tag { tag } if tag.trim().is_empty() || tag.chars().any(char::is_control) => return Err(format!("Invalid tag {:?}", tag)),
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+	fn runs_on(&self, event: &DocumentEvent) -> bool {
+		self.events.is_empty() || self.events.iter().any(|name| event.matches_name(name))
+	}
+
+	fn is_scheduled(&self) -> bool {
+		self.events.iter().any(|name| name == SCHEDULED)
+	}
+
+	/// Why the rule can't work, if it can't, including checks that need the database: that its webhooks exist
+	pub async fn validate_with(&self, db: &SqlitePool) -> Result<Result<(), String>> {
+		if let Err(err) = self.validate() {
+			return Ok(Err(err));
+		}
+
+		for action in &self.actions {
+			if let Action::Webhook { webhook } = action {
+				if webhooks::get_webhook(*webhook, db).await?.is_none() {
+					return Ok(Err(format!("No such webhook: {}", webhook)));
+				}
+			}
+		}
+
+		Ok(Ok(()))
+	}
+}
+
+
+#[derive(sqlx::FromRow)]
+struct RuleRow {
+	id: i64,
+	name: String,
+	enabled: bool,
+	/// Comma separated
+	events: String,
+	/// JSON
+	conditions: String,
+	/// JSON
+	actions: String,
+	date_created: i64,
+}
+
+impl TryFrom<RuleRow> for Rule {
+	type Error = anyhow::Error;
+
+	fn try_from(row: RuleRow) -> Result<Self> {
+		let id = row.id;
+
+		Ok(Rule {
+			id,
+			name: row.name,
+			enabled: row.enabled,
+			events: row.events.split(',').filter(|event| !event.is_empty()).map(str::to_owned).collect(),
+			conditions: serde_json::from_str(&row.conditions).with_context(|| format!("Conditions of rule {}", id))?,
+			actions: serde_json::from_str(&row.actions).with_context(|| format!("Actions of rule {}", id))?,
+			date_created: row.date_created,
+		})
+	}
+}
+
+
+pub async fn list_rules(db: &SqlitePool) -> Result<Vec<Rule>> {
+	sqlx::query_as::<_, RuleRow>("SELECT * FROM rules ORDER BY id")
+		.fetch_all(db)
+		.await
+		.context("Database")?
+		.into_iter()
+		.map(Rule::try_from)
+		.collect()
+}
+
+
+pub async fn get_rule(id: i64, db: &SqlitePool) -> Result<Option<Rule>> {
+	sqlx::query_as::<_, RuleRow>("SELECT * FROM rules WHERE id=?")
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")?
+		.map(Rule::try_from)
+		.transpose()
+}
+
+
+/// Adds a rule, ignoring its ID.  Returns the ID it's given.
+pub async fn add_rule(rule: &Rule, db: &SqlitePool) -> Result<i64> {
+	let result = sqlx::query("INSERT INTO rules (name,enabled,events,conditions,actions,date_created) VALUES (?,?,?,?,?,?)")
+		.bind(&rule.name)
+		.bind(rule.enabled)
+		.bind(rule.events.join(","))
+		.bind(serde_json::to_string(&rule.conditions)?)
+		.bind(serde_json::to_string(&rule.actions)?)
+		.bind(rule.date_created)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.last_insert_rowid())
+}
+
+
+/// Replaces the rule with `rule`'s ID.  Returns false if there isn't one.
+pub async fn update_rule(rule: &Rule, db: &SqlitePool) -> Result<bool> {
+	let result = sqlx::query("UPDATE rules SET name=?,enabled=?,events=?,conditions=?,actions=? WHERE id=?")
+		.bind(&rule.name)
+		.bind(rule.enabled)
+		.bind(rule.events.join(","))
+		.bind(serde_json::to_string(&rule.conditions)?)
+		.bind(serde_json::to_string(&rule.actions)?)
+		.bind(rule.id)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+
+pub async fn delete_rule(id: i64, db: &SqlitePool) -> Result<bool> {
+	let result = sqlx::query("DELETE FROM rules WHERE id=?")
+		.bind(id)
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+
+/// Check the scheduled rules against every document
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RunScheduled;
+
+
+/// Runs the rules: those that run on events as each event comes through the EventBus, and the scheduled ones every
+/// RULES_SCHEDULE_INTERVAL.  Rules run one document at a time, in the order things changed.
+pub struct RuleEngine {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	webhook_dispatcher: Addr<WebhookDispatcher>,
+}
+
+impl RuleEngine {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>, webhook_dispatcher: Addr<WebhookDispatcher>) -> Self {
+		Self {
+			db,
+			event_bus,
+			webhook_dispatcher,
+		}
+	}
+}
+
+impl Actor for RuleEngine {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		self.event_bus.do_send(Subscribe(ctx.address().recipient()));
+		ctx.run_interval(RULES_SCHEDULE_INTERVAL, |_act, ctx| ctx.notify(RunScheduled));
+	}
+}
+
+impl Handler<EventMessage> for RuleEngine {
+	type Result = ();
+
+	fn handle(&mut self, msg: EventMessage, ctx: &mut Self::Context) {
+		let event = msg.0.event;
+		if event.source.is_rule() || event.document.file_type == tree::COLLECTION_TYPE {
+			return;
+		}
+
+		let runner = Runner {
+			db: self.db.clone(),
+			event_bus: self.event_bus.clone(),
+			webhook_dispatcher: self.webhook_dispatcher.clone(),
+		};
+
+		ctx.wait(
+			async move {
+				if let Err(err) = runner.run_event(&event).await {
+					warn!("Unable to run rules for {}: {:?}", event.document.id, err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+impl Handler<RunScheduled> for RuleEngine {
+	type Result = ();
+
+	fn handle(&mut self, _: RunScheduled, ctx: &mut Self::Context) {
+		let runner = Runner {
+			db: self.db.clone(),
+			event_bus: self.event_bus.clone(),
+			webhook_dispatcher: self.webhook_dispatcher.clone(),
+		};
+
+		ctx.wait(
+			async move {
+				if let Err(err) = runner.run_scheduled().await {
+					warn!("Unable to run scheduled rules: {:?}", err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+
+struct Runner {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	webhook_dispatcher: Addr<WebhookDispatcher>,
+}
+
+impl Runner {
+	async fn run_event(&self, event: &DocumentEvent) -> Result<()> {
+		let rules: Vec<Rule> = list_rules(&self.db)
+			.await?
+			.into_iter()
+			.filter(|rule| rule.enabled && rule.runs_on(event))
+			.collect();
+
+		for rule in rules {
+			let pattern = rule.conditions.name_pattern()?;
+			// Earlier rules may have changed things
+			let metadata = database::list_metadata(&self.db).await?;
+			self.run_rule(&rule, pattern.as_ref(), &event.document.id, event.kind.as_str(), &metadata)
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	async fn run_scheduled(&self) -> Result<()> {
+		let rules: Vec<Rule> = list_rules(&self.db)
+			.await?
+			.into_iter()
+			.filter(|rule| rule.enabled && rule.is_scheduled())
+			.collect();
+		if rules.is_empty() {
+			return Ok(());
+		}
+
+		let mut applied = 0;
+
+		for rule in &rules {
+			let pattern = rule.conditions.name_pattern()?;
+			let metadata = database::list_metadata(&self.db).await?;
+			let documents = metadata.iter().filter(|x| x.file_type != tree::COLLECTION_TYPE);
+
+			for document in documents {
+				if self.run_rule(rule, pattern.as_ref(), &document.id, SCHEDULED, &metadata).await? {
+					applied += 1;
+				}
+			}
+		}
+
+		if applied > 0 {
+			info!("Scheduled rules applied to {} documents", applied);
+		}
+
+		Ok(())
+	}
+
+	/// Applies `rule` to document `id` if it matches.  `pattern` is the rule's compiled name condition, and `metadata` is the
+	/// library's, for matching; the actions use the document's latest version.  Returns whether the rule applied.
+	async fn run_rule(&self, rule: &Rule, pattern: Option<&Regex>, id: &str, trigger: &str, metadata: &[DbFileMetadata]) -> Result<bool> {
+		let document = match metadata.iter().find(|x| x.id == id) {
+			Some(document) => document,
+			None => return Ok(false),
+		};
+		// Rules leave the trash alone
+		if is_under(&document.parent, tree::TRASH, metadata) {
+			return Ok(false);
+		}
+
+		if !self.matches(&rule.conditions, pattern, document, metadata).await? {
+			return Ok(false);
+		}
+		debug!("Rule {} ({}) applies to {}", rule.id, rule.name, id);
+
+		for action in &rule.actions {
+			if let Err(err) = self.apply(rule, action, id, trigger).await {
+				warn!("Rule {} ({}) was unable to {:?} {}: {:?}", rule.id, rule.name, action, id, err);
+				break;
+			}
+		}
+
+		Ok(true)
+	}
+
+	async fn matches(
+		&self,
+		conditions: &Conditions,
+		pattern: Option<&Regex>,
+		document: &DbFileMetadata,
+		metadata: &[DbFileMetadata],
+	) -> Result<bool> {
+		if pattern.is_some_and(|pattern| !pattern.is_match(&document.name)) {
+			return Ok(false);
+		}
+
+		if let Some(path) = &conditions.folder {
+			match find_folder(path, metadata) {
+				Some(folder) if is_under(&document.parent, &folder, metadata) => (),
+				_ => return Ok(false),
+			}
+		}
+
+		let age = Utc::now().timestamp() - document.client_date_modified;
+		if conditions.older_than_days.is_some_and(|days| age < days * 24 * 60 * 60) {
+			return Ok(false);
+		}
+		if conditions.newer_than_days.is_some_and(|days| age >= days * 24 * 60 * 60) {
+			return Ok(false);
+		}
+
+		if conditions.min_size.is_some() || conditions.max_size.is_some() {
+			let size: Option<i64> = sqlx::query_scalar("SELECT LENGTH(data) FROM files WHERE id=? AND version=?")
+				.bind(&document.id)
+				.bind(document.version)
+				.fetch_optional(&self.db)
+				.await
+				.context("Database")?
+				.flatten();
+			let size = size.unwrap_or(0);
+
+			if conditions.min_size.is_some_and(|min| size < min) || conditions.max_size.is_some_and(|max| size > max) {
+				return Ok(false);
+			}
+		}
+
+		// The rest need the document itself
+		if conditions.file_type.is_none() && conditions.tags.is_empty() {
+			return Ok(true);
+		}
+
+		let content = match rmdoc::load_document(&document.id, Some(document.version), &self.db).await {
+			Ok(Some((_, loaded))) => loaded.content,
+			Ok(None) => return Ok(false),
+			Err(err) => {
+				debug!("Unable to read {} to check rules: {:?}", document.id, err);
+				return Ok(false);
+			}
+		};
+
+		if conditions.file_type.as_deref().is_some_and(|file_type| file_type != content.file_type) {
+			return Ok(false);
+		}
+
+		let tags = content.tags.unwrap_or_default();
+		Ok(conditions.tags.iter().all(|name| tags.iter().any(|tag| &tag.name == name)))
+	}
+
+	/// Applies an action to the document's latest version
+	async fn apply(&self, rule: &Rule, action: &Action, id: &str, trigger: &str) -> Result<()> {
+		let source = EventSource::rule(rule.id);
+		let document = database::get_metadata_by_id(id, &self.db)
+			.await?
+			.context("The document no longer exists")?;
+		let (mut name, mut parent, mut bookmarked) = (None, None, None);
+
+		match action {
+			Action::Move { folder } => {
+				let (folder, events) = library::folder_by_path(folder, tree::ROOT, source.clone(), &self.db)
+					.await?
+					.map_err(|err| anyhow::anyhow!("Invalid folder: {}", err))?;
+				for event in events {
+					event.publish(&self.event_bus);
+				}
+				parent = Some(folder).filter(|folder| *folder != document.parent);
+			}
+			Action::Rename { name: template } => {
+				name = Some(template.replace("{name}", &document.name).trim().to_owned()).filter(|name| *name != document.name);
+			}
+			Action::Tag { tag } => {
+				let (_, mut loaded) = rmdoc::load_document(id, Some(document.version), &self.db)
+					.await?
+					.context("The document has no data")?;
+
+				if tags::change_tags(&mut loaded.content, None, &[tag.trim().to_owned()], &[]) {
+					let event = library::update_document(&loaded, document.version, source, &self.db)
+						.await?
+						.context("The document changed, or a tablet is uploading a new version of it, while it was being tagged")?;
+					event.publish(&self.event_bus);
+				}
+				return Ok(());
+			}
+			Action::Bookmark { bookmarked: value } => bookmarked = Some(*value).filter(|value| *value != document.bookmarked),
+			Action::Trash => parent = Some(tree::TRASH.to_owned()).filter(|trash| *trash != document.parent),
+			Action::Webhook { webhook } => {
+				let payload = json!({
+					"rule": {"id": rule.id, "name": rule.name},
+					"trigger": trigger,
+					"date": Utc::now(),
+					"document": document,
+				});
+				webhooks::get_webhook(*webhook, &self.db)
+					.await?
+					.with_context(|| format!("No such webhook: {}", webhook))?;
+				webhooks::queue_delivery(*webhook, "rule", id, &payload, &self.db).await?;
+				self.webhook_dispatcher.do_send(ProcessPending);
+				return Ok(());
+			}
+		}
+
+		if name.is_none() && parent.is_none() && bookmarked.is_none() {
+			return Ok(());
+		}
+
+		let event = library::update_metadata(id, document.version, name, parent, bookmarked, source, &self.db)
+			.await?
+			.context("The document changed, or a tablet is uploading a new version of it, while the rule was being applied")?;
+		event.publish(&self.event_bus);
+
+		Ok(())
+	}
+}
+
+
+/// The ID of the folder at `path` (names separated by slashes, from the top level), if there is one
+fn find_folder(path: &str, metadata: &[DbFileMetadata]) -> Option<String> {
+	let mut folder = tree::ROOT.to_owned();
+
+	for name in path.split('/').map(str::trim).filter(|name| !name.is_empty()) {
+		folder = metadata
+			.iter()
+			.find(|x| x.file_type == tree::COLLECTION_TYPE && x.parent == folder && x.name == name)?
+			.id
+			.clone();
+	}
+
+	Some(folder)
+}
+
+
+/// Whether `parent` is `folder` or somewhere under it
+fn is_under(parent: &str, folder: &str, metadata: &[DbFileMetadata]) -> bool {
+	let parents: HashMap<&str, &str> = metadata.iter().map(|x| (x.id.as_str(), x.parent.as_str())).collect();
+	let mut current = parent;
+
+	// Bounded so that a cycle in the tree can't hang us
+	for _ in 0..256 {
+		if current == folder {
+			return true;
+		}
+		match parents.get(current) {
+			Some(parent) => current = parent,
+			None => return false,
+		}
+	}
+
+	false
+}
@@ -0,0 +1,380 @@
+use crate::{
+	database::{self, DbFileMetadata},
+	events::{DocumentEvent, DocumentEventKind, EventBus, EventMessage, Subscribe},
+	rmdoc::{
+		text::{self, ExtractedText, Location},
+		RmDocument,
+	},
+	tree::{self, FolderTree},
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use log::{debug, info, warn};
+use ring::digest;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+
+/// How hits mark the matched terms in their snippets
+const SNIPPET_START: &str = "[";
+const SNIPPET_END: &str = "]";
+
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct SearchHit {
+	pub id: String,
+	pub name: String,
+	/// The page (counting from 0) the text was found on.  None for matches on the document's name or folder, and in EPUB chapters.
+	pub page: Option<i64>,
+	/// The EPUB chapter (counting from 0) the text was found in
+	pub chapter: Option<i64>,
+	pub snippet: String,
+	/// Lower is better
+	pub rank: f64,
+}
+
+
+/// Searches document names, folder paths and text.  Every word of the query has to match; the last one can be the start of a word.
+/// Matches on names rank above matches on folders, which rank above matches in the text.
+pub async fn search(query: &str, limit: i64, db: &SqlitePool) -> Result<Vec<SearchHit>> {
+	let words: Vec<String> = query
+		.split_whitespace()
+		.map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+		.collect();
+	let fts_query = match words.split_last() {
+		Some((last, rest)) if !rest.is_empty() => format!("{} {}*", rest.join(" "), last),
+		Some((last, _)) => format!("{}*", last),
+		None => return Ok(Vec::new()),
+	};
+
+	// Hits take the name of the document's latest version; the index can briefly lag behind deletes, so those are left out
+	let hits: Vec<SearchHit> = sqlx::query_as(
+		"SELECT hit.id,files.name,hit.page,hit.chapter,hit.snippet,hit.rank FROM (SELECT id,page,chapter,snippet(search_index, -1, ?, ?, '…', 12) AS snippet,bm25(search_index, 0.0, 0.0, 0.0, 10.0, 5.0, 1.0) AS rank FROM search_index WHERE search_index MATCH ?) AS hit JOIN (SELECT MAX(version),id,name FROM files WHERE committed=1 AND deleted=0 GROUP BY id) AS files ON files.id=hit.id ORDER BY hit.rank",
+	)
+	.bind(SNIPPET_START)
+	.bind(SNIPPET_END)
+	.bind(&fts_query)
+	.fetch_all(db)
+	.await
+	.context("Database")?;
+
+	// Documents in the trash stay indexed so that they can be found again once they're restored, but aren't shown until then,
+	// so the limit is applied after they're left out
+	let tree = FolderTree::from_metadata(&database::list_metadata(db).await?);
+	Ok(hits
+		.into_iter()
+		.filter(|hit| !tree.is_trashed(&hit.id))
+		.take(limit.max(0) as usize)
+		.collect())
+}
+
+
+/// Catch up on anything that changed while the server wasn't running.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Reindex;
+
+
+/// Keeps the search index up to date: every committed version of a document is re-indexed as its event comes through the EventBus.
+/// Documents are indexed one at a time, in the order they changed.
+pub struct SearchIndexer {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+}
+
+impl SearchIndexer {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>) -> Self {
+		Self { db, event_bus }
+	}
+}
+
+impl Actor for SearchIndexer {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		self.event_bus.do_send(Subscribe(ctx.address().recipient()));
+		ctx.notify(Reindex);
+	}
+}
+
+impl Handler<EventMessage> for SearchIndexer {
+	type Result = ();
+
+	fn handle(&mut self, msg: EventMessage, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+
+		ctx.wait(
+			async move {
+				if let Err(err) = index_event(&msg.0.event, &db).await {
+					warn!("Unable to update the search index for {}: {:?}", msg.0.event.document.id, err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+impl Handler<Reindex> for SearchIndexer {
+	type Result = ();
+
+	fn handle(&mut self, _: Reindex, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+
+		ctx.wait(
+			async move {
+				if let Err(err) = reindex(&db).await {
+					warn!("Unable to update the search index: {:?}", err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+
+async fn index_event(event: &DocumentEvent, db: &SqlitePool) -> Result<()> {
+	if event.kind == DocumentEventKind::Deleted {
+		return remove_document(&event.document.id, db).await;
+	}
+
+	if event.document.file_type != tree::COLLECTION_TYPE {
+		return index_document(&event.document.id, db).await;
+	}
+
+	// Renaming or moving a folder changes the path of everything in it
+	if matches!(&event.previous, Some(previous) if previous.name != event.document.name || previous.parent != event.document.parent) {
+		let metadata = database::list_metadata(db).await?;
+		let tree = FolderTree::from_metadata(&metadata);
+		let documents: HashMap<&str, &DbFileMetadata> = metadata
+			.iter()
+			.filter(|x| x.file_type != tree::COLLECTION_TYPE)
+			.map(|x| (x.id.as_str(), x))
+			.collect();
+
+		let mut titles = Vec::new();
+		for id in tree.descendants(&event.document.id) {
+			if let Some(document) = documents.get(id.as_str()) {
+				titles.push((id, document.name.as_str(), folder_path(&document.parent, db).await?));
+			}
+		}
+
+		let mut tx = database::begin_immediate_transaction(db).await?;
+		for (id, name, path) in &titles {
+			replace_title(id, name, path, &mut tx).await?;
+		}
+		tx.commit().await?;
+	}
+
+	Ok(())
+}
+
+
+/// Indexes anything that changed, or was deleted, since it was last indexed.
+async fn reindex(db: &SqlitePool) -> Result<()> {
+	let documents: HashMap<String, i64> = database::list_metadata(db)
+		.await?
+		.into_iter()
+		.filter(|x| x.file_type != tree::COLLECTION_TYPE)
+		.map(|x| (x.id, x.version))
+		.collect();
+	let indexed: HashMap<String, i64> = sqlx::query_as::<_, (String, i64)>("SELECT id,version FROM search_documents")
+		.fetch_all(db)
+		.await
+		.context("Database")?
+		.into_iter()
+		.collect();
+	let mut count = 0;
+
+	for (id, version) in &documents {
+		if indexed.get(id) != Some(version) {
+			index_document(id, db).await?;
+			count += 1;
+		}
+	}
+
+	for id in indexed.keys().filter(|id| !documents.contains_key(*id)) {
+		remove_document(id, db).await?;
+	}
+
+	if count > 0 {
+		info!("Indexed {} documents for search", count);
+	}
+
+	Ok(())
+}
+
+
+/// (Re-)indexes the latest version of a document.  Its text is only extracted again if its data has changed since it was last indexed;
+/// metadata-only changes (renames, moves, the tablet saving the current page) just update its name and folder.
+async fn index_document(id: &str, db: &SqlitePool) -> Result<()> {
+	let metadata = match database::get_metadata_by_id(id, db).await? {
+		Some(metadata) => metadata,
+		None => return remove_document(id, db).await,
+	};
+	let indexed: Option<(i64, String)> = sqlx::query_as("SELECT version,data_hash FROM search_documents WHERE id=?")
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")?;
+	let path = folder_path(&metadata.parent, db).await?;
+
+	// The data to extract text from (None if it hasn't changed), and its hash (None if the version hasn't changed)
+	let (data, data_hash) = match &indexed {
+		Some((version, _)) if *version == metadata.version => (None, None),
+		_ => {
+			let data = database::get_data_by_id_version(id, metadata.version, db).await?.unwrap_or_default();
+			let data_hash = hex::encode(digest::digest(&digest::SHA256, &data));
+
+			match &indexed {
+				Some((_, indexed_hash)) if *indexed_hash == data_hash => (None, Some(data_hash)),
+				_ => (Some(data), Some(data_hash)),
+			}
+		}
+	};
+
+	// Extracting text is CPU heavy, so it's done on a blocking thread
+	let extracted = match data {
+		Some(data) if !data.is_empty() => {
+			let id = id.to_owned();
+			let extracted = actix_web::web::block(move || match RmDocument::from_zip(&data) {
+				Ok(document) => text::extract_text(&document),
+				Err(err) => {
+					debug!("Unable to read {} for indexing: {:?}", id, err);
+					Vec::new()
+				}
+			})
+			.await
+			.context("Extracting text")?;
+			Some(extracted)
+		}
+		Some(_) => Some(Vec::new()),
+		None => None,
+	};
+
+	let mut tx = database::begin_immediate_transaction(db).await?;
+
+	if let Some(extracted) = &extracted {
+		sqlx::query("DELETE FROM search_index WHERE id=?")
+			.bind(id)
+			.execute(&mut tx)
+			.await
+			.context("Database")?;
+		insert_text(id, extracted, &mut tx).await?;
+	}
+	replace_title(id, &metadata.name, &path, &mut tx).await?;
+
+	if let Some(data_hash) = data_hash {
+		sqlx::query("INSERT OR REPLACE INTO search_documents (id,version,data_hash) VALUES (?,?,?)")
+			.bind(id)
+			.bind(metadata.version)
+			.bind(data_hash)
+			.execute(&mut tx)
+			.await
+			.context("Database")?;
+	}
+
+	tx.commit().await?;
+
+	Ok(())
+}
+
+
+async fn remove_document(id: &str, db: &SqlitePool) -> Result<()> {
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	sqlx::query("DELETE FROM search_index WHERE id=?")
+		.bind(id)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+	sqlx::query("DELETE FROM search_documents WHERE id=?")
+		.bind(id)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+	tx.commit().await?;
+
+	Ok(())
+}
+
+
+/// A document's name and folder path are indexed as a row of their own, with no page or chapter.  Its text is the title and
+/// authors its PDF or EPUB gave, if any.
+async fn replace_title(id: &str, name: &str, path: &str, tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<()> {
+	sqlx::query("DELETE FROM search_index WHERE id=? AND page IS NULL AND chapter IS NULL")
+		.bind(id)
+		.execute(&mut *tx)
+		.await
+		.context("Database")?;
+	// Reading first would leave the transaction unable to write if another connection starts writing in between
+	let info: Option<(Option<String>, String)> = sqlx::query_as("SELECT title,authors FROM document_info WHERE id=?")
+		.bind(id)
+		.fetch_optional(&mut *tx)
+		.await
+		.context("Database")?;
+	let text = match info {
+		Some((title, authors)) => {
+			let authors: Vec<String> = serde_json::from_str(&authors).unwrap_or_default();
+			title.into_iter().chain(authors).collect::<Vec<_>>().join("\n")
+		}
+		None => String::new(),
+	};
+
+	sqlx::query("INSERT INTO search_index (id,page,chapter,name,path,text) VALUES (?,NULL,NULL,?,?,?)")
+		.bind(id)
+		.bind(name)
+		.bind(path)
+		.bind(text)
+		.execute(&mut *tx)
+		.await
+		.context("Database")?;
+
+	Ok(())
+}
+
+
+async fn insert_text(id: &str, extracted: &[ExtractedText], tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<()> {
+	for ExtractedText { location, text } in extracted {
+		let (page, chapter) = match location {
+			Location::Page(page) => (Some(*page as i64), None),
+			Location::Chapter(chapter) => (None, Some(*chapter as i64)),
+		};
+
+		sqlx::query("INSERT INTO search_index (id,page,chapter,name,path,text) VALUES (?,?,?,'','',?)")
+			.bind(id)
+			.bind(page)
+			.bind(chapter)
+			.bind(text)
+			.execute(&mut *tx)
+			.await
+			.context("Database")?;
+	}
+
+	Ok(())
+}
+
+
+/// e.g. "Work/Projects" for an item in the Projects folder; "Trash/..." for items in the trash.
+async fn folder_path(parent: &str, db: &SqlitePool) -> Result<String> {
+	let mut names = Vec::new();
+	let mut current = parent.to_owned();
+
+	// Bounded so that a cycle in the tree can't hang us
+	for _ in 0..256 {
+		if current == tree::TRASH {
+			names.push("Trash".to_owned());
+			break;
+		}
+
+		match database::get_metadata_by_id(&current, db).await? {
+			Some(metadata) => {
+				names.push(metadata.name);
+				current = metadata.parent;
+			}
+			None => break,
+		}
+	}
+
+	names.reverse();
+	Ok(names.join("/"))
+}
@@ -0,0 +1,388 @@
+//! Tags, which newer firmware (2.6+) keeps in each document's `.content`: tags on the whole document, and tags on its pages.
+//! They're indexed from every committed version so they can be listed and looked up, and can be changed from the server.
+use crate::{
+	database,
+	events::{DocumentEvent, DocumentEventKind, EventBus, EventMessage, Subscribe},
+	rmdoc::{self, Content, PageTag, Tag},
+	tree::{self, FolderTree},
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use chrono::Utc;
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::Map;
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+
+#[derive(Serialize)]
+pub struct TagCount {
+	pub name: String,
+	/// Number of documents with the tag
+	pub documents: usize,
+	/// Number of pages with the tag
+	pub pages: usize,
+}
+
+
+#[derive(Serialize)]
+pub struct TaggedDocument {
+	pub id: String,
+	pub name: String,
+	pub parent: String,
+}
+
+
+#[derive(Serialize)]
+pub struct TaggedPage {
+	pub id: String,
+	pub name: String,
+	pub parent: String,
+	/// Counting from 0
+	pub page: i64,
+	pub page_id: String,
+}
+
+
+#[derive(Serialize)]
+pub struct Tagged {
+	pub documents: Vec<TaggedDocument>,
+	pub pages: Vec<TaggedPage>,
+}
+
+
+/// A row of the tags table: a document's tag if `page_id` is None, otherwise one of its pages'
+#[derive(sqlx::FromRow)]
+struct TagRow {
+	id: String,
+	tag: String,
+	page_id: Option<String>,
+	page: Option<i64>,
+}
+
+
+/// Every tag on a document or page, with how many of each have it, by name.  Documents in the trash aren't counted.
+pub async fn list_tags(db: &SqlitePool) -> Result<Vec<TagCount>> {
+	let visible = visible_documents(db).await?;
+	let rows: Vec<TagRow> = sqlx::query_as("SELECT id,tag,page_id,page FROM tags")
+		.fetch_all(db)
+		.await
+		.context("Database")?;
+	let mut counts: BTreeMap<String, TagCount> = BTreeMap::new();
+
+	for row in rows.into_iter().filter(|row| visible.contains_key(&row.id)) {
+		let name = row.tag;
+		let count = counts.entry(name.clone()).or_insert_with(|| TagCount {
+			name,
+			documents: 0,
+			pages: 0,
+		});
+		match row.page_id {
+			Some(_) => count.pages += 1,
+			None => count.documents += 1,
+		}
+	}
+
+	Ok(counts.into_values().collect())
+}
+
+
+/// The documents and pages with `tag`, outside the trash.  Pages are in order within each document.
+pub async fn tagged(tag: &str, db: &SqlitePool) -> Result<Tagged> {
+	let visible = visible_documents(db).await?;
+	let mut rows: Vec<TagRow> = sqlx::query_as("SELECT id,tag,page_id,page FROM tags WHERE tag=?")
+		.bind(tag)
+		.fetch_all(db)
+		.await
+		.context("Database")?;
+	rows.sort_by(|a, b| (visible.get(&a.id), &a.id, a.page).cmp(&(visible.get(&b.id), &b.id, b.page)));
+
+	let mut tagged = Tagged {
+		documents: Vec::new(),
+		pages: Vec::new(),
+	};
+
+	for row in rows {
+		let (name, parent) = match visible.get(&row.id) {
+			Some(document) => document.clone(),
+			None => continue,
+		};
+
+		match (row.page_id, row.page) {
+			(Some(page_id), Some(page)) => tagged.pages.push(TaggedPage {
+				id: row.id,
+				name,
+				parent,
+				page,
+				page_id,
+			}),
+			_ => tagged.documents.push(TaggedDocument { id: row.id, name, parent }),
+		}
+	}
+
+	Ok(tagged)
+}
+
+
+/// Names and folders of the documents that aren't in the trash
+async fn visible_documents(db: &SqlitePool) -> Result<HashMap<String, (String, String)>> {
+	let metadata = database::list_metadata(db).await?;
+	let trashed: HashSet<String> = FolderTree::from_metadata(&metadata).descendants(tree::TRASH).into_iter().collect();
+
+	Ok(metadata
+		.into_iter()
+		.filter(|x| x.file_type != tree::COLLECTION_TYPE && !trashed.contains(&x.id))
+		.map(|x| (x.id, (x.name, x.parent)))
+		.collect())
+}
+
+
+/// Adds and removes tags on a document, or on one of its pages if `page_id` is given.  Tags it already has (or doesn't) are
+/// left alone.  Returns whether anything changed.
+pub fn change_tags(content: &mut Content, page_id: Option<&str>, add: &[String], remove: &[String]) -> bool {
+	let timestamp = Utc::now().timestamp_millis();
+	let mut changed = false;
+
+	match page_id {
+		None => {
+			let tags = content.tags.get_or_insert_with(Vec::new);
+			let count = tags.len();
+			tags.retain(|tag| !remove.contains(&tag.name));
+			changed |= tags.len() != count;
+
+			for name in add {
+				if !tags.iter().any(|tag| &tag.name == name) {
+					tags.push(Tag {
+						name: name.clone(),
+						timestamp,
+						extra: Map::new(),
+					});
+					changed = true;
+				}
+			}
+		}
+		Some(page_id) => {
+			let tags = content.page_tags.get_or_insert_with(Vec::new);
+			let count = tags.len();
+			tags.retain(|tag| tag.page_id != page_id || !remove.contains(&tag.name));
+			changed |= tags.len() != count;
+
+			for name in add {
+				if !tags.iter().any(|tag| tag.page_id == page_id && &tag.name == name) {
+					tags.push(PageTag {
+						name: name.clone(),
+						page_id: page_id.to_owned(),
+						timestamp,
+						extra: Map::new(),
+					});
+					changed = true;
+				}
+			}
+		}
+	}
+
+	changed
+}
+
+
+/// Catch up on anything that changed while the server wasn't running.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Reindex;
+
+
+/// Keeps the tags table up to date: every committed version of a document has its tags re-read as its event comes through the
+/// EventBus.
+pub struct TagIndexer {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+}
+
+impl TagIndexer {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>) -> Self {
+		Self { db, event_bus }
+	}
+}
+
+impl Actor for TagIndexer {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		self.event_bus.do_send(Subscribe(ctx.address().recipient()));
+		ctx.notify(Reindex);
+	}
+}
+
+impl Handler<EventMessage> for TagIndexer {
+	type Result = ();
+
+	fn handle(&mut self, msg: EventMessage, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+
+		ctx.wait(
+			async move {
+				if let Err(err) = index_event(&msg.0.event, &db).await {
+					warn!("Unable to update the tags of {}: {:?}", msg.0.event.document.id, err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+impl Handler<Reindex> for TagIndexer {
+	type Result = ();
+
+	fn handle(&mut self, _: Reindex, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+
+		ctx.wait(
+			async move {
+				if let Err(err) = reindex(&db).await {
+					warn!("Unable to update the tags: {:?}", err);
+				}
+			}
+			.into_actor(self),
+		);
+	}
+}
+
+
+async fn index_event(event: &DocumentEvent, db: &SqlitePool) -> Result<()> {
+	if event.kind == DocumentEventKind::Deleted {
+		return remove_document(&event.document.id, db).await;
+	}
+
+	if event.document.file_type != tree::COLLECTION_TYPE {
+		index_document(&event.document.id, db).await?;
+	}
+
+	Ok(())
+}
+
+
+/// Indexes anything that changed, or was deleted, since it was last indexed.
+async fn reindex(db: &SqlitePool) -> Result<()> {
+	let documents: HashMap<String, i64> = database::list_metadata(db)
+		.await?
+		.into_iter()
+		.filter(|x| x.file_type != tree::COLLECTION_TYPE)
+		.map(|x| (x.id, x.version))
+		.collect();
+	let indexed: HashMap<String, i64> = sqlx::query_as::<_, (String, i64)>("SELECT id,version FROM tag_documents")
+		.fetch_all(db)
+		.await
+		.context("Database")?
+		.into_iter()
+		.collect();
+	let mut count = 0;
+
+	for (id, version) in &documents {
+		if indexed.get(id) != Some(version) {
+			index_document(id, db).await?;
+			count += 1;
+		}
+	}
+
+	for id in indexed.keys().filter(|id| !documents.contains_key(*id)) {
+		remove_document(id, db).await?;
+	}
+
+	if count > 0 {
+		info!("Indexed the tags of {} documents", count);
+	}
+
+	Ok(())
+}
+
+
+/// Re-reads the tags of a document's latest version, unless they've already been read from it (e.g. it was only renamed).
+async fn index_document(id: &str, db: &SqlitePool) -> Result<()> {
+	let metadata = match database::get_metadata_by_id(id, db).await? {
+		Some(metadata) => metadata,
+		None => return remove_document(id, db).await,
+	};
+	let indexed: Option<i64> = sqlx::query_scalar("SELECT version FROM tag_documents WHERE id=?")
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")?;
+	if indexed == Some(metadata.version) {
+		return Ok(());
+	}
+
+	let content = match rmdoc::load_document(id, Some(metadata.version), db).await {
+		Ok(Some((_, document))) => Some(document.content),
+		Ok(None) => None,
+		Err(err) => {
+			debug!("Unable to read {} for its tags: {:?}", id, err);
+			None
+		}
+	};
+	let (tags, page_tags, page_ids) = match content {
+		Some(content) => {
+			let page_ids = content.page_ids();
+			(content.tags.unwrap_or_default(), content.page_tags.unwrap_or_default(), page_ids)
+		}
+		None => Default::default(),
+	};
+
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	sqlx::query("DELETE FROM tags WHERE id=?")
+		.bind(id)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+
+	for tag in tags {
+		sqlx::query("INSERT INTO tags (id,tag,page_id,page) VALUES (?,?,NULL,NULL)")
+			.bind(id)
+			.bind(tag.name)
+			.execute(&mut tx)
+			.await
+			.context("Database")?;
+	}
+
+	// Tags on deleted pages can linger in the .content
+	for tag in page_tags {
+		if let Some(page) = page_ids.iter().position(|page_id| *page_id == tag.page_id) {
+			sqlx::query("INSERT INTO tags (id,tag,page_id,page) VALUES (?,?,?,?)")
+				.bind(id)
+				.bind(tag.name)
+				.bind(tag.page_id)
+				.bind(page as i64)
+				.execute(&mut tx)
+				.await
+				.context("Database")?;
+		}
+	}
+
+	sqlx::query("INSERT OR REPLACE INTO tag_documents (id,version) VALUES (?,?)")
+		.bind(id)
+		.bind(metadata.version)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+	tx.commit().await?;
+
+	Ok(())
+}
+
+
+async fn remove_document(id: &str, db: &SqlitePool) -> Result<()> {
+	let mut tx = database::begin_immediate_transaction(db).await?;
+	sqlx::query("DELETE FROM tags WHERE id=?")
+		.bind(id)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+	sqlx::query("DELETE FROM tag_documents WHERE id=?")
+		.bind(id)
+		.execute(&mut tx)
+		.await
+		.context("Database")?;
+	tx.commit().await?;
+
+	Ok(())
+}
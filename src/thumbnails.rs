@@ -0,0 +1,194 @@
+use crate::{
+	database,
+	rmdoc::{
+		self,
+		raster::{self, ImageFormat},
+		FileType, RmDocument,
+	},
+	tree,
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{info, warn};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+
+/// How many thumbnails maintenance generates per run, so a large library doesn't hold up the rest of maintenance
+const MAX_GENERATED_PER_RUN: usize = 50;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThumbnailSize {
+	Small,
+	Medium,
+	Large,
+}
+
+impl ThumbnailSize {
+	pub fn parse(size: &str) -> Option<Self> {
+		match size {
+			"small" => Some(ThumbnailSize::Small),
+			"medium" => Some(ThumbnailSize::Medium),
+			"large" => Some(ThumbnailSize::Large),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ThumbnailSize::Small => "small",
+			ThumbnailSize::Medium => "medium",
+			ThumbnailSize::Large => "large",
+		}
+	}
+
+	/// Width in pixels; thumbnails have the proportions of the tablet's screen
+	pub fn width(&self) -> u16 {
+		match self {
+			ThumbnailSize::Small => 156,
+			ThumbnailSize::Medium => 312,
+			ThumbnailSize::Large => 702,
+		}
+	}
+}
+
+
+/// A cached thumbnail of a page of a specific version of a document.
+pub async fn get_cached(id: &str, version: i64, page: usize, size: ThumbnailSize, format: ImageFormat, db: &SqlitePool) -> Result<Option<Vec<u8>>> {
+	// Empty rows only mark versions maintenance couldn't make a thumbnail of
+	sqlx::query_as::<_, (Vec<u8>,)>("SELECT data FROM thumbnails WHERE id=? AND version=? AND page=? AND size=? AND format=? AND length(data) > 0")
+		.bind(id)
+		.bind(version)
+		.bind(page as i64)
+		.bind(size.as_str())
+		.bind(format.as_str())
+		.fetch_optional(db)
+		.await
+		.context("Database")
+		.map(|row| row.map(|(data,)| data))
+}
+
+
+/// Renders a thumbnail of a page of a document and caches it.  Returns None if the document doesn't have that page.
+/// Rendering is CPU heavy, so it's done on a blocking thread.
+pub async fn generate(
+	document: RmDocument,
+	version: i64,
+	page: usize,
+	size: ThumbnailSize,
+	format: ImageFormat,
+	db: &SqlitePool,
+) -> Result<Option<Vec<u8>>> {
+	let id = document.id.clone();
+	let data = actix_web::web::block(move || -> Result<Option<Vec<u8>>> {
+		match raster::render_page(&document, page, size.width())? {
+			Some(pixmap) => raster::encode(pixmap, format).map(Some),
+			None => Ok(None),
+		}
+	})
+	.await
+	.context("Rendering thumbnail")??;
+
+	let data = match data {
+		Some(data) => data,
+		None => return Ok(None),
+	};
+
+	cache(&id, version, page, size, format, &data, db).await?;
+
+	Ok(Some(data))
+}
+
+
+async fn cache(id: &str, version: i64, page: usize, size: ThumbnailSize, format: ImageFormat, data: &[u8], db: &SqlitePool) -> Result<()> {
+	sqlx::query("INSERT OR REPLACE INTO thumbnails (id, version, page, size, format, data, date_created) VALUES (?,?,?,?,?,?,?)")
+		.bind(id)
+		.bind(version)
+		.bind(page as i64)
+		.bind(size.as_str())
+		.bind(format.as_str())
+		.bind(data)
+		.bind(Utc::now().timestamp())
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	Ok(())
+}
+
+
+/// Drops thumbnails of old versions and deleted documents, and pre-renders the default thumbnail (first page, medium, PNG)
+/// of each document's latest version, so browsing clients rarely have to wait for one.
+pub async fn refresh(db: &SqlitePool) -> Result<()> {
+	let latest: HashMap<String, i64> = database::list_metadata(db)
+		.await?
+		.into_iter()
+		.filter(|x| x.file_type != tree::COLLECTION_TYPE)
+		.map(|x| (x.id, x.version))
+		.collect();
+	let cached: Vec<(String, i64, i64, String, String)> = sqlx::query_as("SELECT id,version,page,size,format FROM thumbnails")
+		.fetch_all(db)
+		.await
+		.context("Database")?;
+
+	for (id, version, page, size, format) in &cached {
+		if latest.get(id) != Some(version) {
+			sqlx::query("DELETE FROM thumbnails WHERE id=? AND version=? AND page=? AND size=? AND format=?")
+				.bind(id)
+				.bind(version)
+				.bind(page)
+				.bind(size)
+				.bind(format)
+				.execute(db)
+				.await
+				.context("Database")?;
+		}
+	}
+
+	let (size, format) = (ThumbnailSize::Medium, ImageFormat::Png);
+	let missing = latest.iter().filter(|(id, version)| {
+		!cached
+			.iter()
+			.any(|x| &x.0 == *id && x.1 == **version && x.2 == 0 && x.3 == size.as_str() && x.4 == format.as_str())
+	});
+	let (mut attempted, mut generated) = (0, 0);
+
+	for (id, version) in missing {
+		if attempted == MAX_GENERATED_PER_RUN {
+			break;
+		}
+
+		attempted += 1;
+
+		let document = match rmdoc::load_document(id, Some(*version), db).await {
+			Ok(Some((_, document))) if document.file_type() != FileType::Epub => Some(document),
+			Ok(_) => None,
+			Err(err) => {
+				warn!("Error loading {} for its thumbnail: {:?}", id, err);
+				None
+			}
+		};
+
+		let result = match document {
+			Some(document) => generate(document, *version, 0, size, format, db).await,
+			None => Ok(None),
+		};
+
+		match result {
+			Ok(Some(_)) => generated += 1,
+			// An empty row marks the version as done, so that it isn't tried again every run
+			Ok(None) => cache(id, *version, 0, size, format, &[], db).await?,
+			Err(err) => {
+				warn!("Error generating thumbnail for {}: {:?}", id, err);
+				cache(id, *version, 0, size, format, &[], db).await?;
+			}
+		}
+	}
+
+	if generated > 0 {
+		info!("Generated {} thumbnails", generated);
+	}
+
+	Ok(())
+}
@@ -0,0 +1,202 @@
+use crate::{
+	database::{self, DbFileMetadata},
+	events::{DocumentEvent, EventSource},
+	tree::{self, FolderTree},
+};
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::{collections::HashMap, fmt};
+
+
+#[derive(Serialize)]
+pub struct TrashItem {
+	#[serde(flatten)]
+	pub metadata: DbFileMetadata,
+	/// Where the item was before it was trashed, and where it will be restored to
+	pub previous_parent: String,
+	/// When the item was trashed.  For items trashed before the server kept track, this is the time of their last change.
+	pub date_trashed: i64,
+	/// Number of items under this one (for folders)
+	pub descendants: usize,
+	/// Size in bytes of this item and everything under it
+	pub size: i64,
+}
+
+
+#[derive(Serialize)]
+pub struct TrashStats {
+	/// Number of items directly in the trash
+	pub count: usize,
+	/// Number of items in the trash, including the contents of trashed folders
+	pub total_items: usize,
+	/// Size in bytes of everything in the trash
+	pub size: i64,
+}
+
+
+/// Items directly in the trash (i.e. not including the contents of trashed folders).
+pub async fn list_trash(db: &SqlitePool) -> Result<Vec<TrashItem>> {
+	let mut conn = db.acquire().await.context("Database")?;
+	let metadata = database::list_metadata(&mut *conn).await?;
+	let tree = FolderTree::from_metadata(&metadata);
+
+	trash_items(metadata, &tree, &mut conn).await
+}
+
+
+async fn trash_items(metadata: Vec<DbFileMetadata>, tree: &FolderTree, conn: &mut SqliteConnection) -> Result<Vec<TrashItem>> {
+	let sizes = database::list_data_sizes(&mut *conn).await?;
+	let records: HashMap<String, (String, i64)> = sqlx::query_as::<_, (String, String, i64)>("SELECT id,previous_parent,date_trashed FROM trash")
+		.fetch_all(&mut *conn)
+		.await
+		.context("Database")?
+		.into_iter()
+		.map(|(id, previous_parent, date_trashed)| (id, (previous_parent, date_trashed)))
+		.collect();
+
+	let items = metadata
+		.into_iter()
+		.filter(|x| x.parent == tree::TRASH)
+		.map(|x| {
+			let descendants = tree.descendants(&x.id);
+			let size = sizes.get(&x.id).copied().unwrap_or(0) + descendants.iter().map(|id| sizes.get(id).copied().unwrap_or(0)).sum::<i64>();
+			let (previous_parent, date_trashed) = records.get(&x.id).cloned().unwrap_or((tree::ROOT.to_owned(), x.client_date_modified));
+
+			TrashItem {
+				metadata: x,
+				previous_parent,
+				date_trashed,
+				descendants: descendants.len(),
+				size,
+			}
+		})
+		.collect();
+
+	Ok(items)
+}
+
+
+pub async fn trash_stats(db: &SqlitePool) -> Result<TrashStats> {
+	let items = list_trash(db).await?;
+
+	Ok(TrashStats {
+		count: items.len(),
+		total_items: items.iter().map(|x| x.descendants + 1).sum(),
+		size: items.iter().map(|x| x.size).sum(),
+	})
+}
+
+
+/// Moves an item out of the trash, back to where it was before it was trashed.
+/// If that folder no longer exists, or is in the trash itself, the item is restored to the root instead.
+pub async fn restore(id: &str, source: EventSource, db: &SqlitePool) -> Result<Result<DocumentEvent, RestoreError>> {
+	let mut tx = database::begin_immediate_transaction(db).await?;
+
+	let metadata = match database::get_metadata_by_id(id, &mut tx).await? {
+		Some(metadata) if metadata.parent == tree::TRASH => metadata,
+		_ => return Ok(Err(RestoreError::NotInTrash)),
+	};
+
+	// A tablet's upload of the next version would be committed along with the restore, before the tablet has finished with it
+	let latest: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM files WHERE id=? AND deleted=0")
+		.bind(id)
+		.fetch_one(&mut tx)
+		.await
+		.context("Database")?;
+	if latest != Some(metadata.version) {
+		return Ok(Err(RestoreError::UploadPending));
+	}
+
+	let previous_parent: Option<(String,)> = sqlx::query_as("SELECT previous_parent FROM trash WHERE id=?")
+		.bind(id)
+		.fetch_optional(&mut tx)
+		.await
+		.context("Database")?;
+	let tree = FolderTree::from_metadata(&database::list_metadata(&mut tx).await?);
+	let parent = match previous_parent {
+		Some((parent,)) if parent != tree::TRASH && !tree.is_trashed(&parent) && tree.validate_parent(id, &parent).is_ok() => parent,
+		_ => tree::ROOT.to_owned(),
+	};
+
+	let change = database::put_metadata(
+		metadata.id.clone(),
+		metadata.version + 1,
+		Utc::now().timestamp(),
+		None,
+		None,
+		None,
+		None,
+		Some(parent),
+		&mut tx,
+	)
+	.await?
+	.context("Conflict while restoring")?;
+
+	tx.commit().await.context("Database TX")?;
+
+	Ok(Ok(DocumentEvent::from_change(change, source)))
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestoreError {
+	NotInTrash,
+	/// A tablet is part way through uploading a new version of the item
+	UploadPending,
+}
+
+impl fmt::Display for RestoreError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			RestoreError::NotInTrash => write!(f, "Not in trash"),
+			RestoreError::UploadPending => write!(f, "A new version of the item is being uploaded; try again once it has synced"),
+		}
+	}
+}
+
+
+/// When items have to have been trashed before to have been in the trash for at least `days` days, or None if that's too long
+/// ago for a date
+pub fn trashed_before(days: u32) -> Option<i64> {
+	Utc::now().checked_sub_signed(Duration::days(days.into())).map(|date| date.timestamp())
+}
+
+
+/// Permanently deletes items in the trash, along with the contents of trashed folders, the same way the tablet's delete does.
+/// If `trashed_before` is given only items trashed before then are deleted.
+/// Returns the events for each deleted item, which the caller should publish.
+pub async fn empty(trashed_before: Option<i64>, source: EventSource, db: &SqlitePool) -> Result<Vec<DocumentEvent>> {
+	let mut events = Vec::new();
+	let mut tx = database::begin_immediate_transaction(db).await?;
+
+	// Read inside the transaction, so that nothing restored or moved in the meantime is deleted
+	let metadata = database::list_metadata(&mut tx).await?;
+	let tree = FolderTree::from_metadata(&metadata);
+	let items = trash_items(metadata, &tree, &mut tx).await?;
+
+	for item in items {
+		if trashed_before.is_some_and(|trashed_before| item.date_trashed >= trashed_before) {
+			continue;
+		}
+
+		let mut ids = vec![item.metadata.id.clone()];
+		ids.extend(tree.descendants(&item.metadata.id));
+
+		for id in ids {
+			let metadata = match database::get_metadata_by_id(&id, &mut tx).await? {
+				Some(metadata) => metadata,
+				None => continue,
+			};
+
+			if let Some(old_metadata) = database::delete_file(&id, metadata.version, &mut tx).await? {
+				events.push(DocumentEvent::deleted(old_metadata, source.clone()));
+			}
+		}
+	}
+
+	tx.commit().await.context("Database TX")?;
+
+	Ok(events)
+}
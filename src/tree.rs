@@ -0,0 +1,158 @@
+use crate::database::DbFileMetadata;
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+};
+
+
+/// Parent of items at the top level
+pub const ROOT: &str = "";
+/// The tablet moves items to the trash by setting their parent to this
+pub const TRASH: &str = "trash";
+pub const COLLECTION_TYPE: &str = "CollectionType";
+pub const DOCUMENT_TYPE: &str = "DocumentType";
+
+
+pub struct TreeNode {
+	pub file_type: String,
+	pub parent: String,
+}
+
+
+/// The folder structure, as described by each item's parent.
+pub struct FolderTree {
+	nodes: HashMap<String, TreeNode>,
+}
+
+impl FolderTree {
+	pub fn from_metadata(metadata: &[DbFileMetadata]) -> Self {
+		let nodes = metadata
+			.iter()
+			.map(|x| {
+				(
+					x.id.clone(),
+					TreeNode {
+						file_type: x.file_type.clone(),
+						parent: x.parent.clone(),
+					},
+				)
+			})
+			.collect();
+
+		Self { nodes }
+	}
+
+	/// All items under `id`, at any depth, parents before their children.
+	pub fn descendants(&self, id: &str) -> Vec<String> {
+		let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+		for (child_id, child) in &self.nodes {
+			children.entry(child.parent.as_str()).or_default().push(child_id.as_str());
+		}
+
+		let mut result = Vec::new();
+		// Guards against cycles
+		let mut visited = HashSet::from([id]);
+		let mut pending = vec![id];
+
+		while let Some(current) = pending.pop() {
+			for &child_id in children.get(current).into_iter().flatten() {
+				if visited.insert(child_id) {
+					result.push(child_id.to_owned());
+					pending.push(child_id);
+				}
+			}
+		}
+
+		result
+	}
+
+	/// Whether item `id` is in the trash, directly or inside a trashed folder.
+	pub fn is_trashed(&self, id: &str) -> bool {
+		let mut current = id;
+
+		// Bounded by the number of nodes so that a cycle can't hang us
+		for _ in 0..=self.nodes.len() {
+			match self.nodes.get(current) {
+				Some(node) if node.parent == TRASH => return true,
+				Some(node) => current = &node.parent,
+				None => return false,
+			}
+		}
+
+		false
+	}
+
+	/// The parent of item `id`, if there is such an item
+	pub fn parent(&self, id: &str) -> Option<&str> {
+		self.nodes.get(id).map(|node| node.parent.as_str())
+	}
+
+	/// Update (or add) an item, without any validation.  New items are documents unless `file_type` says otherwise.
+	pub fn set(&mut self, id: &str, file_type: Option<&str>, parent: Option<&str>) {
+		let node = self.nodes.entry(id.to_owned()).or_insert_with(|| TreeNode {
+			file_type: DOCUMENT_TYPE.to_owned(),
+			parent: ROOT.to_owned(),
+		});
+
+		if let Some(file_type) = file_type {
+			node.file_type = file_type.to_owned();
+		}
+
+		if let Some(parent) = parent {
+			node.parent = parent.to_owned();
+		}
+	}
+
+	pub fn remove(&mut self, id: &str) {
+		self.nodes.remove(id);
+	}
+
+	/// Checks whether item `id` can live under `parent`.
+	/// The parent must be the root, the trash, or an existing folder that isn't the item itself or one of its descendants.
+	pub fn validate_parent(&self, id: &str, parent: &str) -> Result<(), InvalidParent> {
+		if parent == ROOT || parent == TRASH {
+			return Ok(());
+		}
+
+		match self.nodes.get(parent) {
+			None => return Err(InvalidParent::Missing),
+			Some(node) if node.file_type != COLLECTION_TYPE => return Err(InvalidParent::NotAFolder),
+			Some(_) => (),
+		}
+
+		// Walk up from the new parent; if we run into the item it would become its own ancestor.
+		// Bounded by the number of nodes so that an existing cycle can't hang us.
+		let mut current = parent;
+
+		for _ in 0..=self.nodes.len() {
+			if current == id {
+				return Err(InvalidParent::Cycle);
+			}
+
+			match self.nodes.get(current) {
+				Some(node) => current = &node.parent,
+				None => return Ok(()),
+			}
+		}
+
+		Err(InvalidParent::Cycle)
+	}
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidParent {
+	Missing,
+	NotAFolder,
+	Cycle,
+}
+
+impl fmt::Display for InvalidParent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			InvalidParent::Missing => write!(f, "Parent does not exist"),
+			InvalidParent::NotAFolder => write!(f, "Parent is not a folder"),
+			InvalidParent::Cycle => write!(f, "Parent is the item itself or one of its descendants"),
+		}
+	}
+}
@@ -0,0 +1,310 @@
+use crate::{
+	database,
+	events::{DocumentEvent, EventBus, EventMessage, Subscribe},
+	notifications, WEBHOOK_DELIVERY_EXPIRATION, WEBHOOK_MAX_ATTEMPTS, WEBHOOK_RETRY_BASE_DELAY, WEBHOOK_RETRY_INTERVAL, WEBHOOK_TIMEOUT,
+};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use chrono::Utc;
+use log::{debug, warn};
+use ring::hmac;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Webhook {
+	pub id: i64,
+	pub url: String,
+	/// Comma separated list of event types (DocAdded, DocDeleted) and/or kinds (moved, trashed, ...) this webhook receives.  Empty means all events.
+	pub events: String,
+	/// Only deliver events for documents somewhere under this folder.  None means all documents.
+	pub folder: Option<String>,
+	#[serde(skip)]
+	pub secret: String,
+	pub date_created: i64,
+}
+
+impl Webhook {
+	fn wants_event(&self, event: &DocumentEvent) -> bool {
+		self.events.is_empty() || self.events.split(',').any(|x| event.matches_name(x.trim()))
+	}
+}
+
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct WebhookDelivery {
+	pub id: i64,
+	pub webhook_id: i64,
+	pub event: String,
+	pub document_id: String,
+	pub payload: String,
+	/// pending, delivered or failed
+	pub status: String,
+	pub attempts: i64,
+	pub next_attempt: i64,
+	pub last_status_code: Option<i64>,
+	pub last_error: Option<String>,
+	pub date_created: i64,
+}
+
+
+/// Tell the dispatcher to attempt any pending deliveries now, rather than waiting for the next retry interval.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ProcessPending;
+
+
+/// Receives events from the EventBus, queues a delivery for each matching webhook, and POSTs them.
+/// Deliveries are stored in the database, so failed deliveries are retried (with backoff) across restarts.
+pub struct WebhookDispatcher {
+	db: SqlitePool,
+	event_bus: Addr<EventBus>,
+	client: awc::Client,
+}
+
+impl WebhookDispatcher {
+	pub fn new(db: SqlitePool, event_bus: Addr<EventBus>) -> Self {
+		Self {
+			db,
+			event_bus,
+			client: awc::Client::builder().timeout(WEBHOOK_TIMEOUT).finish(),
+		}
+	}
+}
+
+impl Actor for WebhookDispatcher {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		self.event_bus.do_send(Subscribe(ctx.address().recipient()));
+
+		// Retry failed deliveries, and pick up anything left pending from a previous run
+		ctx.notify(ProcessPending);
+		ctx.run_interval(WEBHOOK_RETRY_INTERVAL, |_act, ctx| ctx.notify(ProcessPending));
+	}
+}
+
+impl Handler<EventMessage> for WebhookDispatcher {
+	type Result = ();
+
+	fn handle(&mut self, msg: EventMessage, ctx: &mut Self::Context) {
+		let db = self.db.clone();
+
+		async move { queue_deliveries(&msg.0.event, &db).await }
+			.into_actor(self)
+			.map(|result, _act, ctx| match result {
+				Ok(0) => (),
+				Ok(_) => ctx.notify(ProcessPending),
+				Err(err) => warn!("Unable to queue webhook deliveries: {:?}", err),
+			})
+			.spawn(ctx);
+	}
+}
+
+impl Handler<ProcessPending> for WebhookDispatcher {
+	type Result = ();
+
+	fn handle(&mut self, _: ProcessPending, _: &mut Self::Context) {
+		let db = self.db.clone();
+		let client = self.client.clone();
+
+		actix_web::rt::spawn(async move {
+			if let Err(err) = process_pending(&db, &client).await {
+				warn!("Error while delivering webhooks: {:?}", err);
+			}
+		});
+	}
+}
+
+
+/// Inserts a pending delivery for every webhook interested in this event.  Returns the number of deliveries queued.
+async fn queue_deliveries(event: &DocumentEvent, db: &SqlitePool) -> Result<usize> {
+	let webhooks = list_webhooks(db).await?;
+	let mut queued = 0;
+
+	for webhook in webhooks {
+		if !webhook.wants_event(event) {
+			continue;
+		}
+
+		if let Some(folder) = &webhook.folder {
+			if !is_in_folder(&event.document.parent, folder, db).await? {
+				continue;
+			}
+		}
+
+		let payload = json!({
+			"event": event.tablet_event(),
+			"kind": event.kind,
+			"date": Utc::now(),
+			"document": notifications::tablet_attributes(event),
+			"previous": event.previous,
+		});
+
+		queue_delivery(webhook.id, event.tablet_event(), &event.document.id, &payload, db).await?;
+		queued += 1;
+	}
+
+	Ok(queued)
+}
+
+
+/// Inserts a pending delivery of `payload` to a webhook.  The dispatcher sends it (signed, and retried if it fails) on its next
+/// run, or straight away if it's sent ProcessPending.
+pub async fn queue_delivery(webhook_id: i64, event: &str, document_id: &str, payload: &serde_json::Value, db: &SqlitePool) -> Result<()> {
+	let now = Utc::now().timestamp();
+
+	sqlx::query("INSERT INTO webhook_deliveries (webhook_id,event,document_id,payload,status,attempts,next_attempt,date_created) VALUES (?,?,?,?,?,?,?,?)")
+		.bind(webhook_id)
+		.bind(event)
+		.bind(document_id)
+		.bind(payload.to_string())
+		.bind("pending")
+		.bind(0)
+		.bind(now)
+		.bind(now)
+		.execute(db)
+		.await
+		.context("Insert webhook delivery")?;
+
+	Ok(())
+}
+
+
+/// Returns true if `parent` is `folder` or any of its descendants.
+async fn is_in_folder(parent: &str, folder: &str, db: &SqlitePool) -> Result<bool> {
+	let mut current = parent.to_owned();
+
+	// Bounded so that a cycle in the tree can't hang us
+	for _ in 0..256 {
+		if current == folder {
+			return Ok(true);
+		}
+
+		match database::get_metadata_by_id(&current, db).await? {
+			Some(metadata) => current = metadata.parent,
+			None => return Ok(false),
+		}
+	}
+
+	Ok(false)
+}
+
+
+async fn process_pending(db: &SqlitePool, client: &awc::Client) -> Result<()> {
+	let now = Utc::now().timestamp();
+
+	// Trim the delivery log
+	sqlx::query("DELETE FROM webhook_deliveries WHERE status != 'pending' AND date_created < ?")
+		.bind(now.checked_sub(WEBHOOK_DELIVERY_EXPIRATION).expect("Overflow"))
+		.execute(db)
+		.await
+		.context("Database")?;
+
+	let deliveries = sqlx::query_as::<_, WebhookDelivery>("SELECT * FROM webhook_deliveries WHERE status='pending' AND next_attempt <= ?")
+		.bind(now)
+		.fetch_all(db)
+		.await
+		.context("Database")?;
+
+	for delivery in deliveries {
+		// Lease the delivery so that an overlapping run doesn't send it twice.  The deliveries before it may have taken a while to
+		// send, so the lease starts now rather than when this run did.
+		let now = Utc::now().timestamp();
+		let leased = sqlx::query("UPDATE webhook_deliveries SET next_attempt=? WHERE id=? AND status='pending' AND next_attempt <= ?")
+			.bind(now + 2 * WEBHOOK_TIMEOUT.as_secs() as i64)
+			.bind(delivery.id)
+			.bind(now)
+			.execute(db)
+			.await
+			.context("Database")?;
+
+		if leased.rows_affected() == 0 {
+			continue;
+		}
+
+		let webhook = match get_webhook(delivery.webhook_id, db).await? {
+			Some(webhook) => webhook,
+			None => {
+				// The webhook was removed after this delivery was queued
+				sqlx::query("UPDATE webhook_deliveries SET status='failed', last_error=? WHERE id=?")
+					.bind("Webhook no longer exists")
+					.bind(delivery.id)
+					.execute(db)
+					.await
+					.context("Database")?;
+				continue;
+			}
+		};
+
+		let (status_code, error) = send(&webhook, &delivery, client).await;
+		let attempts = delivery.attempts + 1;
+		let (status, next_attempt) = if error.is_none() {
+			("delivered", now)
+		} else if attempts >= WEBHOOK_MAX_ATTEMPTS {
+			("failed", now)
+		} else {
+			("pending", Utc::now().timestamp() + WEBHOOK_RETRY_BASE_DELAY * (1 << (attempts - 1)))
+		};
+
+		debug!("Webhook delivery {} to {}: {} ({:?})", delivery.id, webhook.url, status, error);
+
+		sqlx::query("UPDATE webhook_deliveries SET status=?, attempts=?, next_attempt=?, last_status_code=?, last_error=? WHERE id=?")
+			.bind(status)
+			.bind(attempts)
+			.bind(next_attempt)
+			.bind(status_code)
+			.bind(error)
+			.bind(delivery.id)
+			.execute(db)
+			.await
+			.context("Database")?;
+	}
+
+	Ok(())
+}
+
+
+/// POSTs the delivery's payload, signed with the webhook's secret.
+/// Returns the response's status code (if we got that far) and an error message if the delivery failed.
+async fn send(webhook: &Webhook, delivery: &WebhookDelivery, client: &awc::Client) -> (Option<i64>, Option<String>) {
+	let key = hmac::Key::new(hmac::HMAC_SHA256, webhook.secret.as_bytes());
+	let signature = hex::encode(hmac::sign(&key, delivery.payload.as_bytes()).as_ref());
+
+	let response = client
+		.post(&webhook.url)
+		.insert_header(("Content-Type", "application/json"))
+		.insert_header(("X-Webhook-Event", delivery.event.as_str()))
+		.insert_header(("X-Webhook-Delivery", delivery.id.to_string()))
+		.insert_header(("X-Webhook-Signature", format!("sha256={}", signature)))
+		.send_body(delivery.payload.clone())
+		.await;
+
+	match response {
+		Ok(response) if response.status().is_success() => (Some(response.status().as_u16().into()), None),
+		Ok(response) => (
+			Some(response.status().as_u16().into()),
+			Some(format!("Unexpected status: {}", response.status())),
+		),
+		Err(err) => (None, Some(err.to_string())),
+	}
+}
+
+
+pub async fn list_webhooks(db: &SqlitePool) -> Result<Vec<Webhook>> {
+	sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks ORDER BY id")
+		.fetch_all(db)
+		.await
+		.context("Database")
+}
+
+
+pub async fn get_webhook(id: i64, db: &SqlitePool) -> Result<Option<Webhook>> {
+	sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE id=?")
+		.bind(id)
+		.fetch_optional(db)
+		.await
+		.context("Database")
+}
@@ -1,15 +1,25 @@
 import asyncio
 import aiohttp
+import aiohttp.web
+import base64
 import uuid
 from datetime import datetime, timezone
 import os
 import random
+import re
 import string
 import dateutil.parser
 import json
 import sqlite3
 import jwt
 import sys
+import hmac
+import hashlib
+import io
+import struct
+import subprocess
+import zipfile
+import zlib
 
 
 async def main():
@@ -28,6 +38,14 @@ async def main():
 		# Test NewUser API
 		user_token = await api_new_user(session, host, device_token)
 		auth_headers = {"Authorization": f"Bearer {user_token}"}
+		admin_headers = {"Authorization": f"Bearer {admin_token}"}
+
+		# Test rejecting broken uploads.  The other tests upload data that isn't an archive, so with the server's
+		# --validate-uploads, only this is run, by passing --validate-uploads here too.
+		validate_uploads = "--validate-uploads" in sys.argv[2:]
+		await test_upload_validation(session, host, auth_headers, validate_uploads)
+		if validate_uploads:
+			return
 
 		# Stress test
 		await test_stress(session, host, auth_headers)
@@ -36,6 +54,78 @@ async def main():
 		# Test that auth APIs are properly authed
 		await test_authorization(host, device_token, user_token)
 
+		# Test that the folder tree can't be broken
+		await test_invalid_parents(session, host, auth_headers)
+
+		# Test the trash
+		await test_trash(session, host, auth_headers, admin_headers)
+
+		# Test handwriting recognition against a mock recognition service
+		await test_handwriting_recognition(session, host, auth_headers)
+
+		# Test webhooks against a mock receiver
+		await test_webhooks(session, host, auth_headers, admin_headers)
+
+		# Test streaming and long-polling notifications
+		await test_notification_streams(session, host, auth_headers, admin_headers)
+
+		# Test the kinds of change events are published as
+		await test_event_kinds(session, host, auth_headers, admin_headers)
+
+		# Test reading and rewriting the tablet's document archives
+		await test_document_archives(session, host, auth_headers, admin_headers)
+
+		# Test rendering handwriting and typed text
+		await test_render_pages(session, host, auth_headers, admin_headers)
+
+		# Test exporting annotated PDFs
+		await test_export_pdf(session, host, auth_headers, admin_headers)
+
+		# Test rendering and caching thumbnails
+		await test_thumbnails(session, host, auth_headers, admin_headers)
+
+		# Test full-text search
+		await test_search(session, host, auth_headers, admin_headers)
+
+		# Test adding PDFs and EPUBs through the admin API
+		await test_add_documents(session, host, auth_headers, admin_headers)
+
+		# Test converting files the tablet can't open
+		await test_conversion(session, host, auth_headers, admin_headers)
+
+		# Test clipping web pages from a mock site
+		await test_clipper(session, host, auth_headers, admin_headers)
+
+		# Test exporting typed text and highlights as Markdown
+		await test_markdown_export(session, host, auth_headers, admin_headers)
+
+		# Test comparing versions of a document
+		await test_diff_versions(session, host, auth_headers, admin_headers)
+
+		# Test creating folders and notebooks
+		await test_create_folders_and_notebooks(session, host, auth_headers, admin_headers)
+
+		# Test merging, splitting and rearranging the pages of PDFs
+		await test_rearrange_pages(session, host, auth_headers, admin_headers)
+
+		# Test naming PDFs and EPUBs from their metadata
+		await test_document_info(session, host, auth_headers, admin_headers)
+
+		# Test listing and changing tags
+		await test_tags(session, host, auth_headers, admin_headers)
+
+		# Test filing documents with rules
+		await test_rules(session, host, auth_headers, admin_headers)
+
+		# Test scheduled and recurring deliveries
+		await test_deliveries(session, host, auth_headers, admin_headers)
+
+		# Test the daily journal
+		await test_journal(session, host, auth_headers, admin_headers)
+
+		# Test delivering planners made from calendars
+		await test_planners(session, host, auth_headers, admin_headers)
+
 		# Start notifications test
 		websocket_task = asyncio.create_task(websocket_watch(session, host, auth_headers))
 		
@@ -54,7 +144,7 @@ async def main():
 					"file_type": "CollectionType",
 					"current_page": random.randrange(4),
 					"bookmarked": random.randrange(2) == 0,
-					"parent": random.choice([x['id'] for x in state] + [""]),
+					"parent": random.choice([x['id'] for x in state if x['file_type'] == "CollectionType"] + [""]),
 					"date": datetime.now(timezone.utc),
 					"version": 1,
 				}
@@ -99,7 +189,8 @@ async def main():
 				file = random.choice(state)
 				parent = random.choice(state)
 
-				if file == parent:
+				# The server rejects moves into documents and into the item's own subtree
+				if parent['file_type'] != "CollectionType" or is_descendant(state, parent, file):
 					continue
 				
 				file['date'] = datetime.now(timezone.utc)
@@ -266,6 +357,9 @@ async def test_authorization(host, device_token, user_token):
 		lambda session: api_update_metadata(session, host, bad_auth_headers, "", 2, file_type="DocumentType"),
 		lambda session: api_delete_file(session, host, bad_auth_headers, "", 1),
 		lambda session: session.ws_connect(f"https://{host}/notifications/ws/json/1", headers=bad_auth_headers, ssl=False),
+		lambda session: session.get(f"https://{host}/notifications/sse/json/1", headers=bad_auth_headers, ssl=False),
+		lambda session: session.get(f"https://{host}/notifications/poll/json/1?timeout=1", headers=bad_auth_headers, ssl=False),
+		lambda session: session.post(f"https://{host}/api/v1/page", json={}, headers=bad_auth_headers, ssl=False),
 	]
 
 	for call in calls:
@@ -300,6 +394,1794 @@ async def test_stress(session, host, auth_headers):
 	])
 
 
+async def test_invalid_parents(session, host, auth_headers):
+	"""Moving items into documents, nonexistent folders or their own subtree should fail."""
+	folder = str(uuid.uuid4())
+	subfolder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+	date = datetime.now(timezone.utc)
+
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=date, file_type="CollectionType", name="folder", parent="")
+	await api_update_metadata(session, host, auth_headers, subfolder, 1, date=date, file_type="CollectionType", name="subfolder", parent=folder)
+	await api_update_metadata(session, host, auth_headers, document, 1, date=date, file_type="DocumentType", name="document", parent=subfolder)
+
+	for id, parent in [(document, document), (subfolder, document), (document, str(uuid.uuid4())), (folder, subfolder), (folder, folder)]:
+		req = [{"ID": id, "Version": 2, "ModifiedClient": date.isoformat(), "Parent": parent}]
+		async with session.put(f"https://{host}/document-storage/json/2/upload/update-status", json=req, headers=auth_headers, ssl=False) as resp:
+			j = await resp.json()
+			assert not j[0]['Success']
+
+	# A rejected move in a batch doesn't affect the rest of it
+	req = [
+		{"ID": folder, "Version": 2, "ModifiedClient": date.isoformat(), "Parent": subfolder},
+		{"ID": document, "Version": 2, "ModifiedClient": date.isoformat(), "Parent": folder},
+	]
+	async with session.put(f"https://{host}/document-storage/json/2/upload/update-status", json=req, headers=auth_headers, ssl=False) as resp:
+		j = await resp.json()
+		assert [x['Success'] for x in j] == [False, True]
+
+	# Items can be moved into folders created later in the same batch
+	new_folder = str(uuid.uuid4())
+	req = [
+		{"ID": document, "Version": 3, "ModifiedClient": date.isoformat(), "Parent": new_folder},
+		{"ID": new_folder, "Version": 1, "ModifiedClient": date.isoformat(), "Type": "CollectionType", "VissibleName": "new folder", "Parent": ""},
+	]
+	async with session.put(f"https://{host}/document-storage/json/2/upload/update-status", json=req, headers=auth_headers, ssl=False) as resp:
+		j = await resp.json()
+		assert [x['Success'] for x in j] == [True, True]
+
+	# An item whose folder has gone can still be updated where it is
+	await api_delete_file(session, host, auth_headers, new_folder, 1)
+	await api_update_metadata(session, host, auth_headers, document, 4, date=date, current_page=1, parent=new_folder)
+
+	# Trash and root are always valid
+	await api_update_metadata(session, host, auth_headers, document, 5, date=date, parent="trash")
+	await api_update_metadata(session, host, auth_headers, document, 6, date=date, parent="")
+
+	for id, version in [(document, 6), (subfolder, 1), (folder, 1)]:
+		await api_delete_file(session, host, auth_headers, id, version)
+
+
+async def test_upload_validation(session, host, auth_headers, validate_uploads):
+	"""With --validate-uploads, archives should be stored, and uploads that aren't archives of the document, or that unpack to
+	too much, rejected.  Without it, anything should be stored, as the other tests expect."""
+	async def upload(id, data):
+		req = [{"ID": id, "Version": 1, "Type": "DocumentType"}]
+		async with session.put(f"https://{host}/document-storage/json/2/upload/request", json=req, headers=auth_headers, ssl=False) as resp:
+			url = (await resp.json())[0]['BlobURLPut']
+		async with aiohttp.ClientSession() as unchecked_session:
+			async with unchecked_session.put(url, data=data, ssl=False) as resp:
+				return resp.status
+
+	probe = str(uuid.uuid4())
+	if not validate_uploads:
+		assert await upload(probe, b"not a zip") == 200, "The server has --validate-uploads: run the tests with --validate-uploads too"
+		await api_update_metadata(session, host, auth_headers, probe, 1, date=datetime.now(timezone.utc), file_type="DocumentType", name="probe", parent="")
+		await api_delete_file(session, host, auth_headers, probe, 1)
+		return
+	assert await upload(probe, b"not a zip") == 400
+
+	document = str(uuid.uuid4())
+	page = str(uuid.uuid4())
+	content = {"fileType": "notebook", "pageCount": 1, "pages": [page]}
+	data = make_archive(document, content, "Blank\n", {f"{document}/{page}.rm": rm_v5([[(100, 200), (150, 250)]])})
+	assert await upload(document, data) == 200
+	await api_update_metadata(session, host, auth_headers, document, 1, date=datetime.now(timezone.utc), file_type="DocumentType", name="valid", parent="")
+	assert await api_download_file(session, host, auth_headers, document) == data
+
+	def bomb(id):
+		buffer = io.BytesIO()
+		with zipfile.ZipFile(buffer, 'w', zipfile.ZIP_DEFLATED) as archive:
+			archive.writestr(f"{id}.content", json.dumps(content))
+			archive.writestr(f"{id}/{page}.rm", bytes(1024 * 1024))
+		return buffer.getvalue()
+
+	for make, status in [
+		(lambda id: make_archive(id, content, "", {"../escaped": b""}), 400),
+		(lambda id: make_archive(id, content, "", {f"{document}.content": b"{}"}), 400),
+		(lambda id: make_archive(id, None, "", {}), 400),
+		(lambda id: make_archive(id, [], "", {}), 400),
+		(lambda id: make_archive(id, content, "", {})[:100], 400),
+		(bomb, 413),
+	]:
+		rejected = str(uuid.uuid4())
+		assert await upload(rejected, make(rejected)) == status
+
+	await api_delete_file(session, host, auth_headers, document, 1)
+
+
+async def test_trash(session, host, auth_headers, admin_headers):
+	"""Trashed items should be listed with where they came from, go back there when restored, and be deleted when the trash is emptied."""
+	folder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+	date = datetime.now(timezone.utc)
+
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=date, file_type="CollectionType", name="folder", parent="")
+	await api_upload_file(session, host, auth_headers, document, 1, b"data")
+	await api_update_metadata(session, host, auth_headers, document, 1, date=date, file_type="DocumentType", name="document", parent=folder)
+	await api_update_metadata(session, host, auth_headers, document, 2, date=date, parent="trash")
+
+	async with session.get(f"https://{host}/admin/trash", headers=admin_headers, ssl=False) as resp:
+		items = await resp.json()
+		assert [(x['id'], x['previous_parent'], x['size']) for x in items] == [(document, folder, 4)]
+	async with session.get(f"https://{host}/admin/trash/stats", headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json()) == {"count": 1, "total_items": 1, "size": 4}
+
+	async with session.post(f"https://{host}/admin/trash/{document}/restore", headers=admin_headers, ssl=False) as resp:
+		restored = await resp.json()
+		assert restored['parent'] == folder and restored['version'] == 3
+
+	async with aiohttp.ClientSession() as bad_session:
+		# Only items in the trash can be restored
+		async with bad_session.post(f"https://{host}/admin/trash/{document}/restore", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 404
+
+		# An item can't be restored while the tablet is uploading a new version of it
+		await api_update_metadata(session, host, auth_headers, document, 4, date=date, parent="trash")
+		await api_upload_file(session, host, auth_headers, document, 5, b"new data")
+		async with bad_session.post(f"https://{host}/admin/trash/{document}/restore", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 409
+		await api_update_metadata(session, host, auth_headers, document, 5, date=date)
+
+	# An item whose folder has been trashed since is restored to the top level, where it can be seen
+	await api_update_metadata(session, host, auth_headers, folder, 2, date=date, parent="trash")
+	async with session.post(f"https://{host}/admin/trash/{document}/restore", headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['parent'] == ""
+	await api_update_metadata(session, host, auth_headers, document, 7, date=date, parent="trash")
+
+	# Only items that have been in the trash long enough are deleted
+	async with session.post(f"https://{host}/admin/trash/empty?older_than_days=1", headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['deleted'] == 0
+	async with aiohttp.ClientSession() as bad_session:
+		async with bad_session.post(f"https://{host}/admin/trash/empty?older_than_days=4294967295", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 400
+	async with session.post(f"https://{host}/admin/trash/empty", headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['deleted'] == 2
+
+	assert not {document, folder} & {x['ID'] for x in await api_list_files(session, host, auth_headers)}
+
+
+async def test_handwriting_recognition(session, host, auth_headers):
+	"""The tablet's strokes should reach the recognition service (with --hwr-engine http --hwr-url http://127.0.0.1:8085/recognize), and its text should come back as JIIX.
+	With --hwr-engine fixture only the JIIX is checked, and without an engine the test is skipped."""
+	received = []
+
+	async def recognize(request):
+		received.append(await request.json())
+		return aiohttp.web.json_response({"text": "hello world", "words": [{"label": "hello", "candidates": ["hello", "hallo"]}, {"label": " "}, {"label": "world"}]})
+
+	app = aiohttp.web.Application()
+	app.router.add_post("/recognize", recognize)
+	runner = aiohttp.web.AppRunner(app)
+	await runner.setup()
+	await aiohttp.web.TCPSite(runner, "127.0.0.1", 8085).start()
+
+	try:
+		req = {
+			"configuration": {"lang": "en_GB"},
+			"contentType": "Text",
+			"width": 1404,
+			"height": 1872,
+			"xDPI": 226,
+			"yDPI": 226,
+			"strokeGroups": [{"strokes": [
+				{"x": [1, 2, 3], "y": [4, 5, 6], "t": [1, 2, 3], "p": [0.5, 0.6, 0.7]},
+				{"x": [7, 8], "y": [9, 10]},
+			]}],
+		}
+		async with aiohttp.ClientSession() as unchecked_session:
+			async with unchecked_session.post(f"https://{host}/api/v1/page", json=req, headers={**auth_headers, "Accept": "application/vnd.myscript.jiix"}, ssl=False) as resp:
+				if resp.status == 501:
+					print("Skipping handwriting recognition: the server has no --hwr-engine")
+					return
+
+				resp.raise_for_status()
+				jiix = await resp.json(content_type=None)
+
+		assert jiix['type'] == "Text"
+
+		if len(received) == 0:
+			# The fixture engine, with the default --hwr-fixture-text
+			assert jiix['label'] == "hello world"
+		else:
+			assert len(received) == 1
+			assert received[0]['language'] == "en_GB"
+			assert received[0]['content_type'] == "Text"
+			assert [x['x'] for x in received[0]['strokes']] == [[1, 2, 3], [7, 8]]
+			assert received[0]['strokes'][1]['p'] == []
+
+			assert jiix['label'] == "hello world"
+			assert jiix['words'][0]['candidates'] == ["hello", "hallo"]
+
+		# Strokes that don't add up are rejected
+		req['strokeGroups'][0]['strokes'][0]['y'] = [1]
+		async with aiohttp.ClientSession() as bad_session:
+			async with bad_session.post(f"https://{host}/api/v1/page", json=req, headers=auth_headers, ssl=False) as resp:
+				assert resp.status == 400
+	finally:
+		await runner.cleanup()
+
+
+async def test_webhooks(session, host, auth_headers, admin_headers):
+	"""Events for documents in a webhook's folder should be POSTed to it, signed with its secret, and logged as delivered."""
+	received = []
+
+	async def hook(request):
+		received.append((request.headers['X-Webhook-Signature'], await request.read()))
+		return aiohttp.web.Response(text="OK")
+
+	app = aiohttp.web.Application()
+	app.router.add_post("/hook", hook)
+	runner = aiohttp.web.AppRunner(app)
+	await runner.setup()
+	await aiohttp.web.TCPSite(runner, "127.0.0.1", 8086).start()
+
+	folder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+	elsewhere = str(uuid.uuid4())
+	date = datetime.now(timezone.utc)
+
+	try:
+		await api_update_metadata(session, host, auth_headers, folder, 1, date=date, file_type="CollectionType", name="hooked", parent="")
+
+		req = {"url": "http://127.0.0.1:8086/hook", "events": ["DocAdded"], "folder": folder, "secret": "sekrit"}
+		async with session.post(f"https://{host}/admin/webhooks", json=req, headers=admin_headers, ssl=False) as resp:
+			webhook = (await resp.json())['id']
+		async with session.get(f"https://{host}/admin/webhooks", headers=admin_headers, ssl=False) as resp:
+			assert webhook in [x['id'] for x in await resp.json()]
+
+		# Only the document in the folder is delivered
+		await api_update_metadata(session, host, auth_headers, elsewhere, 1, date=date, file_type="DocumentType", name="elsewhere", parent="")
+		await api_update_metadata(session, host, auth_headers, document, 1, date=date, file_type="DocumentType", name="hooked document", parent=folder)
+
+		deliveries = await wait_for_deliveries(session, host, admin_headers, webhook, "delivered", 1)
+		assert [x['document_id'] for x in deliveries] == [document]
+		assert len(received) == 1
+		signature, body = received[0]
+		assert signature == "sha256=" + hmac.new(b"sekrit", body, hashlib.sha256).hexdigest()
+		payload = json.loads(body)
+		assert payload['event'] == "DocAdded"
+		assert payload['document']['id'] == document
+		assert payload['document']['vissibleName'] == "hooked document"
+
+		# Deliveries can be sent again
+		async with session.post(f"https://{host}/admin/webhooks/deliveries/{deliveries[0]['id']}/resend", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+		for _ in range(50):
+			if len(received) == 2:
+				break
+			await asyncio.sleep(0.1)
+		assert received[1][1] == body
+
+		async with aiohttp.ClientSession() as bad_session:
+			for req in [{"url": "ftp://127.0.0.1/hook"}, {"url": "http://127.0.0.1:8086/hook", "events": ["DocMoved"]}]:
+				async with bad_session.post(f"https://{host}/admin/webhooks", json=req, headers=admin_headers, ssl=False) as resp:
+					assert resp.status == 400
+			async with bad_session.post(f"https://{host}/admin/webhooks/deliveries/999999/resend", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+			async with bad_session.delete(f"https://{host}/admin/webhooks/999999", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+
+		async with session.delete(f"https://{host}/admin/webhooks/{webhook}", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+		async with session.get(f"https://{host}/admin/webhooks/{webhook}/deliveries", headers=admin_headers, ssl=False) as resp:
+			assert (await resp.json()) == []
+	finally:
+		await runner.cleanup()
+
+	for id in [document, elsewhere, folder]:
+		await api_delete_file(session, host, auth_headers, id, 1)
+
+
+async def wait_for_deliveries(session, host, admin_headers, webhook, status, count):
+	"""Polls a webhook's delivery log until it has `count` deliveries with `status`."""
+	for _ in range(50):
+		async with session.get(f"https://{host}/admin/webhooks/{webhook}/deliveries?status={status}", headers=admin_headers, ssl=False) as resp:
+			deliveries = await resp.json()
+		if len(deliveries) >= count:
+			return deliveries
+		await asyncio.sleep(0.1)
+
+	raise Exception(f"Timed out waiting for {status} webhook deliveries")
+
+
+async def test_notification_streams(session, host, auth_headers, admin_headers):
+	"""Document events should come over Server-Sent Events and long polling with the same sequence numbers, counting on from the
+	last event before them.  Reconnecting with Last-Event-ID, or asking for events since a sequence number, should replay the
+	ones missed since then, and a long poll with nothing to report should wait for its timeout and return none."""
+	async def poll(**params):
+		async with session.get(f"https://{host}/notifications/poll/json/1", params=params, headers=auth_headers, ssl=False) as resp:
+			return await resp.json()
+
+	async def read_events(resp, count):
+		events, fields = [], {}
+		while len(events) < count:
+			line = (await resp.content.readline()).decode().rstrip("\n")
+			if line == "" and fields:
+				events.append(fields)
+				fields = {}
+			elif line != "" and not line.startswith(":"):
+				key, _, value = line.partition(": ")
+				fields[key] = value
+		return events
+
+	base = (await poll(timeout=0))['next']
+	document = str(uuid.uuid4())
+	sse = f"https://{host}/notifications/sse/json/1?doc={document}"
+
+	async with session.get(sse, headers=auth_headers, ssl=False) as resp:
+		assert resp.content_type == "text/event-stream"
+		await api_upload_file(session, host, auth_headers, document, 1, b"streamed")
+		await api_update_metadata(session, host, auth_headers, document, 1, date=datetime.now(timezone.utc), file_type="DocumentType", name="Streamed", parent="")
+		[added] = await asyncio.wait_for(read_events(resp, 1), 10)
+	assert (added['id'], added['event']) == (str(base + 1), "DocAdded")
+	assert json.loads(added['data'])['message']['attributes']['vissibleName'] == "Streamed"
+
+	# A long poll waits for something to report, here a rename
+	waiting = asyncio.create_task(poll(since=base + 1, doc=document, timeout=10))
+	await asyncio.sleep(0.5)
+	await api_update_metadata(session, host, auth_headers, document, 2, date=datetime.now(timezone.utc), name="Renamed")
+	polled = await waiting
+	assert polled['next'] == base + 2
+	assert [(x['sequence'], x['event'], x['kind'], x['id']) for x in polled['events']] == [(base + 2, "DocAdded", "renamed", document)]
+	assert polled['events'][0]['notification']['message']['attributes']['vissibleName'] == "Renamed"
+
+	async with session.get(sse, headers={**auth_headers, "Last-Event-ID": str(base)}, ssl=False) as resp:
+		replayed = await asyncio.wait_for(read_events(resp, 2), 10)
+	assert [(x['id'], json.loads(x['data'])['message']['attributes']['vissibleName']) for x in replayed] == [(str(base + 1), "Streamed"), (str(base + 2), "Renamed")]
+	async with session.get(f"{sse}&since={base + 1}", headers=auth_headers, ssl=False) as resp:
+		assert [x['id'] for x in await asyncio.wait_for(read_events(resp, 1), 10)] == [str(base + 2)]
+	assert [x['sequence'] for x in (await poll(since=base, doc=document, timeout=0))['events']] == [base + 1, base + 2]
+
+	start = datetime.now()
+	assert await poll(since=base + 2, doc=document, timeout=1) == {"next": base + 2, "events": []}
+	assert (datetime.now() - start).total_seconds() >= 1
+
+	await api_delete_file(session, host, auth_headers, document, 2)
+
+
+async def test_event_kinds(session, host, auth_headers, admin_headers):
+	"""Each change the tablet makes through update-status should be published as the kind of change it is, along with the
+	version before it, and notifications can be filtered by kind."""
+	async def poll(**params):
+		async with session.get(f"https://{host}/notifications/poll/json/1", params=params, headers=auth_headers, ssl=False) as resp:
+			return await resp.json()
+
+	base = (await poll(timeout=0))['next']
+	folder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Kinds", parent="")
+	await api_update_metadata(session, host, auth_headers, document, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Before", parent="")
+	changes = [
+		({"name": "After"}, "renamed"),
+		({"parent": folder}, "moved"),
+		({"parent": "trash"}, "trashed"),
+		({"parent": folder}, "restored"),
+		({"bookmarked": True}, "updated"),
+	]
+	for version, (change, _) in enumerate(changes, 2):
+		await api_update_metadata(session, host, auth_headers, document, version, date=datetime.now(timezone.utc), **change)
+	await api_delete_file(session, host, auth_headers, document, len(changes) + 1)
+
+	polled = await poll(since=base, doc=document, timeout=0)
+	assert [(x['event'], x['kind']) for x in polled['events']] == [("DocAdded", "created")] + [("DocAdded", kind) for _, kind in changes] + [("DocDeleted", "deleted")]
+	assert [x['change']['previous']['parent'] for x in polled['events'][2:5]] == ["", folder, "trash"]
+	assert [x['change']['document']['parent'] for x in polled['events'][2:5]] == [folder, "trash", folder]
+	assert polled['events'][1]['change']['previous']['name'] == "Before" and polled['events'][1]['change']['document']['name'] == "After"
+
+	polled = await poll(since=base, doc=document, event="moved,trashed", timeout=0)
+	assert [x['kind'] for x in polled['events']] == ["moved", "trashed"]
+
+	await api_delete_file(session, host, auth_headers, folder, 1)
+
+
+async def test_document_archives(session, host, auth_headers, admin_headers):
+	"""A firmware 3.x notebook should be described page by page, and rewriting it (here to tag it) should keep everything the
+	server doesn't change, including its page list, and keep an older page list in step with it.  Uploads that aren't archives
+	can't be read."""
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(3)]
+	content = {
+		"fileType": "notebook",
+		"formatVersion": 2,
+		"pageCount": 2,
+		"cPages": {
+			"pages": [
+				{"id": pages[0], "idx": {"timestamp": "1:2", "value": "ba"}, "template": {"timestamp": "1:1", "value": "Blank"}},
+				{"id": pages[1], "idx": {"timestamp": "1:3", "value": "bb"}, "template": {"timestamp": "1:1", "value": "P Lines medium"}},
+				{"id": pages[2], "idx": {"timestamp": "1:4", "value": "bc"}, "deleted": {"timestamp": "1:5", "value": 1}},
+			],
+			"uuids": [{"first": "abc", "second": 1}],
+		},
+		"customZoomScale": 1.5,
+	}
+	files = {
+		f"{document}/{pages[0]}.rm": rm_v5([[(100, 200), (150, 250), (200, 300)]]),
+		f"{document}/{pages[0]}-metadata.json": json.dumps({"layers": [{"name": "Layer 1"}]}).encode(),
+		f"{document}.thumbnails/{pages[0]}.png": b"not really a PNG",
+	}
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\nP Lines medium\n", files), "archive")
+
+	async with session.get(f"https://{host}/admin/documents/{document}", headers=admin_headers, ssl=False) as resp:
+		info = await resp.json()
+		assert info['file_type'] == "notebook"
+		assert [(x['id'], x['template'], x['annotated']) for x in info['pages']] == [(pages[0], "Blank", True), (pages[1], "P Lines medium", False)]
+
+	async with session.post(f"https://{host}/admin/documents/{document}/tags", json={"add": ["Work"]}, headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['version'] == 2
+
+	archive = read_archive(await api_download_file(session, host, auth_headers, document))
+	for name, data in files.items():
+		if name.endswith(".json"):
+			assert json.loads(archive[name]) == json.loads(data)
+		else:
+			assert archive[name] == data
+	new_content = json.loads(archive[f"{document}.content"])
+	assert [x['name'] for x in new_content['tags']] == ["Work"]
+	assert new_content['customZoomScale'] == 1.5
+	assert new_content['cPages'] == content['cPages']
+	assert archive[f"{document}.pagedata"] == b"Blank\nP Lines medium\n"
+
+	# With both lists, cPages is the one the tablet reads, and `pages` is brought into line with it
+	both = str(uuid.uuid4())
+	both_content = {**content, "pages": [pages[1], pages[0]]}
+	await api_add_document(session, host, auth_headers, both, make_archive(both, both_content, "Blank\nP Lines medium\n", {}), "both")
+	async with session.get(f"https://{host}/admin/documents/{both}", headers=admin_headers, ssl=False) as resp:
+		assert [x['id'] for x in (await resp.json())['pages']] == pages[:2]
+	async with session.post(f"https://{host}/admin/documents/{both}/tags", json={"add": ["Work"]}, headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['version'] == 2
+	new_content = json.loads(read_archive(await api_download_file(session, host, auth_headers, both))[f"{both}.content"])
+	assert new_content['pages'] == pages[:2] and new_content['cPages'] == content['cPages']
+	await api_delete_file(session, host, auth_headers, both, 2)
+
+	async with aiohttp.ClientSession() as bad_session:
+		async with bad_session.get(f"https://{host}/admin/documents/{uuid.uuid4()}", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 404
+
+		for data in [b"not a zip", make_archive(document, None, "", {})]:
+			broken = str(uuid.uuid4())
+			await api_add_document(session, host, auth_headers, broken, data, "broken")
+			async with bad_session.get(f"https://{host}/admin/documents/{broken}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 500
+			await api_delete_file(session, host, auth_headers, broken, 1)
+
+	await api_delete_file(session, host, auth_headers, document, 2)
+
+
+async def test_render_pages(session, host, auth_headers, admin_headers):
+	"""Strokes and typed text in version 5 and 6 .rm files should be rendered as SVG.  Text with impossible lengths should be
+	left out, and files cut short should fail."""
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(6)]
+	content = {"fileType": "notebook", "pageCount": len(pages), "pages": pages}
+	v6 = rm_v6([(100, 200), (150, 250), (200, 300)], [("hello <world>", 0)])
+	files = {
+		f"{document}/{pages[0]}.rm": rm_v5([[(100, 200), (150, 250)], [(300, 400), (350, 450)]]),
+		f"{document}/{pages[1]}.rm": v6,
+		f"{document}/{pages[2]}.rm": v6[:len(v6) // 2],
+		# Text with a deleted run that claims to be 4 billion characters long
+		f"{document}/{pages[3]}.rm": rm_v6([(100, 200), (150, 250)], [("dropped", 0), (None, 0xFFFFFFFF)]),
+		# Text typed a character at a time
+		f"{document}/{pages[5]}.rm": rm_v6([], [("x", 0)] * 200000),
+	}
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\n" * len(pages), files), "rendered")
+
+	async with session.get(f"https://{host}/admin/documents/{document}/pages/0.svg", headers=admin_headers, ssl=False) as resp:
+		svg = await resp.text()
+		assert svg.count("<polyline") == 2
+	async with session.get(f"https://{host}/admin/documents/{document}/pages/1.svg", headers=admin_headers, ssl=False) as resp:
+		svg = await resp.text()
+		assert svg.count("<polyline") == 1
+		assert "hello &lt;world&gt;" in svg
+	# Blocks that don't make sense are left out, rather than the whole page failing
+	async with session.get(f"https://{host}/admin/documents/{document}/pages/3.svg", headers=admin_headers, ssl=False) as resp:
+		svg = await resp.text()
+		assert svg.count("<polyline") == 1
+		assert "<text" not in svg
+	# Nothing has been drawn on this page
+	async with session.get(f"https://{host}/admin/documents/{document}/pages/4.svg", headers=admin_headers, ssl=False) as resp:
+		assert "<polyline" not in await resp.text()
+	# Putting a lot of characters in order doesn't take long
+	start = datetime.now()
+	async with session.get(f"https://{host}/admin/documents/{document}/pages/5.svg", headers=admin_headers, ssl=False) as resp:
+		assert "x" * 200000 in await resp.text()
+	assert (datetime.now() - start).total_seconds() < 10
+
+	async with aiohttp.ClientSession() as bad_session:
+		for page, status in [(2, 500), (6, 404)]:
+			async with bad_session.get(f"https://{host}/admin/documents/{document}/pages/{page}.svg", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == status
+
+	await api_delete_file(session, host, auth_headers, document, 1)
+
+
+async def test_export_pdf(session, host, auth_headers, admin_headers):
+	"""A PDF should be exported with the strokes drawn over its pages, and the pages added on the tablet as blank pages, by both
+	the admin API and the `export-pdf` command, which is run from the repository with Cargo.  Earlier versions can be exported
+	too, and notebooks become blank pages with their typed text.  EPUBs, and documents and versions that don't exist, can't be
+	exported."""
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(3)]
+	content = {"fileType": "pdf", "pageCount": len(pages), "pages": pages, "redirectionPageMap": [0, -1, 1]}
+	files = {f"{document}.pdf": make_pdf(2), f"{document}/{pages[2]}.rm": rm_v5([[(100, 200), (150, 250)]])}
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\n" * len(pages), files), "Scanned")
+	files[f"{document}/{pages[0]}.rm"] = rm_v5([[(300, 400), (350, 450)]])
+	await api_upload_file(session, host, auth_headers, document, 2, make_archive(document, content, "Blank\n" * len(pages), files))
+	await api_update_metadata(session, host, auth_headers, document, 2, date=datetime.now(timezone.utc))
+
+	def overlays(pdf):
+		return sum(b" gs\n" in stream for stream in pdf_streams(pdf))
+
+	async def export(id, query=""):
+		async with session.get(f"https://{host}/admin/documents/{id}/export.pdf{query}", headers=admin_headers, ssl=False) as resp:
+			assert resp.content_type == "application/pdf"
+			return resp.headers['Content-Disposition'], await resp.read()
+
+	disposition, exported = await export(document)
+	assert disposition == 'attachment; filename="Scanned.pdf"'
+	# Pages added on the tablet are the size of the screen, 1404 pixels at 226 DPI
+	assert (pdf_page_widths(exported), overlays(exported)) == ([400, 447, 401], 2)
+	_, exported = await export(document, "?version=1")
+	assert (pdf_page_widths(exported), overlays(exported)) == ([400, 447, 401], 1)
+
+	notebook = str(uuid.uuid4())
+	page = str(uuid.uuid4())
+	content = {"fileType": "notebook", "pageCount": 1, "pages": [page]}
+	await api_add_document(session, host, auth_headers, notebook, make_archive(notebook, content, "Blank\n", {f"{notebook}/{page}.rm": rm_v6([], [("Agenda", 0)])}), "Meeting")
+	_, exported = await export(notebook)
+	assert pdf_page_widths(exported) == [447]
+	assert any(b"(Agenda) Tj" in stream for stream in pdf_streams(exported))
+
+	path = f"export-{document}.pdf"
+	command = ["cargo", "run", "-q", "--", "--db", "test.sqlite", "export-pdf", "--id", document, "--version", "1", "-o", path]
+	assert subprocess.run(command).returncode == 0
+	with open(path, "rb") as f:
+		exported = f.read()
+	os.remove(path)
+	assert (pdf_page_widths(exported), overlays(exported)) == ([400, 447, 401], 1)
+	command = ["cargo", "run", "-q", "--", "--db", "test.sqlite", "export-pdf", "--id", str(uuid.uuid4()), "-o", path]
+	assert subprocess.run(command, stderr=subprocess.DEVNULL).returncode != 0
+	assert not os.path.exists(path)
+
+	epub = str(uuid.uuid4())
+	content = {"fileType": "epub", "pageCount": 1, "pages": [str(uuid.uuid4())]}
+	await api_add_document(session, host, auth_headers, epub, make_archive(epub, content, "", {f"{epub}.epub": make_epub(None, {})}), "Novel")
+
+	async with aiohttp.ClientSession() as bad_session:
+		for id, query, status in [(epub, "", 400), (str(uuid.uuid4()), "", 404), (document, "?version=3", 404)]:
+			async with bad_session.get(f"https://{host}/admin/documents/{id}/export.pdf{query}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == status
+
+	await api_delete_file(session, host, auth_headers, document, 2)
+	await api_delete_file(session, host, auth_headers, notebook, 1)
+	await api_delete_file(session, host, auth_headers, epub, 1)
+
+
+async def test_thumbnails(session, host, auth_headers, admin_headers):
+	"""Thumbnails of a page of a notebook or PDF should come in three sizes, with the proportions of the screen, as PNG or WebP.
+	Each is cached for the version it was made from, so a new version gets new ones.  EPUBs, folders, and pages and documents
+	that don't exist have no thumbnails, and sizes and formats that aren't supported should be rejected."""
+	notebook = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(2)]
+	content = {"fileType": "notebook", "pageCount": len(pages), "pages": pages}
+	files = {f"{notebook}/{pages[1]}.rm": rm_v5([[(100, 200), (700, 900)]])}
+	await api_add_document(session, host, auth_headers, notebook, make_archive(notebook, content, "Blank\n" * len(pages), files), "Sketches")
+	pdf = str(uuid.uuid4())
+	content = {"fileType": "pdf", "pageCount": 1, "pages": [str(uuid.uuid4())]}
+	await api_add_document(session, host, auth_headers, pdf, make_archive(pdf, content, "Blank\n", {f"{pdf}.pdf": make_pdf(1)}), "Paper")
+
+	async def thumbnail(id, query=""):
+		async with session.get(f"https://{host}/admin/thumbnail/{id}{query}", headers=admin_headers, ssl=False) as resp:
+			return resp.content_type, await resp.read()
+
+	def png_size(data):
+		assert data.startswith(b"\x89PNG\r\n\x1a\n")
+		return struct.unpack(">II", data[16:24])
+
+	def cached(id):
+		conn = sqlite3.connect('test.sqlite')
+		rows = conn.execute("SELECT version,page,size,format FROM thumbnails WHERE id=? AND length(data) > 0 ORDER BY version,page,size,format", (id,)).fetchall()
+		conn.close()
+		return rows
+
+	content_type, blank = await thumbnail(notebook)
+	assert content_type == "image/png" and png_size(blank) == (312, 416)
+	_, drawn = await thumbnail(notebook, "?page=1")
+	assert png_size(drawn) == (312, 416) and drawn != blank
+	for size, dimensions in [("small", (156, 208)), ("large", (702, 936))]:
+		assert png_size((await thumbnail(notebook, f"?page=1&size={size}"))[1]) == dimensions
+	content_type, webp = await thumbnail(notebook, "?format=webp")
+	assert content_type == "image/webp" and webp[:4] == b"RIFF" and webp[8:12] == b"WEBP"
+	assert png_size((await thumbnail(pdf))[1]) == (312, 416)
+
+	# Asking again gives the cached thumbnail, and a new version gets its own
+	assert (await thumbnail(notebook, "?page=1"))[1] == drawn
+	assert (1, 1, "medium", "png") in cached(notebook)
+	files[f"{notebook}/{pages[0]}.rm"] = rm_v5([[(900, 200), (300, 1200)]])
+	await api_upload_file(session, host, auth_headers, notebook, 2, make_archive(notebook, {"fileType": "notebook", "pageCount": len(pages), "pages": pages}, "Blank\n" * len(pages), files))
+	await api_update_metadata(session, host, auth_headers, notebook, 2, date=datetime.now(timezone.utc))
+	assert (await thumbnail(notebook))[1] not in [blank, drawn]
+	assert (2, 0, "medium", "png") in cached(notebook)
+
+	folder = str(uuid.uuid4())
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Thumbnails", parent="")
+	epub = str(uuid.uuid4())
+	content = {"fileType": "epub", "pageCount": 1, "pages": [str(uuid.uuid4())]}
+	await api_add_document(session, host, auth_headers, epub, make_archive(epub, content, "", {f"{epub}.epub": make_epub(None, {})}), "Novel")
+
+	async with aiohttp.ClientSession() as bad_session:
+		for id, query, status in [
+			(notebook, "?size=huge", 400),
+			(notebook, "?format=gif", 400),
+			(epub, "", 400),
+			(notebook, "?page=2", 404),
+			(folder, "", 404),
+			(str(uuid.uuid4()), "", 404),
+		]:
+			async with bad_session.get(f"https://{host}/admin/thumbnail/{id}{query}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == status
+
+	await api_delete_file(session, host, auth_headers, notebook, 2)
+	await api_delete_file(session, host, auth_headers, pdf, 1)
+	await api_delete_file(session, host, auth_headers, epub, 1)
+	await api_delete_file(session, host, auth_headers, folder, 1)
+
+
+async def test_search(session, host, auth_headers, admin_headers):
+	"""Documents should be found by their name, their folder and the text typed on them, and renaming the folder should update
+	the index.  Documents in the trash shouldn't be found."""
+	folder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+	page = str(uuid.uuid4())
+	content = {"fileType": "notebook", "pageCount": 1, "pages": [page]}
+	files = {f"{document}/{page}.rm": rm_v6([], [("Budget for the zebrafish tanks", 0)])}
+
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Quarterly", parent="")
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\n", files), "Minutes", parent=folder)
+
+	hits = await wait_for_search(session, host, admin_headers, "zebrafish tank", lambda hits: len(hits) == 1)
+	assert (hits[0]['id'], hits[0]['name'], hits[0]['page']) == (document, "Minutes", 0)
+	assert "[zebrafish]" in hits[0]['snippet']
+
+	hits = await wait_for_search(session, host, admin_headers, "quarterly minutes", lambda hits: len(hits) == 1)
+	assert (hits[0]['id'], hits[0]['page']) == (document, None)
+
+	await api_update_metadata(session, host, auth_headers, folder, 2, date=datetime.now(timezone.utc), name="Annual")
+	await wait_for_search(session, host, admin_headers, "annual minutes", lambda hits: [x['id'] for x in hits] == [document])
+	await wait_for_search(session, host, admin_headers, "quarterly minutes", lambda hits: hits == [])
+
+	# Documents in the trash, here inside a trashed folder, aren't found until they're restored
+	await api_update_metadata(session, host, auth_headers, folder, 3, date=datetime.now(timezone.utc), parent="trash")
+	await wait_for_search(session, host, admin_headers, "zebrafish", lambda hits: hits == [])
+	await api_update_metadata(session, host, auth_headers, folder, 4, date=datetime.now(timezone.utc), parent="")
+	await wait_for_search(session, host, admin_headers, "zebrafish", lambda hits: [x['id'] for x in hits] == [document])
+
+	async with aiohttp.ClientSession() as bad_session:
+		for query in ["q=%20%20", "limit=5"]:
+			async with bad_session.get(f"https://{host}/admin/search?{query}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 400
+
+	await api_delete_file(session, host, auth_headers, document, 1)
+	await wait_for_search(session, host, admin_headers, "zebrafish", lambda hits: hits == [])
+	await api_delete_file(session, host, auth_headers, folder, 4)
+
+
+async def wait_for_search(session, host, admin_headers, query, done):
+	"""Searches until `done` is true of the hits, since the index is updated in the background."""
+	for _ in range(50):
+		async with session.get(f"https://{host}/admin/search", params={"q": query}, headers=admin_headers, ssl=False) as resp:
+			hits = await resp.json()
+		if done(hits):
+			return hits
+		await asyncio.sleep(0.1)
+
+	raise Exception(f"Unexpected search results for {query}: {hits}")
+
+
+async def test_add_documents(session, host, auth_headers, admin_headers):
+	"""A PDF or EPUB posted to the admin API should become version 1 of a new document in the folder asked for, with an archive
+	the tablet can open and its pages counted, and tablets should be told about it.  Folders that don't exist or aren't folders,
+	files that aren't documents, and requests without a name should be rejected."""
+	folder = str(uuid.uuid4())
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Inbox", parent="")
+	pdf = make_pdf(3)
+	epub = make_epub(None, {})
+
+	async def add(params, data, content_type):
+		async with session.post(f"https://{host}/admin/documents", params=params, data=data, headers={**admin_headers, "Content-Type": content_type}, ssl=False) as resp:
+			return await resp.json()
+
+	watch = asyncio.create_task(websocket_watch(session, host, auth_headers))
+	await asyncio.sleep(0.5)
+	added = [await add({"name": "Report", "folder": folder}, pdf, "application/pdf"), await add({"name": "Novel"}, epub, "application/epub+zip")]
+	await asyncio.sleep(0.5)
+	watch.cancel()
+	notifications = await watch
+
+	assert [{key: x[key] for key in ["version", "name", "parent", "file_type", "page_count"]} for x in added] == [
+		{"version": 1, "name": "Report", "parent": folder, "file_type": "pdf", "page_count": 3},
+		{"version": 1, "name": "Novel", "parent": "", "file_type": "epub", "page_count": 1},
+	]
+	assert [(x['event'], x['id'], x['vissibleName']) for x in notifications] == [("DocAdded", x['id'], x['name']) for x in added]
+	files = {x['ID']: x for x in await api_list_files(session, host, auth_headers)}
+	assert [(files[x['id']]['Version'], files[x['id']]['Parent'], files[x['id']]['FileType']) for x in added] == [(1, folder, "DocumentType"), (1, "", "DocumentType")]
+
+	for document, file_type, data in [(added[0], "pdf", pdf), (added[1], "epub", epub)]:
+		archive = read_archive(await api_download_file(session, host, auth_headers, document['id']))
+		content = json.loads(archive[f"{document['id']}.content"])
+		assert (content['fileType'], content['pageCount'], len(content['pages'])) == (file_type, document['page_count'], document['page_count'])
+		assert archive[f"{document['id']}.pagedata"].decode().count("\n") == document['page_count']
+		assert archive[f"{document['id']}.{file_type}"] == data
+
+	async with aiohttp.ClientSession() as bad_session:
+		for query, data in [(f"name=bad&folder={uuid.uuid4()}", pdf), (f"name=bad&folder={added[0]['id']}", pdf), ("name=bad", os.urandom(64)), ("folder=", pdf)]:
+			async with bad_session.post(f"https://{host}/admin/documents?{query}", data=data, headers={**admin_headers, "Content-Type": "application/octet-stream"}, ssl=False) as resp:
+				assert resp.status == 400
+
+	for document in added:
+		await api_delete_file(session, host, auth_headers, document['id'], 1)
+	await api_delete_file(session, host, auth_headers, folder, 1)
+
+
+async def test_conversion(session, host, auth_headers, admin_headers):
+	"""Markdown, HTML and plain text should become EPUBs that keep the text's structure, and images PDFs with a page for each.
+	Files that aren't any of these, or images that can't be read, should be rejected."""
+	added = []
+
+	async def add(name, data, content_type):
+		async with session.post(f"https://{host}/admin/documents", params={"name": name}, data=data, headers={**admin_headers, "Content-Type": content_type}, ssl=False) as resp:
+			document = await resp.json()
+		added.append(document['id'])
+		archive = read_archive(await api_download_file(session, host, auth_headers, document['id']))
+		return document, archive
+
+	def epub_text(document, archive):
+		assert document['file_type'] == "epub"
+		epub = read_archive(archive[f"{document['id']}.epub"])
+		assert epub['mimetype'] == b"application/epub+zip"
+		return epub['OEBPS/text.xhtml'].decode()
+
+	document, archive = await add("notes", "# Plans\n\nSome *emphasis* & a list:\n\n- one\n- two\n", "text/markdown")
+	text = epub_text(document, archive)
+	assert "<h1>Plans</h1>" in text and "<em>emphasis</em> &amp; a list" in text and "<li>one</li>" in text
+	assert "<title>notes</title>" in text
+
+	page = "<!DOCTYPE html><html lang=\"de\"><head><title>Page</title><script>alert(1)</script></head><body><h2>Überschrift</h2><p>Text<script>alert(2)</script></p></body></html>"
+	document, archive = await add("page", page.encode(), "application/octet-stream")
+	text = epub_text(document, archive)
+	assert "<h2>Überschrift</h2>" in text and "alert" not in text
+
+	document, archive = await add("plain", "first line\nsecond <line>\n\nnext paragraph", "text/plain")
+	assert "<p>first line<br/>\nsecond &lt;line&gt;</p>" in epub_text(document, archive)
+
+	images = io.BytesIO()
+	with zipfile.ZipFile(images, 'w') as z:
+		z.writestr("b.png", make_png(3, 2))
+		z.writestr("a.png", make_png(2, 3))
+	for data, pages in [(make_png(4, 4), 1), (images.getvalue(), 2)]:
+		document, archive = await add("scan", data, "application/octet-stream")
+		assert (document['file_type'], document['page_count']) == ("pdf", pages)
+		assert archive[f"{document['id']}.pdf"].startswith(b"%PDF-")
+
+	async with aiohttp.ClientSession() as bad_session:
+		empty_zip = io.BytesIO()
+		with zipfile.ZipFile(empty_zip, 'w') as z:
+			z.writestr("readme.txt", "no images here")
+		# Zeros compress to almost nothing, but together these unpack to more than the 1GiB a zip may
+		bomb = io.BytesIO()
+		with zipfile.ZipFile(bomb, 'w', zipfile.ZIP_DEFLATED) as z:
+			for name in ["a.png", "b.png"]:
+				with z.open(name, 'w', force_zip64=True) as entry:
+					entry.write(make_png(2, 2))
+					for _ in range(600):
+						entry.write(bytes(1024 * 1024))
+		for data in [os.urandom(64), make_png(4, 4)[:40], empty_zip.getvalue(), bomb.getvalue()]:
+			async with bad_session.post(f"https://{host}/admin/documents?name=bad", data=data, headers={**admin_headers, "Content-Type": "application/octet-stream"}, ssl=False) as resp:
+				assert resp.status == 400
+
+	for id in added:
+		await api_delete_file(session, host, auth_headers, id, 1)
+
+
+async def test_clipper(session, host, auth_headers, admin_headers):
+	"""Clipping a page should save its article, without the rest of the page, as an EPUB in the Articles folder.  With
+	--clip-fetch-origin http://127.0.0.1:8087, pages and images should be fetched from a mock site on that origin, following
+	redirects that stay on it; without it, only the page given is clipped and fetching is refused.  Requests with nothing to
+	clip, bad URLs and pages the site doesn't have should fail."""
+	origin = "http://127.0.0.1:8087"
+	photo = make_png(3, 2)
+	paragraphs = "".join(f"<p>Paragraph {i} of the story about the lighthouse keeper and the long winter on the island.</p>" for i in range(6))
+	page = (
+		'<html><head><title>The Lighthouse</title></head><body><nav><a href="/">Home</a> Subscribe to our newsletter</nav>'
+		f'<article><h1>The Lighthouse</h1>{paragraphs}<img src="/images/photo.png"></article><footer>Comments are closed</footer></body></html>'
+	)
+	requested = []
+
+	def route(path, response):
+		async def handler(request):
+			requested.append(path)
+			return response
+		app.router.add_get(path, handler)
+
+	app = aiohttp.web.Application()
+	route("/article", aiohttp.web.Response(text=page, content_type="text/html"))
+	route("/images/photo.png", aiohttp.web.Response(body=photo, content_type="image/png"))
+	route("/moved", aiohttp.web.Response(status=302, headers={"Location": "/article"}))
+	route("/away", aiohttp.web.Response(status=302, headers={"Location": "http://127.0.0.1:8086/article"}))
+	runner = aiohttp.web.AppRunner(app)
+	await runner.setup()
+	await aiohttp.web.TCPSite(runner, "127.0.0.1", 8087).start()
+
+	async def clip(request):
+		async with session.post(f"https://{host}/clip", json=request, headers=auth_headers, ssl=False) as resp:
+			clipping = await resp.json()
+		epub = read_archive(read_archive(await api_download_file(session, host, auth_headers, clipping['id']))[f"{clipping['id']}.epub"])
+		return clipping, epub
+
+	added = []
+	try:
+		embedded = page.replace("/images/photo.png", "data:image/png;base64," + base64.b64encode(photo).decode())
+		clipping, epub = await clip({"html": embedded, "url": f"{origin}/article", "title": "Keeper"})
+		added.append(clipping)
+		text = epub['OEBPS/text.xhtml'].decode()
+		assert clipping['name'] == "Keeper" and "Paragraph 5 of the story" in text
+		assert "newsletter" not in text and "Comments are closed" not in text
+		assert epub['OEBPS/images/1.png'] == photo
+		[folder] = [x for x in await api_list_files(session, host, auth_headers) if x['VissibleName'] == "Articles" and x['Parent'] == "" and x['FileType'] == "CollectionType"]
+		assert clipping['parent'] == folder['ID']
+
+		async with aiohttp.ClientSession() as bad_session:
+			async with bad_session.post(f"https://{host}/clip", json={"url": f"{origin}/moved"}, headers=auth_headers, ssl=False) as resp:
+				fetching = resp.status != 400
+				if not fetching:
+					assert "isn't allowed" in await resp.text()
+
+		if fetching:
+			clipping, epub = await clip({"url": f"{origin}/moved"})
+			added.append(clipping)
+			assert (clipping['name'], clipping['parent']) == ("The Lighthouse", folder['ID'])
+			assert "Paragraph 0 of the story" in epub['OEBPS/text.xhtml'].decode() and epub['OEBPS/images/1.png'] == photo
+			assert requested[-3:] == ["/moved", "/article", "/images/photo.png"]
+		else:
+			print("Skipping fetching pages to clip: the server has no --clip-fetch-origin http://127.0.0.1:8087")
+			assert requested == []
+
+		async with aiohttp.ClientSession() as bad_session:
+			for request, status in [
+				({}, 400),
+				({"url": "not a url"}, 400),
+				({"url": "http://127.0.0.1:8088/article"}, 400),
+				({"url": f"{origin}/away"}, 400),
+				({"url": f"{origin}/missing"}, 502 if fetching else 400),
+			]:
+				async with bad_session.post(f"https://{host}/clip", json=request, headers=auth_headers, ssl=False) as resp:
+					assert resp.status == status
+			async with bad_session.post(f"https://{host}/clip", data=b"{", headers=auth_headers, ssl=False) as resp:
+				assert resp.status == 400
+	finally:
+		await runner.cleanup()
+
+	for clipping in added:
+		await api_delete_file(session, host, auth_headers, clipping['id'], 1)
+
+
+async def test_markdown_export(session, host, auth_headers, admin_headers):
+	"""Typed text and highlights should be exported as Markdown with a section for each page, and a folder's documents as a
+	zip laid out like the folder.  Documents and folders that don't exist can't be exported."""
+	folder = str(uuid.uuid4())
+	subfolder = str(uuid.uuid4())
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(3)]
+	content = {"fileType": "notebook", "pageCount": len(pages), "pages": pages}
+	files = {
+		f"{document}/{pages[0]}.rm": rm_v6([], [("Shopping\nmilk & eggs", 0)]),
+		f"{document}.highlights/{pages[2]}.json": json.dumps({"highlights": [[{"start": 40, "text": "a *quoted* line"}, {"start": 10, "text": "first"}]]}),
+	}
+
+	await api_update_metadata(session, host, auth_headers, folder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Exports", parent="")
+	await api_update_metadata(session, host, auth_headers, subfolder, 1, date=datetime.now(timezone.utc), file_type="CollectionType", name="Kitchen", parent=folder)
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\n" * len(pages), files), "Lists", parent=subfolder)
+
+	expected = "# Lists\n\n<a id=\"page-1\"></a>\n\n## Page 1\n\nShopping\n\nmilk & eggs\n\n<a id=\"page-3\"></a>\n\n## Page 3\n\n> first\n\n> a \\*quoted\\* line\n"
+	async with session.get(f"https://{host}/admin/documents/{document}/export.md", headers=admin_headers, ssl=False) as resp:
+		assert resp.content_type == "text/markdown"
+		assert await resp.text() == expected
+
+	async with session.get(f"https://{host}/admin/export/markdown.zip", params={"folder": folder}, headers=admin_headers, ssl=False) as resp:
+		assert read_archive(await resp.read()) == {"Kitchen/Lists.md": expected.encode()}
+
+	async with aiohttp.ClientSession() as bad_session:
+		for path in [f"documents/{uuid.uuid4()}/export.md", f"export/markdown.zip?folder={uuid.uuid4()}", f"export/markdown.zip?folder={document}"]:
+			async with bad_session.get(f"https://{host}/admin/{path}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+
+	await api_delete_file(session, host, auth_headers, document, 1)
+	await api_delete_file(session, host, auth_headers, subfolder, 1)
+	await api_delete_file(session, host, auth_headers, folder, 1)
+
+
+async def test_diff_versions(session, host, auth_headers, admin_headers):
+	"""Comparing two versions of a notebook should list the pages added, removed and moved, and the strokes added and erased on
+	each page.  Versions that don't exist can't be compared."""
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(5)]
+	kept, erased, added = [(100, 200), (150, 250)], [(300, 400), (350, 450)], [(500, 600), (550, 650)]
+
+	def archive(order, strokes):
+		content = {"fileType": "notebook", "pageCount": len(order), "pages": [pages[i] for i in order]}
+		return make_archive(document, content, "Blank\n" * len(order), {f"{document}/{pages[0]}.rm": rm_v5(strokes)})
+
+	await api_add_document(session, host, auth_headers, document, archive([0, 1, 2, 3], [kept, erased]), "diffed")
+	await api_upload_file(session, host, auth_headers, document, 2, archive([3, 0, 2, 4], [kept, added]))
+	await api_update_metadata(session, host, auth_headers, document, 2, date=datetime.now(timezone.utc))
+
+	async with session.get(f"https://{host}/admin/documents/{document}/diff", headers=admin_headers, ssl=False) as resp:
+		diff = await resp.json()
+	assert (diff['from'], diff['to']) == (1, 2)
+	assert diff['pages_added'] == [{"id": pages[4], "index": 3}]
+	assert diff['pages_removed'] == [{"id": pages[1], "index": 1}]
+	assert diff['pages_moved'] == [{"id": pages[3], "from": 3, "to": 0}]
+	[change] = diff['pages_changed']
+	assert (change['id'], change['index'], change['strokes_added'], change['strokes_removed'], change['text_changed']) == (pages[0], 1, 1, 1, False)
+	assert change['svg'].count("<polyline") == 3
+
+	# Comparing a version with itself finds nothing
+	async with session.get(f"https://{host}/admin/documents/{document}/diff?from=1&to=1", headers=admin_headers, ssl=False) as resp:
+		diff = await resp.json()
+	assert [diff[x] for x in ["pages_added", "pages_removed", "pages_moved", "pages_changed"]] == [[], [], [], []]
+
+	async with aiohttp.ClientSession() as bad_session:
+		for query in ["to=3", "to=1", "from=7"]:
+			async with bad_session.get(f"https://{host}/admin/documents/{document}/diff?{query}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+		async with bad_session.get(f"https://{host}/admin/documents/{uuid.uuid4()}/diff", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 404
+
+	await api_delete_file(session, host, auth_headers, document, 2)
+
+
+async def test_create_folders_and_notebooks(session, host, auth_headers, admin_headers):
+	"""Creating a folder by path should create the folders that are missing and reuse the rest, even when it's asked for several
+	times at once, and a new notebook should have the pages and template asked for.  Empty paths, bad page counts and templates,
+	and parents that aren't folders should be rejected."""
+	async with session.post(f"https://{host}/admin/folders", params={"path": "Family/Alex/Notes"}, headers=admin_headers, ssl=False) as resp:
+		notes = await resp.json()
+	assert len(notes['created']) == 3 and notes['id'] == notes['created'][-1]
+
+	async with session.post(f"https://{host}/admin/folders", params={"path": " Family / Alex/Notes/Work/"}, headers=admin_headers, ssl=False) as resp:
+		work = await resp.json()
+	assert work['created'] == [work['id']]
+
+	async with session.post(f"https://{host}/admin/folders", params={"path": "Work", "parent": notes['id']}, headers=admin_headers, ssl=False) as resp:
+		assert await resp.json() == {"id": work['id'], "created": []}
+
+	# Creating the same path several times at once should still create each folder only once
+	path = f"Together {uuid.uuid4().hex[:8]}/Inbox/Unread"
+	async def create_path():
+		async with aiohttp.ClientSession(raise_for_status=True) as own_session:
+			async with own_session.post(f"https://{host}/admin/folders", params={"path": path}, headers=admin_headers, ssl=False) as resp:
+				return await resp.json()
+	together = await asyncio.gather(*[create_path() for _ in range(16)])
+	assert len({x['id'] for x in together}) == 1 and sum(len(x['created']) for x in together) == 3
+
+	files = {x['ID']: x for x in await api_list_files(session, host, auth_headers)}
+	family, alex, _ = notes['created']
+	assert [(files[x]['VissibleName'], files[x]['Parent'], files[x]['FileType']) for x in notes['created'] + work['created']] == [
+		("Family", "", "CollectionType"),
+		("Alex", family, "CollectionType"),
+		("Notes", alex, "CollectionType"),
+		("Work", notes['id'], "CollectionType"),
+	]
+
+	async with session.post(f"https://{host}/admin/notebooks", params={"name": "Meetings", "folder": work['id'], "pages": 3, "template": "P Lines medium"}, headers=admin_headers, ssl=False) as resp:
+		notebook = await resp.json()
+	assert (notebook['name'], notebook['parent'], notebook['page_count']) == ("Meetings", work['id'], 3)
+	async with session.get(f"https://{host}/admin/documents/{notebook['id']}", headers=admin_headers, ssl=False) as resp:
+		info = await resp.json()
+	assert info['file_type'] == "notebook"
+	assert [(x['template'], x['annotated']) for x in info['pages']] == [("P Lines medium", False)] * 3
+
+	# The tablet gets a notebook it can open
+	archive = read_archive(await api_download_file(session, host, auth_headers, notebook['id']))
+	assert json.loads(archive[f"{notebook['id']}.content"])['fileType'] == "notebook"
+	assert archive[f"{notebook['id']}.pagedata"].decode().splitlines() == ["P Lines medium"] * 3
+
+	async with aiohttp.ClientSession() as bad_session:
+		for query in [{"path": " / /"}, {"path": "Nested", "parent": str(uuid.uuid4())}, {"path": "Nested", "parent": notebook['id']}]:
+			async with bad_session.post(f"https://{host}/admin/folders", params=query, headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 400
+		for query in [{"pages": 0}, {"pages": 1001}, {"template": " "}, {"template": "Blank\nBlank"}, {"folder": str(uuid.uuid4())}, {"folder": notebook['id']}]:
+			async with bad_session.post(f"https://{host}/admin/notebooks", params={"name": "rejected", **query}, headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 400
+
+	for id in [notebook['id'], work['id']] + notes['created'][::-1]:
+		await api_delete_file(session, host, auth_headers, id, 1)
+
+
+async def test_rearrange_pages(session, host, auth_headers, admin_headers):
+	"""Merging, splitting, extracting and reordering the pages of PDFs should take each page's template and strokes along with
+	it, and a document reordered in place should keep its firmware's format.  Page specs that don't make sense for the document,
+	orders that don't have every page once, and documents that aren't PDFs should be rejected."""
+	added = []
+
+	async def add_pdf(templates, annotated):
+		document = str(uuid.uuid4())
+		pages = [str(uuid.uuid4()) for _ in templates]
+		content = {"fileType": "pdf", "pageCount": len(pages), "pages": pages}
+		files = {f"{document}.pdf": make_pdf(len(pages))}
+		for index in annotated:
+			files[f"{document}/{pages[index]}.rm"] = rm_v5([[(100, 200), (150, 250)]])
+		await api_add_document(session, host, auth_headers, document, make_archive(document, content, "".join(f"{x}\n" for x in templates), files), "source")
+		return document
+
+	async def pages_of(id):
+		async with session.get(f"https://{host}/admin/documents/{id}", headers=admin_headers, ssl=False) as resp:
+			info = await resp.json()
+		assert info['file_type'] == "pdf"
+		return [(x['template'], x['annotated']) for x in info['pages']]
+
+	async def post(path, body):
+		async with session.post(f"https://{host}/admin/documents/{path}", json=body, headers=admin_headers, ssl=False) as resp:
+			response = await resp.json()
+		added.extend(x['id'] for x in (response if isinstance(response, list) else [response]) if x['version'] == 1)
+		return response
+
+	a = await add_pdf(["A1", "A2", "A3", "A4"], [1])
+	b = await add_pdf(["B1", "B2"], [])
+	notebook = str(uuid.uuid4())
+	await api_add_document(session, host, auth_headers, notebook, make_archive(notebook, {"fileType": "notebook", "pageCount": 1, "pages": [str(uuid.uuid4())]}, "Blank\n", {}), "notebook")
+
+	merged = await post("merge", {"documents": [a, b], "name": "merged"})
+	assert (merged['name'], merged['page_count']) == ("merged", 6)
+	assert await pages_of(merged['id']) == [("A1", False), ("A2", True), ("A3", False), ("A4", False), ("B1", False), ("B2", False)]
+	archive = read_archive(await api_download_file(session, host, auth_headers, merged['id']))
+	assert json.loads(archive[f"{merged['id']}.content"])['redirectionPageMap'] == list(range(6))
+	assert pdf_page_widths(archive[f"{merged['id']}.pdf"]) == [400, 401, 402, 403, 400, 401]
+
+	parts = await post(f"{a}/split", {"ranges": ["1-2", "4-3"]})
+	assert [x['name'] for x in parts] == ["source (pages 1-2)", "source (pages 4-3)"]
+	assert [await pages_of(x['id']) for x in parts] == [[("A1", False), ("A2", True)], [("A4", False), ("A3", False)]]
+
+	parts = await post(f"{a}/split", {"every": 3})
+	assert [x['page_count'] for x in parts] == [3, 1]
+
+	extracted = await post(f"{a}/extract", {"pages": "2, 4-"})
+	assert extracted['name'] == "source (pages 2, 4-)"
+	assert await pages_of(extracted['id']) == [("A2", True), ("A4", False)]
+	archive = read_archive(await api_download_file(session, host, auth_headers, extracted['id']))
+	assert pdf_page_widths(archive[f"{extracted['id']}.pdf"]) == [401, 403]
+
+	reordered = await post(f"{a}/reorder", {"order": "4-1"})
+	assert (reordered['version'], reordered['page_count']) == (2, 4)
+	assert await pages_of(a) == [("A4", False), ("A3", False), ("A2", True), ("A1", False)]
+	archive = read_archive(await api_download_file(session, host, auth_headers, a))
+	assert pdf_page_widths(archive[f"{a}.pdf"]) == [403, 402, 401, 400]
+
+	# A firmware 3.x document keeps its cPages, which now say which page of the PDF each page shows
+	c = str(uuid.uuid4())
+	c_pages = [str(uuid.uuid4()) for _ in range(3)]
+	content = {"fileType": "pdf", "formatVersion": 2, "pageCount": 3, "cPages": {"pages": [
+		{"id": c_pages[0], "idx": {"timestamp": "1:2", "value": "ba"}, "template": {"timestamp": "1:1", "value": "C1"}, "redir": {"timestamp": "1:1", "value": 0}},
+		{"id": c_pages[1], "idx": {"timestamp": "1:3", "value": "bb"}, "template": {"timestamp": "1:1", "value": "Inserted"}},
+		{"id": c_pages[2], "idx": {"timestamp": "1:4", "value": "bc"}, "template": {"timestamp": "1:1", "value": "C2"}, "redir": {"timestamp": "1:1", "value": 1}},
+	]}}
+	await api_add_document(session, host, auth_headers, c, make_archive(c, content, "C1\nInserted\nC2\n", {f"{c}.pdf": make_pdf(2)}), "source")
+	await post(f"{c}/reorder", {"order": "3,2,1"})
+	assert await pages_of(c) == [("C2", False), ("Inserted", False), ("C1", False)]
+	archive = read_archive(await api_download_file(session, host, auth_headers, c))
+	new_content = json.loads(archive[f"{c}.content"])
+	assert new_content['formatVersion'] == 2 and 'pages' not in new_content and 'redirectionPageMap' not in new_content
+	entries = sorted(new_content['cPages']['pages'], key=lambda x: x['idx']['value'])
+	assert [(x['id'], x.get('redir', {}).get('value')) for x in entries] == [(c_pages[2], 0), (c_pages[1], None), (c_pages[0], 1)]
+	assert pdf_page_widths(archive[f"{c}.pdf"]) == [401, 400]
+	await api_delete_file(session, host, auth_headers, c, 2)
+
+	# A tablet's upload that isn't committed yet shouldn't be written over, or committed early
+	upload = await api_download_file(session, host, auth_headers, a)
+	await api_upload_file(session, host, auth_headers, a, 3, upload)
+	async with aiohttp.ClientSession() as bad_session:
+		async with bad_session.post(f"https://{host}/admin/documents/{a}/reorder", json={"order": "4-1"}, headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 409
+	await api_update_metadata(session, host, auth_headers, a, 3, date=datetime.now(timezone.utc))
+	assert await api_download_file(session, host, auth_headers, a) == upload
+
+	async with aiohttp.ClientSession() as bad_session:
+		for path, body, status in [
+			(f"{a}/extract", {"pages": "0"}, 400),
+			(f"{a}/extract", {"pages": "5"}, 400),
+			(f"{a}/extract", {"pages": "2-9"}, 400),
+			(f"{a}/extract", {"pages": "one"}, 400),
+			(f"{a}/extract", {"pages": ""}, 400),
+			(f"{a}/reorder", {"order": "1,1,2,3"}, 400),
+			(f"{a}/reorder", {"order": "1-3"}, 400),
+			(f"{a}/split", {}, 400),
+			(f"{a}/split", {"every": 0}, 400),
+			(f"{a}/split", {"ranges": ["1-2", "3-5"]}, 400),
+			("merge", {"documents": [], "name": "merged"}, 400),
+			("merge", {"documents": [a, notebook], "name": "merged"}, 400),
+			("merge", {"documents": [a, b], "name": "merged", "folder": str(uuid.uuid4())}, 400),
+			(f"{uuid.uuid4()}/extract", {"pages": "1"}, 404),
+		]:
+			async with bad_session.post(f"https://{host}/admin/documents/{path}", json=body, headers=admin_headers, ssl=False) as resp:
+				assert resp.status == status
+
+	for id in added + [b, notebook]:
+		await api_delete_file(session, host, auth_headers, id, 1)
+	await api_delete_file(session, host, auth_headers, a, 3)
+
+
+async def test_document_info(session, host, auth_headers, admin_headers):
+	"""PDFs and EPUBs added through the admin API should be named from their XMP metadata, Info dictionary or package document,
+	and an EPUB's cover should be kept.  Titles that are file names, and metadata that can't be read, should leave the name as it
+	was given.  PDFs that can't be read should be rejected."""
+	added = []
+
+	async def add(data):
+		async with session.post(f"https://{host}/admin/documents", params={"name": "uploaded"}, data=data, headers={**admin_headers, "Content-Type": "application/octet-stream"}, ssl=False) as resp:
+			document = await resp.json()
+		added.append(document['id'])
+		async with session.get(f"https://{host}/admin/documents/{document['id']}", headers=admin_headers, ssl=False) as resp:
+			return document['name'], (await resp.json())['info']
+
+	xmp = (
+		'<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">'
+		'<rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="2019-05-06T07:08:09Z">'
+		'<dc:title><rdf:Alt><rdf:li xml:lang="x-default">The Art of Testing</rdf:li></rdf:Alt></dc:title>'
+		'<dc:creator><rdf:Seq><rdf:li>Ada Lovelace</rdf:li><rdf:li>Charles Babbage</rdf:li></rdf:Seq></dc:creator>'
+		'</rdf:Description></rdf:RDF></x:xmpmeta>'
+	)
+	name, info = await add(make_pdf(1, {"Title": "Outdated title", "CreationDate": "D:20180102030405Z"}, xmp))
+	assert name == "The Art of Testing"
+	assert info == {"title": "The Art of Testing", "authors": ["Ada Lovelace", "Charles Babbage"], "date": "2019-05-06", "has_cover": False}
+
+	# Without XMP, the Info dictionary is used
+	name, info = await add(make_pdf(1, {"Title": "Field Notes", "Author": "Mary Anning; Etheldred Benett", "CreationDate": "D:20180102030405Z"}))
+	assert name == "Field Notes"
+	assert (info['authors'], info['date']) == (["Mary Anning", "Etheldred Benett"], "2018-01-02")
+
+	name, info = await add(make_pdf(1, {"Title": "Microsoft Word - report.docx", "Author": "Someone"}))
+	assert (name, info['title'], info['authors']) == ("uploaded", None, ["Someone"])
+
+	cover = make_png(2, 2)
+	package = (
+		'<package xmlns="http://www.idpf.org/2007/opf" version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">'
+		'<dc:title>A Voyage Round the World</dc:title><dc:creator>Georg Forster</dc:creator><dc:date>1777</dc:date></metadata>'
+		'<manifest><item id="c" href="images/cover%20art.png" media-type="image/png" properties="cover-image"/></manifest></package>'
+	)
+	name, info = await add(make_epub(package, {"OEBPS/images/cover art.png": cover}))
+	assert name == "A Voyage Round the World"
+	assert info == {"title": "A Voyage Round the World", "authors": ["Georg Forster"], "date": "1777", "has_cover": True}
+	async with session.get(f"https://{host}/admin/documents/{added[-1]}/cover", headers=admin_headers, ssl=False) as resp:
+		assert resp.content_type == "image/png"
+		assert await resp.read() == cover
+
+	# Titles and authors can be searched for
+	await wait_for_search(session, host, admin_headers, "lovelace", lambda hits: [x['id'] for x in hits] == [added[0]])
+
+	# An EPUB whose package document is missing is kept, under the name it was given
+	name, info = await add(make_epub(None, {}))
+	assert (name, info) == ("uploaded", None)
+
+	async with aiohttp.ClientSession() as bad_session:
+		for id in [added[0], added[-1], uuid.uuid4()]:
+			async with bad_session.get(f"https://{host}/admin/documents/{id}/cover", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+		async with bad_session.post(f"https://{host}/admin/documents?name=bad", data=b"%PDF-1.4\nnot really", headers={**admin_headers, "Content-Type": "application/pdf"}, ssl=False) as resp:
+			assert resp.status == 400
+
+	for id in added:
+		await api_delete_file(session, host, auth_headers, id, 1)
+
+
+async def test_tags(session, host, auth_headers, admin_headers):
+	"""Tags the tablet puts on documents and pages should be listed and looked up, and changing them should save a new version
+	with them.  Documents in the trash shouldn't be counted.  Empty tags and pages that don't exist should be rejected."""
+	document = str(uuid.uuid4())
+	pages = [str(uuid.uuid4()) for _ in range(2)]
+	receipts, todo, urgent = [f"{x} {random_string(8)}" for x in ["Receipts", "Todo", "Urgent"]]
+	content = {
+		"fileType": "notebook",
+		"pageCount": len(pages),
+		"pages": pages,
+		"tags": [{"name": receipts, "timestamp": 1600000000000}],
+		"pageTags": [{"name": todo, "pageId": pages[1], "timestamp": 1600000000000}],
+	}
+	await api_add_document(session, host, auth_headers, document, make_archive(document, content, "Blank\nBlank\n", {}), "tagged")
+
+	async def wait_for_tagged(tag, done):
+		for _ in range(50):
+			async with session.get(f"https://{host}/admin/tagged", params={"tag": tag}, headers=admin_headers, ssl=False) as resp:
+				tagged = await resp.json()
+			if done(tagged):
+				return tagged
+			await asyncio.sleep(0.1)
+		raise Exception(f"Unexpected documents tagged {tag}: {tagged}")
+
+	async def counts():
+		async with session.get(f"https://{host}/admin/tags", headers=admin_headers, ssl=False) as resp:
+			return {x['name']: (x['documents'], x['pages']) for x in await resp.json() if x['name'] in [receipts, todo, urgent]}
+
+	tagged = await wait_for_tagged(receipts, lambda tagged: tagged['documents'] != [])
+	assert tagged == {"documents": [{"id": document, "name": "tagged", "parent": ""}], "pages": []}
+	tagged = await wait_for_tagged(todo, lambda tagged: tagged['pages'] != [])
+	assert tagged['pages'] == [{"id": document, "name": "tagged", "parent": "", "page": 1, "page_id": pages[1]}]
+	assert await counts() == {receipts: (1, 0), todo: (0, 1)}
+
+	async with session.post(f"https://{host}/admin/documents/{document}/tags", json={"add": [todo], "remove": [receipts]}, headers=admin_headers, ssl=False) as resp:
+		assert await resp.json() == {"id": document, "version": 2, "page": None, "tags": [todo]}
+	async with session.post(f"https://{host}/admin/documents/{document}/tags", json={"add": [f" {urgent} "], "page": 0}, headers=admin_headers, ssl=False) as resp:
+		assert await resp.json() == {"id": document, "version": 3, "page": 0, "tags": [urgent]}
+	# Adding a tag that's already there doesn't make a new version
+	async with session.post(f"https://{host}/admin/documents/{document}/tags", json={"add": [urgent], "page": 0}, headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['version'] == 3
+
+	new_content = json.loads(read_archive(await api_download_file(session, host, auth_headers, document))[f"{document}.content"])
+	assert [x['name'] for x in new_content['tags']] == [todo]
+	assert sorted((x['name'], x['pageId']) for x in new_content['pageTags']) == sorted([(todo, pages[1]), (urgent, pages[0])])
+
+	await wait_for_tagged(receipts, lambda tagged: tagged['documents'] == [])
+	tagged = await wait_for_tagged(todo, lambda tagged: tagged['documents'] != [])
+	assert [x['page'] for x in tagged['pages']] == [1]
+	assert await counts() == {todo: (1, 1), urgent: (0, 1)}
+
+	# Tags can't be changed while a tablet is uploading a new version
+	upload = await api_download_file(session, host, auth_headers, document)
+	await api_upload_file(session, host, auth_headers, document, 4, upload)
+	async with aiohttp.ClientSession() as bad_session:
+		async with bad_session.post(f"https://{host}/admin/documents/{document}/tags", json={"add": [receipts]}, headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 409
+	await api_update_metadata(session, host, auth_headers, document, 4, date=datetime.now(timezone.utc), parent="trash")
+	assert await api_download_file(session, host, auth_headers, document) == upload
+	await wait_for_tagged(todo, lambda tagged: tagged == {"documents": [], "pages": []})
+	assert await counts() == {}
+
+	async with aiohttp.ClientSession() as bad_session:
+		for id, body, status in [
+			(document, {"add": [" "]}, 400),
+			(document, {"add": ["two\nlines"]}, 400),
+			(document, {"add": ["Later"], "page": 2}, 404),
+			(uuid.uuid4(), {"add": ["Later"]}, 404),
+		]:
+			async with bad_session.post(f"https://{host}/admin/documents/{id}/tags", json=body, headers=admin_headers, ssl=False) as resp:
+				assert resp.status == status
+		async with bad_session.get(f"https://{host}/admin/tagged", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 400
+
+	await api_delete_file(session, host, auth_headers, document, 4)
+
+
+async def test_rules(session, host, auth_headers, admin_headers):
+	"""A rule should apply its actions, in order, to documents that match its conditions when its events happen, and scheduled
+	rules when they're run.  Rules that can't work, and rules that don't exist, should be rejected."""
+	received = []
+
+	async def hook(request):
+		body = await request.read()
+		assert request.headers['X-Webhook-Event'] == "rule"
+		assert request.headers['X-Webhook-Signature'] == "sha256=" + hmac.new(b"sekrit", body, hashlib.sha256).hexdigest()
+		received.append(json.loads(body))
+		return aiohttp.web.Response(text="OK")
+
+	app = aiohttp.web.Application()
+	app.router.add_post("/rule", hook)
+	runner = aiohttp.web.AppRunner(app)
+	await runner.setup()
+	await aiohttp.web.TCPSite(runner, "127.0.0.1", 8086).start()
+
+	suffix = uuid.uuid4().hex[:8]
+	rules = []
+
+	async def files():
+		return {x['ID']: x for x in await api_list_files(session, host, auth_headers)}
+
+	async def wait_for_file(id, done):
+		for _ in range(50):
+			file = (await files()).get(id)
+			if file is not None and done(file):
+				return file
+			await asyncio.sleep(0.1)
+		raise Exception(f"Rules didn't apply to {id}: {file}")
+
+	try:
+		# The webhook's own deliveries are for a folder that doesn't exist, so it only gets what the rule sends
+		req = {"url": "http://127.0.0.1:8086/rule", "folder": str(uuid.uuid4()), "secret": "sekrit"}
+		async with session.post(f"https://{host}/admin/webhooks", json=req, headers=admin_headers, ssl=False) as resp:
+			webhook = (await resp.json())['id']
+
+		invoices = {
+			"name": "Invoices",
+			"events": ["created"],
+			"conditions": {"name": f"^Invoice {suffix}", "file_type": "epub"},
+			"actions": [
+				{"type": "move", "folder": f"/Finance {suffix}/Invoices"},
+				{"type": "tag", "tag": "Finance"},
+				{"type": "bookmark"},
+				{"type": "rename", "name": "{name} (filed)"},
+				{"type": "webhook", "webhook": webhook},
+			],
+		}
+		archive = {"name": "Archive", "events": ["scheduled"], "conditions": {"folder": f"/Inbox {suffix}", "older_than_days": 0}, "actions": [{"type": "trash"}]}
+		for rule in [invoices, archive]:
+			async with session.post(f"https://{host}/admin/rules", json=rule, headers=admin_headers, ssl=False) as resp:
+				created = await resp.json()
+			assert (created['name'], created['enabled'], created['events']) == (rule['name'], True, rule['events'])
+			assert [x['type'] for x in created['actions']] == [x['type'] for x in rule['actions']]
+			rules.append(created['id'])
+
+		async with session.get(f"https://{host}/admin/rules", headers=admin_headers, ssl=False) as resp:
+			assert [x['id'] for x in await resp.json()] == rules
+
+		async with session.post(f"https://{host}/admin/documents", params={"name": f"Invoice {suffix}"}, data="Total: 12.00", headers={**admin_headers, "Content-Type": "text/plain"}, ssl=False) as resp:
+			invoice = (await resp.json())['id']
+		async with session.post(f"https://{host}/admin/documents", params={"name": f"Receipt {suffix}"}, data="Total: 3.00", headers={**admin_headers, "Content-Type": "text/plain"}, ssl=False) as resp:
+			receipt = (await resp.json())['id']
+
+		file = await wait_for_file(invoice, lambda file: file['VissibleName'].endswith("(filed)"))
+		assert file['Bookmarked'] and file['Version'] == 5
+		await wait_for_deliveries(session, host, admin_headers, webhook, "delivered", 1)
+		library = await files()
+		folder = library[file['Parent']]
+		assert (folder['VissibleName'], library[folder['Parent']]['VissibleName']) == ("Invoices", f"Finance {suffix}")
+		content = json.loads(read_archive(await api_download_file(session, host, auth_headers, invoice))[f"{invoice}.content"])
+		assert [x['name'] for x in content['tags']] == ["Finance"]
+		assert len(received) == 1
+		assert (received[0]['rule'], received[0]['trigger'], received[0]['document']['id']) == ({"id": rules[0], "name": "Invoices"}, "created", invoice)
+
+		# Only the document that matches is filed
+		assert (library[receipt]['Parent'], library[receipt]['Version']) == ("", 1)
+
+		# A disabled rule doesn't run, even on documents it would otherwise file
+		disabled = {**invoices, "enabled": False, "conditions": {"name": f"^Invoice {suffix}"}}
+		async with session.put(f"https://{host}/admin/rules/{rules[0]}", json=disabled, headers=admin_headers, ssl=False) as resp:
+			assert (await resp.json())['enabled'] == False
+		async with session.get(f"https://{host}/admin/rules/{rules[0]}", headers=admin_headers, ssl=False) as resp:
+			assert (await resp.json())['conditions'] == disabled['conditions']
+
+		async with session.post(f"https://{host}/admin/folders", params={"path": f"Inbox {suffix}/Unsorted"}, headers=admin_headers, ssl=False) as resp:
+			inbox = (await resp.json())['created']
+		async with session.post(f"https://{host}/admin/notebooks", params={"name": f"Invoice {suffix} draft", "folder": inbox[-1]}, headers=admin_headers, ssl=False) as resp:
+			notebook = (await resp.json())['id']
+		# Rules leave documents alone while a tablet is uploading them
+		async with session.post(f"https://{host}/admin/notebooks", params={"name": "Uploading", "folder": inbox[-1]}, headers=admin_headers, ssl=False) as resp:
+			uploading = (await resp.json())['id']
+		upload = await api_download_file(session, host, auth_headers, uploading)
+		await api_upload_file(session, host, auth_headers, uploading, 2, upload)
+
+		async with session.post(f"https://{host}/admin/rules/run", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+		file = await wait_for_file(notebook, lambda file: file['Parent'] == "trash")
+		await asyncio.sleep(0.5)
+		assert (await files())[notebook]['VissibleName'] == f"Invoice {suffix} draft"
+		assert ((await files())[uploading]['Parent'], (await files())[uploading]['Version']) == (inbox[-1], 1)
+		await api_update_metadata(session, host, auth_headers, uploading, 2, date=datetime.now(timezone.utc))
+		assert await api_download_file(session, host, auth_headers, uploading) == upload
+		assert len(received) == 1
+
+		async with aiohttp.ClientSession() as bad_session:
+			for rule in [
+				{**invoices, "name": " "},
+				{**invoices, "events": ["sometimes"]},
+				{**invoices, "conditions": {"name": "("}},
+				{**invoices, "conditions": {"file_type": "docx"}},
+				{**invoices, "conditions": {"colour": "red"}},
+				{**invoices, "conditions": {"older_than_days": 2**62}},
+				{**invoices, "conditions": {"newer_than_days": -1}},
+				{**invoices, "actions": []},
+				{**invoices, "actions": [{"type": "tag", "tag": ""}]},
+				{**invoices, "actions": [{"type": "webhook", "webhook": 999999}]},
+				{**invoices, "actions": [{"type": "webhook", "url": "http://127.0.0.1:8086/rule"}]},
+				{**invoices, "actions": [{"type": "shred"}]},
+			]:
+				async with bad_session.post(f"https://{host}/admin/rules", json=rule, headers=admin_headers, ssl=False) as resp:
+					assert resp.status == 400
+				async with bad_session.put(f"https://{host}/admin/rules/{rules[0]}", json=rule, headers=admin_headers, ssl=False) as resp:
+					assert resp.status == 400
+
+			for method in [bad_session.get, bad_session.delete]:
+				async with method(f"https://{host}/admin/rules/999999", headers=admin_headers, ssl=False) as resp:
+					assert resp.status == 404
+			async with bad_session.put(f"https://{host}/admin/rules/999999", json=invoices, headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+	finally:
+		for id in rules:
+			async with session.delete(f"https://{host}/admin/rules/{id}", headers=admin_headers, ssl=False) as resp:
+				pass
+		async with session.delete(f"https://{host}/admin/webhooks/{webhook}", headers=admin_headers, ssl=False) as resp:
+			pass
+		await runner.cleanup()
+
+	library = await files()
+	for id in [invoice, receipt, notebook, uploading, folder['ID'], folder['Parent']] + inbox[::-1]:
+		await api_delete_file(session, host, auth_headers, id, library[id]['Version'])
+
+	async with session.get(f"https://{host}/admin/rules", headers=admin_headers, ssl=False) as resp:
+		assert await resp.json() == []
+
+
+async def test_deliveries(session, host, auth_headers, admin_headers):
+	"""A delivery should add its file to the library once it's due, and a recurring one should replace the document it added
+	when the file has changed.  A run that can't read the file should say why.  Deliveries that could never run, and deliveries
+	that don't exist, should be rejected."""
+	async with session.post(f"https://{host}/admin/folders", params={"path": f"Deliveries {uuid.uuid4().hex[:8]}"}, headers=admin_headers, ssl=False) as resp:
+		folder = (await resp.json())['id']
+	path = os.path.join(os.path.abspath(os.path.dirname(__file__)), f"delivery-{uuid.uuid4()}.md")
+
+	async def create(params, data=b"", content_type="text/markdown"):
+		async with session.post(f"https://{host}/admin/deliveries", params={"folder": folder, **params}, data=data, headers={**admin_headers, "Content-Type": content_type}, ssl=False) as resp:
+			return await resp.json()
+
+	async def wait_for_delivery(id, done):
+		for _ in range(50):
+			async with session.get(f"https://{host}/admin/deliveries/{id}", headers=admin_headers, ssl=False) as resp:
+				delivery = await resp.json()
+			if done(delivery):
+				return delivery
+			await asyncio.sleep(0.1)
+		raise Exception(f"Delivery {id} didn't run: {delivery}")
+
+	async def run(id):
+		async with session.post(f"https://{host}/admin/deliveries/{id}/run", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+		# Running makes it due now, and afterwards it's due again tomorrow
+		return await wait_for_delivery(id, lambda delivery: delivery['next_run'] > datetime.now().timestamp())
+
+	async def document(id):
+		return {x['ID']: x for x in await api_list_files(session, host, auth_headers)}[id]
+
+	# Due already
+	agenda = await create({"name": "Agenda", "at": "2020-01-01T06:00:00Z"}, "# Today\n\n- Standup")
+	assert (agenda['source'], agenda['next_run'], agenda['document_id']) == ({"type": "upload", "content_type": "text/markdown"}, 1577858400, None)
+	agenda = await wait_for_delivery(agenda['id'], lambda delivery: delivery['document_id'] is not None)
+	assert (agenda['next_run'], agenda['last_error']) == (None, None)
+	delivered = await document(agenda['document_id'])
+	assert (delivered['VissibleName'], delivered['Parent']) == ("Agenda", folder)
+	async with session.get(f"https://{host}/admin/documents/{agenda['document_id']}", headers=admin_headers, ssl=False) as resp:
+		assert (await resp.json())['file_type'] == "epub"
+
+	later = await create({"name": "Later", "at": "2099-01-01T06:00:00Z"}, "Not yet", "text/plain")
+	async with session.get(f"https://{host}/admin/deliveries", headers=admin_headers, ssl=False) as resp:
+		assert [(x['id'], x['next_run']) for x in await resp.json()] == [(agenda['id'], None), (later['id'], 4070930400)]
+	async with session.delete(f"https://{host}/admin/deliveries/{later['id']}", headers=admin_headers, ssl=False) as resp:
+		assert resp.status == 200
+
+	with open(path, "w") as file:
+		file.write("# Report\n\nFirst")
+	report = await create({"name": "Report", "schedule": "@daily", "path": path})
+	assert report['next_run'] > datetime.now().timestamp()
+	report = await run(report['id'])
+	assert (await document(report['document_id']))['Version'] == 1
+
+	# Unchanged, so left alone
+	await asyncio.sleep(1)
+	assert (await run(report['id']))['document_id'] == report['document_id']
+	assert (await document(report['document_id']))['Version'] == 1
+
+	with open(path, "w") as file:
+		file.write("# Report\n\nSecond")
+	await asyncio.sleep(1)
+	assert (await run(report['id']))['document_id'] == report['document_id']
+	assert (await document(report['document_id']))['Version'] == 2
+
+	# A tablet's upload isn't written over; the run fails, and the next one replaces the document once it's synced
+	upload = await api_download_file(session, host, auth_headers, report['document_id'])
+	await api_upload_file(session, host, auth_headers, report['document_id'], 3, upload)
+	with open(path, "w") as file:
+		file.write("# Report\n\nThird")
+	await asyncio.sleep(1)
+	assert "uploading" in (await run(report['id']))['last_error']
+	await api_update_metadata(session, host, auth_headers, report['document_id'], 3, date=datetime.now(timezone.utc))
+	assert await api_download_file(session, host, auth_headers, report['document_id']) == upload
+	await asyncio.sleep(1)
+	assert (await run(report['id']))['last_error'] is None
+	assert (await document(report['document_id']))['Version'] == 4
+
+	os.remove(path)
+	await asyncio.sleep(1)
+	failed = await run(report['id'])
+	assert failed['document_id'] == report['document_id'] and "Unable to read" in failed['last_error']
+
+	async with aiohttp.ClientSession() as bad_session:
+		for params, data, content_type in [
+			({"name": " ", "at": "2099-01-01T06:00"}, "text", "text/plain"),
+			({"name": "Bad"}, "text", "text/plain"),
+			({"name": "Bad", "at": "tomorrow"}, "text", "text/plain"),
+			({"name": "Bad", "schedule": "61 * * * *"}, "text", "text/plain"),
+			({"name": "Bad", "at": "2099-01-01T06:00", "folder": str(uuid.uuid4())}, "text", "text/plain"),
+			({"name": "Bad", "at": "2099-01-01T06:00", "path": "relative.md"}, "", "text/plain"),
+			({"name": "Bad", "at": "2099-01-01T06:00", "path": path}, "", "text/plain"),
+			({"name": "Bad", "at": "2099-01-01T06:00"}, os.urandom(64), "application/octet-stream"),
+		]:
+			async with bad_session.post(f"https://{host}/admin/deliveries", params={"folder": folder, **params}, data=data, headers={**admin_headers, "Content-Type": content_type}, ssl=False) as resp:
+				assert resp.status == 400
+
+		async with bad_session.get(f"https://{host}/admin/deliveries/999999", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 404
+		for method, url in [(bad_session.post, "999999/run"), (bad_session.delete, "999999")]:
+			async with method(f"https://{host}/admin/deliveries/{url}", headers=admin_headers, ssl=False) as resp:
+				assert resp.status == 404
+
+	for delivery in [agenda, report]:
+		async with session.delete(f"https://{host}/admin/deliveries/{delivery['id']}", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+	# What was delivered stays
+	await api_delete_file(session, host, auth_headers, agenda['document_id'], 1)
+	await api_delete_file(session, host, auth_headers, report['document_id'], 4)
+	await api_delete_file(session, host, auth_headers, folder, 1)
+
+
+async def test_journal(session, host, auth_headers, admin_headers):
+	"""With --journal-folder Journal, the server should have made today's notebook, once, in the Journal folder when it started.
+	With --journal-carry-todos too, the next day's notebook should start with copies of the previous one's pages that have
+	unticked checkboxes.  Without --journal-folder, the test is skipped."""
+	files = await api_list_files(session, host, auth_headers)
+	folders = [x for x in files if x['VissibleName'] == "Journal" and x['Parent'] == "" and x['FileType'] == "CollectionType"]
+	if len(folders) == 0:
+		print("Skipping the journal: the server has no --journal-folder Journal")
+		return
+
+	assert len(folders) == 1
+	today = datetime.now().strftime("%Y-%m-%d")
+	[notebook] = [x for x in files if x['Parent'] == folders[0]['ID']]
+	assert (notebook['VissibleName'], notebook['FileType']) == (today, "DocumentType")
+
+	async with session.get(f"https://{host}/admin/documents/{notebook['ID']}", headers=admin_headers, ssl=False) as resp:
+		info = await resp.json()
+	assert info['file_type'] == "notebook"
+	assert [(x['template'], x['annotated']) for x in info['pages']] == [("Blank", False)]
+
+	# Make today's notebook yesterday's, with a to-do that's done and one that isn't, and wind the last run back so that the
+	# next check (once a minute) makes today's again
+	pages = [str(uuid.uuid4()) for _ in range(2)]
+	todo = rm_v6([], [("Call the plumber", 0)], style=6)
+	files = {f"{notebook['ID']}/{pages[0]}.rm": rm_v6([], [("Buy milk", 0)], style=7), f"{notebook['ID']}/{pages[1]}.rm": todo}
+	content = {"fileType": "notebook", "pageCount": len(pages), "pages": pages}
+	await api_upload_file(session, host, auth_headers, notebook['ID'], 2, make_archive(notebook['ID'], content, "Blank\nP Lines medium\n", files))
+	await api_update_metadata(session, host, auth_headers, notebook['ID'], 2, date=datetime.now(timezone.utc), name="Yesterday")
+	conn = sqlite3.connect('test.sqlite')
+	conn.execute("UPDATE config SET value=? WHERE key='journal_last_run'", (str(int(datetime.now().timestamp()) - 2 * 24 * 60 * 60),))
+	conn.commit()
+	conn.close()
+
+	for _ in range(150):
+		made = [x for x in await api_list_files(session, host, auth_headers) if x['Parent'] == folders[0]['ID'] and x['VissibleName'] == today]
+		if made:
+			break
+		await asyncio.sleep(0.5)
+	[made] = made
+	archive = read_archive(await api_download_file(session, host, auth_headers, made['ID']))
+	carried = json.loads(archive[f"{made['ID']}.content"])['pages']
+	assert len(carried) == 2 and archive[f"{made['ID']}.pagedata"] == b"P Lines medium\nBlank\n"
+	assert archive[f"{made['ID']}/{carried[0]}.rm"] == todo and f"{made['ID']}/{carried[1]}.rm" not in archive
+
+
+async def test_planners(session, host, auth_headers, admin_headers):
+	"""A planner delivery should make a PDF with a page for each day or week, with each day's events (including recurring ones,
+	even with absurd intervals) on its page.  Planners that aren't made from a calendar, or cover no days, should be rejected."""
+	calendar = "\r\n".join([
+		"BEGIN:VCALENDAR",
+		"BEGIN:VEVENT", "UID:1", "SUMMARY:Dentist", "DTSTART:20240305T100000", "DTEND:20240305T110000", "END:VEVENT",
+		"BEGIN:VEVENT", "UID:2", "SUMMARY:Standup", "DTSTART:20240304T090000", "DTEND:20240304T091500", "RRULE:FREQ=DAILY;INTERVAL=2", "END:VEVENT",
+		"BEGIN:VEVENT", "UID:3", "SUMMARY:Rarely", "DTSTART:20240306T120000", "DTEND:20240306T130000", "RRULE:FREQ=YEARLY;INTERVAL=4294967295", "END:VEVENT",
+		"BEGIN:VEVENT", "UID:4", "SUMMARY:Forever", "DTSTART:20240307", "RRULE:FREQ=MONTHLY;INTERVAL=999999999;COUNT=999999999", "END:VEVENT",
+		"END:VCALENDAR",
+	])
+	created = []
+
+	async def deliver(params, data=calendar):
+		async with session.post(f"https://{host}/admin/deliveries", params={"name": "Planner", "at": "2020-01-01T00:00:00Z", "start": "2024-03-04", **params}, data=data, headers={**admin_headers, "Content-Type": "text/calendar"}, ssl=False) as resp:
+			delivery = await resp.json()
+		created.append(delivery['id'])
+		for _ in range(50):
+			async with session.get(f"https://{host}/admin/deliveries/{delivery['id']}", headers=admin_headers, ssl=False) as resp:
+				delivery = await resp.json()
+			if delivery['document_id'] is not None or delivery['last_error'] is not None:
+				break
+			await asyncio.sleep(0.1)
+		assert delivery['last_error'] is None
+		async with session.get(f"https://{host}/admin/documents/{delivery['document_id']}", headers=admin_headers, ssl=False) as resp:
+			info = await resp.json()
+		assert info['file_type'] == "pdf"
+		archive = read_archive(await api_download_file(session, host, auth_headers, delivery['document_id']))
+		return delivery['document_id'], info['page_count'], pdf_streams(archive[f"{delivery['document_id']}.pdf"])
+
+	daily, page_count, streams = await deliver({"planner": "daily", "days": 7})
+	# An index, then the days
+	assert page_count == 8
+	assert [sum(f"{name})".encode() in x for x in streams) for name in ["Dentist", "Standup", "Rarely", "Forever"]] == [1, 4, 1, 1]
+
+	weekly, page_count, streams = await deliver({"planner": "weekly", "days": 28})
+	assert page_count == 5
+	assert sum(b"Standup)" in x for x in streams) == 4
+
+	async with aiohttp.ClientSession() as bad_session:
+		for params, data in [
+			({"planner": "daily"}, "Not a calendar"),
+			({"planner": "daily", "days": 0}, calendar),
+			({"planner": "daily", "days": 367}, calendar),
+			({"planner": "daily", "path": f"/nonexistent/{uuid.uuid4()}"}, calendar),
+			# Last, as the query is rejected without reading the body, which can leave the connection stuck
+			({"planner": "monthly"}, calendar),
+		]:
+			async with bad_session.post(f"https://{host}/admin/deliveries", params={"name": "Planner", "at": "2099-01-01T00:00", **params}, data=data, headers={**admin_headers, "Content-Type": "text/calendar"}, ssl=False) as resp:
+				assert resp.status == 400
+
+	for id in created:
+		async with session.delete(f"https://{host}/admin/deliveries/{id}", headers=admin_headers, ssl=False) as resp:
+			assert resp.status == 200
+	for id in [daily, weekly]:
+		await api_delete_file(session, host, auth_headers, id, 1)
+
+
+def pdf_streams(data):
+	"""The decompressed contents of a PDF's streams"""
+	streams = []
+	for stream in re.findall(rb"stream\r?\n(.*?)\r?\nendstream", data, re.S):
+		try:
+			streams.append(zlib.decompress(stream))
+		except zlib.error:
+			streams.append(stream)
+	return streams
+
+
+def make_epub(package, files):
+	"""An EPUB with `package` as its package document (left out if None), and `files` by their paths"""
+	buffer = io.BytesIO()
+
+	with zipfile.ZipFile(buffer, 'w') as epub:
+		epub.writestr("mimetype", "application/epub+zip")
+		epub.writestr("META-INF/container.xml", '<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>')
+		if package is not None:
+			epub.writestr("OEBPS/content.opf", package)
+		for name, data in files.items():
+			epub.writestr(name, data)
+
+	return buffer.getvalue()
+
+
+def make_pdf(page_count, info=None, xmp=None):
+	"""A PDF of blank pages, with `info` (a dict of strings) as its Info dictionary and `xmp` as its XMP metadata if given"""
+	objects = [
+		"<< /Type /Catalog /Pages 2 0 R >>",
+		f"<< /Type /Pages /Kids [{' '.join(f'{i + 3} 0 R' for i in range(page_count))}] /Count {page_count} >>",
+	] + [f"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {400 + i} 600] >>" for i in range(page_count)]
+	trailer = ""
+
+	if info is not None:
+		objects.append("<< " + " ".join(f"/{key} ({value})" for key, value in info.items()) + " >>")
+		trailer = f" /Info {len(objects)} 0 R"
+	if xmp is not None:
+		objects.append(f"<< /Type /Metadata /Subtype /XML /Length {len(xmp)} >>\nstream\n{xmp}\nendstream")
+		objects[0] = f"<< /Type /Catalog /Pages 2 0 R /Metadata {len(objects)} 0 R >>"
+
+	data = b"%PDF-1.4\n"
+	offsets = []
+	for number, obj in enumerate(objects, 1):
+		offsets.append(len(data))
+		data += f"{number} 0 obj\n{obj}\nendobj\n".encode()
+
+	xref = len(data)
+	data += f"xref\n0 {len(objects) + 1}\n0000000000 65535 f \n".encode()
+	data += "".join(f"{offset:010} 00000 n \n" for offset in offsets).encode()
+	data += f"trailer\n<< /Size {len(objects) + 1} /Root 1 0 R{trailer} >>\nstartxref\n{xref}\n%%EOF\n".encode()
+	return data
+
+
+def pdf_page_widths(data):
+	"""The width of each page of a PDF without object streams, e.g. one from make_pdf, in order"""
+	objects = {int(number): body for number, body in re.findall(rb"(\d+) 0 obj\s*(.*?)\s*endobj", data, re.S)}
+	kids = re.search(rb"/Kids\s*\[([^\]]*)\]", data).group(1)
+	return [int(re.search(rb"/MediaBox\s*\[\s*0 0 (\d+)", objects[int(number)]).group(1)) for number in re.findall(rb"(\d+) 0 R", kids)]
+
+
+def make_png(width, height):
+	"""A grey RGB PNG"""
+	def chunk(kind, data):
+		return struct.pack('>I', len(data)) + kind + data + struct.pack('>I', zlib.crc32(kind + data))
+
+	rows = b"".join(b"\x00" + b"\x80" * 3 * width for _ in range(height))
+	return b"\x89PNG\r\n\x1a\n" + chunk(b"IHDR", struct.pack('>IIBBBBB', width, height, 8, 2, 0, 0, 0)) + chunk(b"IDAT", zlib.compress(rows)) + chunk(b"IEND", b"")
+
+
+async def api_add_document(session, host, auth_headers, id, data, name, parent=""):
+	"""Uploads a new document the way the tablet does."""
+	await api_upload_file(session, host, auth_headers, id, 1, data)
+	await api_update_metadata(session, host, auth_headers, id, 1, date=datetime.now(timezone.utc), file_type="DocumentType", name=name, parent=parent)
+
+
+def make_archive(id, content, pagedata, files):
+	"""A document archive with `content` as its .content (left out if None), and `files` by their paths in the archive."""
+	buffer = io.BytesIO()
+
+	with zipfile.ZipFile(buffer, 'w', zipfile.ZIP_DEFLATED) as archive:
+		if content is not None:
+			archive.writestr(f"{id}.content", json.dumps(content))
+		archive.writestr(f"{id}.pagedata", pagedata)
+		for name, data in files.items():
+			archive.writestr(name, data)
+
+	return buffer.getvalue()
+
+
+def read_archive(data):
+	with zipfile.ZipFile(io.BytesIO(data)) as archive:
+		return {name: archive.read(name) for name in archive.namelist()}
+
+
+def rm_v5(strokes):
+	"""A version 5 .rm file with one layer of fineliner strokes, each a list of (x, y) points."""
+	data = b"reMarkable .lines file, version=5".ljust(43) + struct.pack('<II', 1, len(strokes))
+
+	for points in strokes:
+		data += struct.pack('<IIIfII', 17, 0, 0, 2.0, 0, len(points))
+		for x, y in points:
+			data += struct.pack('<6f', x, y, 0, 0, 2.0, 1.0)
+
+	return data
+
+
+def rm_v6(points, text, style=None):
+	"""A version 6 .rm file with one fineliner stroke through `points` (if any), and text typed on the page.  `text` is a list
+	of runs of (string, 0), or (None, length) for deleted runs, each inserted after the one before.  `style` is the tablet's
+	number for the first paragraph's style (e.g. 6 for an unticked checkbox), if it isn't plain."""
+	def varuint(n):
+		data = b""
+		while n >= 0x80:
+			data += bytes([n & 0x7f | 0x80])
+			n >>= 7
+		return data + bytes([n])
+
+	def tag(index, kind): return varuint(index << 4 | kind)
+	def crdt_id(index, a, b): return tag(index, 0xF) + bytes([a]) + varuint(b)
+	def u32(index, value): return tag(index, 0x4) + struct.pack('<I', value)
+	def f32(index, value): return tag(index, 0x4) + struct.pack('<f', value)
+	def f64(index, value): return tag(index, 0x8) + struct.pack('<d', value)
+	def boolean(index, value): return tag(index, 0x1) + bytes([value])
+	def subblock(index, data): return tag(index, 0xC) + struct.pack('<I', len(data)) + data
+	def string(value): return varuint(len(value.encode())) + b"\x01" + value.encode()
+	def block(kind, data, version=1): return struct.pack('<IBBBB', len(data), 0, version, version, kind) + data
+
+	layer = (2, 10)
+	data = b"reMarkable .lines file, version=6".ljust(43)
+	data += block(0x01, crdt_id(1, *layer) + crdt_id(2, 0, 0) + boolean(3, True) + subblock(4, crdt_id(1, 0, 1)))
+	data += block(0x02, crdt_id(1, *layer) + subblock(2, crdt_id(1, 0, 0) + subblock(2, string("Layer 1"))) + subblock(3, crdt_id(1, 0, 0) + boolean(2, True)))
+	data += block(0x04, crdt_id(1, 0, 1) + crdt_id(2, 0, 11) + crdt_id(3, 0, 0) + crdt_id(4, 0, 0) + u32(5, 0) + subblock(6, b"\x02" + crdt_id(2, *layer)))
+
+	if points:
+		encoded = b"".join(struct.pack('<ffHHBB', x, y, 40, 8, 10, 150) for x, y in points)
+		line = u32(1, 17) + u32(2, 0) + f64(3, 2.0) + f32(4, 0) + subblock(5, encoded) + crdt_id(6, 0, 1)
+		data += block(0x05, crdt_id(1, *layer) + crdt_id(2, 2, 20) + crdt_id(3, 0, 0) + crdt_id(4, 0, 0) + u32(5, 0) + subblock(6, b"\x03" + line), 2)
+
+	if text:
+		items, left, counter = [], (0, 0), 16
+		for value, deleted_length in text:
+			item = crdt_id(2, 1, counter) + crdt_id(3, *left) + crdt_id(4, 0, 0) + u32(5, deleted_length)
+			if value is not None:
+				item += subblock(6, string(value))
+			items.append(subblock(0, item))
+			length = len(value) if value is not None else 1
+			left, counter = (1, counter + length - 1), counter + length
+		styles = subblock(2, subblock(1, varuint(0) if style is None else varuint(1) + bytes([0]) + varuint(0) + crdt_id(1, 0, 0) + subblock(2, bytes([17, style]))))
+		root_text = subblock(2, subblock(1, subblock(1, varuint(len(text)) + b"".join(items))) + styles)
+		data += block(0x07, crdt_id(1, 0, 0) + root_text + subblock(3, struct.pack('<dd', -468, 234)) + f32(4, 936))
+
+	return data
+
+
+def is_descendant(state, file, ancestor):
+	"""Returns True if file is ancestor or lives somewhere under it."""
+	seen = set()
+
+	while file is not None and file['id'] not in seen:
+		if file['id'] == ancestor['id']:
+			return True
+
+		seen.add(file['id'])
+		file = next((x for x in state if x['id'] == file['parent']), None)
+
+	return False
+
+
 def random_string(length):
 	return ''.join(random.choice(string.digits + string.punctuation + string.ascii_letters) for i in range(length))
 